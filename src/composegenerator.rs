@@ -1,3 +1,4 @@
 pub mod output;
+pub mod schema;
 pub mod types;
 pub mod v1;
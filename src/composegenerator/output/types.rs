@@ -1,4 +1,4 @@
-use crate::utils::{StringLike, StringOrNumber};
+use crate::utils::{is_false, StringLike, StringOrNumber};
 
 use super::super::types::Command;
 use schemars::JsonSchema;
@@ -9,17 +9,109 @@ use std::collections::BTreeMap;
 pub struct NetworkEntry {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ipv4_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_address: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub aliases: Vec<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(rename = "network")]
+pub struct Network {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "is_false", default)]
+    pub enable_ipv6: bool,
+}
+
+/// The services a service depends on, optionally with the condition to wait for
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum DependsOn {
+    List(Vec<String>),
+    Conditions(BTreeMap<String, DependsOnCondition>),
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+pub struct DependsOnCondition {
+    /// service_started, service_healthy or service_completed_successfully
+    pub condition: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+pub struct Logging {
+    pub driver: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub options: BTreeMap<String, String>,
+}
+
+/// A local image build
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+pub struct Build {
+    /// The build context, relative to the app's dir
+    #[serde(default = "default_build_context")]
+    pub context: String,
+    /// The name of the Dockerfile in the build context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub args: BTreeMap<String, String>,
+}
+
+/// How Docker checks whether a container works, durations are like 30s or 1m30s
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+pub struct Healthcheck {
+    /// The command to run, ["CMD", ...], ["CMD-SHELL", "..."] or a shell command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<Command>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_period: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_interval: Option<String>,
+    /// Disables the healthcheck of the image
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub disable: bool,
+}
+
+/// A resource limit, either one value for the soft and hard limit or both
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum Ulimit {
+    Single(i64),
+    SoftHard { soft: i64, hard: i64 },
+}
+
+fn default_build_context() -> String {
+    ".".to_owned()
 }
 
 #[derive(Clone, Default, Deserialize, Serialize, PartialEq, Debug, JsonSchema)]
 #[serde(rename = "service")]
 pub struct Service {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<Build>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub cap_add: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub command: Option<Command>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub depends_on: Option<Vec<String>>,
+    pub container_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depends_on: Option<DependsOn>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub devices: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns_opt: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns_search: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entrypoint: Option<Command>,
     #[serde(skip_serializing_if = "BTreeMap::is_empty")]
@@ -27,10 +119,16 @@ pub struct Service {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_hosts: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<Healthcheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<Logging>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub hostname: Option<String>,
     pub image: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub init: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub labels: BTreeMap<String, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub network_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -39,10 +137,18 @@ pub struct Service {
     pub ports: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restart: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub security_opt: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop_grace_period: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop_signal: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub sysctls: BTreeMap<String, StringOrNumber>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub tmpfs: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub ulimits: BTreeMap<String, Ulimit>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
@@ -59,4 +165,7 @@ pub struct ComposeSpecification {
     #[serde(default = "BTreeMap::default")]
     #[serde(skip_serializing_if = "BTreeMap::<String, Service>::is_empty")]
     pub services: BTreeMap<String, Service>,
+    #[serde(default = "BTreeMap::default")]
+    #[serde(skip_serializing_if = "BTreeMap::<String, Network>::is_empty")]
+    pub networks: BTreeMap<String, Network>,
 }
@@ -0,0 +1,109 @@
+//! JSON Schemas of the files apps consist of, for validation and autocompletion in editors
+
+use anyhow::{bail, Result};
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde_json::{json, Value};
+
+use crate::manage::settings::SettingsYml;
+
+use super::{types::ResultYml, v1};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaType {
+    /// app.yml
+    App,
+    /// metadata.yml
+    Metadata,
+    /// settings.yml
+    Settings,
+    /// result.yml, which Generate writes for every app
+    Result,
+}
+
+impl SchemaType {
+    fn name(self) -> &'static str {
+        match self {
+            SchemaType::App => "app",
+            SchemaType::Metadata => "metadata",
+            SchemaType::Settings => "settings",
+            SchemaType::Result => "result",
+        }
+    }
+
+    /// The values of the version field this file supports, empty if it has none
+    pub fn supported_versions(self) -> &'static [u8] {
+        match self {
+            SchemaType::App | SchemaType::Metadata => &[1],
+            SchemaType::Settings | SchemaType::Result => &[],
+        }
+    }
+}
+
+fn root_schema<T: JsonSchema>() -> Result<Value> {
+    let generator = SchemaSettings::draft07().into_generator();
+    Ok(serde_json::to_value(generator.into_root_schema_for::<T>())?)
+}
+
+/// Generates the schema of a file, for the given version or the newest one
+/// The `$id` is relative unless a base URL to publish the schemas at is given
+pub fn export(
+    schema_type: SchemaType,
+    version: Option<u8>,
+    base_url: Option<&str>,
+) -> Result<Value> {
+    let supported = schema_type.supported_versions();
+    let version = match (version, supported.last()) {
+        (Some(version), _) if supported.contains(&version) => Some(version),
+        (Some(version), None) => bail!("{} has no versions, got {}", schema_type.name(), version),
+        (Some(version), Some(_)) => bail!(
+            "Unsupported {} version {}, supported are {:?}",
+            schema_type.name(),
+            version,
+            supported
+        ),
+        (None, newest) => newest.copied(),
+    };
+    let mut schema = match (schema_type, version) {
+        (SchemaType::App, Some(1)) => root_schema::<v1::types::AppYml>()?,
+        (SchemaType::Metadata, Some(1)) => root_schema::<v1::types::MetadataYml>()?,
+        (SchemaType::Settings, _) => root_schema::<SettingsYml>()?,
+        (SchemaType::Result, _) => root_schema::<ResultYml>()?,
+        (schema_type, version) => {
+            bail!("No schema for {} version {:?}", schema_type.name(), version)
+        }
+    };
+    let file_name = match version {
+        Some(version) => {
+            // Editors pick the schema by the version the file declares
+            schema["properties"]["version"] = json!({ "const": version });
+            format!("{}.v{}.schema.json", schema_type.name(), version)
+        }
+        None => format!("{}.schema.json", schema_type.name()),
+    };
+    schema["$id"] = Value::String(match base_url {
+        Some(base_url) => format!("{}/{}", base_url.trim_end_matches('/'), file_name),
+        None => file_name,
+    });
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_versioned_schemas() {
+        let schema = export(SchemaType::App, None, Some("https://example.com/schemas/")).unwrap();
+        assert_eq!(
+            schema["$id"],
+            "https://example.com/schemas/app.v1.schema.json"
+        );
+        assert_eq!(schema["properties"]["version"]["const"], 1);
+        assert!(schema["definitions"]["Container"].is_object());
+
+        let schema = export(SchemaType::Settings, None, None).unwrap();
+        assert_eq!(schema["$id"], "settings.schema.json");
+        assert!(export(SchemaType::App, Some(2), None).is_err());
+        assert!(export(SchemaType::Result, Some(1), None).is_err());
+    }
+}
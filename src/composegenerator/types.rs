@@ -6,7 +6,7 @@ use std::collections::{BTreeMap, HashMap};
 
 use crate::{
     composegenerator::output::types::ComposeSpecification,
-    manage::ports::PortMapEntry,
+    manage::{dirs::DataDir, firewall::Protocol, ports::PortMapEntry},
     utils::{find_env_vars, is_false},
 };
 
@@ -35,6 +35,124 @@ impl Command {
     }
 }
 
+/// Where and how much of a container's logs are kept
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingOptions {
+    /// json-file, local, syslog, fluentd, gelf or none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    /// The maximum size of a log file before it is rotated, like 10m
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<String>,
+    /// The number of rotated log files to keep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file: Option<u32>,
+    /// The endpoint to forward logs to with the syslog, fluentd or gelf driver, like udp://10.21.0.2:514
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+/// A live tile the dashboard shows for an app
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Widget {
+    /// How the dashboard displays the data, like status or storage
+    #[serde(rename = "type")]
+    pub widget_type: String,
+    /// Where the dashboard fetches the data, an http URL of one of the app's services like http://main:8080/widget
+    /// In registry.json, the service is replaced by its container hostname
+    pub url: String,
+    /// How often the data is fetched again, in seconds
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval: u32,
+}
+
+fn default_refresh_interval() -> u32 {
+    60
+}
+
+/// A named link into the app the dashboard shows next to "Open"
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct AppAction {
+    /// Unique within the app, like admin or docs
+    pub id: String,
+    /// The label the dashboard shows
+    pub name: String,
+    /// The path on the app's own origin, like /admin
+    pub path: String,
+}
+
+/// A public port that needs to be reachable from the internet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct WanPort {
+    pub service: String,
+    pub public_port: u16,
+    pub protocol: Protocol,
+}
+
+/// Where a service exposes Prometheus metrics
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MetricsEndpoint {
+    /// The port inside the container
+    pub port: u16,
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_owned()
+}
+
+/// How much of the device's bandwidth a service gets when the link is busy
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BandwidthPriority {
+    /// Backups, downloads and sync, which only get what other services leave
+    Bulk,
+    #[default]
+    Normal,
+    /// Web UIs, calls and streaming
+    Interactive,
+}
+
+/// How host scripts should update an app's containers after it was regenerated
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateStrategy {
+    /// Replace the containers like docker compose up does
+    #[default]
+    Recreate,
+    /// Start the new containers before stopping the old ones, so both briefly run at the same time
+    Rolling,
+    /// Stop the whole app before starting the new containers, for apps that can't share their data
+    StopFirst,
+}
+
+/// Bandwidth priority and caps of a service, rates like 10mbit, 500kbit or 1gbit
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Bandwidth {
+    #[serde(default)]
+    pub priority: BandwidthPriority,
+    /// Traffic the service sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload: Option<String>,
+    /// Traffic the service receives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download: Option<String>,
+}
+
+/// A command host tooling runs periodically in one of the app's services
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Task {
+    pub name: String,
+    /// A cron schedule with 5 fields, or a shortcut like @daily
+    pub schedule: String,
+    /// The service the command is executed in
+    pub service: String,
+    pub command: Command,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(untagged)]
 pub enum Dependency {
@@ -69,6 +187,173 @@ pub struct Permission {
     pub hidden: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    /// Nothing is wrong, but users or reviewers should know about it
+    Info,
+    /// Something was skipped, but the app can still be installed
+    Warning,
+    /// The app could not be generated
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum DiagnosticCode {
+    InvalidMount,
+    /// The app references an app that is not available
+    MissingPermissionTarget,
+    PortConflict,
+    /// The app conflicts with an installed app
+    AppConflict,
+    /// A resource the app claims is granted to another app
+    ClaimConflict,
+    /// A dashboard widget is invalid or its URL is not one of the app's services
+    InvalidWidget,
+    /// An action has a duplicate id or a path that is not relative to the app
+    InvalidAction,
+    /// The category is neither a canonical one nor added by a store
+    UnknownCategory,
+    /// A template renders differently every time, so the app would be regenerated on every run
+    NondeterministicTemplate,
+    RenderFailed,
+    ConversionFailed,
+    /// The user's env vars for the app use vars the app has no permission for
+    InvalidUserEnv,
+    /// The app gets a permission it did not request because of what its app.yml uses
+    InferredPermission,
+    /// The app is updated with rolling updates, but publishes ports both containers would need
+    RollingUpdatePortClash,
+    /// An app the app depends on is installed, but disabled
+    DependencyDisabled,
+    /// The app.yml has a key its version does not know, which is ignored
+    UnknownKey,
+    /// The app requires a newer app manager, or its files have a newer version than this one supports
+    ManagerTooOld,
+}
+
+/// A problem found while generating an app
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostic {
+    pub code: DiagnosticCode,
+    pub severity: Severity,
+    pub message: String,
+    /// The path of the app.yml field the problem is in, if it is about a single field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn warning(code: DiagnosticCode, message: String, field: Option<String>) -> Self {
+        Self {
+            code,
+            severity: Severity::Warning,
+            message,
+            field,
+        }
+    }
+
+    pub fn info(code: DiagnosticCode, message: String, field: Option<String>) -> Self {
+        Self {
+            code,
+            severity: Severity::Info,
+            message,
+            field,
+        }
+    }
+
+    pub fn error(code: DiagnosticCode, message: String) -> Self {
+        Self {
+            code,
+            severity: Severity::Error,
+            message,
+            field: None,
+        }
+    }
+}
+
+/// What in an app.yml needs a permission
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PermissionSource {
+    /// A mount of another app's data
+    Mount { service: String, mount: String },
+    /// An env var used in a command, entrypoint, environment value or task
+    EnvVar { name: String },
+    /// network_mode: host
+    HostNetwork { service: String },
+    /// A capability in cap_add
+    Capability { service: String, capability: String },
+    /// A service built on the device
+    Build { service: String },
+    /// An option in security_opt that weakens the container's confinement
+    SecurityOpt { service: String, option: String },
+    /// A device of the host in devices
+    Device { service: String, device: String },
+}
+
+impl PermissionSource {
+    /// The path of the app.yml field that needs the permission, None for env vars, which can be anywhere
+    pub fn field(&self) -> Option<String> {
+        match self {
+            PermissionSource::Mount { service, mount } => {
+                Some(format!("services.{}.mounts.{}", service, mount))
+            }
+            PermissionSource::EnvVar { .. } => None,
+            PermissionSource::HostNetwork { service } => {
+                Some(format!("services.{}.network_mode", service))
+            }
+            PermissionSource::Capability { service, .. } => {
+                Some(format!("services.{}.cap_add", service))
+            }
+            PermissionSource::Build { service } => Some(format!("services.{}.build", service)),
+            PermissionSource::SecurityOpt { service, .. } => {
+                Some(format!("services.{}.security_opt", service))
+            }
+            PermissionSource::Device { service, .. } => {
+                Some(format!("services.{}.devices", service))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PermissionSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermissionSource::Mount { service, mount } => {
+                write!(f, "mount {} of service {}", mount, service)
+            }
+            PermissionSource::EnvVar { name } => write!(f, "env var {}", name),
+            PermissionSource::HostNetwork { service } => {
+                write!(f, "the host network mode of service {}", service)
+            }
+            PermissionSource::Capability {
+                service,
+                capability,
+            } => write!(f, "capability {} of service {}", capability, service),
+            PermissionSource::Build { service } => {
+                write!(f, "the local build of service {}", service)
+            }
+            PermissionSource::SecurityOpt { service, option } => {
+                write!(f, "security option {} of service {}", option, service)
+            }
+            PermissionSource::Device { service, device } => {
+                write!(f, "device {} of service {}", device, service)
+            }
+        }
+    }
+}
+
+/// A permission the app did not request, but gets because of what it uses
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InferredPermission {
+    pub permission: String,
+    pub source: PermissionSource,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct OutputMetadata {
@@ -89,8 +374,35 @@ pub struct OutputMetadata {
     #[serde(default)]
     /// Dependencies the app requires
     pub dependencies: Vec<Dependency>,
-    /// Other permissions the app has
+    /// Apps the app can use if they are installed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub optional_dependencies: Vec<String>,
+    /// Apps that can not be installed at the same time as this app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    /// Resources only one installed app can use
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub claims: Vec<String>,
+    /// Previous ids of the app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// Dashboard tiles, only set for apps that could be generated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub widgets: Vec<Widget>,
+    /// Links into the app besides path, only set for apps that could be generated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AppAction>,
+    /// For every dependency, the installed app that satisfies it, or None if none of its apps are installed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resolved_dependencies: Vec<Option<String>>,
+    /// Other permissions the app has, both requested and inferred
     pub has_permissions: Vec<String>,
+    /// Permissions the app requested in app_yml_jinja_permissions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requested_permissions: Vec<String>,
+    /// Permissions the app gets because of what its app.yml uses, once per use
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inferred_permissions: Vec<InferredPermission>,
     /// App repository name -> repo URL
     pub repo: BTreeMap<String, String>,
     /// A support link for the app
@@ -111,6 +423,8 @@ pub struct OutputMetadata {
     /// A list of containers to update automatically (still validated by the Citadel team)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub update_containers: Option<Vec<String>>,
+    #[serde(default)]
+    pub update_strategy: UpdateStrategy,
     /// For "virtual" apps, the service the app implements
     #[serde(skip_serializing_if = "Option::is_none")]
     pub implements: Option<String>,
@@ -123,6 +437,40 @@ pub struct OutputMetadata {
     #[serde(default, skip_serializing_if = "BTreeMap::<String, String>::is_empty")]
     pub release_notes: BTreeMap<String, String>,
     pub supports_https: bool,
+    /// Problems found while generating the app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    /// The app store the app was synced from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<String>,
+    /// True if the app comes from a local store and did not go through store review
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dev: bool,
+    /// True if the app was converted from a legacy Citadel app.yml on sync
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub legacy: bool,
+    /// True if the user took the app down for maintenance
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub maintenance: bool,
+    /// True if the app is installed, but the user disabled it
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub disabled: bool,
+    /// The version of the app's app.yml, only set for apps that could be generated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_yml_version: Option<u8>,
+    /// The oldest app manager version that can generate the app
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_manager_version: Option<String>,
+}
+
+/// How invalid declarations in an app.yml are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Skip them with a warning, used at runtime so one broken declaration doesn't break the app
+    #[default]
+    Permissive,
+    /// Fail the conversion, used by app store CI
+    Strict,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, JsonSchema)]
@@ -132,6 +480,12 @@ pub struct CaddyEntry {
     pub container_name: String,
     pub is_primary: bool,
     pub is_l4: bool,
+    /// Caddy also listens on IPv6
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ipv6: bool,
+    /// Serve the maintenance page instead of proxying to the app
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub maintenance: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, JsonSchema, Default)]
@@ -139,6 +493,17 @@ pub struct ResultYml {
     pub caddy_entries: Vec<CaddyEntry>,
     pub spec: ComposeSpecification,
     pub metadata: OutputMetadata,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<Task>,
+    /// Service -> its metrics endpoint
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, MetricsEndpoint>,
+    /// Public ports the router should forward from the internet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wan_ports: Vec<WanPort>,
+    /// Service -> its bandwidth settings
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bandwidth: BTreeMap<String, Bandwidth>,
 }
 
 #[non_exhaustive]
@@ -172,23 +537,77 @@ impl AppYml {
         }
     }
 
-    pub fn get_ports(&self, app_id: &str, implements: Option<String>) -> Vec<PortMapEntry> {
+    pub fn get_ports(
+        &self,
+        app_id: &str,
+        implements: Option<String>,
+        mode: ValidationMode,
+    ) -> Result<Vec<PortMapEntry>> {
+        match self {
+            AppYml::V1(app) => app.get_ports(app_id, implements, mode),
+        }
+    }
+
+    /// Returns (service, image) for every service, sorted by service name
+    pub fn get_images(&self) -> Vec<(String, String)> {
+        match self {
+            AppYml::V1(app) => {
+                let mut images = app
+                    .containers()
+                    .into_iter()
+                    .map(|(name, service)| (name.to_owned(), service.image.to_owned()))
+                    .collect::<Vec<_>>();
+                images.sort();
+                images
+            }
+        }
+    }
+
+    /// Replaces images the user pinned to another digest, image in the app.yml -> pinned image
+    pub fn pin_images(&mut self, pins: &BTreeMap<String, String>) {
         match self {
-            AppYml::V1(app) => app.get_ports(app_id, implements),
+            AppYml::V1(app) => {
+                let init_containers = app
+                    .init_containers
+                    .iter_mut()
+                    .map(|init| &mut init.container);
+                for container in app.services.values_mut().chain(init_containers) {
+                    if let Some(pinned) = pins.get(&container.image) {
+                        container.image = pinned.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get_data_dirs(&self) -> Vec<DataDir> {
+        match self {
+            AppYml::V1(app) => app.get_data_dirs(),
         }
     }
 
+    pub fn get_network_aliases(&self) -> Vec<String> {
+        match self {
+            AppYml::V1(app) => app.get_network_aliases(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn convert(
         &self,
         app_id: &str,
         port_map: &[PortMapEntry],
         metadata: MetadataYml,
         available_permissions: &HashMap<String, Vec<Permission>>,
+        mode: ValidationMode,
+        logging_defaults: &LoggingOptions,
+        ipv6: bool,
     ) -> Result<ResultYml> {
         match self {
             AppYml::V1(app) => {
                 #[allow(irrefutable_let_patterns)]
-                let MetadataYml::V1(metadata) = metadata else {
+                let MetadataYml::V1(metadata) = metadata
+                else {
                     return Err(anyhow!("Invalid metadata"));
                 };
                 super::v1::convert::convert_app_yml(
@@ -197,6 +616,9 @@ impl AppYml {
                     metadata.metadata,
                     port_map,
                     available_permissions,
+                    mode,
+                    logging_defaults,
+                    ipv6,
                 )
             }
         }
@@ -222,6 +644,67 @@ impl MetadataYml {
         }
     }
 
+    /// The optional dependencies that are installed
+    pub fn get_satisfied_optional_deps(&self, installed_apps: &[String]) -> Vec<String> {
+        match self {
+            MetadataYml::V1(metadata) => metadata
+                .metadata
+                .optional_dependencies
+                .iter()
+                .filter(|dep| installed_apps.contains(dep))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// The permissions app.yml.jinja gets, without those of optional dependencies that are not installed
+    pub fn get_usable_app_yml_jinja_permissions(&self, installed_apps: &[String]) -> Vec<String> {
+        match self {
+            MetadataYml::V1(metadata) => {
+                let optional_deps = &metadata.metadata.optional_dependencies;
+                metadata
+                    .metadata
+                    .app_yml_jinja_permissions
+                    .iter()
+                    .filter(|perm| {
+                        let app = perm.split('/').next().unwrap_or_default().to_owned();
+                        !optional_deps.contains(&app) || installed_apps.contains(&app)
+                    })
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+
+    /// Rewrites the app ids in dependencies and permissions
+    pub fn localize_ids(&mut self, localize: impl Fn(&str) -> String) {
+        match self {
+            MetadataYml::V1(metadata) => {
+                for dependency in &mut metadata.metadata.dependencies {
+                    match dependency {
+                        Dependency::OneDependency(app) => *app = localize(app),
+                        Dependency::AlternativeDependency(apps) => {
+                            for app in apps {
+                                *app = localize(app);
+                            }
+                        }
+                    }
+                }
+                for app in metadata
+                    .metadata
+                    .optional_dependencies
+                    .iter_mut()
+                    .chain(metadata.metadata.conflicts.iter_mut())
+                {
+                    *app = localize(app);
+                }
+                for permission in &mut metadata.metadata.app_yml_jinja_permissions {
+                    *permission = localize(permission);
+                }
+            }
+        }
+    }
+
     pub fn into_basic_output_metadata(self, app_id: String) -> OutputMetadata {
         match self {
             MetadataYml::V1(metadata) => OutputMetadata {
@@ -233,7 +716,16 @@ impl MetadataYml {
                 developers: metadata.metadata.developers,
                 description: metadata.metadata.description,
                 dependencies: metadata.metadata.dependencies,
-                has_permissions: metadata.metadata.app_yml_jinja_permissions,
+                optional_dependencies: metadata.metadata.optional_dependencies,
+                conflicts: metadata.metadata.conflicts,
+                claims: metadata.metadata.claims,
+                aliases: metadata.metadata.aliases,
+                widgets: Vec::new(),
+                actions: Vec::new(),
+                resolved_dependencies: Vec::new(),
+                has_permissions: metadata.metadata.app_yml_jinja_permissions.clone(),
+                requested_permissions: metadata.metadata.app_yml_jinja_permissions,
+                inferred_permissions: Vec::new(),
                 repo: metadata.metadata.repo,
                 support: metadata.metadata.support,
                 gallery: metadata.metadata.gallery,
@@ -242,6 +734,7 @@ impl MetadataYml {
                 default_password: metadata.metadata.default_password,
                 tor_only: metadata.metadata.tor_only,
                 update_containers: metadata.metadata.update_containers,
+                update_strategy: metadata.metadata.update_strategy,
                 implements: metadata.metadata.implements,
                 version_control: metadata.metadata.version_control,
                 // This is only metadata for an app that's not installable, so compatible can never be true
@@ -250,6 +743,14 @@ impl MetadataYml {
                 port: 0,
                 internal_port: 0,
                 supports_https: false,
+                diagnostics: Vec::new(),
+                store: None,
+                dev: false,
+                legacy: metadata.metadata.legacy,
+                maintenance: false,
+                disabled: false,
+                app_yml_version: None,
+                min_manager_version: metadata.metadata.min_manager_version,
             },
         }
     }
@@ -267,7 +768,16 @@ impl MetadataYml {
                     developers: metadata.developers,
                     description: metadata.description,
                     dependencies: metadata.dependencies,
-                    has_permissions: metadata.app_yml_jinja_permissions,
+                    optional_dependencies: metadata.optional_dependencies,
+                    conflicts: metadata.conflicts,
+                    claims: metadata.claims,
+                    aliases: metadata.aliases,
+                    widgets: Vec::new(),
+                    actions: Vec::new(),
+                    resolved_dependencies: Vec::new(),
+                    has_permissions: metadata.app_yml_jinja_permissions.clone(),
+                    requested_permissions: metadata.app_yml_jinja_permissions,
+                    inferred_permissions: Vec::new(),
                     repo: metadata.repo,
                     support: metadata.support,
                     gallery: metadata.gallery,
@@ -276,6 +786,7 @@ impl MetadataYml {
                     default_password: metadata.default_password,
                     tor_only: metadata.tor_only,
                     update_containers: metadata.update_containers,
+                    update_strategy: metadata.update_strategy,
                     implements: metadata.implements,
                     version_control: metadata.version_control,
                     // This is only metadata for an app that's not installable, so compatible can never be true
@@ -284,6 +795,14 @@ impl MetadataYml {
                     port: 0,
                     internal_port: 0,
                     supports_https: false,
+                    diagnostics: Vec::new(),
+                    store: None,
+                    dev: false,
+                    legacy: metadata.legacy,
+                    maintenance: false,
+                    disabled: false,
+                    app_yml_version: None,
+                    min_manager_version: metadata.min_manager_version,
                 }
             }
         }
@@ -1,40 +1,78 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 use super::{
-    helpers::find_permission_that_matches,
+    helpers::{
+        device_permission, find_permission_that_matches, is_valid_build, is_valid_data_mount,
+        is_valid_dns_opt, is_valid_dns_server, is_valid_domain, is_valid_duration,
+        is_valid_relative_path, is_valid_restart_policy, is_valid_schedule, is_valid_size,
+        is_valid_sysctl, is_valid_tmpfs, parse_duration, security_opt_permission, ULIMITS,
+    },
     types::{AppYml, Container, InputMetadata as Metadata, StringOrMap},
 };
 use crate::{
     composegenerator::{
-        output::types::Service,
-        types::{CaddyEntry, OutputMetadata, Permission, ResultYml},
+        output::types::{
+            DependsOn, DependsOnCondition, Logging, Network, NetworkEntry, Service, Ulimit,
+        },
+        types::{
+            CaddyEntry, Command, Dependency, Diagnostic, DiagnosticCode, InferredPermission,
+            LoggingOptions, OutputMetadata, Permission, PermissionSource, ResultYml,
+            UpdateStrategy, ValidationMode, WanPort,
+        },
+    },
+    manage::{
+        dns::{alias_claim, hostname, NETWORK_NAME},
+        firewall::Protocol,
+        ports::PortMapEntry,
+        traffic,
     },
-    manage::ports::PortMapEntry,
     utils::{find_env_vars, StringLike},
 };
 
-static ALLOWED_ENV_VARS: [&str; 3] = ["API_IP", "DEVICE_HOSTNAME", "DEVICE_IP"];
+static ALLOWED_ENV_VARS: [&str; 4] = ["API_IP", "DEVICE_HOSTNAME", "DEVICE_IP", "DEVICE_IPV6"];
 
-macro_rules! require_permission_metadata {
-    ($metadata:ident, $perm_name:expr) => {
-        if !$metadata.has_permissions.contains(&$perm_name.to_owned()) {
-            $metadata.has_permissions.push($perm_name.to_owned());
+/// Dashboards fetch widget data at most this often, in seconds
+const MIN_WIDGET_REFRESH_INTERVAL: u32 = 5;
+
+/// Fails in strict mode, otherwise records a warning and skips to the next declaration
+macro_rules! skip_invalid {
+    ($mode:expr, $metadata:expr, $code:expr, $field:expr, $($arg:tt)+) => {
+        let message = format!($($arg)+);
+        if $mode == ValidationMode::Strict {
+            bail!(message);
         }
+        tracing::warn!("{}", message);
+        $metadata
+            .diagnostics
+            .push(Diagnostic::warning($code, message, Some($field)));
+        continue;
     };
 }
 
-macro_rules! require_permission {
-    ($result:ident, $perm_name:expr) => {
-        if !$result
-            .metadata
-            .has_permissions
-            .contains(&$perm_name.to_owned())
-        {
-            $result.metadata.has_permissions.push($perm_name.to_owned());
+/// Adds a permission the app needs, recording where it is used unless the app requested it
+fn require_permission(metadata: &mut OutputMetadata, permission: String, source: PermissionSource) {
+    if !metadata.requested_permissions.contains(&permission) {
+        let inferred = InferredPermission {
+            permission: permission.clone(),
+            source,
+        };
+        if !metadata.inferred_permissions.contains(&inferred) {
+            metadata.diagnostics.push(Diagnostic::info(
+                DiagnosticCode::InferredPermission,
+                format!(
+                    "Gets the {} permission because of {}",
+                    inferred.permission, inferred.source
+                ),
+                inferred.source.field(),
+            ));
+            metadata.inferred_permissions.push(inferred);
         }
-    };
+    }
+    if !metadata.has_permissions.contains(&permission) {
+        metadata.has_permissions.push(permission);
+    }
 }
 
 fn validate_env_access(
@@ -55,75 +93,117 @@ fn validate_env_access(
             .map(|cmd| cmd.get_env_vars())
             .unwrap_or_default();
         accessed_env_vars.extend(env_vars_in_entrypoint);
+        let env_vars_in_healthcheck = service
+            .healthcheck
+            .as_ref()
+            .and_then(|healthcheck| healthcheck.test.as_ref())
+            .map(|test| test.get_env_vars())
+            .unwrap_or_default();
+        accessed_env_vars.extend(env_vars_in_healthcheck);
+        for server in &service.dns {
+            accessed_env_vars.extend(find_env_vars(server));
+        }
         for value in service.environment.values() {
             if let StringLike::String(value) = value {
                 accessed_env_vars.extend(find_env_vars(value));
             }
         }
     }
+    for task in &result.tasks {
+        accessed_env_vars.extend(task.command.get_env_vars());
+    }
     for env_var in accessed_env_vars {
-        if !ALLOWED_ENV_VARS.contains(&env_var) {
-            if env_var.starts_with("APP_") {
-                let mut split = env_var.split('_');
-                if split.next() != Some("APP") {
-                    unreachable!();
-                }
-                let Some(app_name) = split.next() else {
-                    require_permission!(result, "root");
-                    continue;
-                };
-                // Because next() is called twice, the iterator is at different elements for the first and second check
-                if split.next().is_none() || split.next().is_some() {
-                    require_permission!(result, "root");
-                } else {
-                    let app_permissions = available_permissions
-                        .get(app_name)
-                        .cloned()
-                        .unwrap_or_default();
-                    let ideal_permission = find_permission_that_matches(
-                        app_name,
-                        &app_permissions,
-                        &result.metadata.has_permissions,
-                        |perm| {
-                            perm.variables.iter().any(|(name, value)| {
-                                name == env_var
-                                    && (value.as_str() == Some(&format!("${}", env_var))
-                                        || value.as_str() == Some(&format!("${{{}}}", env_var)))
-                            })
-                        },
-                    );
-                    if let Some(permission) = ideal_permission {
-                        require_permission!(result, format!("{}/{}", app_name, permission.id));
-                    } else {
-                        require_permission!(result, app_name);
-                    }
-                }
-            } else {
-                require_permission!(result, "root");
-            }
+        let Some(permission) = env_var_permission(
+            env_var,
+            &result.metadata.has_permissions,
+            available_permissions,
+        ) else {
+            continue;
+        };
+        let target_app = permission.split('/').next().unwrap_or_default();
+        if permission != "root" && !available_permissions.contains_key(target_app) {
+            result.metadata.diagnostics.push(Diagnostic::warning(
+                DiagnosticCode::MissingPermissionTarget,
+                format!(
+                    "Env var {} refers to unavailable app {}",
+                    env_var, target_app
+                ),
+                None,
+            ));
         }
+        require_permission(
+            &mut result.metadata,
+            permission,
+            PermissionSource::EnvVar {
+                name: env_var.to_owned(),
+            },
+        );
+    }
+}
+
+/// Returns the permission an app needs to access an env var, or None if every app can access it
+pub fn env_var_permission(
+    env_var: &str,
+    current_permissions: &[String],
+    available_permissions: &HashMap<String, Vec<Permission>>,
+) -> Option<String> {
+    if ALLOWED_ENV_VARS.contains(&env_var) {
+        return None;
+    }
+    if !env_var.starts_with("APP_") {
+        return Some("root".to_owned());
+    }
+    let mut split = env_var.split('_');
+    if split.next() != Some("APP") {
+        unreachable!();
+    }
+    let Some(app_name) = split.next() else {
+        return Some("root".to_owned());
+    };
+    // Because next() is called twice, the iterator is at different elements for the first and second check
+    if split.next().is_none() || split.next().is_some() {
+        return Some("root".to_owned());
     }
+    let app_permissions = available_permissions
+        .get(app_name)
+        .cloned()
+        .unwrap_or_default();
+    let ideal_permission =
+        find_permission_that_matches(app_name, &app_permissions, current_permissions, |perm| {
+            perm.variables.iter().any(|(name, value)| {
+                name == env_var
+                    && (value.as_str() == Some(&format!("${}", env_var))
+                        || value.as_str() == Some(&format!("${{{}}}", env_var)))
+            })
+        });
+    Some(match ideal_permission {
+        Some(permission) => format!("{}/{}", app_name, permission.id),
+        None => app_name.to_owned(),
+    })
 }
 
 pub fn convert_mounts(
+    service_name: &str,
     result: &mut Service,
     input_service: &Container,
     metadata: &mut OutputMetadata,
     available_permissions: &HashMap<String, Vec<Permission>>,
+    mode: ValidationMode,
 ) -> Result<()> {
     for (mount_name, target) in &input_service.mounts {
+        let field = format!("services.{}.mounts.{}", service_name, mount_name);
         match (mount_name.as_str(), target) {
             ("data", StringOrMap::Map(map)) => {
                 for (host_dir, container_dir) in map {
-                    if host_dir.contains(':')
-                        || host_dir.contains("..")
-                        || container_dir.contains(':')
-                        || container_dir.contains("..")
-                        || !find_env_vars(host_dir).is_empty()
-                        || !find_env_vars(container_dir).is_empty()
-                    {
-                        tracing::warn!("Invalid mount name: {}", mount_name);
-                        continue;
+                    if !is_valid_data_mount(host_dir, container_dir) {
+                        skip_invalid!(
+                            mode,
+                            metadata,
+                            DiagnosticCode::InvalidMount,
+                            format!("{}.{}", field, host_dir),
+                            "Invalid mount name: {}",
+                            mount_name
+                        );
                     }
                     result
                         .volumes
@@ -136,8 +216,14 @@ pub fn convert_mounts(
                     || mount_name.contains(':')
                     || mount_name.contains("..")
                 {
-                    tracing::warn!("Invalid mount name: {}", mount_name);
-                    continue;
+                    skip_invalid!(
+                        mode,
+                        metadata,
+                        DiagnosticCode::InvalidMount,
+                        field,
+                        "Invalid mount name: {}",
+                        mount_name
+                    );
                 }
                 match mount_name {
                     "jwt-pubkey" => {
@@ -146,10 +232,31 @@ pub fn convert_mounts(
                     mount_name => {
                         let split = mount_name.split('/').collect::<Vec<_>>();
                         if split.len() > 2 {
-                            tracing::warn!("Invalid mount name: {}", mount_name);
-                            continue;
-                        } else if split.len() == 2 {
-                            let app_name = split[0];
+                            skip_invalid!(
+                                mode,
+                                metadata,
+                                DiagnosticCode::InvalidMount,
+                                field,
+                                "Invalid mount name: {}",
+                                mount_name
+                            );
+                        }
+                        let app_name = split[0];
+                        let source = PermissionSource::Mount {
+                            service: service_name.to_owned(),
+                            mount: mount_name.to_owned(),
+                        };
+                        if !available_permissions.contains_key(app_name) {
+                            metadata.diagnostics.push(Diagnostic::warning(
+                                DiagnosticCode::MissingPermissionTarget,
+                                format!(
+                                    "Mount {} refers to unavailable app {}",
+                                    mount_name, app_name
+                                ),
+                                Some(field.clone()),
+                            ));
+                        }
+                        if split.len() == 2 {
                             let mount_name = split[1];
                             let app_permissions = available_permissions
                                 .get(app_name)
@@ -165,25 +272,26 @@ pub fn convert_mounts(
                                 "${{APPS_DATA_DIR}}/{}/{}:{}",
                                 app_name, mount_name, str
                             ));
-                            if let Some(permission) = ideal_permission {
-                                require_permission_metadata!(
-                                    metadata,
-                                    format!("{}/{}", app_name, permission.id)
-                                );
-                            } else {
-                                require_permission_metadata!(metadata, app_name);
-                            }
+                            let permission = match ideal_permission {
+                                Some(permission) => format!("{}/{}", app_name, permission.id),
+                                None => app_name.to_owned(),
+                            };
+                            require_permission(metadata, permission, source);
                         } else {
                             result
                                 .volumes
                                 .push(format!("${{APPS_DATA_DIR}}/{}:{}", mount_name, str));
-                            require_permission_metadata!(metadata, mount_name);
+                            require_permission(metadata, mount_name.to_owned(), source);
                         }
                     }
                 }
             }
             _ => {
-                tracing::warn!(
+                skip_invalid!(
+                    mode,
+                    metadata,
+                    DiagnosticCode::InvalidMount,
+                    field,
                     "Failed to parse mount {}: {:?} of app {}",
                     mount_name,
                     target,
@@ -195,11 +303,30 @@ pub fn convert_mounts(
     Ok(())
 }
 
+/// The compose bindings of a public port, which is only published on IPv6 if both the port and the config enable it
+fn port_bindings(
+    public_port: u16,
+    internal_port: &str,
+    ipv6_enabled: bool,
+    port_ipv6: bool,
+) -> Vec<String> {
+    let binding = format!("{}:{}", public_port, internal_port);
+    if !ipv6_enabled {
+        return vec![binding];
+    }
+    let mut bindings = vec![format!("0.0.0.0:{}", binding)];
+    if port_ipv6 {
+        bindings.push(format!("[::]:{}", binding));
+    }
+    bindings
+}
+
 fn handle_ports(
     service_name: &str,
     result: &mut Service,
     input_service: &Container,
     port_map: &[PortMapEntry],
+    ipv6: bool,
 ) -> Result<Vec<CaddyEntry>> {
     let mut new_caddy_entries = Vec::new();
     if service_name == "main" {
@@ -211,9 +338,12 @@ fn handle_ports(
             .find(|port| port.internal_port == main_port && port.container == service_name)
             .ok_or_else(|| anyhow!("No port map entry found for port {}", main_port))?;
         if input_service.disable_caddy {
-            result
-                .ports
-                .push(format!("{}:{}", port_map_entry.public_port, main_port));
+            result.ports.extend(port_bindings(
+                port_map_entry.public_port,
+                &main_port.to_string(),
+                ipv6,
+                input_service.ipv6,
+            ));
         } else {
             new_caddy_entries.push(CaddyEntry {
                 public_port: port_map_entry.public_port,
@@ -221,63 +351,330 @@ fn handle_ports(
                 container_name: service_name.to_string(),
                 is_primary: true,
                 is_l4: input_service.direct_tcp,
+                ipv6: ipv6 && input_service.ipv6,
+                maintenance: false,
             });
         }
     }
-    for (public_port, internal_port) in &input_service.required_ports.http {
-        // Just a check, this should always be validated before
-        assert!(port_map
-            .iter()
-            .any(|port| port.internal_port == *internal_port && port.container == service_name));
-        new_caddy_entries.push(CaddyEntry {
-            public_port: *public_port,
-            internal_port: *internal_port,
-            container_name: service_name.to_string(),
-            is_primary: false,
-            is_l4: false,
-        });
+    let required_ports = &input_service.required_ports;
+    for (protocol, ports) in [("http", &required_ports.http), ("tcp", &required_ports.tcp)] {
+        for (public_port, target) in ports {
+            // Just a check, this should always be validated before
+            assert!(port_map
+                .iter()
+                .any(|port| port.internal_port == target.port() && port.container == service_name));
+            new_caddy_entries.push(CaddyEntry {
+                public_port: *public_port,
+                internal_port: target.port(),
+                container_name: service_name.to_string(),
+                is_primary: false,
+                is_l4: protocol == "tcp",
+                ipv6: ipv6 && target.ipv6(),
+                maintenance: false,
+            });
+        }
     }
-    for (public_port, internal_port) in &input_service.required_ports.tcp {
-        // Just a check, this should always be validated before
-        assert!(port_map
-            .iter()
-            .any(|port| port.internal_port == *internal_port && port.container == service_name));
-        new_caddy_entries.push(CaddyEntry {
-            public_port: *public_port,
-            internal_port: *internal_port,
-            container_name: service_name.to_string(),
-            is_primary: false,
-            is_l4: true,
-        });
+    for (suffix, ports) in [
+        ("", &required_ports.direct_tcp),
+        ("/udp", &required_ports.udp),
+    ] {
+        for (public_port, target) in ports {
+            // Just a check, this should always be validated before
+            assert!(port_map
+                .iter()
+                .any(|port| port.internal_port == target.port() && port.container == service_name));
+            result.ports.extend(port_bindings(
+                *public_port,
+                &format!("{}{}", target.port(), suffix),
+                ipv6,
+                target.ipv6(),
+            ));
+        }
     }
-    for (public_port, internal_port) in &input_service.required_ports.direct_tcp {
-        // Just a check, this should always be validated before
-        assert!(port_map
+
+    Ok(new_caddy_entries)
+}
+
+/// The public ports of a service that have wan_required set
+fn get_wan_ports(service_name: &str, input_service: &Container) -> Vec<WanPort> {
+    let required_ports = &input_service.required_ports;
+    let mut wan_ports = [
+        (Protocol::Tcp, &required_ports.http),
+        (Protocol::Tcp, &required_ports.tcp),
+        (Protocol::Tcp, &required_ports.direct_tcp),
+        (Protocol::Udp, &required_ports.udp),
+    ]
+    .into_iter()
+    .flat_map(|(protocol, ports)| {
+        ports
             .iter()
-            .any(|port| port.internal_port == *internal_port && port.container == service_name));
-        result
-            .ports
-            .push(format!("{}:{}", public_port, internal_port));
+            .filter(|(_, target)| target.wan_required())
+            .map(move |(public_port, _)| WanPort {
+                service: service_name.to_owned(),
+                public_port: *public_port,
+                protocol,
+            })
+    })
+    .collect::<Vec<_>>();
+    wan_ports.sort_by_key(|port| (port.public_port, port.protocol));
+    wan_ports
+}
+
+/// Labels that identify containers and networks managed by Nirvati
+fn nirvati_labels(app_id: &str, version: &str, service: Option<&str>) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::from([
+        ("nirvati.app".to_owned(), app_id.to_owned()),
+        ("nirvati.version".to_owned(), version.to_owned()),
+        ("nirvati.managed".to_owned(), "true".to_owned()),
+    ]);
+    if let Some(service) = service {
+        labels.insert("nirvati.service".to_owned(), service.to_owned());
     }
-    for (public_port, internal_port) in &input_service.required_ports.udp {
-        // Just a check, this should always be validated before
-        assert!(port_map
-            .iter()
-            .any(|port| port.internal_port == *internal_port && port.container == service_name));
-        result
-            .ports
-            .push(format!("{}:{}/udp", public_port, internal_port));
+    labels
+}
+
+/// Makes init containers run in order, and services wait until the last one completed,
+/// except services an init container depends on, which have to run during initialization
+fn order_init_containers(result: &mut ResultYml, app_yml: &AppYml) {
+    let Some(last_init) = app_yml.init_containers.last() else {
+        return;
+    };
+    let needed_by_init = app_yml
+        .init_containers
+        .iter()
+        .flat_map(|init| init.container.depends_on.iter().flatten())
+        .collect::<Vec<_>>();
+    // (service, init container it waits for)
+    let mut waits_for = app_yml
+        .init_containers
+        .windows(2)
+        .map(|pair| (pair[1].name.clone(), pair[0].name.clone()))
+        .collect::<Vec<_>>();
+    waits_for.extend(
+        app_yml
+            .services
+            .keys()
+            .filter(|service| !needed_by_init.contains(service))
+            .map(|service| (service.clone(), last_init.name.clone())),
+    );
+    for (service_id, init) in waits_for {
+        let Some(service) = result.spec.services.get_mut(&service_id) else {
+            continue;
+        };
+        let started = |service: &String| {
+            (
+                service.clone(),
+                DependsOnCondition {
+                    condition: "service_started".to_owned(),
+                },
+            )
+        };
+        let mut conditions = match service.depends_on.take() {
+            Some(DependsOn::List(services)) => services.iter().map(started).collect(),
+            Some(DependsOn::Conditions(conditions)) => conditions,
+            None => BTreeMap::new(),
+        };
+        conditions.insert(
+            init,
+            DependsOnCondition {
+                condition: "service_completed_successfully".to_owned(),
+            },
+        );
+        service.depends_on = Some(DependsOn::Conditions(conditions));
     }
+}
 
-    Ok(new_caddy_entries)
+/// The image of the containers that wait for other apps, its nc, wget and timeout are used
+const WAIT_FOR_IMAGE: &str = "busybox:1.36";
+
+/// How long a container waits for another app if wait_for sets no timeout
+const DEFAULT_WAIT_FOR_TIMEOUT: u64 = 60;
+
+/// Adds a one-shot container for every other app a container waits for, which exits once the app is ready,
+/// and makes the containers wait until it completed
+fn add_wait_containers(result: &mut ResultYml, app_id: &str, app_yml: &AppYml) -> Result<()> {
+    for (service_id, service) in app_yml.containers() {
+        for wait_for in &service.wait_for {
+            let is_dependency =
+                result
+                    .metadata
+                    .dependencies
+                    .iter()
+                    .any(|dependency| match dependency {
+                        Dependency::OneDependency(dep) => dep == &wait_for.app,
+                        Dependency::AlternativeDependency(deps) => deps.contains(&wait_for.app),
+                    });
+            if !is_dependency || wait_for.app == app_id {
+                bail!(
+                    "Service {} can only wait for apps the app depends on, not {}",
+                    service_id,
+                    wait_for.app
+                );
+            }
+            let timeout = match &wait_for.timeout {
+                Some(timeout) => parse_duration(timeout)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid wait_for timeout {} for service {}, use a duration like 60s or 2m",
+                            timeout,
+                            service_id
+                        )
+                    })?
+                    .as_secs_f64()
+                    .ceil() as u64,
+                None => DEFAULT_WAIT_FOR_TIMEOUT,
+            };
+            let host = hostname(&wait_for.app, &wait_for.service);
+            // No $ in the script, compose would interpolate it
+            let check = match &wait_for.endpoint {
+                Some(endpoint) if is_valid_relative_path(endpoint) => format!(
+                    "wget -q -T 2 -O /dev/null http://{}:{}{}",
+                    host, wait_for.port, endpoint
+                ),
+                Some(endpoint) => bail!(
+                    "Invalid wait_for endpoint {} for service {}, it has to be a path like /health",
+                    endpoint,
+                    service_id
+                ),
+                None => format!("nc -z -w 2 {} {}", host, wait_for.port),
+            };
+            let name = format!("wait-for-{}-{}", wait_for.app, wait_for.service);
+            if app_yml.containers().iter().any(|(id, _)| **id == name) {
+                bail!(
+                    "The service name {} is used for waiting for {}",
+                    name,
+                    wait_for.app
+                );
+            }
+            result
+                .spec
+                .services
+                .entry(name.clone())
+                .or_insert_with(|| Service {
+                    image: WAIT_FOR_IMAGE.to_owned(),
+                    restart: Some("no".to_owned()),
+                    command: Some(Command::ArraySyntax(vec![
+                        "timeout".to_owned(),
+                        timeout.to_string(),
+                        "sh".to_owned(),
+                        "-c".to_owned(),
+                        format!("until {}; do sleep 2; done", check),
+                    ])),
+                    labels: nirvati_labels(app_id, &result.metadata.version, Some(&name)),
+                    ..Default::default()
+                });
+            let Some(result_service) = result.spec.services.get_mut(service_id) else {
+                continue;
+            };
+            let mut conditions = match result_service.depends_on.take() {
+                Some(DependsOn::List(services)) => services
+                    .into_iter()
+                    .map(|service| {
+                        (
+                            service,
+                            DependsOnCondition {
+                                condition: "service_started".to_owned(),
+                            },
+                        )
+                    })
+                    .collect(),
+                Some(DependsOn::Conditions(conditions)) => conditions,
+                None => BTreeMap::new(),
+            };
+            conditions.insert(
+                name,
+                DependsOnCondition {
+                    condition: "service_completed_successfully".to_owned(),
+                },
+            );
+            result_service.depends_on = Some(DependsOn::Conditions(conditions));
+        }
+    }
+    Ok(())
+}
+
+/// Merges a container's logging options with the defaults, the defaults' address is only used with their driver
+fn convert_logging(
+    options: Option<&LoggingOptions>,
+    defaults: &LoggingOptions,
+) -> Result<Option<Logging>> {
+    let options = options.cloned().unwrap_or_default();
+    let uses_default_driver = options.driver.is_none() || options.driver == defaults.driver;
+    let merged = LoggingOptions {
+        driver: options.driver.clone().or_else(|| defaults.driver.clone()),
+        max_size: options
+            .max_size
+            .clone()
+            .or_else(|| defaults.max_size.clone()),
+        max_file: options.max_file.or(defaults.max_file),
+        address: options.address.clone().or_else(|| {
+            uses_default_driver
+                .then(|| defaults.address.clone())
+                .flatten()
+        }),
+    };
+    if merged == LoggingOptions::default() {
+        return Ok(None);
+    }
+    let driver = merged.driver.unwrap_or_else(|| "json-file".to_owned());
+    let mut logging = Logging {
+        driver,
+        options: BTreeMap::new(),
+    };
+    let rotates = matches!(logging.driver.as_str(), "json-file" | "local");
+    let address_option = match logging.driver.as_str() {
+        "syslog" => Some("syslog-address"),
+        "fluentd" => Some("fluentd-address"),
+        "gelf" => Some("gelf-address"),
+        "json-file" | "local" | "none" => None,
+        driver => bail!("Unsupported logging driver {}", driver),
+    };
+    if rotates {
+        if let Some(max_size) = merged.max_size {
+            if !is_valid_size(&max_size) {
+                bail!("Invalid max_size {}, use a size like 10m", max_size);
+            }
+            logging.options.insert("max-size".to_owned(), max_size);
+        }
+        if let Some(max_file) = merged.max_file {
+            logging
+                .options
+                .insert("max-file".to_owned(), max_file.to_string());
+        }
+    } else if options.max_size.is_some() || options.max_file.is_some() {
+        bail!(
+            "The {} logging driver does not rotate files",
+            logging.driver
+        );
+    }
+    match (address_option, merged.address) {
+        (Some(option), Some(address)) => {
+            logging.options.insert(option.to_owned(), address);
+        }
+        (Some(_), None) if logging.driver == "gelf" => {
+            bail!("The gelf logging driver needs an address")
+        }
+        (None, Some(_)) if options.address.is_some() => {
+            bail!(
+                "The {} logging driver does not forward logs",
+                logging.driver
+            )
+        }
+        _ => {}
+    }
+    Ok(Some(logging))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn convert_app_yml(
     app_id: &str,
     app_yml: &AppYml,
     metadata: Metadata,
     port_map: &[PortMapEntry],
     available_permissions: &HashMap<String, Vec<Permission>>,
+    mode: ValidationMode,
+    logging_defaults: &LoggingOptions,
+    ipv6: bool,
 ) -> Result<ResultYml> {
     let mut result = ResultYml::default();
     let main_port;
@@ -307,7 +704,16 @@ pub fn convert_app_yml(
         developers: metadata.developers,
         description: metadata.description,
         dependencies: metadata.dependencies,
-        has_permissions: metadata.app_yml_jinja_permissions,
+        optional_dependencies: metadata.optional_dependencies,
+        conflicts: metadata.conflicts,
+        claims: metadata.claims,
+        aliases: metadata.aliases,
+        widgets: Vec::new(),
+        actions: Vec::new(),
+        resolved_dependencies: Vec::new(),
+        has_permissions: metadata.app_yml_jinja_permissions.clone(),
+        requested_permissions: metadata.app_yml_jinja_permissions,
+        inferred_permissions: Vec::new(),
         repo: metadata.repo,
         support: metadata.support,
         gallery: metadata.gallery,
@@ -316,6 +722,7 @@ pub fn convert_app_yml(
         default_password: metadata.default_password,
         tor_only: metadata.tor_only,
         update_containers: metadata.update_containers,
+        update_strategy: metadata.update_strategy,
         implements: metadata.implements,
         version_control: metadata.version_control,
         // This is only metadata for an app that's compatible
@@ -324,17 +731,311 @@ pub fn convert_app_yml(
         port: main_port_public,
         internal_port: main_port,
         supports_https,
+        diagnostics: Vec::new(),
+        store: None,
+        dev: false,
+        legacy: metadata.legacy,
+        maintenance: false,
+        disabled: false,
+        app_yml_version: Some(1),
+        min_manager_version: metadata.min_manager_version,
     };
-    for (service_id, service) in &app_yml.services {
-        // These properties need no validation
+    for (index, mut widget) in metadata.widgets.into_iter().enumerate() {
+        let field = format!("widgets.{}", index);
+        if widget.refresh_interval < MIN_WIDGET_REFRESH_INTERVAL {
+            skip_invalid!(
+                mode,
+                result.metadata,
+                DiagnosticCode::InvalidWidget,
+                field,
+                "Widget {} refreshes more often than every {} seconds",
+                index,
+                MIN_WIDGET_REFRESH_INTERVAL
+            );
+        }
+        let Some((service, port_and_path)) = widget
+            .url
+            .strip_prefix("http://")
+            .and_then(|url| url.split_once(':'))
+            .filter(|(service, _)| app_yml.services.contains_key(*service))
+        else {
+            skip_invalid!(
+                mode,
+                result.metadata,
+                DiagnosticCode::InvalidWidget,
+                field,
+                "Widget URL {} has to be http://<service>:<port>/<path> with one of the app's services",
+                widget.url
+            );
+        };
+        widget.url = format!("http://{}:{}", hostname(app_id, service), port_and_path);
+        result.metadata.widgets.push(widget);
+    }
+    for (index, action) in metadata.actions.into_iter().enumerate() {
+        let field = format!("actions.{}", index);
+        if result
+            .metadata
+            .actions
+            .iter()
+            .any(|other| other.id == action.id)
+        {
+            skip_invalid!(
+                mode,
+                result.metadata,
+                DiagnosticCode::InvalidAction,
+                field,
+                "Action id {} is used more than once",
+                action.id
+            );
+        }
+        if !is_valid_relative_path(&action.path) {
+            skip_invalid!(
+                mode,
+                result.metadata,
+                DiagnosticCode::InvalidAction,
+                field,
+                "Action {} has to link to a path like /admin on the app, not {}",
+                action.id,
+                action.path
+            );
+        }
+        result.metadata.actions.push(action);
+    }
+    for (index, init) in app_yml.init_containers.iter().enumerate() {
+        if app_yml.services.contains_key(&init.name)
+            || app_yml.init_containers[..index]
+                .iter()
+                .any(|other| other.name == init.name)
+        {
+            bail!(
+                "Init container {} has the name of another service",
+                init.name
+            );
+        }
+        if init.container.port.is_some() || !init.container.required_ports.is_empty() {
+            bail!("Init container {} can not have ports", init.name);
+        }
+        if init
+            .container
+            .restart
+            .as_deref()
+            .is_some_and(|restart| restart != "no")
+        {
+            bail!("Init container {} can not be restarted", init.name);
+        }
+    }
+    let mut aliases: Vec<&String> = Vec::new();
+    for (service_id, service) in app_yml.containers() {
+        let is_init = app_yml
+            .init_containers
+            .iter()
+            .any(|init| &init.name == service_id);
+        if let Some(restart) = &service.restart {
+            if !is_valid_restart_policy(restart) {
+                bail!(
+                    "Invalid restart policy {} for service {}, use no, always, on-failure[:max-retries] or unless-stopped",
+                    restart,
+                    service_id
+                );
+            }
+        }
+        if let Some(stop_grace_period) = &service.stop_grace_period {
+            if !is_valid_duration(stop_grace_period) {
+                bail!(
+                    "Invalid stop_grace_period {} for service {}, use a duration like 10s or 1m30s",
+                    stop_grace_period,
+                    service_id
+                );
+            }
+        }
+        if let Some(healthcheck) = &service.healthcheck {
+            for duration in [
+                &healthcheck.interval,
+                &healthcheck.timeout,
+                &healthcheck.start_period,
+                &healthcheck.start_interval,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if !is_valid_duration(duration) {
+                    bail!(
+                        "Invalid healthcheck duration {} for service {}, use a duration like 10s or 1m30s",
+                        duration,
+                        service_id
+                    );
+                }
+            }
+        }
+        for (name, limit) in &service.ulimits {
+            if !ULIMITS.contains(&name.as_str()) {
+                bail!("Unknown ulimit {} for service {}", name, service_id);
+            }
+            if let Ulimit::SoftHard { soft, hard } = limit {
+                if soft > hard {
+                    bail!(
+                        "The soft limit of ulimit {} for service {} is above its hard limit",
+                        name,
+                        service_id
+                    );
+                }
+            }
+        }
+        for sysctl in service.sysctls.keys() {
+            if !is_valid_sysctl(sysctl) {
+                bail!(
+                    "Sysctl {} of service {} is not allowed, only net.* sysctls can be set",
+                    sysctl,
+                    service_id
+                );
+            }
+        }
+        if !service.sysctls.is_empty() && service.network_mode.is_some() {
+            bail!(
+                "Service {} can only set sysctls on the app network",
+                service_id
+            );
+        }
+        for option in &service.security_opt {
+            match security_opt_permission(option) {
+                None => bail!(
+                    "Unsupported security_opt {} for service {}",
+                    option,
+                    service_id
+                ),
+                Some(None) => {}
+                Some(Some(permission)) => require_permission(
+                    &mut result.metadata,
+                    permission.to_owned(),
+                    PermissionSource::SecurityOpt {
+                        service: service_id.to_owned(),
+                        option: option.clone(),
+                    },
+                ),
+            }
+        }
+        for device in &service.devices {
+            let Some(permission) = device_permission(device) else {
+                bail!(
+                    "Invalid device {} for service {}, use a device like /dev/dri or /dev/ttyUSB0:/dev/zigbee",
+                    device,
+                    service_id
+                );
+            };
+            require_permission(
+                &mut result.metadata,
+                permission.to_owned(),
+                PermissionSource::Device {
+                    service: service_id.to_owned(),
+                    device: device.clone(),
+                },
+            );
+        }
+        for server in &service.dns {
+            if !is_valid_dns_server(server) {
+                bail!(
+                    "Invalid DNS server {} for service {}, use an IP address or an env var like ${{APP_ADGUARD_MAIN_IP}}",
+                    server,
+                    service_id
+                );
+            }
+        }
+        for domain in &service.dns_search {
+            if !is_valid_domain(domain) {
+                bail!(
+                    "Invalid search domain {} for service {}",
+                    domain,
+                    service_id
+                );
+            }
+        }
+        for option in &service.dns_opt {
+            if !is_valid_dns_opt(option) {
+                bail!(
+                    "Unsupported DNS option {} for service {}",
+                    option,
+                    service_id
+                );
+            }
+        }
+        if let Some(hostname) = &service.hostname {
+            if !is_valid_domain(hostname) {
+                bail!("Invalid hostname {} for service {}", hostname, service_id);
+            }
+        }
+        for alias in &service.aliases {
+            if !is_valid_domain(alias) {
+                bail!("Invalid alias {} for service {}", alias, service_id);
+            }
+            if aliases.contains(&alias) {
+                bail!("Alias {} is used by multiple services", alias);
+            }
+            aliases.push(alias);
+        }
+        if !service.aliases.is_empty() && service.network_mode.is_some() {
+            bail!(
+                "Service {} can only have aliases on the app network",
+                service_id
+            );
+        }
+        for tmpfs in &service.tmpfs {
+            if !is_valid_tmpfs(tmpfs) {
+                bail!(
+                    "Invalid tmpfs {} for service {}, use a path like /tmp or /run:size=64m",
+                    tmpfs,
+                    service_id
+                );
+            }
+        }
+        if let Some(build) = &service.build {
+            if !is_valid_build(&build.context, build.dockerfile.as_deref()) {
+                bail!(
+                    "Invalid build for service {}, the context has to be inside the app's dir and the dockerfile a file name",
+                    service_id
+                );
+            }
+            require_permission(
+                &mut result.metadata,
+                "local-build".to_owned(),
+                PermissionSource::Build {
+                    service: service_id.to_owned(),
+                },
+            );
+        }
+        // These properties need no further validation
         let mut result_service = Service {
+            build: service.build.clone(),
             image: service.image.clone(),
-            restart: service.restart.clone(),
+            restart: Some(
+                service
+                    .restart
+                    .clone()
+                    .unwrap_or_else(|| if is_init { "no" } else { "unless-stopped" }.to_owned()),
+            ),
             stop_grace_period: service.stop_grace_period.clone(),
             stop_signal: service.stop_signal.clone(),
+            healthcheck: service.healthcheck.clone(),
+            ulimits: service.ulimits.clone(),
+            sysctls: service.sysctls.clone(),
+            security_opt: service.security_opt.clone(),
+            devices: service.devices.clone(),
+            tmpfs: service.tmpfs.clone(),
+            dns: service.dns.clone(),
+            dns_search: service.dns_search.clone(),
+            dns_opt: service.dns_opt.clone(),
+            hostname: service.hostname.clone(),
+            networks: (!service.aliases.is_empty()).then(|| {
+                BTreeMap::from([(
+                    NETWORK_NAME.to_owned(),
+                    NetworkEntry {
+                        aliases: service.aliases.clone(),
+                        ..Default::default()
+                    },
+                )])
+            }),
             user: service.user.clone(),
             init: service.init,
-            depends_on: service.depends_on.clone(),
+            depends_on: service.depends_on.clone().map(DependsOn::List),
             extra_hosts: service.extra_hosts.clone(),
             working_dir: service.working_dir.clone(),
             shm_size: service.shm_size.clone(),
@@ -345,42 +1046,613 @@ pub fn convert_app_yml(
             command: service.command.clone(),
             entrypoint: service.entrypoint.clone(),
             environment: service.environment.clone(),
+            labels: nirvati_labels(app_id, &result.metadata.version, Some(service_id)),
+            logging: convert_logging(service.logging.as_ref(), logging_defaults)
+                .with_context(|| format!("Invalid logging for service {}", service_id))?,
             ..Default::default()
         };
+        if let Some(metrics) = &service.metrics {
+            if !metrics.path.starts_with('/') {
+                bail!(
+                    "Invalid metrics path {} for service {}, it has to start with /",
+                    metrics.path,
+                    service_id
+                );
+            }
+            if service.network_mode.is_some() {
+                bail!(
+                    "Service {} can only expose metrics on the app network",
+                    service_id
+                );
+            }
+            result
+                .metrics
+                .insert(service_id.to_owned(), metrics.clone());
+        }
+        if let Some(bandwidth) = &service.bandwidth {
+            for rate in [&bandwidth.max_upload, &bandwidth.max_download]
+                .into_iter()
+                .flatten()
+            {
+                traffic::parse_rate(rate)
+                    .with_context(|| format!("Invalid bandwidth for service {}", service_id))?;
+            }
+            if service.network_mode.is_some() {
+                bail!(
+                    "Service {} can only be shaped on the app network",
+                    service_id
+                );
+            }
+            result
+                .bandwidth
+                .insert(service_id.to_owned(), bandwidth.clone());
+        }
         if let Some(network_mode) = &service.network_mode {
             if network_mode == "host" {
-                require_permission!(result, "network");
+                require_permission(
+                    &mut result.metadata,
+                    "network".to_owned(),
+                    PermissionSource::HostNetwork {
+                        service: service_id.to_owned(),
+                    },
+                );
             } else {
                 bail!("Unsupported network_mode!");
             }
         }
 
         for capability in &service.cap_add {
-            match capability.as_str() {
-                "CAP_NET_RAW" => {
-                    require_permission!(result, "network");
-                }
-                _ => {
-                    require_permission!(result, "root");
-                }
-            }
+            let permission = match capability.as_str() {
+                "CAP_NET_RAW" => "network",
+                _ => "root",
+            };
+            require_permission(
+                &mut result.metadata,
+                permission.to_owned(),
+                PermissionSource::Capability {
+                    service: service_id.to_owned(),
+                    capability: capability.clone(),
+                },
+            );
         }
 
         convert_mounts(
+            service_id,
             &mut result_service,
-            &service,
+            service,
             &mut result.metadata,
             available_permissions,
+            mode,
         )?;
 
         let mut new_caddy_entries =
-            handle_ports(&service_id, &mut result_service, &service, port_map)?;
+            handle_ports(service_id, &mut result_service, service, port_map, ipv6)?;
         result.caddy_entries.append(&mut new_caddy_entries);
+        result
+            .wan_ports
+            .append(&mut get_wan_ports(service_id, service));
         result
             .spec
             .services
             .insert(service_id.to_owned(), result_service);
     }
+    order_init_containers(&mut result, app_yml);
+    add_wait_containers(&mut result, app_id, app_yml)?;
+    if result.metadata.update_strategy == UpdateStrategy::Rolling {
+        let publishing = result
+            .spec
+            .services
+            .iter()
+            .filter(|(_, service)| !service.ports.is_empty())
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        if !publishing.is_empty() {
+            result.metadata.diagnostics.push(Diagnostic::warning(
+                DiagnosticCode::RollingUpdatePortClash,
+                format!(
+                    "The old and new containers can't both use the published ports of {} during a rolling update",
+                    publishing.join(", ")
+                ),
+                None,
+            ));
+        }
+    }
+    // Only one app can use an alias on the shared network
+    result
+        .metadata
+        .claims
+        .extend(aliases.into_iter().map(|alias| alias_claim(alias)));
+    for (index, task) in app_yml.tasks.iter().enumerate() {
+        if app_yml.tasks[..index]
+            .iter()
+            .any(|other| other.name == task.name)
+        {
+            bail!("There are multiple tasks named {}", task.name);
+        }
+        if !app_yml.services.contains_key(&task.service) {
+            bail!(
+                "Task {} runs in unknown service {}",
+                task.name,
+                task.service
+            );
+        }
+        if !is_valid_schedule(&task.schedule) {
+            bail!(
+                "Invalid schedule {} for task {}, use a cron expression like 0 3 * * * or a shortcut like @daily",
+                task.schedule,
+                task.name
+            );
+        }
+    }
+    result.tasks = app_yml.tasks.clone();
+    result.spec.networks.insert(
+        "default".to_owned(),
+        Network {
+            labels: nirvati_labels(app_id, &result.metadata.version, None),
+            enable_ipv6: ipv6,
+        },
+    );
     validate_env_access(&mut result, available_permissions);
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        composegenerator::types::{Severity, Widget},
+        manage::ports::PortPriority,
+    };
+
+    fn convert(app_yml: &str, update_strategy: UpdateStrategy) -> ResultYml {
+        convert_with_metadata(
+            app_yml,
+            Metadata {
+                update_strategy,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn convert_with_metadata(app_yml: &str, metadata: Metadata) -> ResultYml {
+        let app_yml: AppYml = serde_yaml::from_str(app_yml).unwrap();
+        let metadata = Metadata {
+            name: "Notes".to_owned(),
+            version: "1.0.0".to_owned(),
+            ..metadata
+        };
+        let port_map = [PortMapEntry {
+            app: "notes".to_owned(),
+            internal_port: 8080,
+            public_port: 8080,
+            container: "main".to_owned(),
+            implements: None,
+            priority: PortPriority::Optional,
+        }];
+        convert_app_yml(
+            "notes",
+            &app_yml,
+            metadata,
+            &port_map,
+            &HashMap::new(),
+            ValidationMode::Permissive,
+            &LoggingOptions::default(),
+            false,
+        )
+        .unwrap()
+    }
+
+    const PUBLISHED_PORT_APP: &str = "
+version: 1
+services:
+  main:
+    image: notes
+    port: 8080
+    disable_caddy: true
+  worker:
+    image: notes-worker
+metadata: {}
+";
+
+    fn has_port_clash_warning(result: &ResultYml) -> bool {
+        result
+            .metadata
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.code == DiagnosticCode::RollingUpdatePortClash)
+    }
+
+    #[test]
+    fn keeps_the_update_strategy() {
+        for (strategy, name) in [
+            (UpdateStrategy::Recreate, "recreate"),
+            (UpdateStrategy::Rolling, "rolling"),
+            (UpdateStrategy::StopFirst, "stop-first"),
+        ] {
+            let result = convert(PUBLISHED_PORT_APP, strategy);
+            assert_eq!(result.metadata.update_strategy, strategy);
+            // Host scripts read it from the registry
+            assert_eq!(
+                serde_json::to_value(&result.metadata).unwrap()["updateStrategy"],
+                name
+            );
+            assert_eq!(
+                has_port_clash_warning(&result),
+                strategy == UpdateStrategy::Rolling
+            );
+        }
+    }
+
+    #[test]
+    fn warns_about_published_ports_in_rolling_updates() {
+        let result = convert(PUBLISHED_PORT_APP, UpdateStrategy::Rolling);
+        let warning = result
+            .metadata
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.code == DiagnosticCode::RollingUpdatePortClash)
+            .unwrap();
+        assert_eq!(
+            warning.message,
+            "The old and new containers can't both use the published ports of main during a rolling update"
+        );
+
+        // Ports behind Caddy aren't published by the containers
+        let result = convert(
+            "
+version: 1
+services:
+  main:
+    image: notes
+    port: 8080
+metadata: {}
+",
+            UpdateStrategy::Rolling,
+        );
+        assert!(!has_port_clash_warning(&result));
+    }
+
+    #[test]
+    fn chains_init_containers() {
+        let result = convert(
+            "
+version: 1
+services:
+  main:
+    image: notes
+    port: 8080
+    depends_on: [db]
+  db:
+    image: postgres
+init_containers:
+  - name: migrate
+    image: notes
+    depends_on: [db]
+  - name: seed
+    image: notes
+metadata: {}
+",
+            UpdateStrategy::Recreate,
+        );
+        let services = &result.spec.services;
+        let conditions = |service: &str| match &services[service].depends_on {
+            Some(DependsOn::Conditions(conditions)) => conditions
+                .iter()
+                .map(|(name, condition)| (name.as_str(), condition.condition.as_str()))
+                .collect::<Vec<_>>(),
+            other => panic!("{} has no conditions: {:?}", service, other),
+        };
+        // The first init container only waits for what it depends on
+        assert_eq!(
+            services["migrate"].depends_on,
+            Some(DependsOn::List(vec!["db".to_owned()]))
+        );
+        assert_eq!(
+            conditions("seed"),
+            vec![("migrate", "service_completed_successfully")]
+        );
+        assert_eq!(
+            conditions("main"),
+            vec![
+                ("db", "service_started"),
+                ("seed", "service_completed_successfully")
+            ]
+        );
+        // The init containers need db, so it can't wait for them
+        assert_eq!(services["db"].depends_on, None);
+    }
+
+    #[test]
+    fn records_inferred_permissions() {
+        let mut metadata = OutputMetadata {
+            requested_permissions: vec!["lnd".to_owned()],
+            has_permissions: vec!["lnd".to_owned()],
+            ..Default::default()
+        };
+        let env_var = PermissionSource::EnvVar {
+            name: "APP_LND_MACAROON".to_owned(),
+        };
+        require_permission(&mut metadata, "lnd".to_owned(), env_var);
+        assert!(metadata.inferred_permissions.is_empty());
+
+        let env_var = PermissionSource::EnvVar {
+            name: "APP_BITCOIN_RPC_PASS".to_owned(),
+        };
+        require_permission(&mut metadata, "bitcoin".to_owned(), env_var.clone());
+        require_permission(&mut metadata, "bitcoin".to_owned(), env_var.clone());
+        require_permission(
+            &mut metadata,
+            "bitcoin".to_owned(),
+            PermissionSource::Mount {
+                service: "main".to_owned(),
+                mount: "bitcoin".to_owned(),
+            },
+        );
+        assert_eq!(metadata.has_permissions, vec!["lnd", "bitcoin"]);
+        assert_eq!(metadata.inferred_permissions.len(), 2);
+        assert_eq!(metadata.inferred_permissions[0].source, env_var);
+        assert_eq!(
+            metadata.diagnostics[1].message,
+            "Gets the bitcoin permission because of mount bitcoin of service main"
+        );
+        assert_eq!(
+            metadata.diagnostics[1].field.as_deref(),
+            Some("services.main.mounts.bitcoin")
+        );
+    }
+
+    #[test]
+    fn escaped_env_vars_need_no_permission() {
+        let mut result = ResultYml::default();
+        result.spec.services.insert(
+            "main".to_owned(),
+            Service {
+                environment: BTreeMap::from([
+                    (
+                        "HTPASSWD".to_owned(),
+                        StringLike::String("admin:$$2y$$05$$APP_BITCOIN_RPC_PASS".to_owned()),
+                    ),
+                    (
+                        "SCHEDULE".to_owned(),
+                        StringLike::String("echo $$HOME".to_owned()),
+                    ),
+                ]),
+                ..Default::default()
+            },
+        );
+        validate_env_access(&mut result, &HashMap::new());
+        assert!(result.metadata.has_permissions.is_empty());
+        assert!(result.metadata.inferred_permissions.is_empty());
+        // The value is passed to compose as it is, which turns $$ into $
+        assert_eq!(
+            result.spec.services["main"].environment["SCHEDULE"],
+            StringLike::String("echo $$HOME".to_owned())
+        );
+    }
+
+    #[test]
+    fn waits_for_dependencies() {
+        let app_yml: AppYml = serde_yaml::from_str(
+            "
+version: 1
+services:
+  main:
+    image: nextcloud
+    depends_on: [redis]
+    wait_for:
+      - app: postgres
+        port: 5432
+        timeout: 2m
+  redis:
+    image: redis
+metadata: {}
+",
+        )
+        .unwrap();
+        let mut result = ResultYml::default();
+        result.metadata.dependencies = vec![Dependency::OneDependency("postgres".to_owned())];
+        for (name, container) in &app_yml.services {
+            result.spec.services.insert(
+                name.clone(),
+                Service {
+                    image: container.image.clone(),
+                    depends_on: container.depends_on.clone().map(DependsOn::List),
+                    ..Default::default()
+                },
+            );
+        }
+        add_wait_containers(&mut result, "nextcloud", &app_yml).unwrap();
+
+        let wait = &result.spec.services["wait-for-postgres-main"];
+        assert_eq!(
+            wait.command,
+            Some(Command::ArraySyntax(vec![
+                "timeout".to_owned(),
+                "120".to_owned(),
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "until nc -z -w 2 postgres_main 5432; do sleep 2; done".to_owned(),
+            ]))
+        );
+        let Some(DependsOn::Conditions(conditions)) = &result.spec.services["main"].depends_on
+        else {
+            panic!("main has no conditions");
+        };
+        assert_eq!(conditions["redis"].condition, "service_started");
+        assert_eq!(
+            conditions["wait-for-postgres-main"].condition,
+            "service_completed_successfully"
+        );
+
+        result.metadata.dependencies.clear();
+        assert!(add_wait_containers(&mut result, "nextcloud", &app_yml).is_err());
+    }
+
+    #[test]
+    fn strict_mode_fails_on_skipped_declarations() {
+        let app_yml: AppYml = serde_yaml::from_str(
+            "
+version: 1
+services:
+  main:
+    image: notes
+    port: 8080
+    mounts:
+      data:
+        ../escape: /data
+    required_ports:
+      tcp:
+        9000: 9000
+      udp:
+        9000: 9000
+metadata: {}
+",
+        )
+        .unwrap();
+        let convert = |mode| {
+            convert_app_yml(
+                "notes",
+                &app_yml,
+                Metadata::default(),
+                &[PortMapEntry {
+                    app: "notes".to_owned(),
+                    internal_port: 8080,
+                    public_port: 8080,
+                    container: "main".to_owned(),
+                    implements: None,
+                    priority: PortPriority::Optional,
+                }],
+                &HashMap::new(),
+                mode,
+                &LoggingOptions::default(),
+                false,
+            )
+        };
+
+        // The invalid mount is skipped
+        let result = convert(ValidationMode::Permissive).unwrap();
+        assert!(!result.spec.services["main"]
+            .volumes
+            .iter()
+            .any(|volume| volume.ends_with(":/data")));
+        let err = convert(ValidationMode::Strict).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid mount name: data"));
+
+        let ports = app_yml
+            .get_ports("notes", None, ValidationMode::Permissive)
+            .unwrap();
+        assert_eq!(
+            ports.iter().filter(|port| port.public_port == 9000).count(),
+            1
+        );
+        let err = app_yml
+            .get_ports("notes", None, ValidationMode::Strict)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Public port 9000 is declared more than once"
+        );
+    }
+
+    #[test]
+    fn records_skipped_declarations_as_diagnostics() {
+        let result = convert(
+            "
+version: 1
+services:
+  main:
+    image: notes
+    port: 8080
+    mounts:
+      data:
+        ../escape: /data
+      bitcoin/data: /bitcoin
+metadata: {}
+",
+            UpdateStrategy::Recreate,
+        );
+        let diagnostic = |code| {
+            result
+                .metadata
+                .diagnostics
+                .iter()
+                .find(|diagnostic| diagnostic.code == code)
+                .unwrap_or_else(|| panic!("No {:?} diagnostic", code))
+                .clone()
+        };
+        let invalid_mount = diagnostic(DiagnosticCode::InvalidMount);
+        assert_eq!(invalid_mount.severity, Severity::Warning);
+        assert_eq!(invalid_mount.message, "Invalid mount name: data");
+        assert_eq!(
+            invalid_mount.field.as_deref(),
+            Some("services.main.mounts.data.../escape")
+        );
+        let missing_app = diagnostic(DiagnosticCode::MissingPermissionTarget);
+        assert_eq!(
+            missing_app.message,
+            "Mount bitcoin/data refers to unavailable app bitcoin"
+        );
+        // The app is generated anyway, so the diagnostics end up in its registry entry
+        assert!(
+            serde_json::to_value(&result.metadata).unwrap()["diagnostics"]
+                .as_array()
+                .is_some_and(|diagnostics| diagnostics.len() >= 2)
+        );
+    }
+
+    #[test]
+    fn labels_services_and_networks() {
+        let result = convert(PUBLISHED_PORT_APP, UpdateStrategy::Recreate);
+        let labels = |service: Option<&str>| {
+            let mut labels = BTreeMap::from([
+                ("nirvati.app".to_owned(), "notes".to_owned()),
+                ("nirvati.version".to_owned(), "1.0.0".to_owned()),
+                ("nirvati.managed".to_owned(), "true".to_owned()),
+            ]);
+            if let Some(service) = service {
+                labels.insert("nirvati.service".to_owned(), service.to_owned());
+            }
+            labels
+        };
+        for service in ["main", "worker"] {
+            assert_eq!(result.spec.services[service].labels, labels(Some(service)));
+        }
+        assert_eq!(result.spec.networks["default"].labels, labels(None));
+    }
+
+    #[test]
+    fn points_widgets_to_the_service_hostnames() {
+        let widget = |url: &str, refresh_interval: u32| Widget {
+            widget_type: "status".to_owned(),
+            url: url.to_owned(),
+            refresh_interval,
+        };
+        let result = convert_with_metadata(
+            PUBLISHED_PORT_APP,
+            Metadata {
+                widgets: vec![
+                    widget("http://worker:9000/widget", 30),
+                    widget("http://unknown:9000/widget", 30),
+                    widget("https://example.com/widget", 30),
+                    widget("http://main:8080/widget", 1),
+                ],
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            result.metadata.widgets,
+            vec![widget(
+                &format!("http://{}:9000/widget", hostname("notes", "worker")),
+                30
+            )]
+        );
+        let invalid_widgets = result
+            .metadata
+            .diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.code == DiagnosticCode::InvalidWidget)
+            .filter_map(|diagnostic| diagnostic.field.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(invalid_widgets, vec!["widgets.1", "widgets.2", "widgets.3"]);
+    }
+}
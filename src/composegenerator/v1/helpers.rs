@@ -1,4 +1,293 @@
-use crate::composegenerator::types::Permission;
+use std::{
+    net::IpAddr,
+    path::{Component, Path},
+    time::Duration,
+};
+
+use crate::{composegenerator::types::Permission, utils::find_env_vars};
+
+/// Whether a data mount stays inside the app's data dir and the container
+pub fn is_valid_data_mount(host_dir: &str, container_dir: &str) -> bool {
+    // An absolute host dir would replace the data dir when it is joined to it
+    let relative = !host_dir.is_empty()
+        && Path::new(host_dir)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    relative
+        && !(host_dir.contains(':')
+            || host_dir.contains("..")
+            || container_dir.contains(':')
+            || container_dir.contains("..")
+            || !find_env_vars(host_dir).is_empty()
+            || !find_env_vars(container_dir).is_empty())
+}
+
+/// Whether a build context stays inside the app's dir and the Dockerfile is a file name in it
+pub fn is_valid_build(context: &str, dockerfile: Option<&str>) -> bool {
+    !(context.starts_with('/')
+        || context.split('/').any(|part| part == "..")
+        || !find_env_vars(context).is_empty()
+        || dockerfile.is_some_and(|dockerfile| {
+            dockerfile.is_empty()
+                || dockerfile.contains('/')
+                || dockerfile == ".."
+                || !find_env_vars(dockerfile).is_empty()
+        }))
+}
+
+/// Whether a schedule is a cron expression with 5 numeric fields or one of the @hourly, @daily, @weekly, @monthly and @yearly shortcuts
+pub fn is_valid_schedule(schedule: &str) -> bool {
+    if schedule.starts_with('@') {
+        return matches!(
+            schedule,
+            "@hourly" | "@daily" | "@midnight" | "@weekly" | "@monthly" | "@yearly" | "@annually"
+        );
+    }
+    // Minute, hour, day of month, month, day of week (0 and 7 are Sunday)
+    let ranges = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+    let fields = schedule.split_whitespace().collect::<Vec<_>>();
+    fields.len() == ranges.len()
+        && fields.iter().zip(ranges).all(|(field, (min, max))| {
+            field
+                .split(',')
+                .all(|item| is_valid_cron_item(item, min, max))
+        })
+}
+
+/// Whether a list item of a cron field is *, a value or a range, optionally with a step
+fn is_valid_cron_item(item: &str, min: u32, max: u32) -> bool {
+    let (range, step) = match item.split_once('/') {
+        Some((range, step)) => (range, Some(step)),
+        None => (item, None),
+    };
+    if let Some(step) = step {
+        if !matches!(step.parse::<u32>(), Ok(step) if step > 0) {
+            return false;
+        }
+    }
+    let parse = |value: &str| {
+        value
+            .parse::<u32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+    };
+    if range == "*" {
+        return true;
+    }
+    match range.split_once('-') {
+        Some((start, end)) => {
+            matches!((parse(start), parse(end)), (Some(start), Some(end)) if start <= end)
+        }
+        None => parse(range).is_some(),
+    }
+}
+
+/// Whether a string is a size like 500k, 10m or 1g
+pub fn is_valid_size(size: &str) -> bool {
+    let number = size.strip_suffix(['k', 'm', 'g']).unwrap_or(size);
+    !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether a restart policy is one of no, always, on-failure[:max-retries] and unless-stopped
+pub fn is_valid_restart_policy(restart: &str) -> bool {
+    match restart.split_once(':') {
+        Some(("on-failure", retries)) => retries.parse::<u32>().is_ok(),
+        Some(_) => false,
+        None => matches!(restart, "no" | "always" | "on-failure" | "unless-stopped"),
+    }
+}
+
+/// Whether a string is a compose duration like 10s, 1m30s or 1.5h
+pub fn is_valid_duration(duration: &str) -> bool {
+    parse_duration(duration).is_some()
+}
+
+/// Parses a compose duration like 10s, 1m30s or 1.5h
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut rest = duration;
+    if rest.is_empty() {
+        return None;
+    }
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number = rest[..number_end].parse::<f64>().ok()?;
+        rest = &rest[number_end..];
+        let (unit, unit_seconds) = [
+            ("ns", 1e-9),
+            ("us", 1e-6),
+            ("ms", 1e-3),
+            ("s", 1.0),
+            ("m", 60.0),
+            ("h", 3600.0),
+        ]
+        .into_iter()
+        .find(|(unit, _)| rest.starts_with(unit))?;
+        seconds += number * unit_seconds;
+        rest = &rest[unit.len()..];
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// The resource limits compose accepts in ulimits
+pub const ULIMITS: [&str; 15] = [
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+/// Whether a sysctl only affects the container's network namespace, like net.ipv4.ip_forward
+pub fn is_valid_sysctl(sysctl: &str) -> bool {
+    sysctl.strip_prefix("net.").is_some_and(|rest| {
+        !rest.is_empty()
+            && rest.split('.').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            })
+    })
+}
+
+/// The permission a security_opt needs, None if it isn't supported
+pub fn security_opt_permission(option: &str) -> Option<Option<&'static str>> {
+    // no-new-privileges only makes a container more restricted
+    if matches!(
+        option,
+        "no-new-privileges" | "no-new-privileges:true" | "no-new-privileges=true"
+    ) {
+        return Some(None);
+    }
+    let name = option.split([':', '=']).next().unwrap_or_default();
+    if option.len() > name.len() && matches!(name, "apparmor" | "seccomp" | "label" | "systempaths")
+    {
+        return Some(Some("root"));
+    }
+    None
+}
+
+fn is_absolute_path(path: &str) -> bool {
+    path.starts_with('/') && !path.split('/').any(|part| part == "..") && !path.contains(':')
+}
+
+/// The permission a device needs, None if it isn't a valid host_path[:container_path[:permissions]]
+pub fn device_permission(device: &str) -> Option<&'static str> {
+    let mut parts = device.split(':');
+    let host_path = parts.next()?;
+    let container_path = parts.next();
+    let permissions = parts.next();
+    if parts.next().is_some()
+        || !host_path.starts_with("/dev/")
+        || !is_absolute_path(host_path)
+        || container_path.is_some_and(|path| !is_absolute_path(path))
+        || permissions.is_some_and(|permissions| {
+            permissions.is_empty() || !permissions.chars().all(|c| matches!(c, 'r' | 'w' | 'm'))
+        })
+    {
+        return None;
+    }
+    Some(match host_path.trim_end_matches('/') {
+        "/dev/dri" => "gpu",
+        path if path.starts_with("/dev/dri/") => "gpu",
+        "/dev/fuse" => "fuse",
+        _ => "root",
+    })
+}
+
+/// Whether a tmpfs is a container_path[:options] with size, mode and mount flag options
+pub fn is_valid_tmpfs(tmpfs: &str) -> bool {
+    let (path, options) = match tmpfs.split_once(':') {
+        Some((path, options)) => (path, Some(options)),
+        None => (tmpfs, None),
+    };
+    path != "/"
+        && is_absolute_path(path)
+        && options.is_none_or(|options| {
+            options
+                .split(',')
+                .all(|option| match option.split_once('=') {
+                    Some(("size", size)) => is_valid_size(size),
+                    Some(("mode", mode)) => {
+                        !mode.is_empty()
+                            && mode.len() <= 4
+                            && mode.chars().all(|c| ('0'..='7').contains(&c))
+                    }
+                    Some(_) => false,
+                    None => matches!(
+                        option,
+                        "ro" | "rw" | "noexec" | "exec" | "nosuid" | "suid" | "nodev" | "dev"
+                    ),
+                })
+        })
+}
+
+/// Whether a DNS server is an IP address or a single env var like ${APP_ADGUARD_MAIN_IP}
+pub fn is_valid_dns_server(server: &str) -> bool {
+    if server.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    server
+        .strip_prefix("${")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .is_some_and(|name| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+/// Whether a string is a domain like example.com or home.arpa
+pub fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Whether a string is a resolv.conf option like ndots:2 or edns0
+pub fn is_valid_dns_opt(option: &str) -> bool {
+    match option.split_once(':') {
+        Some(("ndots", value)) => value.parse::<u8>().is_ok_and(|value| value <= 15),
+        Some(("timeout", value)) => value.parse::<u8>().is_ok_and(|value| value <= 30),
+        Some(("attempts", value)) => value.parse::<u8>().is_ok_and(|value| value <= 5),
+        Some(_) => false,
+        None => matches!(
+            option,
+            "edns0"
+                | "inet6"
+                | "no-reload"
+                | "no-tld-query"
+                | "rotate"
+                | "single-request"
+                | "single-request-reopen"
+                | "trust-ad"
+                | "use-vc"
+        ),
+    }
+}
+
+/// Whether a path stays on the app's own origin, like /admin?tab=users
+pub fn is_valid_relative_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(|c| c.is_whitespace() || c.is_control())
+}
 
 /// Find the best permission that matches, or None if none matches
 /// app_name is the apps these permissions are exposed by, not the app using them
@@ -15,7 +304,7 @@ where
     if perms_that_expose_this_var.is_empty() {
         None
     } else if perms_that_expose_this_var.len() == 1 {
-        return Some(perms_that_expose_this_var[0]);
+        Some(perms_that_expose_this_var[0])
     } else {
         for perm in perms_that_expose_this_var.iter() {
             if current_permissions.contains(&format!("{}/{}", app_name, perm.id)) {
@@ -28,6 +317,184 @@ where
                 .cmp(&b.includes.len())
                 .then(a.id.cmp(&b.id))
         });
-        return Some(perms_that_expose_this_var[0]);
+        Some(perms_that_expose_this_var[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_mounts_stay_in_the_data_dir() {
+        for valid in ["html", "data/db", "config/"] {
+            assert!(is_valid_data_mount(valid, "/data"), "{}", valid);
+        }
+        for invalid in ["/etc/x", "", ".", "./data", "data/../..", "../data", "a:b"] {
+            assert!(!is_valid_data_mount(invalid, "/data"), "{}", invalid);
+        }
+        assert!(!is_valid_data_mount("data", "/data:/etc"));
+        assert!(!is_valid_data_mount("${APP_SEED}", "/data"));
+    }
+
+    #[test]
+    fn restart_policies() {
+        for valid in [
+            "no",
+            "always",
+            "on-failure",
+            "on-failure:3",
+            "unless-stopped",
+        ] {
+            assert!(is_valid_restart_policy(valid), "{}", valid);
+        }
+        for invalid in ["", "never", "on-failure:", "on-failure:-1", "always:3"] {
+            assert!(!is_valid_restart_policy(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn build_contexts() {
+        assert!(is_valid_build(".", None));
+        assert!(is_valid_build("docker/server", Some("Dockerfile.arm64")));
+        assert!(!is_valid_build("/etc", None));
+        assert!(!is_valid_build("../other-app", None));
+        assert!(!is_valid_build("docker/../..", None));
+        assert!(!is_valid_build("${APP_DATA_DIR}", None));
+        assert!(!is_valid_build(".", Some("../Dockerfile")));
+        assert!(!is_valid_build(".", Some("")));
+    }
+
+    #[test]
+    fn relative_paths() {
+        for valid in ["/", "/admin", "/docs/index.html#setup", "/?tab=users"] {
+            assert!(is_valid_relative_path(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "admin",
+            "https://example.com/admin",
+            "//example.com/admin",
+            "/\\example.com",
+            "/admin page",
+        ] {
+            assert!(!is_valid_relative_path(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn schedules() {
+        for valid in [
+            "@daily",
+            "0 3 * * *",
+            "*/15 * * * 1-5",
+            "0 0,12 1 */2 7",
+            "5-10/5 * * * *",
+        ] {
+            assert!(is_valid_schedule(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "@reboot",
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "10-5 * * * *",
+            "0 3 * * mon",
+        ] {
+            assert!(!is_valid_schedule(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        for valid in ["500k", "10m", "1g", "1024"] {
+            assert!(is_valid_size(valid), "{}", valid);
+        }
+        for invalid in ["", "m", "10mb", "1.5m", "-1m"] {
+            assert!(!is_valid_size(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn sysctls_and_security_opts() {
+        assert!(is_valid_sysctl("net.ipv4.ip_forward"));
+        assert!(is_valid_sysctl("net.ipv4.conf.all.src_valid_mark"));
+        assert!(!is_valid_sysctl("kernel.shmmax"));
+        assert!(!is_valid_sysctl("net."));
+        assert!(!is_valid_sysctl("net..ipv4"));
+        assert!(!is_valid_sysctl("net/ipv4/ip_forward"));
+
+        assert_eq!(
+            security_opt_permission("no-new-privileges:true"),
+            Some(None)
+        );
+        assert_eq!(
+            security_opt_permission("seccomp:unconfined"),
+            Some(Some("root"))
+        );
+        assert_eq!(
+            security_opt_permission("apparmor=unconfined"),
+            Some(Some("root"))
+        );
+        assert_eq!(security_opt_permission("seccomp"), None);
+        assert_eq!(security_opt_permission("privileged"), None);
+    }
+
+    #[test]
+    fn devices_and_tmpfs() {
+        assert_eq!(device_permission("/dev/dri"), Some("gpu"));
+        assert_eq!(
+            device_permission("/dev/dri/renderD128:/dev/dri/renderD128:rw"),
+            Some("gpu")
+        );
+        assert_eq!(device_permission("/dev/fuse"), Some("fuse"));
+        assert_eq!(device_permission("/dev/ttyUSB0:/dev/zigbee"), Some("root"));
+        assert_eq!(device_permission("/dev/driver"), Some("root"));
+        assert_eq!(device_permission("/etc/shadow"), None);
+        assert_eq!(device_permission("/dev/../etc/shadow"), None);
+        assert_eq!(device_permission("/dev/fuse:/dev/fuse:x"), None);
+
+        assert!(is_valid_tmpfs("/tmp"));
+        assert!(is_valid_tmpfs("/run:size=64m,mode=1777,noexec"));
+        assert!(!is_valid_tmpfs("/"));
+        assert!(!is_valid_tmpfs("tmp"));
+        assert!(!is_valid_tmpfs("/tmp:uid=0"));
+        assert!(!is_valid_tmpfs("/tmp:size=64mb"));
+    }
+
+    #[test]
+    fn dns_settings() {
+        assert!(is_valid_dns_server("1.1.1.1"));
+        assert!(is_valid_dns_server("2606:4700:4700::1111"));
+        assert!(is_valid_dns_server("${APP_ADGUARD_MAIN_IP}"));
+        assert!(!is_valid_dns_server("dns.example.com"));
+        assert!(!is_valid_dns_server("${APP_ADGUARD_MAIN_IP:-1.1.1.1}"));
+        assert!(!is_valid_dns_server("$APP_ADGUARD_MAIN_IP"));
+
+        assert!(is_valid_domain("home.arpa"));
+        assert!(is_valid_domain("my-lab"));
+        assert!(!is_valid_domain(""));
+        assert!(!is_valid_domain("example..com"));
+        assert!(!is_valid_domain("-example.com"));
+        assert!(!is_valid_domain("example.com "));
+
+        assert!(is_valid_dns_opt("ndots:2"));
+        assert!(is_valid_dns_opt("edns0"));
+        assert!(!is_valid_dns_opt("ndots:16"));
+        assert!(!is_valid_dns_opt("debug"));
+    }
+
+    #[test]
+    fn durations() {
+        for valid in ["10s", "1m30s", "1.5h", "500ms", "2us"] {
+            assert!(is_valid_duration(valid), "{}", valid);
+        }
+        for invalid in ["", "10", "s", "1d", "1m 30s", "-1s"] {
+            assert!(!is_valid_duration(invalid), "{}", invalid);
+        }
+        assert_eq!(parse_duration("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
     }
 }
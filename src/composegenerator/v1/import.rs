@@ -0,0 +1,908 @@
+//! Converts a plain docker compose file into a draft app.yml and metadata.yml
+//!
+//! Everything that can't be converted, or that needs a permission the app would have to request,
+//! is reported as a note instead of silently changing what the app does.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+use crate::{
+    composegenerator::{
+        output::types::{Build, Healthcheck, Ulimit},
+        types::Command,
+    },
+    utils::{ends_with_escaping_dollar, find_env_vars, StringLike, StringOrNumber},
+};
+
+use super::{
+    convert::env_var_permission,
+    helpers::{
+        device_permission, is_valid_build, is_valid_data_mount, is_valid_dns_opt,
+        is_valid_dns_server, is_valid_domain, is_valid_sysctl, is_valid_tmpfs,
+        security_opt_permission,
+    },
+    types::{AppYml, Container, InputMetadata, MetadataYml, PortTarget, StringOrMap},
+};
+
+/// Service keys the app manager sets itself
+const MANAGED_KEYS: [&str; 4] = ["container_name", "labels", "expose", "logging"];
+
+/// Service keys that give a container access to the host
+const HOST_ACCESS_KEYS: [&str; 6] = [
+    "privileged",
+    "pid",
+    "ipc",
+    "userns_mode",
+    "cgroup_parent",
+    "volumes_from",
+];
+
+/// Where the notes of apps converted from other store formats are kept, for lint
+pub const NOTES_FILE: &str = "import-notes.yml";
+
+/// Something in the compose file that has to be reviewed before publishing the app
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportNote {
+    /// The key path in the compose file, like services.web.volumes
+    pub field: String,
+    pub message: String,
+    /// The permission the app would need to keep this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permission: Option<String>,
+}
+
+impl ImportNote {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ImportNote {
+            field: field.into(),
+            message: message.into(),
+            permission: None,
+        }
+    }
+}
+
+/// The service with the web UI and its port, for formats that declare it outside of the compose file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebUi {
+    pub service: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportedApp {
+    pub app_yml: AppYml,
+    pub metadata: MetadataYml,
+    pub notes: Vec<ImportNote>,
+}
+
+struct Notes(Vec<ImportNote>);
+
+impl Notes {
+    fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(ImportNote::new(field, message));
+    }
+
+    fn permission(
+        &mut self,
+        field: impl Into<String>,
+        message: impl Into<String>,
+        permission: &str,
+    ) {
+        self.0.push(ImportNote {
+            field: field.into(),
+            message: message.into(),
+            permission: Some(permission.to_owned()),
+        });
+    }
+}
+
+fn as_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(bool) => Some(bool.to_string()),
+        _ => None,
+    }
+}
+
+fn string_list(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::Sequence(sequence) => sequence.iter().map(as_string).collect(),
+        _ => None,
+    }
+}
+
+/// Reads a list of KEY=VALUE strings or a map, like environment or build args
+fn key_values(value: &Value) -> Option<Vec<(String, Option<Value>)>> {
+    match value {
+        Value::Mapping(mapping) => mapping
+            .iter()
+            .map(|(key, value)| Some((as_string(key)?, Some(value.clone()))))
+            .collect(),
+        Value::Sequence(sequence) => sequence
+            .iter()
+            .map(|entry| {
+                let entry = as_string(entry)?;
+                Some(match entry.split_once('=') {
+                    Some((key, value)) => (key.to_owned(), Some(Value::String(value.to_owned()))),
+                    None => (entry, None),
+                })
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn command(value: &Value) -> Option<Command> {
+    match value {
+        Value::String(command) => Some(Command::SimpleCmd(command.clone())),
+        value => string_list(value).map(Command::ArraySyntax),
+    }
+}
+
+/// Returns the published and container port and whether the port is UDP
+fn parse_port(value: &Value) -> Option<(u16, u16, bool)> {
+    if let Value::Mapping(mapping) = value {
+        let target = mapping.get("target").and_then(as_string)?.parse().ok()?;
+        let published = match mapping.get("published").and_then(as_string) {
+            Some(published) => published.parse().ok()?,
+            None => target,
+        };
+        let udp = mapping.get("protocol").and_then(Value::as_str) == Some("udp");
+        return Some((published, target, udp));
+    }
+    let port = as_string(value)?;
+    let (ports, udp) = match port.split_once('/') {
+        Some((ports, protocol)) => (ports, protocol == "udp"),
+        None => (port.as_str(), false),
+    };
+    let mut parts = ports.rsplitn(3, ':');
+    let target = parts.next()?.parse().ok()?;
+    let published = match parts.next() {
+        Some(published) => published.parse().ok()?,
+        None => target,
+    };
+    Some((published, target, udp))
+}
+
+/// Returns the source and target of a volume, the source being None for anonymous volumes
+fn parse_volume(value: &Value) -> Option<(Option<String>, String)> {
+    if let Value::Mapping(mapping) = value {
+        let target = mapping.get("target").and_then(as_string)?;
+        return Some((mapping.get("source").and_then(as_string), target));
+    }
+    let volume = as_string(value)?;
+    let mut parts = volume.split(':');
+    let first = parts.next()?.to_owned();
+    Some(match parts.next() {
+        Some(target) => (Some(first), target.to_owned()),
+        None => (None, first),
+    })
+}
+
+/// The name of the data dir for a volume source like ./data, ${DATA_DIR}/db or a named volume,
+/// or None for host paths
+fn data_dir_name(source: &str) -> Option<String> {
+    if source.starts_with('/') || source.starts_with('~') {
+        return None;
+    }
+    let mut name = source;
+    if name.starts_with("${") {
+        name = name.split_once('}').map_or("", |(_, rest)| rest);
+    }
+    Some(
+        name.trim_start_matches("./")
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .to_owned(),
+    )
+}
+
+fn import_service(
+    name: &str,
+    service: &Value,
+    container: &mut Container,
+    main_port: &mut bool,
+    notes: &mut Notes,
+) -> Result<()> {
+    let Value::Mapping(service) = service else {
+        bail!("Service {} is not a map", name);
+    };
+    let invalid = |notes: &mut Notes, key: &str| {
+        notes.add(
+            format!("services.{}.{}", name, key),
+            format!("Could not read {}, it was dropped", key),
+        )
+    };
+    for (key, value) in service {
+        let Some(key) = key.as_str() else {
+            continue;
+        };
+        let field = format!("services.{}.{}", name, key);
+        match key {
+            "image" => container.image = as_string(value).unwrap_or_default(),
+            "user" => container.user = as_string(value),
+            "restart" => container.restart = as_string(value),
+            "stop_grace_period" => container.stop_grace_period = as_string(value),
+            "stop_signal" => container.stop_signal = as_string(value),
+            "working_dir" => container.working_dir = as_string(value),
+            "init" => container.init = value.as_bool(),
+            "shm_size" => {
+                container.shm_size = match value {
+                    Value::Number(number) => number.as_i64().map(StringOrNumber::Int),
+                    value => as_string(value).map(StringOrNumber::String),
+                }
+            }
+            "extra_hosts" => {
+                container.extra_hosts = key_values(value).map(|hosts| {
+                    hosts
+                        .into_iter()
+                        .map(|(host, ip)| match ip.as_ref().and_then(as_string) {
+                            Some(ip) => format!("{}:{}", host, ip),
+                            None => host,
+                        })
+                        .collect()
+                })
+            }
+            "depends_on" => {
+                container.depends_on = match value {
+                    Value::Mapping(mapping) => mapping.keys().map(as_string).collect(),
+                    value => string_list(value),
+                }
+            }
+            "command" => container.command = command(value),
+            "entrypoint" => container.entrypoint = command(value),
+            "build" => {
+                let build = match value {
+                    Value::Mapping(mapping) => Build {
+                        context: mapping
+                            .get("context")
+                            .and_then(as_string)
+                            .unwrap_or_else(|| ".".to_owned()),
+                        dockerfile: mapping.get("dockerfile").and_then(as_string),
+                        args: mapping
+                            .get("args")
+                            .and_then(key_values)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|(key, value)| Some((key, as_string(&value?)?)))
+                            .collect(),
+                    },
+                    value => Build {
+                        context: as_string(value).unwrap_or_else(|| ".".to_owned()),
+                        dockerfile: None,
+                        args: BTreeMap::new(),
+                    },
+                };
+                if !is_valid_build(&build.context, build.dockerfile.as_deref()) {
+                    notes.add(
+                        field,
+                        "The build context has to be in the app's dir, the build was dropped",
+                    );
+                    continue;
+                }
+                notes.permission(
+                    field,
+                    "Builds the image locally, which only works if local builds are enabled",
+                    "local-build",
+                );
+                container.build = Some(build);
+            }
+            "environment" => {
+                let Some(environment) = key_values(value) else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for (env_key, env_value) in environment {
+                    let env_field = format!("{}.{}", field, env_key);
+                    let env_value = match env_value {
+                        Some(Value::String(string)) => StringLike::String(string),
+                        Some(Value::Bool(bool)) => StringLike::Bool(bool),
+                        Some(Value::Number(number)) => match number.as_i64() {
+                            Some(int) => StringLike::Int(int),
+                            None => StringLike::Float(number.as_f64().unwrap_or_default()),
+                        },
+                        _ => {
+                            notes.add(
+                                env_field,
+                                format!(
+                                    "{} is passed through from the host, set a value instead",
+                                    env_key
+                                ),
+                            );
+                            continue;
+                        }
+                    };
+                    if let StringLike::String(string) = &env_value {
+                        for env_var in find_env_vars(string) {
+                            if let Some(permission) =
+                                env_var_permission(env_var, &[], &HashMap::new())
+                            {
+                                notes.permission(
+                                    env_field.clone(),
+                                    format!("Uses {}, replace it with a value or a setting", env_var),
+                                    &permission,
+                                );
+                            }
+                        }
+                    }
+                    container.environment.insert(env_key, env_value);
+                }
+            }
+            "ports" => {
+                let Some(ports) = value.as_sequence() else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for port in ports {
+                    let Some((published, target, udp)) = parse_port(port) else {
+                        notes.add(
+                            field.clone(),
+                            format!(
+                                "Port {} could not be converted, only single ports are supported",
+                                as_string(port).unwrap_or_default()
+                            ),
+                        );
+                        continue;
+                    };
+                    if udp {
+                        container
+                            .required_ports
+                            .udp
+                            .insert(published, PortTarget::Port(target));
+                    } else if !*main_port {
+                        *main_port = true;
+                        container.port = Some(target);
+                        notes.add(
+                            field.clone(),
+                            format!(
+                                "Port {} is proxied through Caddy as the app's web UI, move it to required_ports if it isn't HTTP",
+                                target
+                            ),
+                        );
+                    } else {
+                        container
+                            .required_ports
+                            .tcp
+                            .insert(published, PortTarget::Port(target));
+                    }
+                }
+            }
+            "volumes" => {
+                let Some(volumes) = value.as_sequence() else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for volume in volumes {
+                    let Some((source, target)) = parse_volume(volume) else {
+                        invalid(notes, key);
+                        continue;
+                    };
+                    // Anonymous volumes get a data dir named after their path in the container
+                    let source = source.unwrap_or_else(|| {
+                        target.rsplit('/').next().unwrap_or_default().to_owned()
+                    });
+                    let data_dir = match data_dir_name(&source) {
+                        Some(data_dir) => data_dir,
+                        None if source == "/var/run/docker.sock" => {
+                            notes.permission(
+                                field.clone(),
+                                "Mounts the Docker socket, which gives full control over the host, it was dropped",
+                                "root",
+                            );
+                            continue;
+                        }
+                        None => {
+                            notes.permission(
+                                field.clone(),
+                                format!(
+                                    "Mounts {} from the host, which apps can't do, it was dropped",
+                                    source
+                                ),
+                                "root",
+                            );
+                            continue;
+                        }
+                    };
+                    if data_dir.is_empty() || !is_valid_data_mount(&data_dir, &target) {
+                        notes.add(
+                            field.clone(),
+                            format!(
+                                "{} can't be a data dir, mount a subdir of the app's data dir instead",
+                                source
+                            ),
+                        );
+                        continue;
+                    }
+                    let StringOrMap::Map(data) = container
+                        .mounts
+                        .entry("data".to_owned())
+                        .or_insert_with(|| StringOrMap::Map(BTreeMap::new()))
+                    else {
+                        unreachable!();
+                    };
+                    data.insert(data_dir, target);
+                }
+            }
+            "cap_add" => {
+                container.cap_add = string_list(value).unwrap_or_default();
+                for capability in &container.cap_add {
+                    let permission = match capability.as_str() {
+                        "CAP_NET_RAW" | "NET_RAW" => "network",
+                        _ => "root",
+                    };
+                    notes.permission(
+                        field.clone(),
+                        format!("Adds the capability {}", capability),
+                        permission,
+                    );
+                }
+            }
+            "healthcheck" => match serde_yaml::from_value::<Healthcheck>(value.clone()) {
+                Ok(healthcheck) => container.healthcheck = Some(healthcheck),
+                Err(_) => invalid(notes, key),
+            },
+            "ulimits" => match serde_yaml::from_value::<BTreeMap<String, Ulimit>>(value.clone()) {
+                Ok(ulimits) => container.ulimits = ulimits,
+                Err(_) => invalid(notes, key),
+            },
+            "sysctls" => {
+                let Some(sysctls) = key_values(value) else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for (sysctl, sysctl_value) in sysctls {
+                    let sysctl_value = match sysctl_value {
+                        Some(Value::Number(number)) if number.is_i64() => {
+                            StringOrNumber::Int(number.as_i64().unwrap_or_default())
+                        }
+                        sysctl_value => {
+                            StringOrNumber::String(sysctl_value.as_ref().and_then(as_string).unwrap_or_default())
+                        }
+                    };
+                    if is_valid_sysctl(&sysctl) {
+                        container.sysctls.insert(sysctl, sysctl_value);
+                    } else {
+                        notes.permission(
+                            field.clone(),
+                            format!("Sysctl {} affects the host, only net.* sysctls are supported, it was dropped", sysctl),
+                            "root",
+                        );
+                    }
+                }
+            }
+            "security_opt" => {
+                for option in string_list(value).unwrap_or_default() {
+                    match security_opt_permission(&option) {
+                        None => {
+                            notes.add(
+                                field.clone(),
+                                format!("security_opt {} isn't supported, it was dropped", option),
+                            );
+                            continue;
+                        }
+                        Some(None) => {}
+                        Some(Some(permission)) => notes.permission(
+                            field.clone(),
+                            format!("Weakens the container's confinement with {}", option),
+                            permission,
+                        ),
+                    }
+                    container.security_opt.push(option);
+                }
+            }
+            "devices" => {
+                let Some(devices) = string_list(value) else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for device in devices {
+                    let Some(permission) = device_permission(&device) else {
+                        notes.add(
+                            field.clone(),
+                            format!("Device {} could not be converted, it was dropped", device),
+                        );
+                        continue;
+                    };
+                    notes.permission(
+                        field.clone(),
+                        format!("Uses the device {}", device),
+                        permission,
+                    );
+                    container.devices.push(device);
+                }
+            }
+            "tmpfs" => {
+                let tmpfs = match value {
+                    Value::String(tmpfs) => Some(vec![tmpfs.clone()]),
+                    value => string_list(value),
+                };
+                let Some(tmpfs) = tmpfs else {
+                    invalid(notes, key);
+                    continue;
+                };
+                for tmpfs in tmpfs {
+                    if is_valid_tmpfs(&tmpfs) {
+                        container.tmpfs.push(tmpfs);
+                    } else {
+                        notes.add(
+                            field.clone(),
+                            format!("tmpfs {} could not be converted, it was dropped", tmpfs),
+                        );
+                    }
+                }
+            }
+            "hostname" => match as_string(value).filter(|hostname| is_valid_domain(hostname)) {
+                Some(hostname) => container.hostname = Some(hostname),
+                None => invalid(notes, key),
+            },
+            // Services are always on the app network, only the aliases are kept
+            "networks" => {
+                let Value::Mapping(networks) = value else {
+                    continue;
+                };
+                for network in networks.values() {
+                    let aliases = network.get("aliases").and_then(string_list);
+                    for alias in aliases.unwrap_or_default() {
+                        if !is_valid_domain(&alias) {
+                            notes.add(
+                                field.clone(),
+                                format!("Alias {} could not be converted, it was dropped", alias),
+                            );
+                        } else if !container.aliases.contains(&alias) {
+                            notes.add(
+                                field.clone(),
+                                format!(
+                                    "Alias {} can only be used by one app, make sure it is specific to this app",
+                                    alias
+                                ),
+                            );
+                            container.aliases.push(alias);
+                        }
+                    }
+                }
+            }
+            "dns" | "dns_search" | "dns_opt" => {
+                let entries = match value {
+                    Value::String(entry) => Some(vec![entry.clone()]),
+                    value => string_list(value),
+                };
+                let Some(entries) = entries else {
+                    invalid(notes, key);
+                    continue;
+                };
+                let (is_valid, target): (fn(&str) -> bool, _) = match key {
+                    "dns" => (is_valid_dns_server, &mut container.dns),
+                    "dns_search" => (is_valid_domain, &mut container.dns_search),
+                    _ => (is_valid_dns_opt, &mut container.dns_opt),
+                };
+                for entry in entries {
+                    if is_valid(&entry) {
+                        target.push(entry);
+                    } else {
+                        notes.add(
+                            field.clone(),
+                            format!("{} could not be converted, it was dropped", entry),
+                        );
+                    }
+                }
+            }
+            "network_mode" => {
+                if value.as_str() == Some("host") {
+                    container.network_mode = Some("host".to_owned());
+                    notes.permission(field, "Uses the host network", "network");
+                } else {
+                    notes.add(
+                        field,
+                        "Only network_mode: host is supported, it was dropped",
+                    );
+                }
+            }
+            // Extension fields, which compose ignores too
+            key if MANAGED_KEYS.contains(&key) || key.starts_with("x-") => {}
+            key if HOST_ACCESS_KEYS.contains(&key) => notes.permission(
+                field,
+                format!(
+                    "{} gives the container access to the host and isn't supported by app.yml, it was dropped",
+                    key
+                ),
+                "root",
+            ),
+            key => notes.add(
+                field,
+                format!("{} isn't supported by app.yml, it was dropped", key),
+            ),
+        }
+    }
+    if container.image.is_empty() {
+        bail!("Service {} has no image", name);
+    }
+    Ok(())
+}
+
+/// Notes for the keys of a map that aren't in known, for the fields of other formats' app definitions
+pub fn unknown_keys(value: &Value, prefix: &str, known: &[&str]) -> Vec<ImportNote> {
+    let Value::Mapping(mapping) = value else {
+        return Vec::new();
+    };
+    mapping
+        .keys()
+        .filter_map(as_string)
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| {
+            ImportNote::new(
+                format!("{}.{}", prefix, key),
+                format!("{} isn't supported, it was dropped", key),
+            )
+        })
+        .collect()
+}
+
+/// Replaces references to env vars, as $VAR or ${VAR}, in all strings
+/// Returns whether a Tera expression was inserted, which makes the app an app.yml.jinja
+pub fn replace_env_vars(value: &mut Value, replacements: &[(&str, &str)]) -> bool {
+    match value {
+        Value::String(string) => {
+            let mut templated = false;
+            for (env_var, replacement) in replacements {
+                for reference in [format!("${{{}}}", env_var), format!("${}", env_var)] {
+                    // $APP_SEED is a prefix of $APP_SEED_1 and similar vars of other apps
+                    let mut rest = string.as_str();
+                    let mut replaced = String::new();
+                    while let Some(index) = rest.find(&reference) {
+                        let after = &rest[index + reference.len()..];
+                        replaced.push_str(&rest[..index]);
+                        // $$VAR is a literal $VAR, not a reference
+                        let escaped = ends_with_escaping_dollar(&replaced);
+                        if !escaped
+                            && (reference.ends_with('}')
+                                || !after.starts_with(|char: char| {
+                                    char.is_ascii_alphanumeric() || char == '_'
+                                }))
+                        {
+                            replaced.push_str(replacement);
+                            templated |= replacement.contains("{{");
+                        } else {
+                            replaced.push_str(&reference);
+                        }
+                        rest = after;
+                    }
+                    replaced.push_str(rest);
+                    *string = replaced;
+                }
+            }
+            templated
+        }
+        Value::Sequence(sequence) => sequence.iter_mut().fold(false, |templated, value| {
+            replace_env_vars(value, replacements) | templated
+        }),
+        Value::Mapping(mapping) => mapping.values_mut().fold(false, |templated, value| {
+            replace_env_vars(value, replacements) | templated
+        }),
+        _ => false,
+    }
+}
+
+/// A version for metadata.yml from the tag of an image like nginx:1.25, if the tag looks like a version
+fn version_from_image(image: &str) -> Option<String> {
+    let image = image.split('@').next()?;
+    let (_, tag) = image
+        .rsplit_once(':')
+        .filter(|(_, tag)| !tag.contains('/'))?;
+    let version = tag.strip_prefix('v').unwrap_or(tag);
+    version
+        .starts_with(|char: char| char.is_ascii_digit())
+        .then(|| version.to_owned())
+}
+
+/// Converts a compose file parsed with manage::yaml::parse_value
+/// Without a web UI, the first published TCP port of the first service with one is used
+pub fn import_compose(
+    compose: &Value,
+    app_name: &str,
+    web_ui: Option<&WebUi>,
+) -> Result<ImportedApp> {
+    let Value::Mapping(compose) = compose else {
+        bail!("The compose file is not a map");
+    };
+    let mut notes = Notes(Vec::new());
+    for key in compose.keys().filter_map(Value::as_str) {
+        if !matches!(
+            key,
+            "services" | "volumes" | "networks" | "version" | "name"
+        ) && !key.starts_with("x-")
+        {
+            notes.add(
+                key,
+                format!("{} isn't supported by app.yml, it was dropped", key),
+            );
+        }
+    }
+    let services = compose
+        .get("services")
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| anyhow!("The compose file has no services"))?;
+    // Sorted, so the first service by name gets the main port
+    let services = services
+        .iter()
+        .filter_map(|(name, service)| Some((as_string(name)?, service)))
+        .collect::<BTreeMap<_, _>>();
+    let mut app_yml = AppYml {
+        version: 1,
+        ..Default::default()
+    };
+    let mut main_port = web_ui.is_some();
+    for (name, service) in services {
+        let mut container = Container::default();
+        import_service(&name, service, &mut container, &mut main_port, &mut notes)?;
+        app_yml.services.insert(name, container);
+    }
+    if let Some(web_ui) = web_ui {
+        let container = app_yml
+            .services
+            .get_mut(&web_ui.service)
+            .ok_or_else(|| anyhow!("The web UI is in unknown service {}", web_ui.service))?;
+        container.port = Some(web_ui.port);
+        // Caddy publishes the port
+        container
+            .required_ports
+            .tcp
+            .retain(|_, target| target.port() != web_ui.port);
+    }
+
+    // Apps need a main service, which is the one with the web UI
+    if !app_yml.services.contains_key("main") {
+        let main = app_yml
+            .services
+            .iter()
+            .find(|(_, container)| container.port.is_some())
+            .or_else(|| app_yml.services.iter().min_by_key(|(name, _)| *name))
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| anyhow!("The compose file has no services"))?;
+        let container = app_yml.services.remove(&main).expect("Service exists");
+        app_yml.services.insert("main".to_owned(), container);
+        for container in app_yml.services.values_mut() {
+            for dependency in container.depends_on.iter_mut().flatten() {
+                if *dependency == main {
+                    *dependency = "main".to_owned();
+                }
+            }
+        }
+        notes.add(
+            format!("services.{}", main),
+            format!(
+                "Service {} was renamed to main, update references to its hostname",
+                main
+            ),
+        );
+    }
+
+    let images = app_yml
+        .services
+        .values()
+        .map(|container| container.image.as_str())
+        .collect::<BTreeSet<_>>();
+    let version = images
+        .iter()
+        .find_map(|image| version_from_image(image))
+        .unwrap_or_else(|| "0.1.0".to_owned());
+    let metadata = MetadataYml {
+        version: 1,
+        metadata: InputMetadata {
+            name: app_name.to_owned(),
+            version,
+            gallery: Some(Vec::new()),
+            ..Default::default()
+        },
+    };
+    Ok(ImportedApp {
+        app_yml,
+        metadata,
+        notes: notes.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"
+services:
+  web:
+    image: ghcr.io/example/web:v2.3.1
+    container_name: web
+    ports:
+      - "8080:80"
+      - "2222:22"
+      - "5353:53/udp"
+    volumes:
+      - ./config:/config
+      - db-data:/var/lib/data:ro
+      - /var/run/docker.sock:/var/run/docker.sock
+    environment:
+      - TZ=UTC
+      - API_KEY=${API_KEY}
+    cap_add:
+      - NET_ADMIN
+    privileged: true
+    depends_on:
+      db:
+        condition: service_healthy
+  db:
+    image: postgres:16
+    environment:
+      POSTGRES_PORT: 5432
+volumes:
+  db-data:
+secrets:
+  token:
+    file: ./token
+"#;
+
+    #[test]
+    fn imports_compose_file() {
+        let compose = serde_yaml::from_str(COMPOSE).unwrap();
+        let imported = import_compose(&compose, "Example", None).unwrap();
+        // web has the web UI, so it becomes the main service
+        let web = &imported.app_yml.services["main"];
+        assert_eq!(web.port, Some(80));
+        assert_eq!(web.required_ports.tcp[&2222], PortTarget::Port(22));
+        assert_eq!(web.required_ports.udp[&5353], PortTarget::Port(53));
+        assert_eq!(
+            web.mounts["data"],
+            StringOrMap::Map(BTreeMap::from([
+                ("config".to_owned(), "/config".to_owned()),
+                ("db-data".to_owned(), "/var/lib/data".to_owned()),
+            ]))
+        );
+        assert_eq!(web.depends_on, Some(vec!["db".to_owned()]));
+        assert_eq!(
+            imported.app_yml.services["db"].environment["POSTGRES_PORT"],
+            StringLike::Int(5432)
+        );
+        // db comes first by name, but has no ports
+        assert_eq!(imported.app_yml.services["db"].port, None);
+        assert_eq!(imported.metadata.metadata.version, "2.3.1");
+
+        let permissions = imported
+            .notes
+            .iter()
+            .filter_map(|note| Some((note.field.as_str(), note.permission.as_deref()?)))
+            .collect::<Vec<_>>();
+        assert!(permissions.contains(&("services.web.volumes", "root")));
+        assert!(imported
+            .notes
+            .iter()
+            .any(|note| note.field == "services.web"));
+        assert!(permissions.contains(&("services.web.environment.API_KEY", "root")));
+        assert!(permissions.contains(&("services.web.cap_add", "root")));
+        assert!(permissions.contains(&("services.web.privileged", "root")));
+        assert!(imported.notes.iter().any(|note| note.field == "secrets"));
+
+        let web_ui = WebUi {
+            service: "db".to_owned(),
+            port: 5432,
+        };
+        let imported = import_compose(&compose, "Example", Some(&web_ui)).unwrap();
+        assert_eq!(imported.app_yml.services["main"].port, Some(5432));
+        assert_eq!(imported.app_yml.services["web"].port, None);
+        assert_eq!(
+            imported.app_yml.services["web"].required_ports.tcp[&8080],
+            PortTarget::Port(80)
+        );
+    }
+
+    #[test]
+    fn keeps_escaped_env_vars() {
+        let mut value = Value::String("$APP_SEED $$APP_SEED $$$APP_SEED $${APP_SEED}".to_owned());
+        replace_env_vars(&mut value, &[("APP_SEED", "seed")]);
+        assert_eq!(
+            value,
+            Value::String("seed $$APP_SEED $$seed $${APP_SEED}".to_owned())
+        );
+    }
+}
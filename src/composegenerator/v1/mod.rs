@@ -1,5 +1,6 @@
 pub mod convert;
-mod helpers;
+pub(crate) mod helpers;
+pub mod import;
 pub mod types;
 
-pub const RESERVED_NAMES: [&str; 3] = ["root", "network", "apps"];
+pub const RESERVED_NAMES: [&str; 6] = ["root", "network", "apps", "local-build", "gpu", "fuse"];
@@ -2,22 +2,73 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 
-use crate::composegenerator::types::{Command, Dependency, Permission};
-use crate::manage::ports::{PortMapEntry, PortPriority};
-use crate::utils::{is_false, StringLike, StringOrNumber};
+use anyhow::{bail, Result};
+
+use crate::composegenerator::{
+    output::types::{Build, Healthcheck, Ulimit},
+    types::{
+        AppAction, Bandwidth, Command, Dependency, LoggingOptions, MetricsEndpoint, Permission,
+        Task, UpdateStrategy, ValidationMode, Widget,
+    },
+};
+use crate::manage::{
+    dirs::{owner_from_user, DataDir, DEFAULT_MODE},
+    ports::{PortMapEntry, PortPriority},
+};
+
+use super::helpers::is_valid_data_mount;
+use crate::utils::{is_false, key_value_map, StringLike, StringOrNumber};
+
+/// The container port a public port is forwarded to, either just the port or the port with options
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(untagged)]
+pub enum PortTarget {
+    Port(u16),
+    WithOptions {
+        port: u16,
+        /// Also publish the port on IPv6, if IPv6 is enabled in the config
+        #[serde(default, skip_serializing_if = "is_false")]
+        ipv6: bool,
+        /// Ask the router to forward the port from the internet, for example for P2P nodes
+        #[serde(default, skip_serializing_if = "is_false")]
+        wan_required: bool,
+    },
+}
+
+impl PortTarget {
+    pub fn port(self) -> u16 {
+        match self {
+            PortTarget::Port(port) | PortTarget::WithOptions { port, .. } => port,
+        }
+    }
+
+    pub fn ipv6(self) -> bool {
+        matches!(self, PortTarget::WithOptions { ipv6: true, .. })
+    }
+
+    pub fn wan_required(self) -> bool {
+        matches!(
+            self,
+            PortTarget::WithOptions {
+                wan_required: true,
+                ..
+            }
+        )
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
 pub struct PortsDefinition {
     /// Ports that may not be proxied through Caddy
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub direct_tcp: HashMap<u16, u16>,
+    pub direct_tcp: HashMap<u16, PortTarget>,
     /// TCP ports that may be proxied through Caddy (and support TLS)
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub tcp: HashMap<u16, u16>,
+    pub tcp: HashMap<u16, PortTarget>,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub http: HashMap<u16, u16>,
+    pub http: HashMap<u16, PortTarget>,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub udp: HashMap<u16, u16>,
+    pub udp: HashMap<u16, PortTarget>,
 }
 
 impl PortsDefinition {
@@ -36,6 +87,27 @@ pub enum StringOrMap {
     Map(BTreeMap<String, String>),
 }
 
+fn default_wait_for_service() -> String {
+    "main".to_owned()
+}
+
+/// Another app a container waits for before it starts, like a database app
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct WaitFor {
+    /// The app, which has to be one of the app's dependencies
+    pub app: String,
+    #[serde(default = "default_wait_for_service")]
+    pub service: String,
+    /// The port that has to accept connections
+    pub port: u16,
+    /// An HTTP path like /health on the port that has to respond successfully
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// How long to wait before the container fails to start, like 2m, 60s by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, JsonSchema)]
 pub struct Container {
     // These can be copied directly without validation
@@ -54,21 +126,78 @@ pub struct Container {
     pub init: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_hosts: Option<Vec<String>>,
+    /// The hostname inside the container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Names other apps can reach the service under on the app network, like postgres
+    /// Every alias can only be used by one app
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub aliases: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub working_dir: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub shm_size: Option<StringOrNumber>,
+    /// Overrides the logging defaults from the host's config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<LoggingOptions>,
+    /// A Prometheus metrics endpoint monitoring apps can scrape
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsEndpoint>,
+    /// Priority and caps for traffic shaping on the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<Bandwidth>,
+    /// Other apps that have to be ready before the container starts
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub wait_for: Vec<WaitFor>,
     // These need security checks
+    /// Builds the image locally instead of pulling it, the image is used as tag for the built image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<Build>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entrypoint: Option<Command>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub command: Option<Command>,
-    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        default,
+        deserialize_with = "key_value_map"
+    )]
     pub environment: BTreeMap<String, StringLike>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub cap_add: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub network_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<Healthcheck>,
+    /// Resource limits like nofile or memlock, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub ulimits: BTreeMap<String, Ulimit>,
+    /// Only net.* sysctls, which only affect the container's own network namespace
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        default,
+        deserialize_with = "key_value_map"
+    )]
+    pub sysctls: BTreeMap<String, StringOrNumber>,
+    /// Devices like /dev/dri, as host_path[:container_path[:permissions]]
+    /// /dev/dri needs the gpu permission, /dev/fuse the fuse permission and other devices the root permission
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub devices: Vec<String>,
+    /// In-memory filesystems, as container_path[:options] like /tmp:size=64m
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub tmpfs: Vec<String>,
+    /// DNS servers as IP addresses, or env vars like ${APP_ADGUARD_MAIN_IP} of apps the app has permissions for
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns: Vec<String>,
+    /// Domains to search for hostnames without a domain
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns_search: Vec<String>,
+    /// Resolver options like ndots:2 or edns0
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns_opt: Vec<String>,
+    /// no-new-privileges is always allowed, apparmor, seccomp, label and systempaths options need the root permission
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub security_opt: Vec<String>,
     // These are not directly present in a compose file and need to be converted
     #[serde(skip_serializing_if = "Option::is_none")]
     pub port: Option<u16>,
@@ -88,6 +217,9 @@ pub struct Container {
     #[serde(default = "bool::default")]
     #[serde(skip_serializing_if = "is_false")]
     pub disable_caddy: bool,
+    /// Also publish the main port on IPv6, if IPv6 is enabled in the config
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ipv6: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
@@ -107,6 +239,25 @@ pub struct InputMetadata {
     #[serde(default)]
     /// Other apps this app depends on
     pub dependencies: Vec<Dependency>,
+    /// Apps this app can use if they are installed, but does not require
+    /// Their permissions can be requested in app_yml_jinja_permissions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub optional_dependencies: Vec<String>,
+    /// Apps that can not be installed at the same time as this app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    /// Resources only one installed app can use, like a device path or hostPort:<port>
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub claims: Vec<String>,
+    /// Previous ids of the app, installations under these ids are migrated to the current id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// Tiles the dashboard shows for the app, with data from its own services
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub widgets: Vec<Widget>,
+    /// Named links into the app, like an admin page or docs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AppAction>,
     /// App repository name -> repo URL
     pub repo: BTreeMap<String, String>,
     /// A support link for the app
@@ -128,6 +279,9 @@ pub struct InputMetadata {
     /// A list of containers to update automatically (still validated by the Citadel team)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub update_containers: Option<Vec<String>>,
+    /// How host scripts update the app's containers: recreate, rolling or stop-first
+    #[serde(default)]
+    pub update_strategy: UpdateStrategy,
     /// For "virtual" apps, the service the app implements
     #[serde(skip_serializing_if = "Option::is_none")]
     pub implements: Option<String>,
@@ -144,6 +298,12 @@ pub struct InputMetadata {
         skip_serializing_if = "Vec::<String>::is_empty"
     )]
     pub app_yml_jinja_permissions: Vec<String>,
+    /// Set when the app was converted from a legacy Citadel app.yml on sync
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub legacy: bool,
+    /// The oldest app manager version that can generate this app, older ones skip it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_manager_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, JsonSchema)]
@@ -166,6 +326,9 @@ pub struct AppYmlMetadata {
         skip_serializing_if = "Vec::<String>::is_empty"
     )]
     pub has_permissions: Vec<String>,
+    /// Why the app needs the root permission, for store reviewers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_justification: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, JsonSchema)]
@@ -173,9 +336,23 @@ pub struct AppYmlMetadata {
 pub struct AppYml {
     pub version: u8,
     pub services: HashMap<String, Container>,
+    /// One-shot containers that run in order before the services start, for example for migrations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub init_containers: Vec<InitContainer>,
+    /// Commands to run periodically, like backups
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<Task>,
     pub metadata: AppYmlMetadata,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct InitContainer {
+    /// The service name of the container, which may not be used by another service
+    pub name: String,
+    #[serde(flatten)]
+    pub container: Container,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, JsonSchema)]
 /// Nirvati app metadata definition
 pub struct MetadataYml {
@@ -184,7 +361,36 @@ pub struct MetadataYml {
 }
 
 impl AppYml {
-    pub fn get_ports(&self, own_id: &str, implements: Option<String>) -> Vec<PortMapEntry> {
+    /// The services and init containers, by service name
+    pub fn containers(&self) -> Vec<(&String, &Container)> {
+        self.services
+            .iter()
+            .chain(
+                self.init_containers
+                    .iter()
+                    .map(|init| (&init.name, &init.container)),
+            )
+            .collect()
+    }
+
+    /// The network aliases of all services, sorted
+    pub fn get_network_aliases(&self) -> Vec<String> {
+        let mut aliases = self
+            .containers()
+            .into_iter()
+            .flat_map(|(_, container)| container.aliases.iter().cloned())
+            .collect::<Vec<_>>();
+        aliases.sort();
+        aliases.dedup();
+        aliases
+    }
+
+    pub fn get_ports(
+        &self,
+        own_id: &str,
+        implements: Option<String>,
+        mode: ValidationMode,
+    ) -> Result<Vec<PortMapEntry>> {
         let mut ports = Vec::new();
         for (container_name, container) in self.services.iter() {
             if let Some(port) = container.port {
@@ -200,7 +406,7 @@ impl AppYml {
             for (public_port, container_port) in container.required_ports.direct_tcp.iter() {
                 ports.push(PortMapEntry {
                     app: own_id.to_owned(),
-                    internal_port: *container_port,
+                    internal_port: container_port.port(),
                     public_port: *public_port,
                     container: container_name.to_owned(),
                     implements: implements.clone(),
@@ -210,7 +416,7 @@ impl AppYml {
             for (public_port, container_port) in container.required_ports.tcp.iter() {
                 ports.push(PortMapEntry {
                     app: own_id.to_owned(),
-                    internal_port: *container_port,
+                    internal_port: container_port.port(),
                     public_port: *public_port,
                     container: container_name.to_owned(),
                     implements: implements.clone(),
@@ -219,11 +425,14 @@ impl AppYml {
             }
             for (public_port, container_port) in container.required_ports.udp.iter() {
                 if ports.iter().any(|p| p.public_port == *public_port) {
+                    if mode == ValidationMode::Strict {
+                        bail!("Public port {} is declared more than once", public_port);
+                    }
                     continue;
                 }
                 ports.push(PortMapEntry {
                     app: own_id.to_owned(),
-                    internal_port: *container_port,
+                    internal_port: container_port.port(),
                     public_port: *public_port,
                     container: container_name.to_owned(),
                     implements: implements.clone(),
@@ -232,11 +441,14 @@ impl AppYml {
             }
             for (public_port, container_port) in container.required_ports.http.iter() {
                 if ports.iter().any(|p| p.public_port == *public_port) {
+                    if mode == ValidationMode::Strict {
+                        bail!("Public port {} is declared more than once", public_port);
+                    }
                     continue;
                 }
                 ports.push(PortMapEntry {
                     app: own_id.to_owned(),
-                    internal_port: *container_port,
+                    internal_port: container_port.port(),
                     public_port: *public_port,
                     container: container_name.to_owned(),
                     implements: implements.clone(),
@@ -244,6 +456,39 @@ impl AppYml {
                 });
             }
         }
-        ports
+        Ok(ports)
+    }
+    /// The directories the app's data mounts need, sorted by path
+    pub fn get_data_dirs(&self) -> Vec<DataDir> {
+        let mut dirs: Vec<DataDir> = Vec::new();
+        // If containers share a dir, the owner of the first service by name wins, init containers only come after them
+        let mut services = self.services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(name, _)| *name);
+        let init_containers = self
+            .init_containers
+            .iter()
+            .map(|init| (&init.name, &init.container));
+        for (_, container) in services.into_iter().chain(init_containers) {
+            let Some(StringOrMap::Map(data_mounts)) = container.mounts.get("data") else {
+                continue;
+            };
+            let (uid, gid) = owner_from_user(container.user.as_deref());
+            for (host_dir, container_dir) in data_mounts {
+                if !is_valid_data_mount(host_dir, container_dir)
+                    || dirs.iter().any(|dir| &dir.path == host_dir)
+                {
+                    continue;
+                }
+                dirs.push(DataDir {
+                    path: host_dir.to_owned(),
+                    uid,
+                    gid,
+                    mode: DEFAULT_MODE.to_owned(),
+                    host_path: None,
+                });
+            }
+        }
+        dirs.sort_by(|a, b| a.path.cmp(&b.path));
+        dirs
     }
 }
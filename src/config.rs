@@ -0,0 +1,241 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::types::{LoggingOptions, ValidationMode},
+    manage::{ports::PortPolicy, secrets::SecretsConfig, snapshots::SnapshotConfig},
+    tera::js::SandboxOptions,
+};
+
+/// The system-wide config file, which is optional
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/nirvati/config.toml";
+
+fn default_runtime() -> String {
+    "docker".to_string()
+}
+
+fn default_subnet() -> String {
+    "10.21.0.0/16".to_string()
+}
+
+fn default_tor_proxy() -> String {
+    "socks5://127.0.0.1:9050".to_string()
+}
+
+/// The range public ports are dynamically assigned from, inclusive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Default for PortRange {
+    fn default() -> Self {
+        Self {
+            start: 1,
+            end: u16::MAX,
+        }
+    }
+}
+
+/// Host-level app manager configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The Nirvati root directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// The container runtime the host scripts use
+    #[serde(default = "default_runtime")]
+    pub runtime: String,
+    /// The subnet app containers are assigned IPs from
+    #[serde(default = "default_subnet")]
+    pub subnet: String,
+    /// The IPv6 subnet app containers are assigned IPs from, IPv6 is disabled if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_subnet: Option<String>,
+    /// Ports that may never be assigned to apps, in addition to 80 and 443
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved_ports: Vec<u16>,
+    /// Where ports are moved to when an app's preferred public port is taken
+    #[serde(default)]
+    pub port_range: PortRange,
+    /// Also reserve ports that services outside of Nirvati are listening on
+    #[serde(default)]
+    pub scan_host_ports: bool,
+    /// Fail on invalid declarations in app.yml files instead of skipping them
+    #[serde(default)]
+    pub strict: bool,
+    /// Allow apps that build their images locally
+    #[serde(default)]
+    pub allow_local_builds: bool,
+    /// Logging options for every container that doesn't set them itself
+    #[serde(default)]
+    pub logging: LoggingOptions,
+    /// Limits of the JS helpers in _tera
+    #[serde(default)]
+    pub sandbox: SandboxOptions,
+    /// Never access the network, for air-gapped devices
+    #[serde(default)]
+    pub offline: bool,
+    /// The HTTP or SOCKS5 proxy all network access goes through, $NIRVATI_PROXY takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// The SOCKS5 proxy of the Tor daemon, which sync --prefer-tor downloads stores through
+    #[serde(default = "default_tor_proxy")]
+    pub tor_proxy: String,
+    /// The device only uses Tor, so all network access goes through tor_proxy unless a proxy is set
+    #[serde(default)]
+    pub tor_only: bool,
+    /// Where secretRef: values in settings and permission variables are resolved from
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    /// Storage pool name -> the dir app data dirs in it are created in, for example on an HDD
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage_pools: BTreeMap<String, PathBuf>,
+    /// How app data dirs can be snapshotted
+    #[serde(default)]
+    pub snapshots: SnapshotConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            root: None,
+            runtime: default_runtime(),
+            subnet: default_subnet(),
+            ipv6_subnet: None,
+            reserved_ports: Vec::new(),
+            port_range: PortRange::default(),
+            scan_host_ports: false,
+            strict: false,
+            allow_local_builds: false,
+            logging: LoggingOptions::default(),
+            sandbox: SandboxOptions::default(),
+            offline: false,
+            proxy: None,
+            tor_proxy: default_tor_proxy(),
+            tor_only: false,
+            secrets: SecretsConfig::default(),
+            storage_pools: BTreeMap::new(),
+            snapshots: SnapshotConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from the given path, which has to exist,
+    /// or from the default path, in which case a missing file results in the default config
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG_PATH), false),
+        };
+        if !required && !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?;
+        let config: Self = toml::from_str(&contents)
+            .map_err(|err| anyhow!("Invalid {}: {}", path.display(), err))?;
+        if config.port_range.start > config.port_range.end {
+            bail!(
+                "Invalid {}: port_range starts after it ends",
+                path.display()
+            );
+        }
+        Ok(config)
+    }
+
+    pub fn validation_mode(&self) -> ValidationMode {
+        if self.strict {
+            ValidationMode::Strict
+        } else {
+            ValidationMode::Permissive
+        }
+    }
+
+    pub fn port_policy(&self) -> PortPolicy {
+        PortPolicy::new(
+            &self.reserved_ports,
+            self.port_range.start..=self.port_range.end,
+        )
+    }
+
+    /// Determines the proxy for all network access
+    /// $NIRVATI_PROXY takes precedence over the config file, in tor_only mode Tor is used if neither sets one
+    pub fn resolve_proxy(&self) -> Option<String> {
+        std::env::var("NIRVATI_PROXY")
+            .ok()
+            .filter(|proxy| !proxy.is_empty())
+            .or_else(|| self.proxy.clone())
+            .or_else(|| self.tor_only.then(|| self.tor_proxy.clone()))
+    }
+
+    /// Determines the Nirvati root
+    /// The directory passed on the command line takes precedence over $NIRVATI_DIR, which takes precedence over the config file
+    pub fn resolve_root(&self, cli_dir: Option<&Path>) -> Result<PathBuf> {
+        self.resolve_root_with_env(cli_dir, std::env::var_os("NIRVATI_DIR"))
+    }
+
+    fn resolve_root_with_env(
+        &self,
+        cli_dir: Option<&Path>,
+        env_dir: Option<OsString>,
+    ) -> Result<PathBuf> {
+        if let Some(dir) = cli_dir {
+            return Ok(dir.to_path_buf());
+        }
+        if let Some(dir) = env_dir.filter(|dir| !dir.is_empty()) {
+            return Ok(PathBuf::from(dir));
+        }
+        self.root.clone().ok_or_else(|| {
+            anyhow!(
+                "No Nirvati root found, pass it as argument or with --dir, set NIRVATI_DIR or set root in {}",
+                DEFAULT_CONFIG_PATH
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_the_root_in_order_of_precedence() {
+        let config = Config {
+            root: Some(PathBuf::from("/config")),
+            ..Default::default()
+        };
+        let env = || Some(OsString::from("/env"));
+        let cli = Some(Path::new("/cli"));
+        assert_eq!(
+            config.resolve_root_with_env(cli, env()).unwrap(),
+            Path::new("/cli")
+        );
+        assert_eq!(
+            config.resolve_root_with_env(None, env()).unwrap(),
+            Path::new("/env")
+        );
+        // An empty NIRVATI_DIR counts as not set
+        assert_eq!(
+            config
+                .resolve_root_with_env(None, Some(OsString::new()))
+                .unwrap(),
+            Path::new("/config")
+        );
+        assert_eq!(
+            config.resolve_root_with_env(None, None).unwrap(),
+            Path::new("/config")
+        );
+        assert!(Config::default().resolve_root_with_env(None, None).is_err());
+    }
+}
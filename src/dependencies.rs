@@ -1,3 +1,10 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::composegenerator::types::{Dependency, Diagnostic, DiagnosticCode, OutputMetadata};
+
+/// Maps every app and app/permission to the apps that depend on it or use it
+pub type ReverseIndex = BTreeMap<String, BTreeSet<String>>;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Node {
     pub id: String,
@@ -59,6 +66,135 @@ pub fn sort_deps(nodes: Vec<Node>) -> Vec<String> {
     sorted
 }
 
+/// Records which installed app satisfies each of an app's dependencies
+/// For alternative dependencies, the first installed alternative is used
+/// Apps with a dependency that is not satisfied are marked as incompatible
+pub fn resolve_dependencies(metadata: &mut OutputMetadata, installed_apps: &[String]) {
+    metadata.resolved_dependencies = metadata
+        .dependencies
+        .iter()
+        .map(|dependency| {
+            let options = match dependency {
+                Dependency::OneDependency(dep) => std::slice::from_ref(dep),
+                Dependency::AlternativeDependency(deps) => deps.as_slice(),
+            };
+            options
+                .iter()
+                .find(|dep| installed_apps.contains(dep))
+                .cloned()
+        })
+        .collect();
+    if metadata.resolved_dependencies.iter().any(Option::is_none) {
+        metadata.compatible = false;
+    }
+}
+
+/// Returns the apps out of `apps` that conflict with an app, in either direction
+pub fn get_conflicts(catalog: &[OutputMetadata], app: &str, apps: &[String]) -> Vec<String> {
+    let declared = catalog
+        .iter()
+        .find(|entry| entry.id == app)
+        .map(|entry| entry.conflicts.as_slice())
+        .unwrap_or_default();
+    apps.iter()
+        .filter(|other| other.as_str() != app)
+        .filter(|other| {
+            declared.contains(other)
+                || catalog.iter().any(|entry| {
+                    entry.id == **other && entry.conflicts.iter().any(|conflict| conflict == app)
+                })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Marks apps that conflict with an installed app as incompatible
+pub fn mark_conflicts(registry: &mut [OutputMetadata], installed_apps: &[String]) {
+    let conflicts = registry
+        .iter()
+        .filter(|entry| !installed_apps.contains(&entry.id))
+        .map(|entry| {
+            (
+                entry.id.clone(),
+                get_conflicts(registry, &entry.id, installed_apps),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    for entry in registry.iter_mut() {
+        entry
+            .diagnostics
+            .retain(|diagnostic| diagnostic.code != DiagnosticCode::AppConflict);
+        let Some(conflicts) = conflicts.get(&entry.id).filter(|apps| !apps.is_empty()) else {
+            continue;
+        };
+        entry.compatible = false;
+        entry.diagnostics.push(Diagnostic::warning(
+            DiagnosticCode::AppConflict,
+            format!(
+                "Conflicts with {}, which is installed",
+                conflicts.join(", ")
+            ),
+            None,
+        ));
+    }
+}
+
+/// Marks apps that depend on a disabled app as incompatible
+pub fn mark_disabled_dependencies(registry: &mut [OutputMetadata], disabled_apps: &[String]) {
+    for entry in registry.iter_mut() {
+        entry
+            .diagnostics
+            .retain(|diagnostic| diagnostic.code != DiagnosticCode::DependencyDisabled);
+        let disabled = entry
+            .resolved_dependencies
+            .iter()
+            .flatten()
+            .filter(|dep| disabled_apps.contains(dep))
+            .cloned()
+            .collect::<Vec<_>>();
+        if disabled.is_empty() {
+            continue;
+        }
+        entry.compatible = false;
+        entry.diagnostics.push(Diagnostic::warning(
+            DiagnosticCode::DependencyDisabled,
+            format!("Depends on {}, which is disabled", disabled.join(", ")),
+            None,
+        ));
+    }
+}
+
+/// Builds the reverse dependency index from the dependencies and permissions in the registry
+pub fn reverse_index(catalog: &[OutputMetadata]) -> ReverseIndex {
+    let mut index = ReverseIndex::new();
+    for app in catalog {
+        let dependencies = app
+            .dependencies
+            .iter()
+            .flat_map(|dependency| match dependency {
+                Dependency::OneDependency(dep) => vec![dep],
+                Dependency::AlternativeDependency(deps) => deps.iter().collect(),
+            });
+        for used in dependencies.chain(app.has_permissions.iter()) {
+            index
+                .entry(used.to_owned())
+                .or_default()
+                .insert(app.id.clone());
+        }
+    }
+    index
+}
+
+/// Returns the apps that depend on an app or use any of its permissions
+pub fn get_consumers(index: &ReverseIndex, app: &str) -> BTreeSet<String> {
+    index
+        .iter()
+        .filter(|(used, _)| used.split('/').next() == Some(app))
+        .flat_map(|(_, consumers)| consumers.iter().cloned())
+        .filter(|consumer| consumer != app)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,4 +256,122 @@ mod tests {
         let sorted = sort_deps(nodes);
         assert_eq!(sorted, vec!["f", "e", "d"]);
     }
+
+    #[test]
+    fn test_resolve_dependencies() {
+        let mut metadata = OutputMetadata {
+            dependencies: vec![
+                Dependency::OneDependency("bitcoin".to_owned()),
+                Dependency::AlternativeDependency(vec!["electrs".to_owned(), "fulcrum".to_owned()]),
+            ],
+            compatible: true,
+            ..Default::default()
+        };
+        resolve_dependencies(&mut metadata, &["fulcrum".to_owned(), "bitcoin".to_owned()]);
+        assert_eq!(
+            metadata.resolved_dependencies,
+            vec![Some("bitcoin".to_owned()), Some("fulcrum".to_owned())]
+        );
+        assert!(metadata.compatible);
+        resolve_dependencies(&mut metadata, &["bitcoin".to_owned()]);
+        assert_eq!(
+            metadata.resolved_dependencies,
+            vec![Some("bitcoin".to_owned()), None]
+        );
+        assert!(!metadata.compatible);
+    }
+
+    #[test]
+    fn test_mark_conflicts() {
+        let mut registry = vec![
+            OutputMetadata {
+                id: "adguard".to_owned(),
+                conflicts: vec!["pihole".to_owned()],
+                compatible: true,
+                ..Default::default()
+            },
+            OutputMetadata {
+                id: "pihole".to_owned(),
+                compatible: true,
+                ..Default::default()
+            },
+        ];
+        mark_conflicts(&mut registry, &["pihole".to_owned()]);
+        assert!(!registry[0].compatible);
+        assert_eq!(registry[0].diagnostics[0].code, DiagnosticCode::AppConflict);
+        assert!(registry[1].compatible);
+        assert_eq!(
+            get_conflicts(&registry, "pihole", &["adguard".to_owned()]),
+            vec!["adguard".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_mark_disabled_dependencies() {
+        let mut registry = vec![
+            OutputMetadata {
+                id: "electrs".to_owned(),
+                dependencies: vec![Dependency::OneDependency("bitcoin".to_owned())],
+                resolved_dependencies: vec![Some("bitcoin".to_owned())],
+                compatible: true,
+                ..Default::default()
+            },
+            OutputMetadata {
+                id: "bitcoin".to_owned(),
+                compatible: true,
+                ..Default::default()
+            },
+        ];
+        mark_disabled_dependencies(&mut registry, &["bitcoin".to_owned()]);
+        assert!(!registry[0].compatible);
+        assert_eq!(
+            registry[0].diagnostics[0].code,
+            DiagnosticCode::DependencyDisabled
+        );
+        assert!(registry[1].compatible);
+        // Diagnostics are not duplicated when marking again
+        mark_disabled_dependencies(&mut registry, &["bitcoin".to_owned()]);
+        assert_eq!(registry[0].diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_reverse_index() {
+        let app =
+            |id: &str, dependencies: Vec<Dependency>, has_permissions: &[&str]| OutputMetadata {
+                id: id.to_owned(),
+                dependencies,
+                has_permissions: has_permissions.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            };
+        let catalog = vec![
+            app("bitcoin", vec![], &[]),
+            app(
+                "electrs",
+                vec![Dependency::OneDependency("bitcoin".to_owned())],
+                &["bitcoin/rpc"],
+            ),
+            app(
+                "explorer",
+                vec![Dependency::AlternativeDependency(vec![
+                    "electrs".to_owned(),
+                    "fulcrum".to_owned(),
+                ])],
+                &[],
+            ),
+        ];
+        let index = reverse_index(&catalog);
+        assert_eq!(
+            index.get("bitcoin/rpc"),
+            Some(&BTreeSet::from(["electrs".to_owned()]))
+        );
+        assert_eq!(
+            index.get("fulcrum"),
+            Some(&BTreeSet::from(["explorer".to_owned()]))
+        );
+        assert_eq!(
+            get_consumers(&index, "bitcoin"),
+            BTreeSet::from(["electrs".to_owned()])
+        );
+        assert!(get_consumers(&index, "explorer").is_empty());
+    }
 }
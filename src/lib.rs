@@ -0,0 +1,13 @@
+#![allow(dead_code)]
+
+pub mod composegenerator;
+pub mod config;
+pub mod dependencies;
+pub mod manage;
+pub mod offline;
+pub mod proxy;
+pub mod repos;
+pub mod tera;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub(crate) mod utils;
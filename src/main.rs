@@ -1,223 +1,1151 @@
-#![allow(dead_code)]
-
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
+use app_manager::{
+    composegenerator,
+    config::Config,
+    dependencies::{get_conflicts, get_consumers},
+    manage::{
+        self, events::EventKind, hooks::HookEvent, lock::LockError, maintenance::MaintenanceState,
+        scaffold::AppTemplate,
+    },
+};
 use clap::{Parser, Subcommand};
-use manage::files::get_all_metadata_ymls;
 use serde::{Deserialize, Serialize};
 
-use crate::composegenerator::v1::RESERVED_NAMES;
-
-mod composegenerator;
-mod dependencies;
-mod manage;
-mod repos;
-mod tera;
-pub(crate) mod utils;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// The Nirvati root, defaults to $NIRVATI_DIR or the root set in the config file
+    #[clap(long, global = true)]
+    dir: Option<PathBuf>,
+    /// The config file to use instead of /etc/nirvati/config.toml
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    /// Overrides the container runtime set in the config file
+    #[clap(long, global = true)]
+    runtime: Option<String>,
+    /// Overrides the app subnet set in the config file
+    #[clap(long, global = true)]
+    subnet: Option<String>,
+    /// Fail on invalid declarations in app.yml files instead of skipping them
+    #[clap(long, global = true)]
+    strict: bool,
+    /// Allow apps that build their images locally, even if the config file doesn't
+    #[clap(long, global = true)]
+    allow_local_builds: bool,
+    /// How long to wait for other running operations before giving up, in seconds
+    #[clap(long, global = true, default_value_t = 0)]
+    lock_timeout: u64,
+    /// Log every call of a JS helper or sandbox callback with its arguments
+    #[clap(long, global = true)]
+    audit_sandbox: bool,
+    /// Never access the network: syncing stores that have to be downloaded fails and update checks are skipped
+    #[clap(long, global = true)]
+    offline: bool,
+    /// Print the progress of generating as JSON lines on stderr
+    #[clap(long, global = true)]
+    progress: bool,
+    /// The snapshot taken before this operation, which is recorded in the event log
+    #[clap(long, global = true)]
+    snapshot_id: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Generates docker-compose.yml files
-    Generate { dir: String },
-    /// Installs an app
+    Generate {
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
+        /// Write the timings of every phase and app to this file, in Chrome's trace event format
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+    /// Installs an app, or a separate instance of it with <app>@<instance>
+    #[command(allow_missing_positional = true)]
     Install {
-        dir: String,
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
         app: String,
         #[clap(long)]
         settings: Option<String>,
+        /// Install the app even if it conflicts with an installed app
+        #[clap(long)]
+        force: bool,
     },
+    #[command(allow_missing_positional = true)]
     AttemptInstall {
-        dir: String,
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
         app: String,
         #[clap(long)]
         settings: Option<String>,
     },
+    /// Copies the apps from the store checkouts in repos/ to apps/ and regenerates
+    /// Prints the updated and removed apps and the errors of every store as JSON
+    Sync {
+        /// Download stores through the tor_proxy of the config, from their onion mirrors first
+        #[clap(long)]
+        prefer_tor: bool,
+    },
+    /// Creates a new app from a template and validates it
+    #[command(allow_missing_positional = true)]
+    NewApp {
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
+        id: String,
+        #[clap(long, value_enum, default_value_t = AppTemplate::Web)]
+        template: AppTemplate,
+    },
+    /// Creates an app with a draft app.yml and metadata.yml from a docker compose file and prints what has to be reviewed
+    ImportCompose {
+        file: PathBuf,
+        /// The id of the new app
+        #[clap(long)]
+        id: String,
+    },
+    /// Renders and converts an app without writing the result, to check if it is valid
+    #[command(allow_missing_positional = true)]
+    Validate {
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
+        app: String,
+    },
+    /// Prints the JSON Schema of an app file, for validation and autocompletion in editors
+    Schema {
+        #[clap(long = "type", value_enum)]
+        schema_type: composegenerator::schema::SchemaType,
+        /// The version of the file format, defaults to the newest one
+        #[clap(long)]
+        format_version: Option<u8>,
+        /// The URL the schemas are published at, to make their $id absolute
+        #[clap(long)]
+        base_url: Option<String>,
+    },
+    /// Checks generated apps against store policies, failing if a rule with level error is violated
+    Lint {
+        /// Lint only this app
+        app: Option<String>,
+        /// The dir with one subdir per app, defaults to apps/ in the Nirvati root
+        #[clap(long)]
+        apps_dir: Option<PathBuf>,
+        /// A YAML file that changes rule levels and description limits
+        #[clap(long)]
+        lint_config: Option<PathBuf>,
+    },
+    /// Creates the data dirs listed in an app's dirs.yml, needs to run as root to set their owner
+    EnsureDirs { app: String },
+    /// Prints a CycloneDX bill of materials of the container images of an app or all installed apps
+    Sbom { app: Option<String> },
+    /// Prints the disk usage of the data dirs of an app or all installed apps, compared to their quota
+    DiskUsage { app: Option<String> },
+    /// Writes an app as a standalone docker compose project with a .env and a README of the dirs it needs
+    Export {
+        app: String,
+        out_dir: PathBuf,
+        /// Write secrets to .env instead of leaving them empty
+        #[clap(long)]
+        include_secrets: bool,
+    },
+    /// Prints which dataset or subvolume to snapshot before updating an app, and a name for the snapshot
+    PreUpdateSnapshotManifest { app: String },
+    /// Prints the container runtime the host scripts start apps with, from the config file or --runtime
+    Runtime,
+    /// Checks the containers in update_containers of installed apps for new images and writes apps/updates.json
+    CheckUpdates {
+        /// Pin images with an outdated digest to the latest one and regenerate
+        #[clap(long)]
+        apply: bool,
+    },
+    /// Shows the event log of past operations
+    History {
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
+        /// Only show events that touched this app
+        #[clap(long)]
+        app: Option<String>,
+        /// Only show the last n events
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Renders a single app and prints the compose spec, Caddy entries and registry entry
+    #[command(allow_missing_positional = true)]
+    Preview {
+        /// The Nirvati root, takes precedence over --dir, for scripts that pass it before the other arguments
+        dir: Option<PathBuf>,
+        app: String,
+        #[clap(long)]
+        settings: Option<String>,
+        /// Apps to simulate as installed
+        #[clap(long, value_delimiter = ',')]
+        pretend_installed: Vec<String>,
+    },
+    /// Lists outputs of apps that are neither in any app store nor installed
+    Prune {
+        /// Remove them instead of only listing them
+        #[clap(long)]
+        remove: bool,
+    },
+    /// Prints the steps needed to install and uninstall apps, without changing anything
+    Plan {
+        /// Apps to install
+        #[clap(long, value_delimiter = ',')]
+        install: Vec<String>,
+        /// Apps to uninstall
+        #[clap(long, value_delimiter = ',')]
+        uninstall: Vec<String>,
+    },
+    /// Writes the user's state (installed apps, settings, ports, addresses, stores and the seed) to a JSON bundle
+    ExportState {
+        /// Write the bundle to this file instead of stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Restores the state from a bundle, syncs the apps and regenerates
+    ImportState { bundle: PathBuf },
+    /// Changes an app's settings and regenerates the app and its dependents if their compose files use them
+    Configure {
+        app: String,
+        /// The settings to change, as JSON object
+        #[clap(long)]
+        settings: String,
+    },
+    /// Prints an app's registry entry and the installed apps that depend on it
+    Info { app: String },
+    /// Stops running an installed app, which keeps its settings, data and ports
+    Disable { app: String },
+    /// Runs a disabled app again
+    Enable { app: String },
+    /// Serves a maintenance page instead of the app, or takes the app out of maintenance
+    Maintenance {
+        app: String,
+        #[clap(value_enum)]
+        state: MaintenanceState,
+    },
+    /// Serves the registry, app info, search index and update status as JSON over HTTP, until stopped
+    Serve {
+        #[clap(long, default_value = "127.0.0.1:8485")]
+        listen: SocketAddr,
+    },
+    /// Accepts JSON-RPC requests (generate, install, plan, status) on a unix socket, until stopped
+    Rpc {
+        /// Defaults to db/app-manager.sock in the Nirvati root
+        #[clap(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Prints what the permissions an app has grant, and which apps expose them
+    ExplainPermissions { app: String },
+    /// Prints the installed apps that use an app or app/permission, and how they use it
+    WhoUses { permission: String },
+    /// Installs and uninstalls multiple apps with a single generate pass, and writes apps/state.yml
+    Apply {
+        /// Apps to install
+        #[clap(long, value_delimiter = ',')]
+        install: Vec<String>,
+        /// Apps to uninstall
+        #[clap(long, value_delimiter = ',')]
+        uninstall: Vec<String>,
+    },
+}
+
+impl Commands {
+    /// The Nirvati root passed as the first argument, which older host scripts do
+    fn positional_dir(&self) -> Option<&Path> {
+        match self {
+            Commands::Generate { dir, .. }
+            | Commands::Install { dir, .. }
+            | Commands::AttemptInstall { dir, .. }
+            | Commands::NewApp { dir, .. }
+            | Commands::Validate { dir, .. }
+            | Commands::History { dir, .. }
+            | Commands::Preview { dir, .. } => dir.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether this command modifies the Nirvati root and needs to hold the lock
+    /// The RPC server doesn't hold it, every mutating request takes the lock while it runs
+    fn is_mutating(&self) -> bool {
+        match self {
+            Commands::Generate { .. }
+            | Commands::Sync { .. }
+            | Commands::Install { .. }
+            | Commands::AttemptInstall { .. }
+            | Commands::Apply { .. }
+            | Commands::ImportState { .. }
+            | Commands::Configure { .. }
+            | Commands::Maintenance { .. }
+            | Commands::Disable { .. }
+            | Commands::Enable { .. }
+            | Commands::NewApp { .. }
+            | Commands::ImportCompose { .. } => true,
+            Commands::CheckUpdates { apply } => *apply,
+            Commands::Prune { remove } => *remove,
+            Commands::Validate { .. }
+            | Commands::EnsureDirs { .. }
+            | Commands::Sbom { .. }
+            | Commands::DiskUsage { .. }
+            | Commands::Export { .. }
+            | Commands::PreUpdateSnapshotManifest { .. }
+            | Commands::Runtime
+            | Commands::History { .. }
+            | Commands::Preview { .. }
+            | Commands::Info { .. }
+            | Commands::Serve { .. }
+            | Commands::Rpc { .. }
+            | Commands::ExplainPermissions { .. }
+            | Commands::WhoUses { .. }
+            | Commands::ExportState { .. }
+            | Commands::Plan { .. }
+            | Commands::Lint { .. }
+            | Commands::Schema { .. } => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct AppInstallState {
     success: bool,
     has_permissions: Vec<String>,
-    other_app_permission_additions: HashMap<String, Vec<String>>,
+    other_app_permission_additions: BTreeMap<String, Vec<String>>,
+    /// What the permissions above grant, for the consent prompt
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    permission_details: BTreeMap<String, manage::permissions::PermissionExplanation>,
 }
 
-fn handle_cmd(cmd: Commands) -> Result<()> {
-    match cmd {
-        Commands::Generate { dir } => {
-            let dir = std::path::Path::new(&dir);
-            let apps_dir = dir.join("apps");
-            let installed_apps = manage::files::get_installed_apps(dir)?;
-            let mut available_permissions = installed_apps
-                .iter()
-                .flat_map(|app| {
-                    // Apps can only be installed if they have an app.yml, so assume app.yml files exist for installed apps
-                    let app_yml = manage::files::read_app_yml(&apps_dir, app);
-                    let Ok(app_yml) = app_yml else {
-                        return vec![app.to_owned()];
-                    };
-                    let mut permissions = app_yml
-                        .into_exported_permissions()
-                        .into_iter()
-                        .map(|elem| format!("{}/{}", app, elem.id))
+/// The result of an Apply, written to apps/state.yml
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ApplyState {
+    success: bool,
+    installed: Vec<String>,
+    uninstalled: Vec<String>,
+    /// The permissions of the installed apps
+    has_permissions: BTreeMap<String, Vec<String>>,
+}
+
+fn plan(
+    nirvati_dir: &Path,
+    config: &Config,
+    install: &[String],
+    uninstall: &[String],
+) -> Result<manage::plan::InstallPlan> {
+    let catalog = manage::plan::Catalog::load(nirvati_dir)?;
+    let current_state = manage::plan::SystemState::load(
+        nirvati_dir,
+        manage::get_port_policy(nirvati_dir, config)?,
+    )?;
+    manage::plan::compute_install_plan(
+        &catalog,
+        &current_state,
+        &manage::plan::TargetState::from_changes(&current_state, install, uninstall),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct InstallParams {
+    app: String,
+    #[serde(default)]
+    settings: Option<serde_json::Value>,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlanParams {
+    #[serde(default)]
+    install: Vec<String>,
+    #[serde(default)]
+    uninstall: Vec<String>,
+}
+
+/// Installs an app, writes what it would change to its state file and uninstalls it again
+fn attempt_install(
+    nirvati_dir: &Path,
+    config: &Config,
+    app: &str,
+    settings: Option<String>,
+    state_path: &Path,
+) -> Result<()> {
+    if let Some(settings) = settings {
+        let settings = serde_json::from_str(&settings)?;
+        manage::files::save_app_settings(app, settings, nirvati_dir)?;
+    }
+    // First, load the current registry.json
+    let registry = manage::files::get_app_registry(nirvati_dir)?;
+    if let Err(err) = manage::generate(nirvati_dir, config) {
+        let state = AppInstallState {
+            success: false,
+            has_permissions: vec![],
+            other_app_permission_additions: BTreeMap::new(),
+            permission_details: BTreeMap::new(),
+        };
+        manage::canonical::write_yaml(state_path, &state)?;
+        return Err(err);
+    };
+    manage::files::add_installed_app(app, nirvati_dir)?;
+    // Do another generate pass to ensure all apps that depend on this app also have their config regenerated
+    if let Err(err) = manage::generate(nirvati_dir, config) {
+        manage::files::remove_installed_app(app, nirvati_dir)?;
+        let state = AppInstallState {
+            success: false,
+            has_permissions: vec![],
+            other_app_permission_additions: BTreeMap::new(),
+            permission_details: BTreeMap::new(),
+        };
+        manage::canonical::write_yaml(state_path, &state)?;
+        return Err(err);
+    }
+    let new_registry = manage::files::get_app_registry(nirvati_dir)?;
+    let registry_map: HashMap<
+        String,
+        &composegenerator::types::OutputMetadata,
+        std::collections::hash_map::RandomState,
+    > = HashMap::from_iter(registry.iter().map(|app| (app.id.clone(), app)));
+    let new_registry_map: HashMap<
+        String,
+        &composegenerator::types::OutputMetadata,
+        std::collections::hash_map::RandomState,
+    > = HashMap::from_iter(new_registry.iter().map(|app| (app.id.clone(), app)));
+    let other_app_permission_additions: BTreeMap<String, Vec<String>> =
+        BTreeMap::from_iter(registry_map.into_iter().filter_map(|(app, app_info)| {
+            if let Some(new_app_info) = new_registry_map.get(app) {
+                if app_info.has_permissions != new_app_info.has_permissions {
+                    let added_permissions = new_app_info
+                        .has_permissions
+                        .iter()
+                        .filter_map(|elem| {
+                            if !app_info.has_permissions.contains(elem) {
+                                Some(elem.to_owned())
+                            } else {
+                                None
+                            }
+                        })
                         .collect::<Vec<_>>();
-                    permissions.push(app.to_owned());
-                    permissions
-                })
-                .collect::<Vec<_>>();
-            let mut builtin_permissions = RESERVED_NAMES
-                .iter()
-                .map(|elem| elem.to_string())
-                .collect::<Vec<_>>();
-            available_permissions.append(&mut builtin_permissions);
-            tera::process_metadata_yml_jinjas(dir, &installed_apps, &available_permissions)?;
-            {
-                let registry = get_all_metadata_ymls(dir)?;
-                let registry_file = dir.join("apps").join("registry.json");
-                let registry_file = std::fs::File::create(registry_file)?;
-                serde_json::to_writer_pretty(registry_file, &registry)?;
-            }
-            let apps = manage::determine_jinja_processing_order(dir, &installed_apps)?;
-            let permission_map = HashMap::from_iter(installed_apps.iter().filter_map(|app| {
-                // Apps can only be installed if they have an app.yml, so assume app.yml files exist for installed apps
-                match manage::files::read_app_yml(dir, app) {
-                    Err(err) => {
-                        tracing::warn!("Failed to read app.yml for app {}: {:#}", app, err);
-                        None
-                    }
-                    Ok(app_yml) => Some((app.to_owned(), app_yml.into_exported_permissions())),
+                    Some((app.clone(), added_permissions))
+                } else {
+                    None
                 }
-            }));
-            manage::processing::process_app_ymls(dir, &apps, permission_map)?;
+            } else {
+                None
+            }
+        }));
+    if let Some(new_app) = new_registry_map.get(app) {
+        let mut permissions = new_app.has_permissions.clone();
+        permissions.extend(other_app_permission_additions.values().flatten().cloned());
+        let state = AppInstallState {
+            success: true,
+            has_permissions: new_app.has_permissions.clone(),
+            permission_details: manage::permissions::explain_all(nirvati_dir, &permissions)?,
+            other_app_permission_additions,
+        };
+        manage::canonical::write_yaml(state_path, &state)?;
+    } else {
+        let state = AppInstallState {
+            success: false,
+            has_permissions: vec![],
+            other_app_permission_additions: BTreeMap::new(),
+            permission_details: BTreeMap::new(),
+        };
+        manage::canonical::write_yaml(state_path, &state)?;
+    }
+    manage::files::remove_installed_app(app, nirvati_dir).expect("Removing app failed!");
+    // Restore the old registry.json
+    manage::files::write_app_registry(nirvati_dir, &registry)?;
+    // Do another generate pass to ensure all changes have been reverted
+    if let Err(msg) = manage::generate(nirvati_dir, config) {
+        tracing::error!("Failed to generate: {:#}", msg);
+        manage::files::remove_installed_app(app, nirvati_dir)?;
+    }
+    Ok(())
+}
+
+/// Where AttemptInstall writes its result, apps/<app>/state.yml
+/// The dir of a new instance is removed again, so its result is written to state@<instance>.yml in the dir of its app,
+/// this has to be called before the instance is created
+fn attempt_state_path(nirvati_dir: &Path, app: &str) -> PathBuf {
+    match manage::instances::split_instance_id(app) {
+        (base_app, Some(instance)) if !nirvati_dir.join("apps").join(app).is_dir() => nirvati_dir
+            .join("apps")
+            .join(base_app)
+            .join(format!("state@{}.yml", instance)),
+        _ => nirvati_dir.join("apps").join(app).join("state.yml"),
+    }
+}
+
+/// Apps that fail don't fail the whole command, so they are only logged
+fn warn_failed_apps(failed_apps: &[manage::processing::FailedApp]) {
+    for failed_app in failed_apps {
+        tracing::warn!(
+            "App {} failed to generate: {}",
+            failed_app.app,
+            failed_app.diagnostic.message
+        );
+    }
+}
+
+/// Disables or enables an installed app and regenerates it and the apps that depend on it
+fn set_disabled(nirvati_dir: &Path, config: &Config, app: String, disabled: bool) -> Result<()> {
+    let installed_apps = manage::files::get_installed_apps(nirvati_dir)?;
+    if !installed_apps.contains(&app) {
+        return Err(anyhow::anyhow!("App is not installed"));
+    }
+    manage::files::set_disabled(&app, disabled, nirvati_dir)?;
+    let affected_apps = manage::get_dependents(nirvati_dir, &installed_apps, &app)?;
+    let failed_apps =
+        manage::events::record(nirvati_dir, EventKind::Generate, &affected_apps, || {
+            manage::regenerate_apps(nirvati_dir, config, &affected_apps)
+        })?;
+    warn_failed_apps(&failed_apps);
+    Ok(())
+}
+
+/// Runs an RPC request the same way as the matching command
+fn handle_rpc(
+    method: &str,
+    params: serde_json::Value,
+    nirvati_dir: &Path,
+    config: &Config,
+) -> Result<serde_json::Value> {
+    match method {
+        "generate" => {
+            let failed_apps =
+                manage::events::record(nirvati_dir, EventKind::Generate, &[], || {
+                    manage::generate(nirvati_dir, config)
+                })?;
+            return Ok(serde_json::json!({ "failedApps": failed_apps }));
+        }
+        "install" => {
+            let params: InstallParams = manage::rpc::params(params)?;
+            handle_cmd(
+                Commands::Install {
+                    dir: None,
+                    app: params.app,
+                    settings: params.settings.map(|settings| settings.to_string()),
+                    force: params.force,
+                },
+                nirvati_dir,
+                config,
+            )?
+        }
+        "plan" => {
+            let params: PlanParams = manage::rpc::params(params)?;
+            let plan = plan(nirvati_dir, config, &params.install, &params.uninstall)?;
+            return Ok(serde_json::to_value(plan)?);
+        }
+        method => return Err(manage::rpc::RpcError::MethodNotFound(method.to_owned()).into()),
+    }
+    Ok(serde_json::Value::Null)
+}
+
+fn handle_cmd(cmd: Commands, nirvati_dir: &Path, config: &Config) -> Result<()> {
+    match cmd {
+        Commands::Generate { profile, .. } => {
+            if profile.is_some() {
+                manage::profile::enable();
+            }
+            let failed_apps =
+                manage::events::record(nirvati_dir, EventKind::Generate, &[], || {
+                    manage::profile::measure("generate", None, || {
+                        manage::generate(nirvati_dir, config)
+                    })
+                })?;
+            warn_failed_apps(&failed_apps);
+            if let Some(profile) = profile {
+                manage::profile::save(&profile)?;
+            }
         }
-        Commands::Install { dir, app, settings } => {
+        Commands::Sync { prefer_tor } => {
+            // In tor_only mode, the proxy already is Tor unless another one is set
+            let tor_proxy = if prefer_tor {
+                Some(config.tor_proxy.clone())
+            } else if config.tor_only {
+                config.resolve_proxy()
+            } else {
+                None
+            };
+            let report = app_manager::repos::sync_apps(nirvati_dir, tor_proxy.as_deref())?;
+            let apps = report.origins.keys().cloned().collect::<Vec<_>>();
+            let failed_apps =
+                manage::events::record(nirvati_dir, EventKind::Generate, &apps, || {
+                    manage::generate(nirvati_dir, config)
+                })?;
+            warn_failed_apps(&failed_apps);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Commands::Install {
+            app,
+            settings,
+            force,
+            ..
+        } => {
             // We don't interact with Docker here, the host scripts do that
-            let nirvati_dir = std::path::Path::new(&dir);
-            let app_dir = nirvati_dir.join("apps").join(&app);
-            if !app_dir.exists() {
+            if !manage::instances::app_exists(nirvati_dir, &app) {
                 return Err(anyhow::anyhow!("App does not exist"));
             }
-            if let Some(settings) = settings {
-                let settings = serde_json::from_str(&settings)?;
-                manage::files::save_app_settings(&app, settings, nirvati_dir)?;
+            let registry = manage::files::get_app_registry(nirvati_dir)?;
+            // A new instance isn't in the registry yet, it has the conflicts of its app
+            let registry_id = if registry.iter().any(|entry| entry.id == app) {
+                app.as_str()
+            } else {
+                manage::instances::split_instance_id(&app).0
+            };
+            let conflicts = get_conflicts(
+                &registry,
+                registry_id,
+                &manage::files::get_installed_apps(nirvati_dir)?,
+            );
+            if !conflicts.is_empty() && !force {
+                return Err(anyhow::anyhow!(
+                    "App conflicts with {}, use --force to install it anyway",
+                    conflicts.join(", ")
+                ));
             }
-            handle_cmd(Commands::Generate { dir: dir.clone() })?;
-            manage::files::add_installed_app(&app, nirvati_dir)?;
-            // Do another generate pass to ensure all apps that depend on this app also have their config regenerated
-            if let Err(msg) = handle_cmd(Commands::Generate { dir: dir.clone() }) {
-                tracing::error!("Failed to generate: {:#}", msg);
-                manage::files::remove_installed_app(&app, nirvati_dir)?;
+            let new_instance = !nirvati_dir.join("apps").join(&app).exists();
+            manage::instances::create_instance(nirvati_dir, &app)?;
+            let installed = manage::events::record(
+                nirvati_dir,
+                EventKind::Install,
+                std::slice::from_ref(&app),
+                || {
+                    if let Some(settings) = settings {
+                        let settings = serde_json::from_str(&settings)?;
+                        manage::files::save_app_settings(&app, settings, nirvati_dir)?;
+                    }
+                    manage::generate(nirvati_dir, config)?;
+                    manage::files::add_installed_app(&app, nirvati_dir)?;
+                    // Regenerate the app and all apps that depend on it, now that it is installed
+                    let installed_apps = manage::files::get_installed_apps(nirvati_dir)?;
+                    let affected_apps = manage::get_dependents(nirvati_dir, &installed_apps, &app)?;
+                    if let Err(msg) = manage::regenerate_apps(nirvati_dir, config, &affected_apps) {
+                        if manage::cancel::is_cancelled(&msg) {
+                            return Err(msg);
+                        }
+                        tracing::error!("Failed to generate: {:#}", msg);
+                        manage::files::remove_installed_app(&app, nirvati_dir)?;
+                        return Ok(false);
+                    }
+                    manage::hooks::notify(
+                        nirvati_dir,
+                        HookEvent::InstallSucceeded { app: app.clone() },
+                    );
+                    Ok(true)
+                },
+            );
+            // Don't leave the copy of an instance behind if it couldn't be installed
+            if new_instance && !matches!(installed, Ok(true)) {
+                manage::instances::remove_instance(nirvati_dir, &app)?;
             }
+            installed?;
         }
-        Commands::AttemptInstall { dir, app, settings } => {
-            let nirvati_dir = std::path::Path::new(&dir);
-            let app_dir = nirvati_dir.join("apps").join(&app);
-            let state_yml = nirvati_dir.join("apps").join(&app).join("state.yml");
-            let state_yml = std::fs::File::create(state_yml)?;
-            if !app_dir.exists() {
+        Commands::AttemptInstall { app, settings, .. } => {
+            if !manage::instances::app_exists(nirvati_dir, &app) {
                 return Err(anyhow::anyhow!("App does not exist"));
             }
-            if let Some(settings) = settings {
-                let settings = serde_json::from_str(&settings)?;
-                manage::files::save_app_settings(&app, settings, nirvati_dir)?;
+            let new_instance = !nirvati_dir.join("apps").join(&app).exists();
+            let state_path = attempt_state_path(nirvati_dir, &app);
+            manage::instances::create_instance(nirvati_dir, &app)?;
+            let attempted = attempt_install(nirvati_dir, config, &app, settings, &state_path);
+            // The app is uninstalled again, so an instance created for the attempt is removed too
+            if new_instance {
+                manage::instances::remove_instance(nirvati_dir, &app)?;
             }
-            // First, load the current registry.json
-            let registry = manage::files::get_app_registry(nirvati_dir)?;
-            if let Err(err) = handle_cmd(Commands::Generate { dir: dir.clone() }) {
-                let state = AppInstallState {
-                    success: false,
-                    has_permissions: vec![],
-                    other_app_permission_additions: HashMap::new(),
-                };
-                serde_yaml::to_writer(state_yml, &state)?;
-                return Err(err);
+            attempted?;
+        }
+        Commands::NewApp { id, template, .. } => {
+            let app_dir = manage::scaffold::scaffold_app(nirvati_dir, &id, template)?;
+            println!("Created {}", app_dir.display());
+            handle_cmd(
+                Commands::Validate { dir: None, app: id },
+                nirvati_dir,
+                config,
+            )?;
+        }
+        Commands::ImportCompose { file, id } => {
+            let (app_dir, notes) = manage::scaffold::import_compose(nirvati_dir, &file, &id)?;
+            tracing::info!("Created {}", app_dir.display());
+            println!("{}", serde_json::to_string_pretty(&notes)?);
+        }
+        Commands::Validate { app, .. } => {
+            manage::validate::validate_app(nirvati_dir, &app, config)?;
+            println!("App {} is valid", app);
+        }
+        Commands::Schema {
+            schema_type,
+            format_version,
+            base_url,
+        } => {
+            let schema =
+                composegenerator::schema::export(schema_type, format_version, base_url.as_deref())?;
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        }
+        Commands::Lint {
+            app,
+            apps_dir,
+            lint_config,
+        } => {
+            let lint_config = match lint_config {
+                Some(path) => manage::lint::LintConfig::load(&path)?,
+                None => manage::lint::LintConfig::default(),
             };
-            manage::files::add_installed_app(&app, nirvati_dir)?;
-            // Do another generate pass to ensure all apps that depend on this app also have their config regenerated
-            if let Err(err) = handle_cmd(Commands::Generate { dir: dir.clone() }) {
-                manage::files::remove_installed_app(&app, nirvati_dir)?;
-                let state = AppInstallState {
-                    success: false,
-                    has_permissions: vec![],
-                    other_app_permission_additions: HashMap::new(),
+            let apps_dir = apps_dir.unwrap_or_else(|| nirvati_dir.join("apps"));
+            let findings = match app {
+                Some(app) => manage::lint::lint_app(&apps_dir, &app, &lint_config)?,
+                None => manage::lint::lint_apps(&apps_dir, &lint_config)?,
+            };
+            for finding in &findings {
+                let severity = match finding.severity {
+                    composegenerator::types::Severity::Info => "info",
+                    composegenerator::types::Severity::Warning => "warning",
+                    composegenerator::types::Severity::Error => "error",
                 };
-                serde_yaml::to_writer(state_yml, &state)?;
-                return Err(err);
-            }
-            let new_registry = manage::files::get_app_registry(nirvati_dir)?;
-            let registry_map: HashMap<
-                String,
-                &composegenerator::types::OutputMetadata,
-                std::collections::hash_map::RandomState,
-            > = HashMap::from_iter(registry.iter().map(|app| (app.id.clone(), app)));
-            let new_registry_map: HashMap<
-                String,
-                &composegenerator::types::OutputMetadata,
-                std::collections::hash_map::RandomState,
-            > = HashMap::from_iter(new_registry.iter().map(|app| (app.id.clone(), app)));
-            let other_app_permission_additions: HashMap<
-                String,
-                Vec<String>,
-                std::collections::hash_map::RandomState,
-            > = HashMap::from_iter(registry_map.into_iter().filter_map(|(app, app_info)| {
-                if let Some(new_app_info) = new_registry_map.get(&app) {
-                    if app_info.has_permissions != new_app_info.has_permissions {
-                        let added_permissions = new_app_info
-                            .has_permissions
-                            .iter()
-                            .filter_map(|elem| {
-                                if !app_info.has_permissions.contains(elem) {
-                                    Some(elem.to_owned())
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect::<Vec<_>>();
-                        Some((app.clone(), added_permissions))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+                match &finding.field {
+                    Some(field) => println!(
+                        "{}: {} [{}] {} ({})",
+                        finding.app, severity, finding.rule, finding.message, field
+                    ),
+                    None => println!(
+                        "{}: {} [{}] {}",
+                        finding.app, severity, finding.rule, finding.message
+                    ),
                 }
-            }));
-            if let Some(new_app) = new_registry_map.get(&app) {
-                let state = AppInstallState {
-                    success: true,
-                    has_permissions: new_app.has_permissions.clone(),
-                    other_app_permission_additions,
-                };
-                serde_yaml::to_writer(state_yml, &state)?;
-            } else {
-                let state = AppInstallState {
-                    success: false,
-                    has_permissions: vec![],
-                    other_app_permission_additions: HashMap::new(),
-                };
-                serde_yaml::to_writer(state_yml, &state).expect("Writing failed!");
             }
-            manage::files::remove_installed_app(&app, nirvati_dir).expect("Removing app failed!");
-            // Restore the old registry.json
-            manage::files::write_app_registry( nirvati_dir, &registry)?;
-            // Do another generate pass to ensure all changes have been reverted
-            if let Err(msg) = handle_cmd(Commands::Generate { dir: dir.clone() }) {
-                tracing::error!("Failed to generate: {:#}", msg);
-                manage::files::remove_installed_app(&app, nirvati_dir)?;
+            let errors = findings
+                .iter()
+                .filter(|finding| finding.severity == composegenerator::types::Severity::Error)
+                .count();
+            if errors > 0 {
+                anyhow::bail!("{} lint errors", errors);
+            }
+        }
+        Commands::EnsureDirs { app } => {
+            let dirs = manage::files::get_data_dirs(nirvati_dir, &app)?;
+            for dir in manage::dirs::ensure_dirs(nirvati_dir, &app, &dirs)? {
+                println!("Created {}", dir.display());
+            }
+        }
+        Commands::Sbom { app } => {
+            let apps = match app {
+                Some(app) => vec![app],
+                None => manage::files::get_installed_apps(nirvati_dir)?,
+            };
+            let bom = manage::sbom::build_bom(nirvati_dir, &apps)?;
+            println!("{}", serde_json::to_string_pretty(&bom)?);
+        }
+        Commands::DiskUsage { app } => {
+            let apps = match app {
+                Some(app) => vec![app],
+                None => manage::files::get_installed_apps(nirvati_dir)?,
+            };
+            let quotas = manage::files::get_disk_quotas(nirvati_dir)?;
+            let usage = manage::quota::disk_usage(nirvati_dir, &apps, &quotas)?;
+            println!("{}", serde_json::to_string_pretty(&usage)?);
+        }
+        Commands::Export {
+            app,
+            out_dir,
+            include_secrets,
+        } => {
+            let summary =
+                manage::export::export_app(nirvati_dir, &app, config, &out_dir, include_secrets)?;
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
+        Commands::PreUpdateSnapshotManifest { app } => {
+            let manifest =
+                manage::snapshots::pre_update_manifest(nirvati_dir, &config.snapshots, &app)?;
+            println!("{}", serde_json::to_string_pretty(&manifest)?);
+        }
+        Commands::Runtime => println!("{}", config.runtime),
+        Commands::CheckUpdates { apply } => {
+            if app_manager::offline::is_offline() {
+                tracing::warn!("Skipping the update check in offline mode");
+                return Ok(());
+            }
+            let updates = manage::updates::check_updates(nirvati_dir)?;
+            manage::files::save_updates(nirvati_dir, &updates)?;
+            manage::files::update_usage_stats(nirvati_dir, |stats| {
+                stats.last_update_check = Some(manage::events::now());
+            })?;
+            for (app, app_updates) in &updates {
+                for update in app_updates {
+                    println!("{}: {} ({})", app, update.image, update.service);
+                }
+            }
+            if apply {
+                let changed_apps = manage::updates::apply_digest_updates(nirvati_dir, &updates)?;
+                if !changed_apps.is_empty() {
+                    manage::events::record(
+                        nirvati_dir,
+                        EventKind::Generate,
+                        &changed_apps,
+                        || manage::generate(nirvati_dir, config),
+                    )?;
+                }
+            }
+        }
+        Commands::History { app, limit, .. } => {
+            let mut events = manage::events::read_events(nirvati_dir)?;
+            if let Some(app) = app {
+                events.retain(|event| event.apps.contains(&app));
+            }
+            if let Some(limit) = limit {
+                events = events.split_off(events.len().saturating_sub(limit));
+            }
+            for event in events {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+        }
+        Commands::Preview {
+            app,
+            settings,
+            pretend_installed,
+            ..
+        } => {
+            let settings = settings
+                .map(|settings| serde_json::from_str(&settings))
+                .transpose()?;
+            let result = manage::validate::preview_app(
+                nirvati_dir,
+                &app,
+                config,
+                manage::validate::PreviewOptions {
+                    pretend_installed,
+                    settings,
+                    ..Default::default()
+                },
+            )?;
+            print!("{}", serde_yaml::to_string(&result)?);
+        }
+        Commands::Prune { remove } => {
+            let orphans = manage::prune::find_orphans(nirvati_dir)?;
+            println!("{}", serde_json::to_string_pretty(&orphans)?);
+            if remove {
+                manage::prune::remove_orphans(nirvati_dir, &orphans)?;
+            }
+        }
+        Commands::Plan { install, uninstall } => {
+            let plan = plan(nirvati_dir, config, &install, &uninstall)?;
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        }
+        Commands::ExportState { output } => {
+            let bundle = manage::state::export_state(nirvati_dir)?;
+            match output {
+                Some(output) => manage::canonical::write_json(&output, &bundle)?,
+                None => println!("{}", serde_json::to_string_pretty(&bundle)?),
+            }
+        }
+        Commands::ImportState { bundle } => {
+            let bundle = serde_json::from_reader(std::fs::File::open(bundle)?)?;
+            manage::state::import_state(nirvati_dir, &bundle)?;
+            handle_cmd(Commands::Sync { prefer_tor: false }, nirvati_dir, config)?;
+        }
+        Commands::Configure { app, settings } => {
+            let Some(schema) = manage::settings::read_settings_yml(nirvati_dir, &app)? else {
+                return Err(anyhow::anyhow!("App does not have settings"));
+            };
+            let old_settings =
+                manage::files::get_app_settings(nirvati_dir, &app)?.unwrap_or_default();
+            let mut new_settings = old_settings.clone();
+            new_settings.extend(serde_json::from_str::<HashMap<_, _>>(&settings)?);
+            manage::settings::validate_settings(&schema, &new_settings)?;
+            let changed = manage::settings::changed_keys(&old_settings, &new_settings);
+            let impact = manage::settings::get_impact(nirvati_dir, &app, changed)?;
+            manage::files::save_app_settings(&app, new_settings, nirvati_dir)?;
+            let installed_apps = manage::files::get_installed_apps(nirvati_dir)?;
+            if installed_apps.contains(&app) {
+                if impact.compose {
+                    let affected_apps = manage::get_dependents(nirvati_dir, &installed_apps, &app)?;
+                    manage::events::record(
+                        nirvati_dir,
+                        EventKind::Generate,
+                        &affected_apps,
+                        || manage::regenerate_apps(nirvati_dir, config, &affected_apps),
+                    )?;
+                }
+                if !impact.config_templates.is_empty() {
+                    // Config templates are rendered by the host
+                    manage::files::set_next_app_regenerate(nirvati_dir, manage::events::now())?;
+                }
             }
+            println!("{}", serde_json::to_string_pretty(&impact)?);
+        }
+        Commands::Info { app } => {
+            let info = manage::serve::app_info(nirvati_dir, &app)?
+                .ok_or_else(|| anyhow::anyhow!("App does not exist"))?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Commands::Disable { app } => set_disabled(nirvati_dir, config, app, true)?,
+        Commands::Enable { app } => set_disabled(nirvati_dir, config, app, false)?,
+        Commands::Maintenance { app, state } => {
+            if !nirvati_dir.join("apps").join(&app).exists() {
+                return Err(anyhow::anyhow!("App does not exist"));
+            }
+            manage::files::set_maintenance(&app, state.is_on(), nirvati_dir)?;
+            // Apps that use it are not affected, so only the app is regenerated
+            if manage::files::get_installed_apps(nirvati_dir)?.contains(&app) {
+                let apps = vec![app];
+                let failed_apps =
+                    manage::events::record(nirvati_dir, EventKind::Generate, &apps, || {
+                        manage::regenerate_apps(nirvati_dir, config, &apps)
+                    })?;
+                warn_failed_apps(&failed_apps);
+            }
+        }
+        Commands::Serve { listen } => manage::serve::serve(nirvati_dir, listen)?,
+        // The server needs the lock timeout, so run() starts it
+        // run() starts the server before taking the lock, RPC requests can't start another one
+        Commands::Rpc { .. } => {
+            return Err(anyhow::anyhow!(
+                "The RPC server can only be started as its own command"
+            ))
+        }
+        Commands::ExplainPermissions { app } => {
+            let metadata = manage::files::get_app_registry(nirvati_dir)?
+                .into_iter()
+                .find(|entry| entry.id == app)
+                .ok_or_else(|| anyhow::anyhow!("App does not exist"))?;
+            let explanations =
+                manage::permissions::explain_all(nirvati_dir, &metadata.has_permissions)?;
+            println!("{}", serde_json::to_string_pretty(&explanations)?);
+        }
+        Commands::WhoUses { permission } => {
+            let users = manage::permissions::find_users(
+                &manage::files::get_app_registry(nirvati_dir)?,
+                &manage::files::get_reverse_index(nirvati_dir)?,
+                &manage::files::get_installed_apps(nirvati_dir)?,
+                &permission,
+            );
+            println!("{}", serde_json::to_string_pretty(&users)?);
+        }
+        Commands::Apply { install, uninstall } => {
+            let state_yml = nirvati_dir.join("apps").join("state.yml");
+            let mut state = ApplyState {
+                success: false,
+                installed: vec![],
+                uninstalled: vec![],
+                has_permissions: BTreeMap::new(),
+            };
+            if let Some(app) = install
+                .iter()
+                .find(|app| !manage::instances::app_exists(nirvati_dir, app))
+            {
+                return Err(anyhow::anyhow!("App {} does not exist", app));
+            }
+            let new_instances = install
+                .iter()
+                .filter(|app| !nirvati_dir.join("apps").join(app).exists())
+                .cloned()
+                .collect::<Vec<_>>();
+            // Don't leave the copies of instances behind if they couldn't be installed
+            let remove_new_instances = || -> Result<()> {
+                for app in &new_instances {
+                    manage::instances::remove_instance(nirvati_dir, app)?;
+                }
+                Ok(())
+            };
+            for app in &install {
+                if let Err(err) = manage::instances::create_instance(nirvati_dir, app) {
+                    remove_new_instances()?;
+                    return Err(err);
+                }
+            }
+            let catalog = manage::plan::Catalog::load(nirvati_dir)?;
+            let current_state = manage::plan::SystemState::load(
+                nirvati_dir,
+                manage::get_port_policy(nirvati_dir, config)?,
+            )?;
+            let plan = manage::plan::compute_install_plan(
+                &catalog,
+                &current_state,
+                &manage::plan::TargetState::from_changes(&current_state, &install, &uninstall),
+            );
+            let plan = match plan {
+                Ok(plan) => plan,
+                Err(err) => {
+                    remove_new_instances()?;
+                    manage::canonical::write_yaml(&state_yml, &state)?;
+                    return Err(err);
+                }
+            };
+            let apps = plan
+                .install
+                .iter()
+                .chain(plan.uninstall.iter())
+                .cloned()
+                .collect::<Vec<_>>();
+            let rdeps = manage::files::get_reverse_index(nirvati_dir)?;
+            for app in &plan.uninstall {
+                let users = get_consumers(&rdeps, app)
+                    .into_iter()
+                    .filter(|consumer| {
+                        current_state.installed_apps.contains(consumer)
+                            && !plan.uninstall.contains(consumer)
+                    })
+                    .collect::<Vec<_>>();
+                if !users.is_empty() {
+                    tracing::warn!("{} is used by {}", app, users.join(", "));
+                }
+            }
+            let kind = if plan.install.is_empty() {
+                EventKind::Uninstall
+            } else {
+                EventKind::Install
+            };
+            let result = manage::events::record(nirvati_dir, kind, &apps, || {
+                manage::plan::apply_plan(nirvati_dir, &plan, config)
+            });
+            if result.is_ok() {
+                let registry = manage::files::get_app_registry(nirvati_dir)?;
+                state.success = true;
+                state.has_permissions = registry
+                    .into_iter()
+                    .filter(|app| plan.install.contains(&app.id))
+                    .map(|app| (app.id, app.has_permissions))
+                    .collect();
+                state.installed = plan.install;
+                state.uninstalled = plan.uninstall;
+                for app in &state.installed {
+                    manage::hooks::notify(
+                        nirvati_dir,
+                        HookEvent::InstallSucceeded { app: app.clone() },
+                    );
+                }
+            } else {
+                remove_new_instances()?;
+            }
+            manage::canonical::write_yaml(&state_yml, &state)?;
+            result?;
         }
     }
     Ok(())
 }
 
+fn run(cli: Cli) -> Result<()> {
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(runtime) = cli.runtime {
+        config.runtime = runtime;
+    }
+    if let Some(subnet) = cli.subnet {
+        config.subnet = subnet;
+    }
+    if cli.strict {
+        config.strict = true;
+    }
+    if cli.allow_local_builds {
+        config.allow_local_builds = true;
+    }
+    if cli.audit_sandbox {
+        config.sandbox.audit = true;
+    }
+    if cli.offline {
+        config.offline = true;
+    }
+    app_manager::tera::configure_sandbox(config.sandbox.clone());
+    app_manager::offline::set_offline(config.offline);
+    app_manager::proxy::set_proxy(config.resolve_proxy())?;
+    manage::events::set_snapshot_id(cli.snapshot_id.clone());
+    if cli.progress {
+        manage::progress::set_sink(Some(manage::progress::to_stderr()));
+    }
+    let nirvati_dir = match cli.command {
+        // Schemas and the runtime don't depend on a Nirvati root, so they can be printed anywhere
+        Commands::Schema { .. } | Commands::Runtime => cli.dir.clone().unwrap_or_default(),
+        _ => config.resolve_root(cli.command.positional_dir().or(cli.dir.as_deref()))?,
+    };
+    manage::secrets::configure(&nirvati_dir, &config.secrets);
+    manage::dirs::configure_storage_pools(&nirvati_dir, &config.storage_pools);
+    let lock_timeout = std::time::Duration::from_secs(cli.lock_timeout);
+    if let Commands::Rpc { socket } = cli.command {
+        let socket = socket.unwrap_or_else(|| nirvati_dir.join("db").join("app-manager.sock"));
+        let dispatch_dir = nirvati_dir.clone();
+        let server = manage::rpc::Server::new(
+            &nirvati_dir,
+            lock_timeout,
+            Box::new(move |method, params| handle_rpc(method, params, &dispatch_dir, &config)),
+        );
+        return manage::rpc::listen(&socket, server);
+    }
+    let _lock = if cli.command.is_mutating() {
+        Some(manage::lock::acquire(&nirvati_dir, lock_timeout)?)
+    } else {
+        None
+    };
+    handle_cmd(cli.command, &nirvati_dir, &config)
+}
+
 fn main() {
     tracing_subscriber::fmt::init();
     let cli = Cli::parse();
-    handle_cmd(cli.command).expect("An error occurred!");
+    if let Err(err) = run(cli) {
+        if let Some(LockError::OperationInProgress) = err.downcast_ref::<LockError>() {
+            eprintln!("{}", err);
+            // EX_TEMPFAIL, so host scripts can retry later
+            std::process::exit(75);
+        }
+        panic!("An error occurred!: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positional_dir_takes_precedence_over_the_flag() {
+        let cli = Cli::try_parse_from([
+            "app-manager",
+            "install",
+            "/root",
+            "notes",
+            "--dir",
+            "/other",
+        ])
+        .unwrap();
+        assert_eq!(cli.command.positional_dir(), Some(Path::new("/root")));
+        assert_eq!(cli.dir.as_deref(), Some(Path::new("/other")));
+
+        let cli = Cli::try_parse_from(["app-manager", "install", "notes"]).unwrap();
+        assert_eq!(cli.command.positional_dir(), None);
+        let Commands::Install { app, .. } = cli.command else {
+            panic!("Not parsed as install");
+        };
+        assert_eq!(app, "notes");
+
+        let cli = Cli::try_parse_from(["app-manager", "generate", "/root"]).unwrap();
+        assert_eq!(cli.command.positional_dir(), Some(Path::new("/root")));
+    }
+
+    #[test]
+    fn new_instances_write_their_attempt_state_to_their_app() {
+        // The testing module of the library isn't built for the binary's tests
+        let nirvati_dir =
+            std::env::temp_dir().join(format!("nirvati-attempt-state-{}", std::process::id()));
+        std::fs::create_dir_all(nirvati_dir.join("apps").join("notes")).unwrap();
+        assert_eq!(
+            attempt_state_path(&nirvati_dir, "notes"),
+            nirvati_dir.join("apps").join("notes").join("state.yml")
+        );
+        assert_eq!(
+            attempt_state_path(&nirvati_dir, "notes@work"),
+            nirvati_dir
+                .join("apps")
+                .join("notes")
+                .join("state@work.yml")
+        );
+        // Existing instances keep their dir after the attempt
+        std::fs::create_dir_all(nirvati_dir.join("apps").join("notes@work")).unwrap();
+        assert_eq!(
+            attempt_state_path(&nirvati_dir, "notes@work"),
+            nirvati_dir
+                .join("apps")
+                .join("notes@work")
+                .join("state.yml")
+        );
+        std::fs::remove_dir_all(&nirvati_dir).unwrap();
+    }
 }
@@ -1,16 +1,235 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
 
-use crate::dependencies::{sort_deps, Node};
+use crate::{
+    composegenerator::{
+        types::{DiagnosticCode, Permission},
+        v1::RESERVED_NAMES,
+    },
+    config::Config,
+    dependencies::{get_consumers, sort_deps, Node},
+};
 use anyhow::{anyhow, Result};
+use ports::PortPolicy;
+use processing::FailedApp;
 
+pub mod aliases;
+pub mod cancel;
+pub mod canonical;
+pub mod categories;
+pub mod changelog;
+pub mod claims;
+pub mod compat;
+pub mod dirs;
+pub mod dns;
+pub mod events;
+pub mod export;
 pub mod files;
+pub mod firewall;
+pub mod hooks;
+pub mod images;
+pub mod instances;
+pub mod lint;
+pub mod lock;
+pub mod maintenance;
+pub mod metrics;
+pub mod permissions;
+pub mod plan;
 pub mod ports;
 pub mod processing;
+pub mod profile;
+pub mod progress;
+pub mod prune;
+pub mod quota;
+pub mod rpc;
+pub mod sbom;
+pub mod scaffold;
+pub mod search;
+pub mod secrets;
+pub mod serve;
+pub mod settings;
+pub mod shutdown;
+pub mod snapshots;
+pub mod state;
+pub mod traffic;
+pub mod updates;
+pub mod upnp;
+pub mod user_env;
+pub mod validate;
+pub mod yaml;
+
+/// Processes all metadata.yml.jinja files, writes registry.json and generates all apps that can be generated
+/// Apps that fail don't stop the others from being generated, they are returned and get a diagnostic in the registry
+pub fn generate(dir: &Path, config: &Config) -> Result<Vec<FailedApp>> {
+    let mut installed_apps = files::get_installed_apps(dir)?;
+    let available_permissions = get_available_permissions(dir, &installed_apps);
+    let mut failed_apps =
+        crate::tera::process_metadata_yml_jinjas(dir, &installed_apps, &available_permissions)?;
+    // Aliases can be set in metadata.yml.jinja, so renamed apps can only be migrated after rendering them
+    if !aliases::migrate_aliases(dir)?.is_empty() {
+        installed_apps = files::get_installed_apps(dir)?;
+        let available_permissions = get_available_permissions(dir, &installed_apps);
+        failed_apps =
+            crate::tera::process_metadata_yml_jinjas(dir, &installed_apps, &available_permissions)?;
+    }
+    files::write_app_registry(dir, &files::get_all_metadata_ymls(dir)?)?;
+    let apps = determine_jinja_processing_order(dir, &installed_apps)?;
+    let permission_map = get_exported_permissions(dir, &installed_apps);
+    processing::process_app_ymls(dir, &apps, permission_map, Vec::new(), failed_apps, config)
+}
+
+/// Regenerates only the given apps, which have to be in processing order, and keeps the outputs of all other apps
+/// Falls back to a full generate if the ports of other apps would have to move
+pub fn regenerate_apps(dir: &Path, config: &Config, apps: &[String]) -> Result<Vec<FailedApp>> {
+    let installed_apps = files::get_installed_apps(dir)?;
+    let available_permissions = get_available_permissions(dir, &installed_apps);
+    let mut failed_apps = Vec::new();
+    for app in apps {
+        let metadata_yml_jinja = dir.join("apps").join(app).join("metadata.yml.jinja");
+        if metadata_yml_jinja.is_file() {
+            if let Err(err) = crate::tera::process_metadata_yml_jinja(
+                metadata_yml_jinja,
+                &installed_apps,
+                &available_permissions,
+                dir,
+            ) {
+                tracing::error!(
+                    "Failed to process metadata.yml.jinja for app {}: {:#}",
+                    app,
+                    err
+                );
+                failed_apps.push(FailedApp::new(app, DiagnosticCode::RenderFailed, &err));
+            }
+        }
+    }
+    let kept_ports = files::get_port_map(dir)?
+        .into_iter()
+        .filter(|entry| !apps.contains(&entry.app))
+        .collect::<Vec<_>>();
+    let permission_map = get_exported_permissions(dir, &installed_apps);
+    match processing::process_app_ymls(dir, apps, permission_map, kept_ports, failed_apps, config) {
+        Err(err) if err.downcast_ref::<processing::KeptPortsMoved>().is_some() => {
+            tracing::debug!("{}, regenerating all apps", err);
+            generate(dir, config)
+        }
+        result => result,
+    }
+}
+
+/// Returns the app and all apps that depend on it, directly or through other apps, in processing order
+pub fn get_dependents(dir: &Path, installed_apps: &[String], app: &str) -> Result<Vec<String>> {
+    let nodes = get_jinja_processing_nodes(dir, installed_apps)?;
+    let rdeps = files::get_reverse_index(dir)?;
+    let mut affected = vec![app.to_owned()];
+    // Dependencies come first in the sorted order, so one pass finds all indirect dependents too
+    for id in sort_deps(nodes.clone()) {
+        let Some(node) = nodes.iter().find(|node| node.id == id) else {
+            continue;
+        };
+        let uses_affected_app = node.dependencies.iter().any(|dep| affected.contains(dep))
+            || affected
+                .iter()
+                .any(|app| get_consumers(&rdeps, app).contains(&id));
+        if !affected.contains(&id) && uses_affected_app {
+            affected.push(id);
+        }
+    }
+    Ok(affected)
+}
+
+/// Returns the installed apps, the permissions they export and the built-in permissions, as used in metadata.yml.jinja files
+fn get_available_permissions(dir: &Path, installed_apps: &[String]) -> Vec<String> {
+    let apps_dir = dir.join("apps");
+    let mut available_permissions = installed_apps
+        .iter()
+        .flat_map(|app| {
+            // Apps can only be installed if they have an app.yml, so assume app.yml files exist for installed apps
+            let app_yml = files::read_app_yml(&apps_dir, app);
+            let Ok(app_yml) = app_yml else {
+                return vec![app.to_owned()];
+            };
+            let mut permissions = app_yml
+                .into_exported_permissions()
+                .into_iter()
+                .map(|elem| format!("{}/{}", app, elem.id))
+                .collect::<Vec<_>>();
+            permissions.push(app.to_owned());
+            permissions
+        })
+        .collect::<Vec<_>>();
+    let mut builtin_permissions = RESERVED_NAMES
+        .iter()
+        .map(|elem| elem.to_string())
+        .collect::<Vec<_>>();
+    available_permissions.append(&mut builtin_permissions);
+    available_permissions
+}
+
+/// Returns which ports apps may use
+/// With scan_host_ports, this also reserves ports used by services outside of Nirvati
+pub fn get_port_policy(dir: &Path, config: &Config) -> Result<PortPolicy> {
+    let mut policy = config.port_policy();
+    if config.scan_host_ports {
+        // Running apps show up as listening too, so their ports are not external
+        let installed_apps = files::get_installed_apps(dir)?;
+        let own_ports = files::get_port_map(dir)?
+            .into_iter()
+            .filter(|entry| installed_apps.contains(&entry.app))
+            .map(|entry| entry.public_port)
+            .collect::<BTreeSet<_>>();
+        policy
+            .reserved
+            .extend(ports::get_host_ports_in_use().difference(&own_ports));
+    }
+    Ok(policy)
+}
+
+/// Collects the permissions exported by all installed apps
+pub fn get_exported_permissions(
+    nirvati_dir: &Path,
+    installed_apps: &[String],
+) -> HashMap<String, Vec<Permission>> {
+    HashMap::from_iter(installed_apps.iter().filter_map(|app| {
+        // Apps can only be installed if they have an app.yml, so assume app.yml files exist for installed apps
+        match files::read_app_yml(nirvati_dir, app) {
+            Err(err) => {
+                tracing::warn!("Failed to read app.yml for app {}: {:#}", app, err);
+                None
+            }
+            Ok(app_yml) => Some((app.to_owned(), app_yml.into_exported_permissions())),
+        }
+    }))
+}
+
+/// Turns a permission map into the "app" and "app/permission" strings used in templates
+pub fn get_permission_strings(permissions: &HashMap<String, Vec<Permission>>) -> Vec<String> {
+    permissions
+        .iter()
+        .flat_map(|(app, perms)| {
+            let mut permissions = perms
+                .iter()
+                .map(|perm| format!("{}/{}", app, perm.id))
+                .collect::<Vec<_>>();
+            permissions.push(app.to_owned());
+            permissions
+        })
+        .collect()
+}
 
 pub fn determine_jinja_processing_order(
     nirvati_dir: &Path,
     installed_apps: &[String],
 ) -> Result<Vec<String>> {
+    Ok(sort_deps(get_jinja_processing_nodes(
+        nirvati_dir,
+        installed_apps,
+    )?))
+}
+
+/// Returns the apps with an app.yml.jinja that can be processed, with the apps they need permissions from
+fn get_jinja_processing_nodes(nirvati_dir: &Path, installed_apps: &[String]) -> Result<Vec<Node>> {
     // Loop through all subdirs that contain a metadata.yml file
     // For each of them, read the metadata.yml file
     // And add it to the list of nodes
@@ -39,25 +258,24 @@ pub fn determine_jinja_processing_order(
             nodes.push(Node {
                 // We can bail here because this should have been validated during repo sync
                 id: app_id.to_owned(),
+                // Permissions of optional dependencies that are not installed are left out
                 dependencies: metadata
-                    .into_app_yml_jinja_permissions()
+                    .get_usable_app_yml_jinja_permissions(installed_apps)
                     .into_iter()
                     .map(|perm| perm.split('/').next().unwrap().to_string())
                     .collect(),
             });
         }
     }
-    Ok(sort_deps(
-        nodes
-            .into_iter()
-            .filter(|node| {
-                // Ensure all dependencies are installed
-                node.dependencies
-                    .iter()
-                    .all(|dep| installed_apps.contains(dep))
-            })
-            .collect::<Vec<_>>(),
-    ))
+    Ok(nodes
+        .into_iter()
+        .filter(|node| {
+            // Ensure all dependencies are installed
+            node.dependencies
+                .iter()
+                .all(|dep| installed_apps.contains(dep))
+        })
+        .collect())
 }
 
 pub fn determine_jinja_config_processing_order(
@@ -102,3 +320,145 @@ pub fn determine_jinja_config_processing_order(
             .collect::<Vec<_>>(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{files, regenerate_apps};
+    use crate::{config::Config, manage::ports::PortMapEntry, testing::Fixture};
+
+    /// The basic fixture with a copy of its app as other, both want port 80, which is reserved
+    fn two_apps() -> Fixture {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let apps_dir = fixture.root().join("apps");
+        std::fs::create_dir_all(apps_dir.join("other")).unwrap();
+        for file in ["metadata.yml", "app.yml.jinja"] {
+            std::fs::copy(
+                apps_dir.join("example").join(file),
+                apps_dir.join("other").join(file),
+            )
+            .unwrap();
+        }
+        fixture.generate().unwrap();
+        fixture
+    }
+
+    fn port_of(port_map: &[PortMapEntry], app: &str) -> PortMapEntry {
+        port_map
+            .iter()
+            .find(|entry| entry.app == app)
+            .unwrap_or_else(|| panic!("{} has no port", app))
+            .clone()
+    }
+
+    #[test]
+    fn regenerating_keeps_the_ports_of_other_apps() {
+        let fixture = two_apps();
+        let mut port_map = files::get_port_map(fixture.root()).unwrap();
+        // A full generate would move it back next to the port of other
+        let mut kept = port_of(&port_map, "example");
+        kept.public_port = 9000;
+        port_map.retain(|entry| entry.app != "example");
+        port_map.push(kept.clone());
+        files::save_port_map(fixture.root(), port_map).unwrap();
+
+        let failed =
+            regenerate_apps(fixture.root(), &Config::default(), &["other".to_owned()]).unwrap();
+        assert!(failed.is_empty());
+        let port_map = files::get_port_map(fixture.root()).unwrap();
+        assert_eq!(port_of(&port_map, "example"), kept);
+        assert_ne!(port_of(&port_map, "other").public_port, 9000);
+    }
+
+    #[test]
+    fn regenerating_falls_back_to_a_full_generate_if_kept_ports_move() {
+        let fixture = two_apps();
+        let apps_dir = fixture.root().join("apps");
+        let taken = port_of(&files::get_port_map(fixture.root()).unwrap(), "example").public_port;
+        // Other now requires the port of example, which has a lower priority and has to move
+        let app_yml_jinja = std::fs::read_to_string(apps_dir.join("other").join("app.yml.jinja"))
+            .unwrap()
+            .replace(
+                "    port: 80\n",
+                &format!(
+                    "    port: 80\n    required_ports:\n      tcp:\n        {}: 80\n",
+                    taken
+                ),
+            );
+        std::fs::write(apps_dir.join("other").join("app.yml.jinja"), app_yml_jinja).unwrap();
+        // Only a full generate renders example again
+        let example_jinja = apps_dir.join("example").join("app.yml.jinja");
+        let app_yml_jinja = std::fs::read_to_string(&example_jinja)
+            .unwrap()
+            .replace("nginx:1.25-alpine", "nginx:1.27-alpine");
+        std::fs::write(&example_jinja, app_yml_jinja).unwrap();
+
+        let failed =
+            regenerate_apps(fixture.root(), &Config::default(), &["other".to_owned()]).unwrap();
+        assert!(failed.is_empty());
+        let port_map = files::get_port_map(fixture.root()).unwrap();
+        assert!(port_map
+            .iter()
+            .any(|entry| entry.app == "other" && entry.public_port == taken));
+        assert_ne!(port_of(&port_map, "example").public_port, taken);
+        assert!(
+            std::fs::read_to_string(apps_dir.join("example").join("app.yml"))
+                .unwrap()
+                .contains("nginx:1.27-alpine")
+        );
+    }
+
+    #[test]
+    fn templates_get_the_installed_optional_dependencies() {
+        let fixture = two_apps();
+        let example_dir = fixture.root().join("apps").join("example");
+        let mut metadata_yml = std::fs::read_to_string(example_dir.join("metadata.yml")).unwrap();
+        metadata_yml.push_str("  optional_dependencies:\n    - other\n");
+        std::fs::write(example_dir.join("metadata.yml"), metadata_yml).unwrap();
+        let app_yml_jinja = std::fs::read_to_string(example_dir.join("app.yml.jinja"))
+            .unwrap()
+            .replace(
+                "    command:\n",
+                "    command:\n      - \"--optional=[{{ satisfied_optional_deps | join(sep=',') }}]\"\n",
+            );
+        std::fs::write(example_dir.join("app.yml.jinja"), app_yml_jinja).unwrap();
+
+        fixture.generate().unwrap();
+        let app_yml = std::fs::read_to_string(example_dir.join("app.yml")).unwrap();
+        assert!(app_yml.contains("--optional=[]"));
+
+        files::add_installed_app("other", fixture.root()).unwrap();
+        fixture.generate().unwrap();
+        let app_yml = std::fs::read_to_string(example_dir.join("app.yml")).unwrap();
+        assert!(app_yml.contains("--optional=[other]"));
+    }
+
+    #[test]
+    fn templates_get_their_own_metadata() {
+        let fixture = two_apps();
+        let example_dir = fixture.root().join("apps").join("example");
+        std::fs::write(
+            example_dir.join("settings.yml"),
+            "settings:\n  flavor:\n    type: string\n    default: alpine\n  title:\n    type: string\n",
+        )
+        .unwrap();
+        let app_yml_jinja = std::fs::read_to_string(example_dir.join("app.yml.jinja"))
+            .unwrap()
+            .replace(
+                "nginx:1.25-alpine",
+                "nginx:{{ app.version }}-{{ app.settingDefaults.flavor }}-{{ app.id }}",
+            );
+        std::fs::write(example_dir.join("app.yml.jinja"), app_yml_jinja).unwrap();
+
+        fixture.generate().unwrap();
+        let app_yml = std::fs::read_to_string(example_dir.join("app.yml")).unwrap();
+        assert!(app_yml.contains("nginx:1.0.0-alpine-example"));
+    }
+}
@@ -0,0 +1,108 @@
+//! Migrations for apps that were renamed in their store
+//!
+//! An app lists its previous ids in `aliases`. If one of them is installed, but no longer exists
+//! as an app, the installation is moved to the new id. The app keeps its data dir, because the
+//! old dir name is recorded in db/data-dirs.json.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::files;
+
+/// Moves installed apps that were renamed to their new id
+/// Returns the migrated (alias, app) pairs
+pub fn migrate_aliases(nirvati_dir: &Path) -> Result<Vec<(String, String)>> {
+    let installed_apps = files::get_installed_apps(nirvati_dir)?;
+    let mut migrated = Vec::new();
+    for metadata in files::get_all_metadata_ymls(nirvati_dir)? {
+        for alias in &metadata.aliases {
+            if !installed_apps.contains(alias)
+                || installed_apps.contains(&metadata.id)
+                || nirvati_dir.join("apps").join(alias).is_dir()
+            {
+                continue;
+            }
+            migrate(nirvati_dir, alias, &metadata.id)?;
+            tracing::info!("Migrated app {} to its new id {}", alias, metadata.id);
+            migrated.push((alias.clone(), metadata.id.clone()));
+        }
+    }
+    Ok(migrated)
+}
+
+fn migrate(nirvati_dir: &Path, alias: &str, app: &str) -> Result<()> {
+    files::rename_installed_app(alias, app, nirvati_dir)?;
+    let mut port_map = files::get_port_map(nirvati_dir)?;
+    for entry in port_map.iter_mut().filter(|entry| entry.app == alias) {
+        entry.app = app.to_owned();
+    }
+    files::save_port_map(nirvati_dir, port_map)?;
+    let mut dns = files::get_dns_map(nirvati_dir)?;
+    if let Some(entries) = dns.remove(alias) {
+        dns.insert(app.to_owned(), entries);
+        files::save_dns_map(nirvati_dir, &dns)?;
+    }
+    let mut data_dir_names = files::get_data_dir_names(nirvati_dir)?;
+    let data_dir_name = data_dir_names
+        .remove(alias)
+        .unwrap_or_else(|| alias.to_owned());
+    if nirvati_dir.join("app-data").join(&data_dir_name).is_dir() {
+        data_dir_names.insert(app.to_owned(), data_dir_name);
+    }
+    files::save_data_dir_names(nirvati_dir, &data_dir_names)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::migrate_aliases;
+    use crate::{
+        manage::{dirs::app_data_dir, files},
+        testing::Fixture,
+    };
+
+    #[test]
+    fn migrates_renamed_apps_and_keeps_their_data() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root();
+        let metadata_yml_path = root.join("apps").join("example").join("metadata.yml");
+        let mut metadata_yml = std::fs::read_to_string(&metadata_yml_path).unwrap();
+        metadata_yml.push_str("  aliases:\n    - old-example\n");
+        std::fs::write(&metadata_yml_path, metadata_yml).unwrap();
+        std::fs::write(
+            root.join("db").join("user.json"),
+            r#"{"name":"Fixture","password":"fixture","installedApps":["old-example"],"appSettings":{"old-example":{"title":"Mine"}}}"#,
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.join("app-data").join("old-example")).unwrap();
+
+        assert_eq!(
+            migrate_aliases(root).unwrap(),
+            vec![("old-example".to_owned(), "example".to_owned())]
+        );
+        assert_eq!(
+            files::get_installed_apps(root).unwrap(),
+            vec!["example".to_owned()]
+        );
+        let user_json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(root.join("db").join("user.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(user_json["appSettings"]["example"]["title"], "Mine");
+        assert_eq!(
+            app_data_dir(root, "example"),
+            root.join("app-data").join("old-example")
+        );
+        // Once migrated, there is nothing left to do
+        assert!(migrate_aliases(root).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+//! Cancelling a running generate or install in RPC mode, for example when a user aborts an install in the UI
+//!
+//! Generate checks for cancellation between apps and phases. Before a request runs, apps/ and db/, everything
+//! generate and install write to, are copied to db/.checkpoint, so they can be restored if it is cancelled halfway.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+
+const CHECKPOINT_DIR: &str = ".checkpoint";
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The operation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Asks the running operation to stop at the next check
+pub fn request() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Forgets a cancellation that came in after the last operation finished
+pub fn reset() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+/// Fails with Cancelled if the running operation should stop
+pub fn check() -> Result<()> {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
+
+pub fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Cancelled>().is_some()
+}
+
+/// The dirs generate and install write to, relative to the Nirvati root
+const TRACKED_DIRS: [&str; 2] = ["apps", "db"];
+
+/// The files and dirs in the tracked dirs, relative to the Nirvati root
+#[derive(Debug, Default)]
+struct Tree {
+    /// Files and symlinks, symlinked local store apps are restored as symlinks
+    files: BTreeSet<PathBuf>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+/// The checkpoint itself, the lock and caches don't have to be restored
+fn is_untracked(nirvati_dir: &Path, relative: &Path) -> bool {
+    relative == Path::new("db").join(CHECKPOINT_DIR)
+        || relative == Path::new("db").join(".appmgr.lock")
+        || nirvati_dir.join(relative) == crate::tera::js::cache_dir(nirvati_dir)
+}
+
+fn walk(nirvati_dir: &Path, relative: &Path, tree: &mut Tree) -> Result<()> {
+    for entry in std::fs::read_dir(nirvati_dir.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if is_untracked(nirvati_dir, &path) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            walk(nirvati_dir, &path, tree)?;
+            tree.dirs.insert(path);
+        } else {
+            tree.files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+fn scan(nirvati_dir: &Path) -> Result<Tree> {
+    let mut tree = Tree::default();
+    for dir in TRACKED_DIRS {
+        if nirvati_dir.join(dir).is_dir() {
+            walk(nirvati_dir, Path::new(dir), &mut tree)?;
+            tree.dirs.insert(PathBuf::from(dir));
+        }
+    }
+    Ok(tree)
+}
+
+/// Removes a file, symlink or dir, without following symlinks
+fn remove_entry(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// Copies a file, or recreates a symlink, replacing what is at the target
+fn copy_entry(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    remove_entry(to)?;
+    if std::fs::symlink_metadata(from)?.file_type().is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+    } else {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Copies of everything an operation can change, which are restored if it is cancelled
+pub struct Checkpoint {
+    nirvati_dir: PathBuf,
+    tree: Tree,
+}
+
+impl Checkpoint {
+    pub fn take(nirvati_dir: &Path) -> Result<Self> {
+        let checkpoint_dir = nirvati_dir.join("db").join(CHECKPOINT_DIR);
+        remove_entry(&checkpoint_dir)?;
+        let tree = scan(nirvati_dir)?;
+        for file in &tree.files {
+            copy_entry(&nirvati_dir.join(file), &checkpoint_dir.join(file))?;
+        }
+        Ok(Checkpoint {
+            nirvati_dir: nirvati_dir.to_owned(),
+            tree,
+        })
+    }
+
+    /// Puts back the copied files and removes everything the operation created, like the dir of a new instance
+    pub fn restore(self) -> Result<()> {
+        let checkpoint_dir = self.nirvati_dir.join("db").join(CHECKPOINT_DIR);
+        let current = scan(&self.nirvati_dir)?;
+        for file in current.files.difference(&self.tree.files) {
+            remove_entry(&self.nirvati_dir.join(file))?;
+        }
+        for dir in current.dirs.difference(&self.tree.dirs) {
+            remove_entry(&self.nirvati_dir.join(dir))?;
+        }
+        for dir in &self.tree.dirs {
+            let path = self.nirvati_dir.join(dir);
+            if !std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+                remove_entry(&path)?;
+                std::fs::create_dir_all(&path)?;
+            }
+        }
+        for file in &self.tree.files {
+            copy_entry(&checkpoint_dir.join(file), &self.nirvati_dir.join(file))?;
+        }
+        self.discard()
+    }
+
+    pub fn discard(self) -> Result<()> {
+        let checkpoint_dir = self.nirvati_dir.join("db").join(CHECKPOINT_DIR);
+        remove_entry(&checkpoint_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn restores_checkpoint() {
+        let nirvati_dir = TempDir::new("cancel");
+        std::fs::create_dir_all(nirvati_dir.join("db")).unwrap();
+        std::fs::create_dir_all(nirvati_dir.join("apps").join("notes")).unwrap();
+        std::fs::write(nirvati_dir.join("db").join("user.json"), "before").unwrap();
+        std::fs::write(nirvati_dir.join("apps").join("registry.json"), "before").unwrap();
+
+        let checkpoint = Checkpoint::take(&nirvati_dir).unwrap();
+        std::fs::write(nirvati_dir.join("db").join("user.json"), "after").unwrap();
+        std::fs::write(nirvati_dir.join("apps").join("registry.json"), "after").unwrap();
+        std::fs::write(
+            nirvati_dir.join("apps").join("notes").join("result.yml"),
+            "",
+        )
+        .unwrap();
+        checkpoint.restore().unwrap();
+
+        let read = |file: &str| std::fs::read_to_string(nirvati_dir.join(file)).ok();
+        let user_json = read("db/user.json");
+        let registry = read("apps/registry.json");
+        let result_yml = read("apps/notes/result.yml");
+        let checkpoint_dir = nirvati_dir.join("db").join(CHECKPOINT_DIR).exists();
+
+        assert_eq!(user_json.as_deref(), Some("before"));
+        assert_eq!(registry.as_deref(), Some("before"));
+        assert_eq!(result_yml, None);
+        assert!(!checkpoint_dir);
+    }
+}
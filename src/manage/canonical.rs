@@ -0,0 +1,88 @@
+//! Canonical serialization of the files Generate writes, so identical inputs produce byte-identical outputs
+//!
+//! Keys of objects and mappings are sorted, floats use serde's shortest representation that round-trips,
+//! and every file ends with a newline. Lists whose order doesn't matter are sorted by their writers.
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_yaml::Value;
+
+/// Pretty-printed JSON with sorted keys
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    // Maps in serde_json's Value are sorted by key
+    let mut json = serde_json::to_string_pretty(&serde_json::to_value(value)?)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// JSON with sorted keys and without whitespace, for files that are only read by programs
+pub fn to_compact_json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut json = serde_json::to_string(&serde_json::to_value(value)?)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// YAML with sorted keys
+pub fn to_yaml<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut value = serde_yaml::to_value(value)?;
+    sort_keys(&mut value);
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+pub fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    std::fs::write(path, to_json(value)?)?;
+    Ok(())
+}
+
+pub fn write_yaml<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    std::fs::write(path, to_yaml(value)?)?;
+    Ok(())
+}
+
+fn sort_key(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        key => serde_yaml::to_string(key).unwrap_or_default(),
+    }
+}
+
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            let mut entries = std::mem::take(mapping).into_iter().collect::<Vec<_>>();
+            entries.sort_by_cached_key(|(key, _)| sort_key(key));
+            for (_, value) in &mut entries {
+                sort_keys(value);
+            }
+            *mapping = entries.into_iter().collect();
+        }
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(sort_keys),
+        Value::Tagged(tagged) => sort_keys(&mut tagged.value),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn sorts_keys() {
+        let value = HashMap::from([
+            ("zebra", vec![HashMap::from([("b", 1.5), ("a", 0.1)])]),
+            ("apple", vec![]),
+        ]);
+
+        assert_eq!(
+            to_yaml(&value).unwrap(),
+            "apple: []\nzebra:\n- a: 0.1\n  b: 1.5\n"
+        );
+        assert_eq!(
+            to_compact_json(&value).unwrap(),
+            "{\"apple\":[],\"zebra\":[{\"a\":0.1,\"b\":1.5}]}\n"
+        );
+    }
+}
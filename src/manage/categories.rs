@@ -0,0 +1,185 @@
+//! App categories shown in the UI
+//!
+//! Apps should use one of the canonical categories, or one a store adds in a categories.yml in its root.
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::{Diagnostic, DiagnosticCode, OutputMetadata, ValidationMode};
+
+use super::files;
+
+pub const CATEGORIES: [&str; 16] = [
+    "Bitcoin",
+    "Lightning",
+    "Finance",
+    "Productivity",
+    "Files",
+    "Media",
+    "Social",
+    "Communication",
+    "Networking",
+    "Security",
+    "Home automation",
+    "Automation",
+    "AI",
+    "Developer tools",
+    "Gaming",
+    "Utilities",
+];
+
+/// An entry in apps/categories.json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CategoryCount {
+    pub name: String,
+    /// Number of apps in the registry with this category
+    pub apps: usize,
+}
+
+/// The canonical categories, followed by the ones stores add, in the order of db/sources.yml
+pub fn known_categories(nirvati_dir: &Path) -> Result<Vec<String>> {
+    let mut categories = CATEGORIES
+        .iter()
+        .map(|category| category.to_string())
+        .collect::<Vec<_>>();
+    for store in files::get_sources(nirvati_dir)?.stores {
+        for category in files::get_store_categories(nirvati_dir, &store)? {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+    }
+    Ok(categories)
+}
+
+/// Other app stores' names of categories -> the canonical category
+const ALIASES: [(&str, &str); 16] = [
+    ("developer", "Developer tools"),
+    ("development", "Developer tools"),
+    ("analytics", "Developer tools"),
+    ("crypto", "Finance"),
+    ("automation", "Home automation"),
+    ("network", "Networking"),
+    ("cloud", "Files"),
+    ("backup", "Files"),
+    ("documents", "Files"),
+    ("data", "Files"),
+    ("chat", "Communication"),
+    ("photography", "Media"),
+    ("gallery", "Media"),
+    ("music", "Media"),
+    ("books", "Media"),
+    ("notes", "Productivity"),
+];
+
+/// Maps a category of another app store format to a canonical one, falling back to Utilities
+pub fn canonical(category: &str) -> String {
+    let category = category.to_lowercase();
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == category)
+        .map(|(_, canonical)| *canonical)
+        .or_else(|| {
+            CATEGORIES
+                .iter()
+                .find(|canonical| canonical.to_lowercase() == category)
+                .copied()
+        })
+        .unwrap_or("Utilities")
+        .to_owned()
+}
+
+/// Number of single-character edits to turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The known category closest to an unknown one, if it looks like a typo or a different casing
+pub fn suggest<'a>(category: &str, known: &'a [String]) -> Option<&'a str> {
+    let category = category.to_lowercase();
+    known
+        .iter()
+        .map(|candidate| {
+            (
+                edit_distance(&category, &candidate.to_lowercase()),
+                candidate,
+            )
+        })
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// Adds a diagnostic if the app's category is not known, or fails in strict mode
+pub fn check(metadata: &mut OutputMetadata, known: &[String], mode: ValidationMode) -> Result<()> {
+    if known.contains(&metadata.category) {
+        return Ok(());
+    }
+    let message = match suggest(&metadata.category, known) {
+        Some(suggestion) => format!(
+            "Unknown category {}, did you mean {}?",
+            metadata.category, suggestion
+        ),
+        None => format!("Unknown category {}", metadata.category),
+    };
+    if mode == ValidationMode::Strict {
+        anyhow::bail!(message);
+    }
+    tracing::warn!("App {}: {}", metadata.id, message);
+    metadata.diagnostics.push(Diagnostic::warning(
+        DiagnosticCode::UnknownCategory,
+        message,
+        Some("category".to_owned()),
+    ));
+    Ok(())
+}
+
+/// Counts the apps in each category, known categories come first even if they have no apps
+pub fn count(registry: &[OutputMetadata], known: &[String]) -> Vec<CategoryCount> {
+    let mut counts = known
+        .iter()
+        .map(|name| CategoryCount {
+            name: name.clone(),
+            apps: 0,
+        })
+        .collect::<Vec<_>>();
+    for app in registry {
+        match counts.iter_mut().find(|count| count.name == app.category) {
+            Some(count) => count.apps += 1,
+            None => counts.push(CategoryCount {
+                name: app.category.clone(),
+                apps: 1,
+            }),
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_near_matches() {
+        let known = CATEGORIES
+            .iter()
+            .map(|category| category.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(suggest("bitcoin", &known), Some("Bitcoin"));
+        assert_eq!(suggest("Productivty", &known), Some("Productivity"));
+        assert_eq!(suggest("Developer Tool", &known), Some("Developer tools"));
+        assert_eq!(suggest("Cooking", &known), None);
+    }
+}
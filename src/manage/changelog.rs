@@ -0,0 +1,117 @@
+//! The "what's new" feed in apps/changelog.json, built from the release notes of all apps
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::OutputMetadata;
+
+/// A release of an app with its notes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangelogEntry {
+    pub version: String,
+    pub notes: String,
+    /// True if the app is installed at an older version
+    pub newer: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppChangelog {
+    /// The version the app was installed at, if it is installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_version: Option<String>,
+    /// Newest release first
+    pub entries: Vec<ChangelogEntry>,
+}
+
+/// Contents of apps/changelog.json, app id -> its releases
+pub type Changelog = BTreeMap<String, AppChangelog>;
+
+/// Compares versions like 1.2.10 and 1.10.0-beta part by part, numerically where both parts are numbers
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |version: &str| {
+        version
+            .trim_start_matches('v')
+            .split(['.', '-', '+'])
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (split(a), split(b));
+    for (a_part, b_part) in a.iter().zip(&b) {
+        let ordering = match (a_part.parse::<u64>(), b_part.parse::<u64>()) {
+            (Ok(a_number), Ok(b_number)) => a_number.cmp(&b_number),
+            _ => a_part.cmp(b_part),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Adds the registry version of installed apps that don't have an installed version yet,
+/// and removes the versions of apps that are no longer installed
+pub fn update_installed_versions(
+    installed_versions: &mut BTreeMap<String, String>,
+    registry: &[OutputMetadata],
+    installed_apps: &[String],
+) {
+    installed_versions.retain(|app, _| installed_apps.contains(app));
+    for app in registry {
+        if installed_apps.contains(&app.id) && !installed_versions.contains_key(&app.id) {
+            installed_versions.insert(app.id.clone(), app.version.clone());
+        }
+    }
+}
+
+/// Collects the release notes of the apps in the registry
+pub fn build_changelog(
+    registry: &[OutputMetadata],
+    installed_versions: &BTreeMap<String, String>,
+) -> Changelog {
+    registry
+        .iter()
+        .filter(|app| !app.release_notes.is_empty())
+        .map(|app| {
+            let installed_version = installed_versions.get(&app.id).cloned();
+            let mut entries = app
+                .release_notes
+                .iter()
+                .map(|(version, notes)| ChangelogEntry {
+                    version: version.clone(),
+                    notes: notes.clone(),
+                    newer: installed_version.as_ref().is_some_and(|installed| {
+                        compare_versions(version, installed) == Ordering::Greater
+                    }),
+                })
+                .collect::<Vec<_>>();
+            entries.sort_by(|a, b| compare_versions(&b.version, &a.version));
+            (
+                app.id.clone(),
+                AppChangelog {
+                    installed_version,
+                    entries,
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(compare_versions("1.2.10", "1.2.9"), Ordering::Greater);
+        assert_eq!(compare_versions("v1.10.0", "1.9.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(
+            compare_versions("2.0.0-beta", "2.0.0-alpha"),
+            Ordering::Greater
+        );
+    }
+}
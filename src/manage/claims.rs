@@ -0,0 +1,57 @@
+//! Exclusive resources apps can claim, like hardware devices or host ports outside the port map
+
+use std::collections::BTreeMap;
+
+/// Grants every claimed resource to a single app
+/// Installed apps are processed first, then apps are processed alphabetically
+/// An app only gets its claims if all of them are still free, otherwise it conflicts
+/// Returns (claim -> app it is granted to, apps_with_conflicts)
+pub fn resolve_claims(
+    claims: &BTreeMap<String, Vec<String>>,
+    installed_apps: &[String],
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut apps = claims
+        .iter()
+        .filter(|(_, app_claims)| !app_claims.is_empty())
+        .collect::<Vec<_>>();
+    apps.sort_by_key(|(app, _)| (!installed_apps.contains(app), app.to_owned()));
+    let mut granted = BTreeMap::new();
+    let mut apps_with_conflicts = Vec::new();
+    for (app, app_claims) in apps {
+        if app_claims.iter().any(|claim| granted.contains_key(claim)) {
+            apps_with_conflicts.push(app.clone());
+            continue;
+        }
+        for claim in app_claims {
+            granted.insert(claim.clone(), app.clone());
+        }
+    }
+    (granted, apps_with_conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn installed_apps_win() {
+        let claims = BTreeMap::from([
+            (
+                "adguard".to_owned(),
+                vec!["hostPort:53".to_owned(), "dvb-tuner".to_owned()],
+            ),
+            ("pihole".to_owned(), vec!["hostPort:53".to_owned()]),
+            ("tvheadend".to_owned(), vec!["dvb-tuner".to_owned()]),
+        ]);
+        let (granted, apps_with_conflicts) = resolve_claims(&claims, &["pihole".to_owned()]);
+        assert_eq!(
+            granted,
+            BTreeMap::from([
+                ("hostPort:53".to_owned(), "pihole".to_owned()),
+                ("dvb-tuner".to_owned(), "tvheadend".to_owned()),
+            ])
+        );
+        assert_eq!(apps_with_conflicts, vec!["adguard".to_owned()]);
+    }
+}
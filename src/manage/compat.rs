@@ -0,0 +1,95 @@
+//! Which apps this app manager can generate
+//!
+//! Apps can require a minimum app manager version with min_manager_version in their metadata.yml,
+//! and app.yml and metadata.yml files with a newer version than this app manager supports can't be read.
+//! Generate skips these apps with a managerTooOld diagnostic instead of failing to convert them.
+
+use crate::composegenerator::types::{Diagnostic, DiagnosticCode};
+
+use super::changelog::compare_versions;
+
+/// The version of this app manager, compared to the min_manager_version of apps
+pub const MANAGER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Returned when reading an app.yml or metadata.yml with a newer version than this app manager supports
+#[derive(Debug)]
+pub struct NewerSchemaVersion {
+    pub file: &'static str,
+    pub version: i64,
+    /// The newest version of the file this app manager supports
+    pub newest: u8,
+}
+
+impl std::fmt::Display for NewerSchemaVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} version {} is newer than the newest version this app manager supports ({}), update the app manager to use this app",
+            self.file, self.version, self.newest
+        )
+    }
+}
+
+impl std::error::Error for NewerSchemaVersion {}
+
+/// The diagnostic for an app that requires a newer app manager than this one, if it does
+pub fn check_manager_version(min_manager_version: Option<&str>) -> Option<Diagnostic> {
+    let min_manager_version = min_manager_version?;
+    if compare_versions(MANAGER_VERSION, min_manager_version).is_ge() {
+        return None;
+    }
+    Some(Diagnostic::error(
+        DiagnosticCode::ManagerTooOld,
+        format!(
+            "The app requires app manager {}, but this is {}, update the app manager to use this app",
+            min_manager_version, MANAGER_VERSION
+        ),
+    ))
+}
+
+/// The code for an app that failed to read or render, managerTooOld if a file was too new instead of invalid
+pub fn failure_code(err: &anyhow::Error, code: DiagnosticCode) -> DiagnosticCode {
+    if err
+        .chain()
+        .any(|cause| cause.downcast_ref::<NewerSchemaVersion>().is_some())
+    {
+        DiagnosticCode::ManagerTooOld
+    } else {
+        code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn checks_min_manager_version() {
+        assert_eq!(check_manager_version(None), None);
+        assert_eq!(check_manager_version(Some("0.0.1")), None);
+        assert_eq!(check_manager_version(Some(MANAGER_VERSION)), None);
+        let diagnostic = check_manager_version(Some("999.0.0")).unwrap();
+        assert_eq!(diagnostic.code, DiagnosticCode::ManagerTooOld);
+        assert!(diagnostic.message.contains("999.0.0"));
+    }
+
+    #[test]
+    fn detects_newer_schema_versions() {
+        let newer = anyhow::Error::new(NewerSchemaVersion {
+            file: "app.yml",
+            version: 2,
+            newest: 1,
+        })
+        .context("Failed to read app.yml");
+        assert_eq!(
+            failure_code(&newer, DiagnosticCode::ConversionFailed),
+            DiagnosticCode::ManagerTooOld
+        );
+        assert_eq!(
+            failure_code(&anyhow!("invalid"), DiagnosticCode::RenderFailed),
+            DiagnosticCode::RenderFailed
+        );
+    }
+}
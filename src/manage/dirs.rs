@@ -0,0 +1,251 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::ResultYml;
+
+/// Owner of data dirs whose container does not run as a numeric user
+pub const DEFAULT_OWNER: (u32, u32) = (1000, 1000);
+pub const DEFAULT_MODE: &str = "0755";
+
+/// A directory an app bind mounts from its data dir, listed in apps/<app>/dirs.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    /// Relative to the app's data dir
+    pub path: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// Octal permission bits
+    pub mode: String,
+    /// Where the dir is on the host, only set if the app's data is in a storage pool other than the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_path: Option<PathBuf>,
+}
+
+/// Parses the owner from a compose user like "1000" or "1000:1000"
+/// Named users can't be resolved outside the container, so they get the default owner
+pub fn owner_from_user(user: Option<&str>) -> (u32, u32) {
+    let Some(user) = user else {
+        return DEFAULT_OWNER;
+    };
+    let (uid, gid) = user.split_once(':').unwrap_or((user, user));
+    match (uid.parse(), gid.parse()) {
+        (Ok(uid), Ok(gid)) => (uid, gid),
+        _ => DEFAULT_OWNER,
+    }
+}
+
+/// The pool apps are in unless the user chose another one, which is app-data in the Nirvati root
+pub const DEFAULT_POOL: &str = "default";
+
+static STORAGE_POOLS: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// Sets the storage pools from the config, relative paths are relative to the Nirvati root
+pub fn configure_storage_pools(nirvati_dir: &Path, pools: &BTreeMap<String, PathBuf>) {
+    *STORAGE_POOLS.lock().unwrap_or_else(|err| err.into_inner()) = pools
+        .iter()
+        .map(|(name, path)| (name.clone(), nirvati_dir.join(path)))
+        .collect();
+}
+
+/// The dir app data dirs are created in, for the default pool if pool is None
+pub fn pool_dir(nirvati_dir: &Path, pool: Option<&str>) -> Result<PathBuf> {
+    match pool {
+        None | Some(DEFAULT_POOL) => Ok(nirvati_dir.join("app-data")),
+        Some(pool) => STORAGE_POOLS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(pool)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Storage pool {} is not in [storage_pools] of the config",
+                    pool
+                )
+            }),
+    }
+}
+
+/// The name of an app's data dir, which keeps its old name if the app was renamed
+fn data_dir_name(nirvati_dir: &Path, app: &str) -> String {
+    super::files::get_data_dir_names(nirvati_dir)
+        .ok()
+        .and_then(|mut names| names.remove(app))
+        .unwrap_or_else(|| app.to_owned())
+}
+
+/// The data dir of an app in the pool the user chose
+pub fn try_app_data_dir(nirvati_dir: &Path, app: &str) -> Result<PathBuf> {
+    let pool = super::files::get_storage_pool(nirvati_dir, app)?;
+    Ok(pool_dir(nirvati_dir, pool.as_deref())?.join(data_dir_name(nirvati_dir, app)))
+}
+
+/// The data dir of an app, in the default pool if the user chose one that doesn't exist
+pub fn app_data_dir(nirvati_dir: &Path, app: &str) -> PathBuf {
+    try_app_data_dir(nirvati_dir, app).unwrap_or_else(|err| {
+        tracing::warn!("Using the default storage pool for {}: {:#}", app, err);
+        nirvati_dir
+            .join("app-data")
+            .join(data_dir_name(nirvati_dir, app))
+    })
+}
+
+/// Replaces ${APP_DATA_DIR} in the mounts of an app in another pool than the default one with its data dir,
+/// and records where its dirs are
+pub fn apply_pool(result: &mut ResultYml, dirs: &mut [DataDir], data_dir: &Path) {
+    let data_dir = data_dir.to_string_lossy();
+    for service in result.spec.services.values_mut() {
+        for volume in service.volumes.iter_mut() {
+            if let Some(rest) = volume.strip_prefix("${APP_DATA_DIR}") {
+                *volume = format!("{}{}", data_dir, rest);
+            }
+        }
+    }
+    for dir in dirs {
+        dir.host_path = Some(Path::new(data_dir.as_ref()).join(&dir.path));
+    }
+}
+
+/// Creates all missing data dirs of an app with their owner and mode
+/// Existing dirs are left untouched, changing ownership requires running as root
+pub fn ensure_dirs(nirvati_dir: &Path, app: &str, dirs: &[DataDir]) -> Result<Vec<PathBuf>> {
+    let data_dir = app_data_dir(nirvati_dir, app);
+    let mut created = Vec::new();
+    for dir in dirs {
+        // dirs.yml comes from the app.yml, so an absolute path or .. could point anywhere on the host
+        let relative = Path::new(&dir.path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        let path = data_dir.join(&dir.path);
+        if !relative || !path.starts_with(&data_dir) || path == data_dir {
+            bail!(
+                "Data dir {} of {} is outside of its data dir",
+                dir.path,
+                app
+            );
+        }
+        if path.exists() {
+            continue;
+        }
+        std::fs::create_dir_all(&path)?;
+        let mode = u32::from_str_radix(&dir.mode, 8)
+            .map_err(|_| anyhow!("Invalid mode {} for {}", dir.mode, dir.path))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        std::os::unix::fs::chown(&path, Some(dir.uid), Some(dir.gid))
+            .map_err(|err| anyhow!("Failed to change owner of {}: {}", path.display(), err))?;
+        created.push(path);
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::{
+        manage::files,
+        testing::{Fixture, TempDir},
+    };
+
+    fn data_dir(path: &str, dir: &TempDir) -> DataDir {
+        // Chowning to the owner of the test's dir works without root
+        let metadata = std::fs::metadata(dir.path()).unwrap();
+        DataDir {
+            path: path.to_owned(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: "0750".to_owned(),
+            host_path: None,
+        }
+    }
+
+    #[test]
+    fn creates_data_dirs() {
+        let dir = TempDir::new("dirs");
+        let created = ensure_dirs(&dir, "notes", &[data_dir("data/db", &dir)]).unwrap();
+        let path = dir.join("app-data").join("notes").join("data").join("db");
+        assert_eq!(created, vec![path.clone()]);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        // Existing dirs are left as they are
+        assert!(ensure_dirs(&dir, "notes", &[data_dir("data/db", &dir)])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn data_dirs_cant_escape_the_app_data_dir() {
+        let dir = TempDir::new("dirs-escape");
+        let outside = dir.join("outside");
+        for path in [
+            outside.to_string_lossy().into_owned(),
+            "../../outside".to_owned(),
+            "".to_owned(),
+        ] {
+            let err = ensure_dirs(&dir, "notes", &[data_dir(&path, &dir)]).unwrap_err();
+            assert!(
+                err.to_string().contains("outside of its data dir"),
+                "{}",
+                path
+            );
+        }
+        assert!(!outside.exists());
+        assert!(!dir.join("app-data").join("notes").exists());
+    }
+
+    #[test]
+    fn puts_apps_in_the_chosen_storage_pool() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root();
+        configure_storage_pools(
+            root,
+            &BTreeMap::from([("hdd".to_owned(), PathBuf::from("hdd/app-data"))]),
+        );
+        let choose_pool = |pool: &str| {
+            std::fs::write(
+                root.join("db").join("user.json"),
+                format!(
+                    r#"{{"name":"Fixture","password":"fixture","installedApps":["example"],"storagePools":{{"example":"{}"}}}}"#,
+                    pool
+                ),
+            )
+            .unwrap();
+        };
+
+        choose_pool("hdd");
+        assert!(fixture.generate().unwrap().is_empty());
+        let data_dir = root.join("hdd").join("app-data").join("example");
+        assert_eq!(app_data_dir(root, "example"), data_dir);
+        let dirs = files::get_data_dirs(root, "example").unwrap();
+        assert!(!dirs.is_empty());
+        for dir in dirs {
+            assert_eq!(dir.host_path, Some(data_dir.join(&dir.path)));
+        }
+        let result_yml =
+            std::fs::read_to_string(root.join("apps").join("example").join("result.yml")).unwrap();
+        assert!(result_yml.contains(&data_dir.to_string_lossy().into_owned()));
+
+        // Pools that are not in the config fail the app
+        choose_pool("ssd");
+        let failed = fixture.generate().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].app, "example");
+        assert!(failed[0]
+            .diagnostic
+            .message
+            .contains("Storage pool ssd is not in [storage_pools]"));
+    }
+}
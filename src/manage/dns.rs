@@ -0,0 +1,333 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::output::types::NetworkEntry, composegenerator::types::ResultYml,
+    utils::StringLike,
+};
+
+use super::instances::sanitize_id;
+
+/// The network app containers are attached to
+pub const NETWORK_NAME: &str = "default";
+
+/// Addresses at the start of the subnet that are left for the gateway and Nirvati's own services
+const RESERVED_ADDRESSES: u32 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DnsEntry {
+    pub hostname: String,
+    pub ip: Ipv4Addr,
+    /// Only set if the config has an IPv6 subnet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+    /// Other names of the service on the app network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+}
+
+/// The contents of apps/dns.yml, app -> service -> entry
+pub type DnsMap = BTreeMap<String, BTreeMap<String, DnsEntry>>;
+
+pub fn hostname(app: &str, service: &str) -> String {
+    format!("{}_{}", sanitize_id(app), service)
+}
+
+/// The claim an app makes for a network alias, so only one app gets it
+pub fn alias_claim(alias: &str) -> String {
+    format!("alias:{}", alias)
+}
+
+/// The prefix of the env vars for a service, for example APP_DEMO_DB_MAIN for the main service of demo-db
+pub fn env_var_prefix(app: &str, service: &str) -> String {
+    format!("APP_{}_{}", sanitize_id(app), service)
+        .to_uppercase()
+        .replace('-', "_")
+}
+
+/// Returns the first address and the number of addresses in a subnet like 10.21.0.0/16
+fn parse_subnet(subnet: &str) -> Result<(u32, u32)> {
+    let (address, prefix) = subnet
+        .split_once('/')
+        .ok_or_else(|| anyhow!("Subnet {} has no prefix length", subnet))?;
+    let address: Ipv4Addr = address.parse()?;
+    let prefix: u32 = prefix.parse()?;
+    if !(8..=30).contains(&prefix) {
+        bail!("Subnet {} needs a prefix length between 8 and 30", subnet);
+    }
+    let size = 1 << (32 - prefix);
+    Ok((u32::from(address) & !(size - 1), size))
+}
+
+/// Returns the first address and the prefix length of an IPv6 subnet like fd00:21::/64
+fn parse_ipv6_subnet(subnet: &str) -> Result<(u128, u32)> {
+    let (address, prefix) = subnet
+        .split_once('/')
+        .ok_or_else(|| anyhow!("Subnet {} has no prefix length", subnet))?;
+    let address: Ipv6Addr = address.parse()?;
+    let prefix: u32 = prefix.parse()?;
+    if !(8..=124).contains(&prefix) {
+        bail!("Subnet {} needs a prefix length between 8 and 124", subnet);
+    }
+    Ok((u128::from(address) & !(u128::MAX >> prefix), prefix))
+}
+
+/// Assigns an address to every service, keeping the addresses services had before
+/// Entries of apps that are not in `services` are kept as they are
+/// With an IPv6 subnet, every service also gets the address at the same offset in it, so both stay stable together
+pub fn assign_addresses(
+    previous: &DnsMap,
+    services: &BTreeMap<String, Vec<String>>,
+    subnet: &str,
+    ipv6_subnet: Option<&str>,
+) -> Result<DnsMap> {
+    let (network, size) = parse_subnet(subnet)?;
+    let ipv6_network = ipv6_subnet
+        .map(|ipv6_subnet| {
+            let (ipv6_network, prefix) = parse_ipv6_subnet(ipv6_subnet)?;
+            if 128 - prefix < size.trailing_zeros() {
+                bail!(
+                    "IPv6 subnet {} is smaller than {}, it needs a prefix length of at most {}",
+                    ipv6_subnet,
+                    subnet,
+                    128 - size.trailing_zeros()
+                );
+            }
+            Ok(ipv6_network)
+        })
+        .transpose()?;
+    let in_subnet = |ip: &Ipv4Addr| {
+        let offset = u32::from(*ip).wrapping_sub(network);
+        (RESERVED_ADDRESSES..size - 1).contains(&offset)
+    };
+    let mut result = previous.clone();
+    result.retain(|app, _| !services.contains_key(app));
+    let mut used = result
+        .values()
+        .flat_map(|entries| entries.values().map(|entry| entry.ip))
+        .collect::<BTreeSet<_>>();
+    let ipv6_of = |ip: Ipv4Addr| {
+        ipv6_network.map(|ipv6_network| {
+            Ipv6Addr::from(ipv6_network + u128::from(u32::from(ip).wrapping_sub(network)))
+        })
+    };
+    let mut new_services = Vec::new();
+    for (app, app_services) in services {
+        let app_entries = result.entry(app.clone()).or_default();
+        for service in app_services {
+            let previous_ip = previous
+                .get(app)
+                .and_then(|entries| entries.get(service))
+                .map(|entry| entry.ip)
+                .filter(|ip| in_subnet(ip) && !used.contains(ip));
+            if let Some(ip) = previous_ip {
+                used.insert(ip);
+                app_entries.insert(
+                    service.clone(),
+                    DnsEntry {
+                        hostname: hostname(app, service),
+                        ip,
+                        ipv6: ipv6_of(ip),
+                        aliases: Vec::new(),
+                    },
+                );
+            } else {
+                new_services.push((app, service));
+            }
+        }
+    }
+    let mut free = (RESERVED_ADDRESSES..size - 1)
+        .map(|offset| Ipv4Addr::from(network + offset))
+        .filter(|ip| !used.contains(ip));
+    for (app, service) in new_services {
+        let ip = free
+            .next()
+            .ok_or_else(|| anyhow!("No free addresses left in {}", subnet))?;
+        result.entry(app.clone()).or_default().insert(
+            service.clone(),
+            DnsEntry {
+                hostname: hostname(app, service),
+                ip,
+                ipv6: ipv6_of(ip),
+                aliases: Vec::new(),
+            },
+        );
+    }
+    // Kept entries of other apps get their IPv6 address too, or lose it if IPv6 was turned off
+    for entries in result.values_mut() {
+        for entry in entries.values_mut() {
+            entry.ipv6 = ipv6_of(entry.ip);
+        }
+    }
+    Ok(result)
+}
+
+/// Records the network aliases of an app's services in its entries
+pub fn set_aliases(dns: &mut DnsMap, app: &str, result: &ResultYml) {
+    let Some(entries) = dns.get_mut(app) else {
+        return;
+    };
+    for (service_name, entry) in entries.iter_mut() {
+        entry.aliases = result
+            .spec
+            .services
+            .get(service_name)
+            .and_then(|service| service.networks.as_ref())
+            .and_then(|networks| networks.get(NETWORK_NAME))
+            .map(|network| network.aliases.clone())
+            .unwrap_or_default();
+    }
+}
+
+/// Returns the app and the apps it has permissions for
+pub fn visible_apps<'a>(app: &'a str, permissions: &'a [String]) -> Vec<&'a str> {
+    let mut visible_apps = vec![app];
+    for permission in permissions {
+        let permission_app = permission.split('/').next().unwrap_or_default();
+        if !visible_apps.contains(&permission_app) {
+            visible_apps.push(permission_app);
+        }
+    }
+    visible_apps
+}
+
+/// Sets container names and addresses for an app's services,
+/// and exposes the hostnames and addresses of its own services and the apps it has permissions for as env vars
+pub fn apply_to_result(result: &mut ResultYml, app: &str, dns: &DnsMap) {
+    let mut env_vars = BTreeMap::new();
+    for visible_app in visible_apps(app, &result.metadata.has_permissions) {
+        for (service, entry) in dns.get(visible_app).into_iter().flatten() {
+            let prefix = env_var_prefix(visible_app, service);
+            env_vars.insert(format!("{}_HOST", prefix), entry.hostname.clone());
+            env_vars.insert(format!("{}_IP", prefix), entry.ip.to_string());
+            if let Some(ipv6) = entry.ipv6 {
+                env_vars.insert(format!("{}_IPV6", prefix), ipv6.to_string());
+            }
+        }
+    }
+    let own_entries = dns.get(app);
+    for (service_name, service) in result.spec.services.iter_mut() {
+        for (key, value) in &env_vars {
+            service
+                .environment
+                .entry(key.clone())
+                .or_insert_with(|| StringLike::String(value.clone()));
+        }
+        let Some(entry) = own_entries.and_then(|entries| entries.get(service_name)) else {
+            continue;
+        };
+        service.container_name = Some(entry.hostname.clone());
+        if service.network_mode.is_none() {
+            // Keeps the aliases the service has on the network
+            let network: &mut NetworkEntry = service
+                .networks
+                .get_or_insert_with(BTreeMap::new)
+                .entry(NETWORK_NAME.to_string())
+                .or_default();
+            network.ipv4_address = Some(entry.ip.to_string());
+            network.ipv6_address = entry.ipv6.map(|ipv6| ipv6.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn addresses_stay_stable() {
+        let services = BTreeMap::from([
+            ("app1".to_owned(), vec!["main".to_owned()]),
+            ("app2".to_owned(), vec!["db".to_owned(), "main".to_owned()]),
+        ]);
+        let dns = assign_addresses(&DnsMap::new(), &services, "10.21.0.0/16", None).unwrap();
+        assert_eq!(dns["app1"]["main"].hostname, "app1_main");
+        assert_eq!(dns["app1"]["main"].ip, Ipv4Addr::new(10, 21, 0, 16));
+        assert_eq!(dns["app2"]["db"].ip, Ipv4Addr::new(10, 21, 0, 17));
+        assert_eq!(dns["app2"]["main"].ip, Ipv4Addr::new(10, 21, 0, 18));
+
+        // app1 is not regenerated and keeps its entry, app2 gets a new service without moving the others
+        let services = BTreeMap::from([(
+            "app2".to_owned(),
+            vec!["cache".to_owned(), "db".to_owned(), "main".to_owned()],
+        )]);
+        let new_dns = assign_addresses(&dns, &services, "10.21.0.0/16", None).unwrap();
+        assert_eq!(new_dns["app1"], dns["app1"]);
+        assert_eq!(new_dns["app2"]["db"], dns["app2"]["db"]);
+        assert_eq!(new_dns["app2"]["main"], dns["app2"]["main"]);
+        assert_eq!(new_dns["app2"]["cache"].ip, Ipv4Addr::new(10, 21, 0, 19));
+    }
+
+    #[test]
+    fn keeps_aliases() {
+        let services = BTreeMap::from([("app1".to_owned(), vec!["main".to_owned()])]);
+        let mut dns = assign_addresses(&DnsMap::new(), &services, "10.21.0.0/16", None).unwrap();
+        let mut result = ResultYml::default();
+        result.spec.services.insert(
+            "main".to_owned(),
+            crate::composegenerator::output::types::Service {
+                networks: Some(BTreeMap::from([(
+                    NETWORK_NAME.to_owned(),
+                    NetworkEntry {
+                        aliases: vec!["postgres".to_owned()],
+                        ..Default::default()
+                    },
+                )])),
+                ..Default::default()
+            },
+        );
+        set_aliases(&mut dns, "app1", &result);
+        apply_to_result(&mut result, "app1", &dns);
+
+        assert_eq!(dns["app1"]["main"].aliases, vec!["postgres".to_owned()]);
+        let network = &result.spec.services["main"].networks.as_ref().unwrap()[NETWORK_NAME];
+        assert_eq!(network.aliases, vec!["postgres".to_owned()]);
+        assert_eq!(network.ipv4_address.as_deref(), Some("10.21.0.16"));
+    }
+
+    #[test]
+    fn full_subnet() {
+        let services = BTreeMap::from([(
+            "app1".to_owned(),
+            (0..20).map(|i| i.to_string()).collect::<Vec<_>>(),
+        )]);
+        assert!(assign_addresses(&DnsMap::new(), &services, "10.21.0.0/27", None).is_err());
+        assert!(assign_addresses(&DnsMap::new(), &services, "10.21.0.0/26", None).is_ok());
+    }
+
+    #[test]
+    fn ipv6_addresses_follow_ipv4() {
+        let services =
+            BTreeMap::from([("app1".to_owned(), vec!["db".to_owned(), "main".to_owned()])]);
+        let dns = assign_addresses(
+            &DnsMap::new(),
+            &services,
+            "10.21.0.0/16",
+            Some("fd00:21::/64"),
+        )
+        .unwrap();
+        assert_eq!(dns["app1"]["db"].ipv6, Some("fd00:21::10".parse().unwrap()));
+        assert_eq!(
+            dns["app1"]["main"].ipv6,
+            Some("fd00:21::11".parse().unwrap())
+        );
+
+        let without_ipv6 = assign_addresses(&dns, &BTreeMap::new(), "10.21.0.0/16", None).unwrap();
+        assert_eq!(without_ipv6["app1"]["main"].ip, dns["app1"]["main"].ip);
+        assert_eq!(without_ipv6["app1"]["main"].ipv6, None);
+
+        assert!(assign_addresses(
+            &DnsMap::new(),
+            &services,
+            "10.21.0.0/16",
+            Some("fd00:21::/120")
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::{collections::BTreeMap, io::Write, path::Path, sync::Mutex};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::OutputMetadata;
+
+use super::{
+    files,
+    hooks::{self, HookEvent},
+    ports::PortMapEntry,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    Generate,
+    Install,
+    Uninstall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortChange {
+    pub app: String,
+    pub container: String,
+    pub internal_port: u16,
+    /// None if the port was not assigned before
+    pub old_public_port: Option<u16>,
+    /// None if the port is no longer assigned
+    pub new_public_port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionChange {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+/// An entry in db/appmgr-events.jsonl
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    /// Seconds since epoch
+    pub timestamp: u64,
+    pub kind: EventKind,
+    /// Apps the operation targeted or whose registry entry changed
+    pub apps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports_reassigned: Vec<PortChange>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub permissions_changed: BTreeMap<String, PermissionChange>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    /// The snapshot host tooling took before the operation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_id: Option<String>,
+}
+
+static SNAPSHOT_ID: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets the snapshot that is recorded with the events of this run
+pub fn set_snapshot_id(snapshot_id: Option<String>) {
+    *SNAPSHOT_ID.lock().unwrap_or_else(|err| err.into_inner()) = snapshot_id;
+}
+
+/// The parts of the state that are compared before and after an operation
+#[derive(Debug, Clone, Default)]
+pub struct StateSnapshot {
+    registry: Vec<OutputMetadata>,
+    ports: Vec<PortMapEntry>,
+    installed_apps: Vec<String>,
+}
+
+impl StateSnapshot {
+    pub fn take(nirvati_dir: &Path) -> Self {
+        Self {
+            registry: files::get_app_registry(nirvati_dir).unwrap_or_default(),
+            ports: files::get_port_map(nirvati_dir).unwrap_or_default(),
+            installed_apps: files::get_installed_apps(nirvati_dir).unwrap_or_default(),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+pub(crate) fn diff_ports(before: &[PortMapEntry], after: &[PortMapEntry]) -> Vec<PortChange> {
+    let key = |entry: &PortMapEntry| {
+        (
+            entry.app.clone(),
+            entry.container.clone(),
+            entry.internal_port,
+        )
+    };
+    let before = BTreeMap::from_iter(before.iter().map(|entry| (key(entry), entry.public_port)));
+    let after = BTreeMap::from_iter(after.iter().map(|entry| (key(entry), entry.public_port)));
+    let mut keys = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|key| {
+            let old_public_port = before.get(key).copied();
+            let new_public_port = after.get(key).copied();
+            if old_public_port == new_public_port {
+                return None;
+            }
+            Some(PortChange {
+                app: key.0.clone(),
+                container: key.1.clone(),
+                internal_port: key.2,
+                old_public_port,
+                new_public_port,
+            })
+        })
+        .collect()
+}
+
+fn diff_permissions(
+    before: &[OutputMetadata],
+    after: &[OutputMetadata],
+) -> BTreeMap<String, PermissionChange> {
+    let mut changes = BTreeMap::new();
+    for new_entry in after {
+        let old_permissions = before
+            .iter()
+            .find(|entry| entry.id == new_entry.id)
+            .map(|entry| entry.has_permissions.clone())
+            .unwrap_or_default();
+        let change = PermissionChange {
+            added: new_entry
+                .has_permissions
+                .iter()
+                .filter(|perm| !old_permissions.contains(perm))
+                .cloned()
+                .collect(),
+            removed: old_permissions
+                .iter()
+                .filter(|perm| !new_entry.has_permissions.contains(perm))
+                .cloned()
+                .collect(),
+        };
+        if !change.added.is_empty() || !change.removed.is_empty() {
+            changes.insert(new_entry.id.clone(), change);
+        }
+    }
+    changes
+}
+
+impl Event {
+    /// Builds an event by comparing the state before and after an operation
+    pub fn from_snapshots(
+        kind: EventKind,
+        apps: &[String],
+        before: &StateSnapshot,
+        after: &StateSnapshot,
+        error: Option<&anyhow::Error>,
+    ) -> Self {
+        let mut touched_apps = apps.to_vec();
+        for new_entry in &after.registry {
+            let old_entry = before
+                .registry
+                .iter()
+                .find(|entry| entry.id == new_entry.id);
+            if old_entry != Some(new_entry) && !touched_apps.contains(&new_entry.id) {
+                touched_apps.push(new_entry.id.clone());
+            }
+        }
+        Event {
+            timestamp: now(),
+            kind,
+            apps: touched_apps,
+            ports_reassigned: diff_ports(&before.ports, &after.ports),
+            permissions_changed: diff_permissions(&before.registry, &after.registry),
+            errors: error
+                .map(|err| vec![format!("{:#}", err)])
+                .unwrap_or_default(),
+            snapshot_id: SNAPSHOT_ID
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone(),
+        }
+    }
+}
+
+pub fn append_event(nirvati_dir: &Path, event: &Event) -> Result<()> {
+    let events_path = nirvati_dir.join("db").join("appmgr-events.jsonl");
+    let mut events_file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(events_path)?;
+    writeln!(events_file, "{}", serde_json::to_string(event)?)?;
+    Ok(())
+}
+
+pub fn read_events(nirvati_dir: &Path) -> Result<Vec<Event>> {
+    let events_path = nirvati_dir.join("db").join("appmgr-events.jsonl");
+    if !events_path.exists() {
+        return Ok(Vec::new());
+    }
+    let events = std::fs::read_to_string(events_path)?;
+    let mut result = Vec::new();
+    for line in events.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(event) => result.push(event),
+            Err(err) => tracing::warn!("Skipping invalid event log entry: {:#}", err),
+        }
+    }
+    Ok(result)
+}
+
+/// Counts the installed and uninstalled apps and the generation in db/appmgr-stats.json
+fn update_stats(
+    nirvati_dir: &Path,
+    before: &StateSnapshot,
+    after: &StateSnapshot,
+    duration: std::time::Duration,
+    failed: bool,
+) -> Result<()> {
+    files::update_usage_stats(nirvati_dir, |stats| {
+        let count_missing = |apps: &[String], other: &[String]| {
+            apps.iter().filter(|app| !other.contains(app)).count() as u64
+        };
+        stats.installs += count_missing(&after.installed_apps, &before.installed_apps);
+        stats.uninstalls += count_missing(&before.installed_apps, &after.installed_apps);
+        stats.generations += 1;
+        stats.last_generation = Some(now());
+        stats.last_generation_duration_ms = Some(duration.as_millis() as u64);
+        stats.last_generation_failed = failed;
+    })
+}
+
+/// Runs an operation and records its effects in the event log and the usage stats
+/// Failing to write them never fails the operation itself
+pub fn record<T, F>(nirvati_dir: &Path, kind: EventKind, apps: &[String], operation: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let before = StateSnapshot::take(nirvati_dir);
+    let start = std::time::Instant::now();
+    let result = operation();
+    let duration = start.elapsed();
+    let after = StateSnapshot::take(nirvati_dir);
+    let event = Event::from_snapshots(kind, apps, &before, &after, result.as_ref().err());
+    if let Err(err) = append_event(nirvati_dir, &event) {
+        tracing::warn!("Failed to write event log: {:#}", err);
+    }
+    if let Err(err) = update_stats(nirvati_dir, &before, &after, duration, result.is_err()) {
+        tracing::warn!("Failed to write usage stats: {:#}", err);
+    }
+    if !event.permissions_changed.is_empty() {
+        hooks::notify(
+            nirvati_dir,
+            HookEvent::PermissionsChanged {
+                changes: event.permissions_changed,
+            },
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manage::ports::PortPriority, testing::TempDir};
+    use pretty_assertions::assert_eq;
+
+    fn entry(app: &str, internal_port: u16, public_port: u16) -> PortMapEntry {
+        PortMapEntry {
+            app: app.to_owned(),
+            internal_port,
+            public_port,
+            container: "main".to_owned(),
+            implements: None,
+            priority: PortPriority::Optional,
+        }
+    }
+
+    #[test]
+    fn diff_ports_reports_moved_added_and_removed_ports() {
+        let before = vec![entry("app1", 80, 81), entry("app2", 80, 82)];
+        let after = vec![entry("app1", 80, 83), entry("app3", 80, 82)];
+        assert_eq!(
+            diff_ports(&before, &after),
+            vec![
+                PortChange {
+                    app: "app1".to_owned(),
+                    container: "main".to_owned(),
+                    internal_port: 80,
+                    old_public_port: Some(81),
+                    new_public_port: Some(83),
+                },
+                PortChange {
+                    app: "app2".to_owned(),
+                    container: "main".to_owned(),
+                    internal_port: 80,
+                    old_public_port: Some(82),
+                    new_public_port: None,
+                },
+                PortChange {
+                    app: "app3".to_owned(),
+                    container: "main".to_owned(),
+                    internal_port: 80,
+                    old_public_port: None,
+                    new_public_port: Some(82),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn records_installs_and_failures_in_the_usage_stats() {
+        let nirvati_dir = TempDir::new("usage-stats");
+        std::fs::create_dir_all(nirvati_dir.join("db")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("db").join("user.json"),
+            r#"{"name":"Test","password":"test","installedApps":["notes"]}"#,
+        )
+        .unwrap();
+        let apps = ["bitcoin".to_owned()];
+
+        record(&nirvati_dir, EventKind::Install, &apps, || {
+            files::add_installed_app("bitcoin", &nirvati_dir)
+        })
+        .unwrap();
+        let stats = files::get_usage_stats(&nirvati_dir).unwrap();
+        assert_eq!(
+            (stats.installs, stats.uninstalls, stats.generations),
+            (1, 0, 1)
+        );
+        assert!(stats.last_generation.is_some());
+        assert!(!stats.last_generation_failed);
+
+        // Failed operations still count what they changed
+        let result: Result<()> = record(&nirvati_dir, EventKind::Uninstall, &apps, || {
+            files::remove_installed_app("bitcoin", &nirvati_dir)?;
+            files::remove_installed_app("notes", &nirvati_dir)?;
+            anyhow::bail!("Failed to generate")
+        });
+        assert!(result.is_err());
+        let stats = files::get_usage_stats(&nirvati_dir).unwrap();
+        assert_eq!(
+            (stats.installs, stats.uninstalls, stats.generations),
+            (1, 2, 2)
+        );
+        assert!(stats.last_generation_failed);
+    }
+}
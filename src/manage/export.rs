@@ -0,0 +1,368 @@
+//! Exports an app as a standalone docker compose project, to run it outside Nirvati or debug it in isolation
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    composegenerator::output::types::ComposeSpecification,
+    config::Config,
+    utils::{find_env_vars, unescape_env_value, StringLike},
+};
+
+use super::{dirs::owner_from_user, validate};
+
+/// Env vars whose name contains one of these are treated as secrets
+const SECRET_MARKERS: [&str; 7] = [
+    "PASS",
+    "SECRET",
+    "TOKEN",
+    "KEY",
+    "SEED",
+    "MACAROON",
+    "CREDENTIAL",
+];
+
+const DATA_DIR_VAR: &str = "${APP_DATA_DIR}/";
+
+/// What export wrote, printed as JSON
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSummary {
+    pub files: Vec<PathBuf>,
+    /// Secrets in .env that were left empty
+    pub redacted: Vec<String>,
+    /// Env vars the host sets on Nirvati, which are empty in .env
+    pub unset: Vec<String>,
+    /// Config templates the host hasn't rendered yet
+    pub missing_config_files: Vec<String>,
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+fn env_value(value: &StringLike) -> String {
+    match value {
+        StringLike::String(value) => value.clone(),
+        StringLike::Int(value) => value.to_string(),
+        StringLike::Bool(value) => value.to_string(),
+        StringLike::Float(value) => value.to_string(),
+    }
+}
+
+/// Secrets shorter than this aren't replaced, they could be part of unrelated values
+const MIN_RENDERED_SECRET_LEN: usize = 8;
+
+/// Adds a secret to the vars of .env and returns the var for it, with a suffix if another secret has the name
+fn add_secret(secrets: &mut BTreeMap<String, String>, name: &str, secret: &str) -> String {
+    let mut var = name.to_owned();
+    let mut suffix = 1;
+    while secrets.get(&var).is_some_and(|value| value != secret) {
+        suffix += 1;
+        var = format!("{}_{}", name, suffix);
+    }
+    secrets.insert(var.clone(), secret.to_owned());
+    var
+}
+
+/// Replaces rendered secrets, like the output of derive_entropy, in all strings of the spec with references to .env
+fn replace_rendered(
+    value: &mut serde_yaml::Value,
+    rendered: &BTreeMap<String, String>,
+    secrets: &mut BTreeMap<String, String>,
+) {
+    match value {
+        serde_yaml::Value::String(string) => {
+            for (secret, name) in rendered {
+                if secret.len() >= MIN_RENDERED_SECRET_LEN && string.contains(secret.as_str()) {
+                    let var = add_secret(secrets, name, secret);
+                    *string = string.replace(secret.as_str(), &format!("${{{}}}", var));
+                }
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for value in sequence {
+                replace_rendered(value, rendered, secrets);
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for value in mapping.values_mut() {
+                replace_rendered(value, rendered, secrets);
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => replace_rendered(&mut tagged.value, rendered, secrets),
+        _ => {}
+    }
+}
+
+/// Replaces the remaining env values of services that look like secrets with references to .env
+fn move_env_secrets(spec: &mut ComposeSpecification, secrets: &mut BTreeMap<String, String>) {
+    for service in spec.services.values_mut() {
+        for (key, value) in service.environment.iter_mut() {
+            let string = env_value(value);
+            if !is_secret(key) || !find_env_vars(&string).is_empty() {
+                continue;
+            }
+            // .env values are quoted, so compose doesn't unescape $$ in them
+            let var = add_secret(secrets, key, &unescape_env_value(&string));
+            *value = StringLike::String(format!("${{{}}}", var));
+        }
+    }
+}
+
+/// Quotes a value for .env if compose would otherwise change it
+fn dotenv_value(value: &str) -> String {
+    if value
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || "-_./:@+,".contains(char))
+    {
+        value.to_owned()
+    } else {
+        format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+}
+
+/// The dirs services mount from the data dir, with the owner of the first service by name that mounts them
+fn data_dirs(spec: &ComposeSpecification) -> BTreeMap<String, (u32, u32)> {
+    let mut dirs = BTreeMap::new();
+    for service in spec.services.values() {
+        for volume in &service.volumes {
+            let Some(host_path) = volume
+                .strip_prefix(DATA_DIR_VAR)
+                .and_then(|rest| rest.split(':').next())
+            else {
+                continue;
+            };
+            dirs.entry(host_path.to_owned())
+                .or_insert_with(|| owner_from_user(service.user.as_deref()));
+        }
+    }
+    dirs
+}
+
+/// The outputs of config templates in the app's dir, and the templates that haven't been rendered
+fn config_files(app_dir: &Path) -> Result<(Vec<String>, Vec<String>)> {
+    let mut rendered = Vec::new();
+    let mut missing = Vec::new();
+    for entry in std::fs::read_dir(app_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(output) = name.strip_suffix(".jinja") else {
+            continue;
+        };
+        if matches!(output, "app.yml" | "metadata.yml") || !entry.path().is_file() {
+            continue;
+        }
+        if app_dir.join(output).is_file() {
+            rendered.push(output.to_owned());
+        } else {
+            missing.push(output.to_owned());
+        }
+    }
+    rendered.sort();
+    missing.sort();
+    Ok((rendered, missing))
+}
+
+fn write_file(out_dir: &Path, name: &str, contents: &str) -> Result<PathBuf> {
+    let path = out_dir.join(name);
+    std::fs::write(&path, contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+pub fn export_app(
+    nirvati_dir: &Path,
+    app: &str,
+    config: &Config,
+    out_dir: &Path,
+    include_secrets: bool,
+) -> Result<ExportSummary> {
+    let result = validate::preview_app(nirvati_dir, app, config, Default::default())?;
+    let mut spec = result.spec;
+    // The addresses are from the subnet of the shared Nirvati network, the project gets its own network
+    for service in spec.services.values_mut() {
+        for network in service
+            .networks
+            .iter_mut()
+            .flat_map(|networks| networks.values_mut())
+        {
+            network.ipv4_address = None;
+            network.ipv6_address = None;
+        }
+    }
+    let mut secrets = BTreeMap::new();
+    let mut spec_value = serde_yaml::to_value(&spec)?;
+    replace_rendered(&mut spec_value, &super::secrets::rendered(), &mut secrets);
+    let mut spec: ComposeSpecification = serde_yaml::from_value(spec_value)?;
+    move_env_secrets(&mut spec, &mut secrets);
+    let compose = super::canonical::to_yaml(&spec)?;
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+    let mut summary = ExportSummary::default();
+    summary
+        .files
+        .push(write_file(out_dir, "docker-compose.yml", &compose)?);
+
+    let mut env = format!(
+        "# {} {}, exported by app-manager\nAPP_DATA_DIR=./data\n",
+        result.metadata.name, result.metadata.version
+    );
+    if !secrets.is_empty() {
+        if include_secrets {
+            env.push_str("\n# Secrets\n");
+        } else {
+            env.push_str("\n# Secrets, redacted. Set them or export with --include-secrets\n");
+        }
+    }
+    for (var, value) in &secrets {
+        if include_secrets {
+            let _ = writeln!(env, "{}={}", var, dotenv_value(value));
+        } else {
+            let _ = writeln!(env, "{}=", var);
+            summary.redacted.push(var.clone());
+        }
+    }
+    summary.unset = find_env_vars(&compose)
+        .into_iter()
+        .filter(|var| *var != "APP_DATA_DIR" && !secrets.contains_key(*var))
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if !summary.unset.is_empty() {
+        env.push_str("\n# Set by the host on Nirvati\n");
+        for var in &summary.unset {
+            let _ = writeln!(env, "{}=", var);
+        }
+    }
+    summary.files.push(write_file(out_dir, ".env", &env)?);
+
+    let app_dir = nirvati_dir.join("apps").join(app);
+    let (config_files, missing_config_files) = config_files(&app_dir)?;
+    for name in &config_files {
+        let path = out_dir.join(name);
+        std::fs::copy(app_dir.join(name), &path)
+            .with_context(|| format!("Failed to copy {}", name))?;
+        summary.files.push(path);
+    }
+    summary.missing_config_files = missing_config_files;
+
+    let data_dirs = data_dirs(&spec);
+    for dir in data_dirs.keys() {
+        std::fs::create_dir_all(out_dir.join("data").join(dir))?;
+    }
+    let mut readme = format!(
+        "# {} {}\n\nExported from Nirvati with `app-manager export`. Fill in the empty values in `.env`, then start the app with `docker compose up -d` in this dir.\n",
+        result.metadata.name, result.metadata.version
+    );
+    if !data_dirs.is_empty() {
+        readme.push_str("\n## Required directories\n\nThe containers expect these dirs with this owner, run `chown` on them before starting the app:\n\n| Path | Owner | Mode |\n| --- | --- | --- |\n");
+        for (dir, (uid, gid)) in &data_dirs {
+            let _ = writeln!(
+                readme,
+                "| data/{} | {}:{} | {} |",
+                dir,
+                uid,
+                gid,
+                super::dirs::DEFAULT_MODE
+            );
+        }
+    }
+    if !config_files.is_empty() || !summary.missing_config_files.is_empty() {
+        readme.push_str("\n## Config files\n\n");
+        for name in &config_files {
+            let _ = writeln!(readme, "- `{}`", name);
+        }
+        for name in &summary.missing_config_files {
+            let _ = writeln!(
+                readme,
+                "- `{}` is missing, it is rendered by the host after installing the app",
+                name
+            );
+        }
+    }
+    summary
+        .files
+        .push(write_file(out_dir, "README.md", &readme)?);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composegenerator::{output::types::Service, types::Command};
+
+    #[test]
+    fn moves_secrets_to_env_file() {
+        let mut spec = ComposeSpecification::default();
+        for (name, password) in [("main", "a"), ("db", "b")] {
+            spec.services.insert(
+                name.to_owned(),
+                Service {
+                    command: Some(Command::SimpleCmd(
+                        "server --password 0123456789abcdef".to_owned(),
+                    )),
+                    environment: BTreeMap::from([
+                        (
+                            "DB_PASSWORD".to_owned(),
+                            StringLike::String(password.to_owned()),
+                        ),
+                        (
+                            "RPC_PASS".to_owned(),
+                            StringLike::String("${APP_BITCOIN_RPC_PASS}".to_owned()),
+                        ),
+                        ("PORT".to_owned(), StringLike::Int(80)),
+                        (
+                            "ADMIN_HTPASSWD".to_owned(),
+                            StringLike::String("admin:$$2y$$05$$abc".to_owned()),
+                        ),
+                    ]),
+                    ..Default::default()
+                },
+            );
+        }
+        let rendered = BTreeMap::from([
+            ("0123456789abcdef".to_owned(), "PASSWORD".to_owned()),
+            ("short".to_owned(), "SHORT".to_owned()),
+        ]);
+        let mut secrets = BTreeMap::new();
+        let mut spec_value = serde_yaml::to_value(&spec).unwrap();
+        replace_rendered(&mut spec_value, &rendered, &mut secrets);
+        let mut spec: ComposeSpecification = serde_yaml::from_value(spec_value).unwrap();
+        move_env_secrets(&mut spec, &mut secrets);
+        assert_eq!(
+            secrets.keys().collect::<Vec<_>>(),
+            vec!["ADMIN_HTPASSWD", "DB_PASSWORD", "DB_PASSWORD_2", "PASSWORD"]
+        );
+        // Compose would have passed the hash with single $ to the container
+        assert_eq!(secrets["ADMIN_HTPASSWD"], "admin:$2y$05$abc");
+        let main = &spec.services["main"];
+        assert_eq!(
+            main.command,
+            Some(Command::SimpleCmd(
+                "server --password ${PASSWORD}".to_owned()
+            ))
+        );
+        assert_eq!(
+            main.environment["DB_PASSWORD"],
+            StringLike::String("${DB_PASSWORD_2}".to_owned())
+        );
+        assert_eq!(
+            main.environment["RPC_PASS"],
+            StringLike::String("${APP_BITCOIN_RPC_PASS}".to_owned())
+        );
+        assert_eq!(main.environment["PORT"], StringLike::Int(80));
+
+        assert_eq!(dotenv_value("abc-1.2"), "abc-1.2");
+        assert_eq!(dotenv_value("a b'$c"), "'a b\\'$c'");
+    }
+}
@@ -1,15 +1,45 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
 use anyhow::{anyhow, Result};
 use cached::proc_macro::once;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Map;
 
-use crate::composegenerator::types::{AppYml, MetadataYml, OutputMetadata};
+use crate::{
+    composegenerator::{
+        schema::SchemaType,
+        types::{AppYml, Diagnostic, DiagnosticCode, MetadataYml, OutputMetadata, Task},
+    },
+    dependencies::{resolve_dependencies, ReverseIndex},
+    repos::{Origins, Sources, StoreIds, StoreSource, StoreSummary},
+};
 
-use super::ports::PortMapEntry;
+use super::{
+    canonical,
+    categories::CategoryCount,
+    changelog::Changelog,
+    compat::NewerSchemaVersion,
+    dirs::DataDir,
+    dns::DnsMap,
+    firewall::FirewallRule,
+    hooks::HooksConfig,
+    metrics::ScrapeTarget,
+    ports::PortMapEntry,
+    quota::QuotaEntry,
+    search::SearchIndex,
+    shutdown::ShutdownEntry,
+    snapshots::SnapshotEntry,
+    traffic::TrafficEntry,
+    updates::Updates,
+    upnp::{ForwardingStatus, UpnpEntry},
+    yaml,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum SimpleValue {
     String(String),
@@ -29,6 +59,40 @@ pub struct UserJson {
     #[serde(rename = "nextAppRegen", default)]
     // The time app config files need to be regenerated, in seconds since epoch
     next_app_regen: u64,
+    /// Extra env vars for every service of an app, set by the user
+    #[serde(rename = "userEnv", default)]
+    user_env: HashMap<String, BTreeMap<String, String>>,
+    /// The version every installed app was at when it was installed
+    #[serde(rename = "installedVersions", default)]
+    installed_versions: BTreeMap<String, String>,
+    /// The storage pool every app that isn't in the default pool is in
+    #[serde(rename = "storagePools", default)]
+    storage_pools: HashMap<String, String>,
+    /// Disk quotas of apps, like 50G
+    #[serde(rename = "diskQuotas", default)]
+    disk_quotas: HashMap<String, String>,
+    /// Apps the user took down for maintenance
+    #[serde(rename = "maintenanceApps", default)]
+    maintenance_apps: Vec<String>,
+    /// Installed apps the user disabled, they are not run but keep their settings, data and ports
+    #[serde(rename = "disabledApps", default)]
+    disabled_apps: Vec<String>,
+}
+
+/// Local counters for the dashboard in db/appmgr-stats.json, they are never sent anywhere
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct UsageStats {
+    pub installs: u64,
+    pub uninstalls: u64,
+    /// Number of operations that generated apps, including installs and uninstalls
+    pub generations: u64,
+    /// When the last generation finished, in seconds since epoch
+    pub last_generation: Option<u64>,
+    pub last_generation_duration_ms: Option<u64>,
+    pub last_generation_failed: bool,
+    /// When check-updates last ran, in seconds since epoch
+    pub last_update_check: Option<u64>,
 }
 
 /// Read the app registry
@@ -39,10 +103,12 @@ pub fn get_app_registry(nirvati_dir: &Path) -> Result<Vec<OutputMetadata>> {
     Ok(app_registry)
 }
 
+/// Writes the registry sorted by app id
 pub fn write_app_registry(nirvati_dir: &Path, app_registry: &[OutputMetadata]) -> Result<()> {
     let app_registry_path = nirvati_dir.join("apps").join("registry.json");
-    let app_registry = serde_json::to_string_pretty(app_registry)?;
-    std::fs::write(app_registry_path, app_registry)?;
+    let mut app_registry = app_registry.iter().collect::<Vec<_>>();
+    app_registry.sort_by(|a, b| a.id.cmp(&b.id));
+    canonical::write_json(&app_registry_path, &app_registry)?;
     Ok(())
 }
 
@@ -66,6 +132,12 @@ pub fn get_user_json_default(nirvati_dir: &Path) -> Result<UserJson> {
             https: None,
             app_settings: HashMap::new(),
             next_app_regen: 0,
+            user_env: HashMap::new(),
+            installed_versions: BTreeMap::new(),
+            storage_pools: HashMap::new(),
+            disk_quotas: HashMap::new(),
+            maintenance_apps: Vec::new(),
+            disabled_apps: Vec::new(),
         };
         return Ok(user_json);
     }
@@ -87,6 +159,79 @@ pub fn get_app_settings(
     Ok(user_json.app_settings.get(app_id).cloned())
 }
 
+pub fn get_user_env(nirvati_dir: &Path, app_id: &str) -> Result<BTreeMap<String, String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.user_env.get(app_id).cloned().unwrap_or_default())
+}
+
+pub fn get_storage_pool(nirvati_dir: &Path, app_id: &str) -> Result<Option<String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.storage_pools.get(app_id).cloned())
+}
+
+pub fn get_disk_quotas(nirvati_dir: &Path) -> Result<HashMap<String, String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.disk_quotas)
+}
+
+pub fn get_maintenance_apps(nirvati_dir: &Path) -> Result<Vec<String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.maintenance_apps)
+}
+
+pub fn set_maintenance(app_id: &str, in_maintenance: bool, nirvati_dir: &Path) -> Result<()> {
+    set_listed("maintenanceApps", app_id, in_maintenance, nirvati_dir)
+}
+
+pub fn get_disabled_apps(nirvati_dir: &Path) -> Result<Vec<String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.disabled_apps)
+}
+
+pub fn set_disabled(app_id: &str, disabled: bool, nirvati_dir: &Path) -> Result<()> {
+    set_listed("disabledApps", app_id, disabled, nirvati_dir)
+}
+
+/// Installed apps that are not disabled, which host scripts should run
+pub fn get_apps_to_run(nirvati_dir: &Path) -> Result<Vec<String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json
+        .installed_apps
+        .into_iter()
+        .filter(|app| !user_json.disabled_apps.contains(app))
+        .collect())
+}
+
+/// Writes apps/run.yml, the apps host scripts should start, so they don't have to start every app that has outputs
+pub fn save_apps_to_run(nirvati_dir: &Path, apps: &[String]) -> Result<()> {
+    let run_yml_path = nirvati_dir.join("apps").join("run.yml");
+    canonical::write_yaml(&run_yml_path, apps)?;
+    Ok(())
+}
+
+/// Adds an app to or removes it from a list of app ids in user.json, creating the list if it doesn't exist
+fn set_listed(key: &str, app_id: &str, listed: bool, nirvati_dir: &Path) -> Result<()> {
+    // Serialize the user.json as serde_json::Value to avoid accidentally deleting fields
+    let user_json_path = nirvati_dir.join("db").join("user.json");
+    let user_json = std::fs::read_to_string(&user_json_path)?;
+    let mut user_json: serde_json::Value = serde_json::from_str(&user_json)?;
+    let app_list = user_json
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("user.json is not an object"))?
+        .entry(key)
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("{} is not an array", key))?;
+    let app = serde_json::Value::String(app_id.to_string());
+    if listed && !app_list.contains(&app) {
+        app_list.push(app);
+    } else if !listed {
+        app_list.retain(|elem| elem != &app);
+    }
+    canonical::write_json(&user_json_path, &user_json)?;
+    Ok(())
+}
+
 pub fn add_installed_app(app_id: &str, nirvati_dir: &Path) -> Result<()> {
     // Serialize the user.json as serde_json::Value to avoid accidentally deleting fields
     let user_json_path = nirvati_dir.join("db").join("user.json");
@@ -102,8 +247,7 @@ pub fn add_installed_app(app_id: &str, nirvati_dir: &Path) -> Result<()> {
     if !app_list.contains(&serde_json::Value::String(app_id.to_string())) {
         app_list.push(serde_json::Value::String(app_id.to_string()));
     }
-    let user_json = serde_json::to_string_pretty(&user_json)?;
-    std::fs::write(user_json_path, user_json)?;
+    canonical::write_json(&user_json_path, &user_json)?;
     Ok(())
 }
 
@@ -133,11 +277,111 @@ pub fn remove_installed_app(app_id: &str, nirvati_dir: &Path) -> Result<()> {
     if let Some(index) = index {
         installed_apps.remove(index);
     }
-    let user_json = serde_json::to_string_pretty(&user_json)?;
-    std::fs::write(user_json_path, user_json)?;
+    canonical::write_json(&user_json_path, &user_json)?;
+    Ok(())
+}
+
+/// Replaces an app in installedApps and moves its settings, for apps that were renamed
+pub fn rename_installed_app(old_id: &str, new_id: &str, nirvati_dir: &Path) -> Result<()> {
+    // Serialize the user.json as serde_json::Value to avoid accidentally deleting fields
+    let user_json_path = nirvati_dir.join("db").join("user.json");
+    let user_json = std::fs::read_to_string(&user_json_path)?;
+    let mut user_json: serde_json::Value = serde_json::from_str(&user_json)?;
+    let user_json_obj = user_json
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("user.json is not an object"))?;
+    user_json_obj
+        .get("installedApps")
+        .ok_or_else(|| anyhow!("user.json does not contain installedApps"))?
+        .as_array()
+        .ok_or_else(|| anyhow!("installedApps is not an array"))?;
+    // Lists of app ids keep the app under its new id
+    for key in ["installedApps", "maintenanceApps", "disabledApps"] {
+        if let Some(apps) = user_json_obj
+            .get_mut(key)
+            .and_then(|apps| apps.as_array_mut())
+        {
+            for app in apps.iter_mut() {
+                if app.as_str() == Some(old_id) {
+                    *app = serde_json::Value::String(new_id.to_string());
+                }
+            }
+        }
+    }
+    // Per-app values move to the new id
+    for key in [
+        "appSettings",
+        "userEnv",
+        "installedVersions",
+        "storagePools",
+        "diskQuotas",
+    ] {
+        if let Some(values) = user_json_obj
+            .get_mut(key)
+            .and_then(|values| values.as_object_mut())
+        {
+            if let Some(value) = values.remove(old_id) {
+                values.insert(new_id.to_string(), value);
+            }
+        }
+    }
+    canonical::write_json(&user_json_path, &user_json)?;
     Ok(())
 }
 
+pub fn get_installed_versions(nirvati_dir: &Path) -> Result<BTreeMap<String, String>> {
+    let user_json = get_user_json_default(nirvati_dir)?;
+    Ok(user_json.installed_versions)
+}
+
+pub fn save_installed_versions(
+    nirvati_dir: &Path,
+    installed_versions: &BTreeMap<String, String>,
+) -> Result<()> {
+    // Serialize the user.json as serde_json::Value to avoid accidentally deleting fields
+    let user_json_path = nirvati_dir.join("db").join("user.json");
+    if !user_json_path.exists() {
+        return Ok(());
+    }
+    let user_json = std::fs::read_to_string(&user_json_path)?;
+    let mut user_json: serde_json::Value = serde_json::from_str(&user_json)?;
+    user_json
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("user.json is not an object"))?
+        .insert(
+            "installedVersions".to_string(),
+            serde_json::to_value(installed_versions)?,
+        );
+    canonical::write_json(&user_json_path, &user_json)?;
+    Ok(())
+}
+
+pub fn get_usage_stats(nirvati_dir: &Path) -> Result<UsageStats> {
+    let stats_json_path = nirvati_dir.join("db").join("appmgr-stats.json");
+    if stats_json_path.exists() {
+        let stats_json = std::fs::read_to_string(stats_json_path)?;
+        Ok(serde_json::from_str(&stats_json)?)
+    } else {
+        Ok(UsageStats::default())
+    }
+}
+
+pub fn save_usage_stats(nirvati_dir: &Path, stats: &UsageStats) -> Result<()> {
+    let stats_json_path = nirvati_dir.join("db").join("appmgr-stats.json");
+    canonical::write_json(&stats_json_path, stats)?;
+    Ok(())
+}
+
+/// Reads db/appmgr-stats.json, applies a change and writes it back
+pub fn update_usage_stats<F>(nirvati_dir: &Path, update: F) -> Result<()>
+where
+    F: FnOnce(&mut UsageStats),
+{
+    let mut stats = get_usage_stats(nirvati_dir)?;
+    update(&mut stats);
+    save_usage_stats(nirvati_dir, &stats)
+}
+
 pub fn get_next_app_regenerate(nirvati_dir: &Path) -> Result<u64> {
     let user_json = get_user_json_default(nirvati_dir)?;
     Ok(user_json.next_app_regen)
@@ -154,8 +398,7 @@ pub fn set_next_app_regenerate(nirvati_dir: &Path, time: u64) -> Result<()> {
         .get_mut("nextAppRegen")
         .ok_or_else(|| anyhow!("user.json does not contain nextAppRegen"))?;
     *next_app_regen = serde_json::Value::Number(serde_json::Number::from(time));
-    let user_json = serde_json::to_string_pretty(&user_json)?;
-    std::fs::write(user_json_path, user_json)?;
+    canonical::write_json(&user_json_path, &user_json)?;
     Ok(())
 }
 
@@ -211,8 +454,7 @@ pub fn save_app_settings(
                     .collect::<Result<Map<String, serde_json::Value>>>()?,
             ),
         );
-    let user_json = serde_json::to_string_pretty(&user_json)?;
-    std::fs::write(user_json_path, user_json)?;
+    canonical::write_json(&user_json_path, &user_json)?;
     Ok(())
 }
 
@@ -229,8 +471,10 @@ pub fn get_available_permissions(nirvati_dir: &Path) -> Result<Vec<String>> {
 
 pub fn save_permissions(nirvati_dir: &Path, permissions: Vec<String>) -> Result<()> {
     let permissions_json_path = nirvati_dir.join("apps").join("permissions.json");
-    let permissions_json = serde_json::to_string(&permissions)?;
-    std::fs::write(permissions_json_path, permissions_json)?;
+    std::fs::write(
+        permissions_json_path,
+        canonical::to_compact_json(&permissions)?,
+    )?;
     Ok(())
 }
 
@@ -245,36 +489,419 @@ pub fn get_port_map(nirvati_dir: &Path) -> Result<Vec<PortMapEntry>> {
     }
 }
 
-pub fn save_port_map(nirvati_dir: &Path, port_map: Vec<PortMapEntry>) -> Result<()> {
+pub fn save_port_map(nirvati_dir: &Path, mut port_map: Vec<PortMapEntry>) -> Result<()> {
     let port_map_yml_path = nirvati_dir.join("apps").join("ports.yml");
-    let port_map_yml = serde_yaml::to_string(&port_map)?;
-    std::fs::write(port_map_yml_path, port_map_yml)?;
+    port_map.sort_by(|a, b| {
+        (&a.app, &a.container, a.internal_port, a.public_port).cmp(&(
+            &b.app,
+            &b.container,
+            b.internal_port,
+            b.public_port,
+        ))
+    });
+    canonical::write_yaml(&port_map_yml_path, &port_map)?;
+    Ok(())
+}
+
+pub fn get_dns_map(nirvati_dir: &Path) -> Result<DnsMap> {
+    let dns_yml_path = nirvati_dir.join("apps").join("dns.yml");
+    if dns_yml_path.exists() {
+        let dns_yml = std::fs::read_to_string(dns_yml_path)?;
+        Ok(serde_yaml::from_str(&dns_yml)?)
+    } else {
+        Ok(DnsMap::new())
+    }
+}
+
+pub fn save_dns_map(nirvati_dir: &Path, dns: &DnsMap) -> Result<()> {
+    let dns_yml_path = nirvati_dir.join("apps").join("dns.yml");
+    canonical::write_yaml(&dns_yml_path, dns)?;
+    Ok(())
+}
+
+pub fn get_scrape_targets(nirvati_dir: &Path) -> Result<Vec<ScrapeTarget>> {
+    let scrape_yml_path = nirvati_dir.join("apps").join("prometheus-scrape.yml");
+    if scrape_yml_path.exists() {
+        let scrape_yml = std::fs::read_to_string(scrape_yml_path)?;
+        Ok(serde_yaml::from_str(&scrape_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_scrape_targets(nirvati_dir: &Path, targets: &[ScrapeTarget]) -> Result<()> {
+    let scrape_yml_path = nirvati_dir.join("apps").join("prometheus-scrape.yml");
+    canonical::write_yaml(&scrape_yml_path, targets)?;
+    Ok(())
+}
+
+pub fn get_firewall_rules(nirvati_dir: &Path) -> Result<Vec<FirewallRule>> {
+    let firewall_yml_path = nirvati_dir.join("apps").join("firewall.yml");
+    if firewall_yml_path.exists() {
+        let firewall_yml = std::fs::read_to_string(firewall_yml_path)?;
+        Ok(serde_yaml::from_str(&firewall_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_firewall_rules(nirvati_dir: &Path, rules: &[FirewallRule]) -> Result<()> {
+    let firewall_yml_path = nirvati_dir.join("apps").join("firewall.yml");
+    canonical::write_yaml(&firewall_yml_path, rules)?;
+    Ok(())
+}
+
+pub fn get_upnp_entries(nirvati_dir: &Path) -> Result<Vec<UpnpEntry>> {
+    let upnp_yml_path = nirvati_dir.join("apps").join("upnp.yml");
+    if upnp_yml_path.exists() {
+        let upnp_yml = std::fs::read_to_string(upnp_yml_path)?;
+        Ok(serde_yaml::from_str(&upnp_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_upnp_entries(nirvati_dir: &Path, entries: &[UpnpEntry]) -> Result<()> {
+    let upnp_yml_path = nirvati_dir.join("apps").join("upnp.yml");
+    canonical::write_yaml(&upnp_yml_path, entries)?;
+    Ok(())
+}
+
+pub fn get_shutdown_entries(nirvati_dir: &Path) -> Result<Vec<ShutdownEntry>> {
+    let shutdown_yml_path = nirvati_dir.join("apps").join("shutdown.yml");
+    if shutdown_yml_path.exists() {
+        let shutdown_yml = std::fs::read_to_string(shutdown_yml_path)?;
+        Ok(serde_yaml::from_str(&shutdown_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_shutdown_entries(nirvati_dir: &Path, entries: &[ShutdownEntry]) -> Result<()> {
+    let shutdown_yml_path = nirvati_dir.join("apps").join("shutdown.yml");
+    canonical::write_yaml(&shutdown_yml_path, entries)?;
+    Ok(())
+}
+
+/// Reads the forwarding status the host wrote, which is empty if it hasn't forwarded anything yet
+pub fn get_upnp_status(nirvati_dir: &Path) -> Result<Vec<ForwardingStatus>> {
+    let status_yml_path = nirvati_dir.join("apps").join("upnp-status.yml");
+    if status_yml_path.exists() {
+        let status_yml = std::fs::read_to_string(status_yml_path)?;
+        yaml::from_str("upnp-status.yml", &status_yml)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn get_traffic_entries(nirvati_dir: &Path) -> Result<Vec<TrafficEntry>> {
+    let traffic_yml_path = nirvati_dir.join("apps").join("traffic.yml");
+    if traffic_yml_path.exists() {
+        let traffic_yml = std::fs::read_to_string(traffic_yml_path)?;
+        Ok(serde_yaml::from_str(&traffic_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_traffic_entries(nirvati_dir: &Path, entries: &[TrafficEntry]) -> Result<()> {
+    let traffic_yml_path = nirvati_dir.join("apps").join("traffic.yml");
+    canonical::write_yaml(&traffic_yml_path, entries)?;
+    Ok(())
+}
+
+pub fn get_quota_entries(nirvati_dir: &Path) -> Result<Vec<QuotaEntry>> {
+    let quotas_yml_path = nirvati_dir.join("apps").join("quotas.yml");
+    if quotas_yml_path.exists() {
+        let quotas_yml = std::fs::read_to_string(quotas_yml_path)?;
+        Ok(serde_yaml::from_str(&quotas_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_quota_entries(nirvati_dir: &Path, entries: &[QuotaEntry]) -> Result<()> {
+    let quotas_yml_path = nirvati_dir.join("apps").join("quotas.yml");
+    canonical::write_yaml(&quotas_yml_path, entries)?;
+    Ok(())
+}
+
+pub fn save_snapshot_entries(nirvati_dir: &Path, entries: &[SnapshotEntry]) -> Result<()> {
+    let snapshots_yml_path = nirvati_dir.join("apps").join("snapshots.yml");
+    canonical::write_yaml(&snapshots_yml_path, entries)?;
+    Ok(())
+}
+
+pub fn get_data_dirs(nirvati_dir: &Path, app: &str) -> Result<Vec<DataDir>> {
+    let dirs_yml_path = nirvati_dir.join("apps").join(app).join("dirs.yml");
+    if dirs_yml_path.exists() {
+        let dirs_yml = std::fs::read_to_string(dirs_yml_path)?;
+        yaml::from_str("dirs.yml", &dirs_yml)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn save_data_dirs(nirvati_dir: &Path, app: &str, dirs: &[DataDir]) -> Result<()> {
+    let dirs_yml_path = nirvati_dir.join("apps").join(app).join("dirs.yml");
+    canonical::write_yaml(&dirs_yml_path, dirs)?;
+    Ok(())
+}
+
+pub fn save_tasks(nirvati_dir: &Path, app: &str, tasks: &[Task]) -> Result<()> {
+    let tasks_yml_path = nirvati_dir.join("apps").join(app).join("tasks.yml");
+    canonical::write_yaml(&tasks_yml_path, tasks)?;
+    Ok(())
+}
+
+pub fn save_updates(nirvati_dir: &Path, updates: &Updates) -> Result<()> {
+    let updates_json_path = nirvati_dir.join("apps").join("updates.json");
+    canonical::write_json(&updates_json_path, updates)?;
+    Ok(())
+}
+
+pub fn get_hooks_config(nirvati_dir: &Path) -> Result<HooksConfig> {
+    let hooks_yml_path = nirvati_dir.join("db").join("hooks.yml");
+    if hooks_yml_path.exists() {
+        let hooks_yml = std::fs::read_to_string(hooks_yml_path)?;
+        Ok(serde_yaml::from_str(&hooks_yml)?)
+    } else {
+        Ok(HooksConfig::default())
+    }
+}
+
+pub fn get_sources(nirvati_dir: &Path) -> Result<Sources> {
+    let sources_yml_path = nirvati_dir.join("db").join("sources.yml");
+    if sources_yml_path.exists() {
+        let sources_yml = std::fs::read_to_string(sources_yml_path)?;
+        Ok(serde_yaml::from_str(&sources_yml)?)
+    } else {
+        Ok(Sources::default())
+    }
+}
+
+/// Categories a store adds to the canonical ones, listed in categories.yml in its root
+pub fn get_store_categories(nirvati_dir: &Path, store: &StoreSource) -> Result<Vec<String>> {
+    let categories_yml_path = store.checkout_dir(nirvati_dir).join("categories.yml");
+    if categories_yml_path.is_file() {
+        let categories_yml = std::fs::read_to_string(categories_yml_path)?;
+        Ok(serde_yaml::from_str(&categories_yml)?)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn get_app_origins(nirvati_dir: &Path) -> Result<Origins> {
+    let origins_json_path = nirvati_dir.join("apps").join("origins.json");
+    if origins_json_path.exists() {
+        let origins_json = std::fs::read_to_string(origins_json_path)?;
+        Ok(serde_json::from_str(&origins_json)?)
+    } else {
+        Ok(Origins::new())
+    }
+}
+
+pub fn save_app_origins(nirvati_dir: &Path, origins: &Origins) -> Result<()> {
+    let origins_json_path = nirvati_dir.join("apps").join("origins.json");
+    canonical::write_json(&origins_json_path, origins)?;
+    Ok(())
+}
+
+/// App -> name of its dir in app-data, for apps that were renamed and kept their old data dir
+pub type DataDirNames = BTreeMap<String, String>;
+
+pub fn get_data_dir_names(nirvati_dir: &Path) -> Result<DataDirNames> {
+    let data_dirs_json_path = nirvati_dir.join("db").join("data-dirs.json");
+    if data_dirs_json_path.exists() {
+        let data_dirs_json = std::fs::read_to_string(data_dirs_json_path)?;
+        Ok(serde_json::from_str(&data_dirs_json)?)
+    } else {
+        Ok(DataDirNames::new())
+    }
+}
+
+pub fn save_data_dir_names(nirvati_dir: &Path, names: &DataDirNames) -> Result<()> {
+    let data_dirs_json_path = nirvati_dir.join("db").join("data-dirs.json");
+    canonical::write_json(&data_dirs_json_path, names)?;
+    Ok(())
+}
+
+/// App -> (image in its app.yml -> the image with the digest check-updates pinned it to)
+/// They are kept in db, so syncing the app's store doesn't undo them
+pub type DigestPins = BTreeMap<String, BTreeMap<String, String>>;
+
+pub fn get_digest_pins(nirvati_dir: &Path) -> Result<DigestPins> {
+    let pins_json_path = nirvati_dir.join("db").join("digest-pins.json");
+    if pins_json_path.exists() {
+        let pins_json = std::fs::read_to_string(pins_json_path)?;
+        Ok(serde_json::from_str(&pins_json)?)
+    } else {
+        Ok(DigestPins::new())
+    }
+}
+
+pub fn save_digest_pins(nirvati_dir: &Path, pins: &DigestPins) -> Result<()> {
+    let pins_json_path = nirvati_dir.join("db").join("digest-pins.json");
+    canonical::write_json(&pins_json_path, pins)?;
+    Ok(())
+}
+
+pub fn get_reverse_index(nirvati_dir: &Path) -> Result<ReverseIndex> {
+    let rdeps_json_path = nirvati_dir.join("apps").join("rdeps.json");
+    if rdeps_json_path.exists() {
+        let rdeps_json = std::fs::read_to_string(rdeps_json_path)?;
+        Ok(serde_json::from_str(&rdeps_json)?)
+    } else {
+        Ok(ReverseIndex::new())
+    }
+}
+
+pub fn save_reverse_index(nirvati_dir: &Path, index: &ReverseIndex) -> Result<()> {
+    let rdeps_json_path = nirvati_dir.join("apps").join("rdeps.json");
+    canonical::write_json(&rdeps_json_path, index)?;
+    Ok(())
+}
+
+pub fn save_store_summaries(nirvati_dir: &Path, stores: &[StoreSummary]) -> Result<()> {
+    let stores_json_path = nirvati_dir.join("apps").join("stores.json");
+    canonical::write_json(&stores_json_path, stores)?;
+    Ok(())
+}
+
+pub fn save_categories(nirvati_dir: &Path, categories: &[CategoryCount]) -> Result<()> {
+    let categories_json_path = nirvati_dir.join("apps").join("categories.json");
+    canonical::write_json(&categories_json_path, categories)?;
+    Ok(())
+}
+
+pub fn save_changelog(nirvati_dir: &Path, changelog: &Changelog) -> Result<()> {
+    let changelog_json_path = nirvati_dir.join("apps").join("changelog.json");
+    canonical::write_json(&changelog_json_path, changelog)?;
+    Ok(())
+}
+
+pub fn save_search_index(nirvati_dir: &Path, index: &SearchIndex) -> Result<()> {
+    let search_json_path = nirvati_dir.join("apps").join("search.json");
+    std::fs::write(search_json_path, canonical::to_compact_json(index)?)?;
     Ok(())
 }
 
 //#[once(sync_writes = true, time = 10000, result = true)]
 pub fn read_app_yml(nirvati_dir: &Path, app_name: &str) -> Result<AppYml> {
     let app_yml_path = nirvati_dir.join("apps").join(app_name).join("app.yml");
-    let app_yml: serde_yaml::Value = serde_yaml::from_str(&std::fs::read_to_string(app_yml_path)?)?;
-    let app_version = app_yml
+    parse_app_yml(&std::fs::read_to_string(app_yml_path)?)
+}
+
+pub fn parse_app_yml(contents: &str) -> Result<AppYml> {
+    parse_app_yml_with_unknown_keys(contents).map(|(app_yml, _)| app_yml)
+}
+
+/// Like read_app_yml, but also returns unknownKey warnings for the keys the app.yml's version does not have
+pub fn read_app_yml_with_unknown_keys(
+    nirvati_dir: &Path,
+    app_name: &str,
+) -> Result<(AppYml, Vec<Diagnostic>)> {
+    let app_yml_path = nirvati_dir.join("apps").join(app_name).join("app.yml");
+    parse_app_yml_with_unknown_keys(&std::fs::read_to_string(app_yml_path)?)
+}
+
+/// The error for a version field no schema exists for
+fn unsupported_version(file: &'static str, schema_type: SchemaType, version: i64) -> anyhow::Error {
+    let supported = schema_type.supported_versions();
+    match supported.last() {
+        Some(newest) if version > i64::from(*newest) => NewerSchemaVersion {
+            file,
+            version,
+            newest: *newest,
+        }
+        .into(),
+        _ => anyhow!(
+            "{} version {} is not supported, supported are {:?}",
+            file,
+            version,
+            supported
+        ),
+    }
+}
+
+fn app_yml_version(app_yml: &serde_yaml::Value) -> Result<i64> {
+    app_yml
         .get("version")
         .ok_or_else(|| anyhow!("app.yml does not contain a version"))?
         .as_i64()
-        .ok_or_else(|| anyhow!("app.yml version is not an integer"))?;
-    match app_version {
+        .ok_or_else(|| anyhow!("app.yml version is not an integer"))
+}
+
+/// Deserializes an app.yml as the given version, with the keys that version does not have
+fn app_yml_as_version(
+    version: i64,
+    app_yml: serde_yaml::Value,
+    contents: &str,
+) -> Result<(AppYml, Vec<String>)> {
+    match version {
         1 => {
-            let app_yml = AppYml::V1(serde_yaml::from_value(app_yml)?);
-            Ok(app_yml)
+            let (app_yml, unknown_keys) =
+                yaml::from_value_with_unknown_keys("app.yml", app_yml, contents)?;
+            Ok((AppYml::V1(app_yml), unknown_keys))
         }
-        _ => Err(anyhow!("app.yml version is not supported")),
+        _ => Err(unsupported_version("app.yml", SchemaType::App, version)),
     }
 }
 
+/// Parses an app.yml and warns about the keys its version does not have
+/// If a newer supported version has a key, the warning names the version the author needs to bump to
+pub fn parse_app_yml_with_unknown_keys(contents: &str) -> Result<(AppYml, Vec<Diagnostic>)> {
+    let app_yml = yaml::parse_value("app.yml", contents)?;
+    let version = app_yml_version(&app_yml)?;
+    let (parsed, unknown_keys) = app_yml_as_version(version, app_yml.clone(), contents)?;
+    if unknown_keys.is_empty() {
+        return Ok((parsed, Vec::new()));
+    }
+    // A newer version may fail to parse the file for other reasons, then it can't tell which of the keys it has
+    let newer_versions = SchemaType::App
+        .supported_versions()
+        .iter()
+        .filter(|newer| i64::from(**newer) > version)
+        .filter_map(|newer| {
+            app_yml_as_version(i64::from(*newer), app_yml.clone(), contents)
+                .ok()
+                .map(|(_, unknown_keys)| (*newer, unknown_keys))
+        })
+        .collect::<Vec<_>>();
+    let diagnostics = unknown_keys
+        .into_iter()
+        .map(|key| {
+            let min_version = newer_versions
+                .iter()
+                .find(|(_, unknown_keys)| !unknown_keys.contains(&key))
+                .map(|(newer, _)| *newer);
+            let message = match min_version {
+                Some(min_version) => format!(
+                    "{} is not part of app.yml version {} and is ignored, set version: {} to use it",
+                    key, version, min_version
+                ),
+                None => format!(
+                    "{} is not part of app.yml version {} or any newer version this app manager supports, so it is ignored",
+                    key, version
+                ),
+            };
+            Diagnostic::warning(DiagnosticCode::UnknownKey, message, Some(key))
+        })
+        .collect();
+    Ok((parsed, diagnostics))
+}
+
 //#[once(sync_writes = true, time = 10000, result = true)]
 pub fn read_metadata_yml(nirvati_dir: &Path, app_name: &str) -> Result<MetadataYml> {
     let metadata_yml_path = nirvati_dir.join("apps").join(app_name).join("metadata.yml");
-    let metadata_yml: serde_yaml::Value =
-        serde_yaml::from_str(&std::fs::read_to_string(metadata_yml_path)?)?;
+    let mut metadata_yml = parse_metadata_yml(&std::fs::read_to_string(metadata_yml_path)?)?;
+    let store_ids = StoreIds::load(nirvati_dir)?;
+    metadata_yml.localize_ids(|id| store_ids.localize(id));
+    Ok(metadata_yml)
+}
+
+pub fn parse_metadata_yml(contents: &str) -> Result<MetadataYml> {
+    let metadata_yml = yaml::parse_value("metadata.yml", contents)?;
     let metadata_version = metadata_yml
         .get("version")
         .ok_or_else(|| anyhow!("metadata.yml does not contain a version"))?
@@ -282,23 +909,34 @@ pub fn read_metadata_yml(nirvati_dir: &Path, app_name: &str) -> Result<MetadataY
         .ok_or_else(|| anyhow!("metadata.yml version is not an integer"))?;
     match metadata_version {
         1 => {
-            let metadata_yml = MetadataYml::V1(serde_yaml::from_value(metadata_yml)?);
+            let metadata_yml =
+                MetadataYml::V1(yaml::from_value("metadata.yml", metadata_yml, contents)?);
             Ok(metadata_yml)
         }
-        _ => Err(anyhow!("metadata.yml version is not supported")),
+        _ => Err(unsupported_version(
+            "metadata.yml",
+            SchemaType::Metadata,
+            metadata_version,
+        )),
     }
 }
 
 pub fn get_all_metadata_ymls(nirvati_dir: &Path) -> Result<Vec<OutputMetadata>> {
+    let store_ids = StoreIds::load(nirvati_dir)?;
+    let installed_apps = get_installed_apps(nirvati_dir)?;
     let mut metadata_ymls = Vec::new();
     for entry in std::fs::read_dir(nirvati_dir.join("apps"))? {
         let entry = entry?;
-        if !entry.file_type()?.is_dir() {
+        // Apps from local stores can be symlinked
+        if !entry.path().is_dir() {
             continue;
         }
         let app_id = entry.file_name().to_str().unwrap().to_owned();
         if let Ok(metadata_yml) = read_metadata_yml(nirvati_dir, &app_id) {
-            metadata_ymls.push(metadata_yml.into_basic_output_metadata(app_id));
+            let mut metadata = metadata_yml.into_basic_output_metadata(app_id);
+            store_ids.apply_origin(&mut metadata);
+            resolve_dependencies(&mut metadata, &installed_apps);
+            metadata_ymls.push(metadata);
         }
     }
     Ok(metadata_ymls)
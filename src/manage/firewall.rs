@@ -0,0 +1,170 @@
+//! The public ports the host firewall has to open or block, written to apps/firewall.yml
+//!
+//! Host scripts apply this to nftables or ufw after every generate, so the firewall always matches the compose files.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::ResultYml;
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    /// The port is published, but only meant to be reached through Tor
+    Block,
+}
+
+/// An entry of apps/firewall.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRule {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub action: Action,
+    pub app: String,
+    pub service: String,
+    /// Whether the port is also published on IPv6
+    #[serde(default)]
+    pub ipv6: bool,
+}
+
+/// Returns the public port, protocol and whether it is bound on IPv6 of a compose port binding like [::]:5353:53/udp
+fn parse_binding(binding: &str) -> Option<(u16, Protocol, bool)> {
+    let (ports, protocol) = match binding.split_once('/') {
+        Some((ports, "udp")) => (ports, Protocol::Udp),
+        Some((ports, "tcp")) => (ports, Protocol::Tcp),
+        Some(_) => return None,
+        None => (binding, Protocol::Tcp),
+    };
+    let mut parts = ports.rsplitn(3, ':');
+    let _internal_port = parts.next()?;
+    let public_port = parts.next()?.parse().ok()?;
+    let ipv6 = parts.next().is_some_and(|host| host.starts_with('['));
+    Some((public_port, protocol, ipv6))
+}
+
+/// Returns the rules for the ports of an app, which are blocked if the app is only reachable through Tor
+pub fn get_rules(app: &str, result: &ResultYml) -> Vec<FirewallRule> {
+    let action = if result.metadata.tor_only {
+        Action::Block
+    } else {
+        Action::Allow
+    };
+    // Bindings on IPv4 and IPv6 are one rule
+    let mut rules = BTreeMap::new();
+    let mut add = |port, protocol, service: &str, ipv6| {
+        rules
+            .entry((port, protocol))
+            .and_modify(|rule: &mut FirewallRule| rule.ipv6 |= ipv6)
+            .or_insert_with(|| FirewallRule {
+                port,
+                protocol,
+                action,
+                app: app.to_owned(),
+                service: service.to_owned(),
+                ipv6,
+            });
+    };
+    for entry in &result.caddy_entries {
+        add(
+            entry.public_port,
+            Protocol::Tcp,
+            &entry.container_name,
+            entry.ipv6,
+        );
+    }
+    for (service_name, service) in &result.spec.services {
+        for binding in &service.ports {
+            if let Some((port, protocol, ipv6)) = parse_binding(binding) {
+                add(port, protocol, service_name, ipv6);
+            }
+        }
+    }
+    rules.into_values().collect()
+}
+
+/// Replaces the rules of the processed apps and removes those of apps that are not installed
+pub fn merge_rules(
+    previous: Vec<FirewallRule>,
+    processed_apps: &[String],
+    installed_apps: &[String],
+    new_rules: Vec<FirewallRule>,
+) -> Vec<FirewallRule> {
+    let mut rules = previous
+        .into_iter()
+        .filter(|rule| !processed_apps.contains(&rule.app))
+        .chain(new_rules)
+        .filter(|rule| installed_apps.contains(&rule.app))
+        .collect::<Vec<_>>();
+    rules.sort_by_key(|rule| (rule.port, rule.protocol));
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composegenerator::{output::types::Service, types::CaddyEntry};
+
+    #[test]
+    fn rules_from_result() {
+        let mut result = ResultYml::default();
+        result.caddy_entries.push(CaddyEntry {
+            public_port: 81,
+            internal_port: 80,
+            container_name: "main".to_owned(),
+            is_primary: true,
+            is_l4: false,
+            ipv6: true,
+            maintenance: false,
+        });
+        result.spec.services.insert(
+            "main".to_owned(),
+            Service {
+                ports: vec![
+                    "2222:22".to_owned(),
+                    "0.0.0.0:5353:53/udp".to_owned(),
+                    "[::]:5353:53/udp".to_owned(),
+                ],
+                ..Default::default()
+            },
+        );
+        let rules = get_rules("demo", &result);
+        let summary = rules
+            .iter()
+            .map(|rule| (rule.port, rule.protocol, rule.ipv6))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (81, Protocol::Tcp, true),
+                (2222, Protocol::Tcp, false),
+                (5353, Protocol::Udp, true),
+            ]
+        );
+        assert!(rules.iter().all(|rule| rule.action == Action::Allow));
+
+        result.metadata.tor_only = true;
+        assert!(get_rules("demo", &result)
+            .iter()
+            .all(|rule| rule.action == Action::Block));
+
+        let merged = merge_rules(
+            rules,
+            &[],
+            &["other".to_owned()],
+            get_rules("other", &result),
+        );
+        assert!(merged.iter().all(|rule| rule.app == "other"));
+    }
+}
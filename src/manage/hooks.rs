@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    events::{now, PermissionChange},
+    files,
+};
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// The contents of db/hooks.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HooksConfig {
+    /// Executable that is called with the event name as argument and a JSON payload on stdin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<PathBuf>,
+    /// How long the hook may run before it is killed, in seconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            notify: None,
+            timeout: default_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum HookEvent {
+    InstallSucceeded {
+        app: String,
+    },
+    PermissionsChanged {
+        changes: BTreeMap<String, PermissionChange>,
+    },
+    PortConflict {
+        apps: Vec<String>,
+    },
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::InstallSucceeded { .. } => "installSucceeded",
+            HookEvent::PermissionsChanged { .. } => "permissionsChanged",
+            HookEvent::PortConflict { .. } => "portConflict",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HookPayload<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a HookEvent,
+}
+
+fn run_hook(hook: &Path, timeout: Duration, event: &HookEvent) -> Result<()> {
+    let payload = serde_json::to_string(&HookPayload {
+        timestamp: now(),
+        event,
+    })?;
+    let mut child = Command::new(hook)
+        .arg(event.name())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(payload.as_bytes())?;
+    }
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                return Err(anyhow!("Hook exited with {}", status));
+            }
+            return Ok(());
+        }
+        if started.elapsed() > timeout {
+            child.kill()?;
+            // Reap the killed hook, so it doesn't stay around as a zombie
+            child.wait()?;
+            return Err(anyhow!("Hook timed out"));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs the configured notification hook, if there is one
+/// Hook failures are only logged, they never fail the operation that triggered them
+pub fn notify(nirvati_dir: &Path, event: HookEvent) {
+    let config = match files::get_hooks_config(nirvati_dir) {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::warn!("Failed to read hooks.yml: {:#}", err);
+            return;
+        }
+    };
+    let Some(hook) = config.notify else {
+        return;
+    };
+    if let Err(err) = run_hook(&hook, Duration::from_secs(config.timeout), &event) {
+        tracing::warn!("Failed to run {} hook: {:#}", event.name(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn write_hook(dir: &TempDir, script: &str) -> PathBuf {
+        let hook = dir.join("hook.sh");
+        std::fs::write(&hook, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        hook
+    }
+
+    #[test]
+    fn passes_the_event_to_the_hook() {
+        let dir = TempDir::new("hooks");
+        let out = dir.join("out");
+        let hook = write_hook(
+            &dir,
+            &format!("echo \"$1\" > {0}; cat >> {0}", out.display()),
+        );
+        let event = HookEvent::InstallSucceeded {
+            app: "notes".to_owned(),
+        };
+        run_hook(&hook, Duration::from_secs(10), &event).unwrap();
+        let out = std::fs::read_to_string(out).unwrap();
+        let (name, payload) = out.split_once('\n').unwrap();
+        assert_eq!(name, "installSucceeded");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["event"], "installSucceeded");
+        assert_eq!(payload["app"], "notes");
+
+        let hook = write_hook(&dir, "exit 3");
+        assert!(run_hook(&hook, Duration::from_secs(10), &event).is_err());
+    }
+
+    #[test]
+    fn kills_and_reaps_hooks_that_time_out() {
+        let dir = TempDir::new("hooks-timeout");
+        let pid_file = dir.join("pid");
+        let hook = write_hook(
+            &dir,
+            &format!("echo $$ > {}; exec sleep 30", pid_file.display()),
+        );
+        let started = Instant::now();
+        let err = run_hook(
+            &hook,
+            Duration::from_millis(500),
+            &HookEvent::PortConflict { apps: Vec::new() },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Hook timed out");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        // A zombie would still be listed in /proc until it is waited for
+        let pid = std::fs::read_to_string(pid_file).unwrap();
+        assert!(!Path::new("/proc").join(pid.trim()).exists());
+    }
+}
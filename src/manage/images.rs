@@ -0,0 +1,382 @@
+use std::{collections::HashMap, io::Read, time::Duration};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+/// The registry images without a registry host are pulled from
+const DOCKER_HUB_HOST: &str = "registry-1.docker.io";
+
+/// Manifest types to accept, so multi-arch images return the digest of their index
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.docker.distribution.manifest.v2+json, application/vnd.oci.image.manifest.v1+json";
+
+lazy_static! {
+    static ref CHALLENGE_PARAM_REGEX: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+}
+
+/// A parsed image reference like ghcr.io/nirvati/app:1.0@sha256:...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub registry: Option<String>,
+    pub name: String,
+    pub tag: Option<String>,
+    /// For example sha256:abc...
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(image: &str) -> Self {
+        let (rest, digest) = match image.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest.to_owned())),
+            None => (image, None),
+        };
+        // A colon after the last slash separates the tag, a colon before it is a registry port
+        let name_start = rest.rfind('/').map(|idx| idx + 1).unwrap_or(0);
+        let (name, tag) = match rest[name_start..].rfind(':') {
+            Some(idx) => (
+                &rest[..name_start + idx],
+                Some(rest[name_start + idx + 1..].to_owned()),
+            ),
+            None => (rest, None),
+        };
+        // The first part is a registry if it looks like a host name
+        let (registry, name) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (Some(first.to_owned()), rest.to_owned())
+            }
+            _ => (None, name.to_owned()),
+        };
+        Self {
+            registry,
+            name,
+            tag,
+            digest,
+        }
+    }
+
+    /// The package URL, as described in https://github.com/package-url/purl-spec
+    pub fn purl(&self) -> String {
+        let mut purl = format!("pkg:docker/{}", self.repository());
+        let mut qualifiers = Vec::new();
+        match (&self.digest, &self.tag) {
+            (Some(digest), tag) => {
+                purl.push_str(&format!("@{}", digest.replace(':', "%3A")));
+                if let Some(tag) = tag {
+                    qualifiers.push(format!("tag={}", tag));
+                }
+            }
+            (None, Some(tag)) => purl.push_str(&format!("@{}", tag)),
+            (None, None) => {}
+        }
+        if let Some(registry) = &self.registry {
+            qualifiers.push(format!("repository_url={}", registry));
+        }
+        if !qualifiers.is_empty() {
+            purl.push('?');
+            purl.push_str(&qualifiers.join("&"));
+        }
+        purl
+    }
+
+    /// The host the registry API is served on
+    pub fn registry_host(&self) -> &str {
+        self.registry.as_deref().unwrap_or(DOCKER_HUB_HOST)
+    }
+
+    /// The repository path in the registry API, including the library/ namespace for official Docker Hub images
+    pub fn repository(&self) -> String {
+        if self.registry.is_none() && !self.name.contains('/') {
+            format!("library/{}", self.name)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// Formats the reference again, optionally with another digest
+    pub fn to_string_with_digest(&self, digest: Option<&str>) -> String {
+        let mut image = match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.name),
+            None => self.name.clone(),
+        };
+        if let Some(tag) = &self.tag {
+            image.push_str(&format!(":{}", tag));
+        }
+        if let Some(digest) = digest {
+            image.push_str(&format!("@{}", digest));
+        }
+        image
+    }
+}
+
+/// Parses the parameters of a WWW-Authenticate: Bearer header
+fn parse_bearer_challenge(header: &str) -> Option<HashMap<String, String>> {
+    let params = header.strip_prefix("Bearer ")?;
+    Some(
+        CHALLENGE_PARAM_REGEX
+            .captures_iter(params)
+            .map(|captures| (captures[1].to_owned(), captures[2].to_owned()))
+            .collect(),
+    )
+}
+
+/// A version tag like v1.2.3-alpine, which is only comparable to tags with the same prefix, length and suffix
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VersionTag<'a> {
+    prefix: &'a str,
+    parts: Vec<u64>,
+    suffix: &'a str,
+}
+
+impl<'a> VersionTag<'a> {
+    fn parse(tag: &'a str) -> Option<Self> {
+        let prefix = if tag.starts_with('v') { "v" } else { "" };
+        let rest = &tag[prefix.len()..];
+        let version_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (version, suffix) = rest.split_at(version_end);
+        let parts = version
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        Some(Self {
+            prefix,
+            parts,
+            suffix,
+        })
+    }
+
+    fn is_comparable(&self, other: &Self) -> bool {
+        self.prefix == other.prefix
+            && self.suffix == other.suffix
+            && self.parts.len() == other.parts.len()
+    }
+}
+
+/// Whether a tag names a version like 1.2 or v1.2.3-alpine, unlike moving tags like latest
+pub(crate) fn is_version_tag(tag: &str) -> bool {
+    VersionTag::parse(tag).is_some()
+}
+
+/// Returns the tags that have a newer version than `current`, oldest first
+pub fn newer_tags(current: &str, tags: &[String]) -> Vec<String> {
+    let Some(current) = VersionTag::parse(current) else {
+        return Vec::new();
+    };
+    let mut newer = tags
+        .iter()
+        .filter_map(|tag| VersionTag::parse(tag).map(|version| (version, tag)))
+        .filter(|(version, _)| version.is_comparable(&current) && version.parts > current.parts)
+        .collect::<Vec<_>>();
+    newer.sort_by(|(a, _), (b, _)| a.parts.cmp(&b.parts));
+    newer.into_iter().map(|(_, tag)| tag.to_owned()).collect()
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+}
+
+/// The parts of an OCI image or artifact manifest that are needed to download its layers
+#[derive(Deserialize, Debug, Clone)]
+pub struct Manifest {
+    #[serde(default)]
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// A minimal client for the OCI distribution API, supporting anonymous bearer token auth
+pub struct RegistryClient {
+    agent: ureq::Agent,
+    /// Tokens by registry host and repository
+    tokens: HashMap<(String, String), String>,
+}
+
+impl Default for RegistryClient {
+    fn default() -> Self {
+        // The configured proxy is validated when it is set
+        let agent = crate::proxy::agent_builder(Duration::from_secs(30), None)
+            .unwrap_or_else(|_| ureq::AgentBuilder::new().timeout(Duration::from_secs(30)));
+        Self {
+            agent: agent.build(),
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl RegistryClient {
+    /// A client that connects through proxy, like socks5://127.0.0.1:9050 for Tor, or the configured proxy if none is given
+    pub fn new(proxy: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            agent: crate::proxy::agent_builder(Duration::from_secs(30), proxy)?.build(),
+            tokens: HashMap::new(),
+        })
+    }
+
+    fn fetch_token(&self, challenge: &str) -> Result<String> {
+        let params = parse_bearer_challenge(challenge)
+            .ok_or_else(|| anyhow!("Unsupported authentication: {}", challenge))?;
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| anyhow!("No realm in authentication challenge"))?;
+        let mut request = self.agent.get(realm);
+        for key in ["service", "scope"] {
+            if let Some(value) = params.get(key) {
+                request = request.query(key, value);
+            }
+        }
+        let response: TokenResponse = serde_json::from_str(&request.call()?.into_string()?)?;
+        Ok(response.token)
+    }
+
+    fn request(&mut self, method: &str, image: &ImageRef, path: &str) -> Result<ureq::Response> {
+        let key = (image.registry_host().to_owned(), image.repository());
+        let url = format!("https://{}/v2/{}/{}", key.0, key.1, path);
+        crate::offline::ensure_online(format!("Accessing the registry {}", key.0))?;
+        let mut retried = false;
+        loop {
+            let mut request = self
+                .agent
+                .request(method, &url)
+                .set("Accept", MANIFEST_TYPES);
+            if let Some(token) = self.tokens.get(&key) {
+                request = request.set("Authorization", &format!("Bearer {}", token));
+            }
+            match request.call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response)) if !retried => {
+                    let challenge = response
+                        .header("WWW-Authenticate")
+                        .ok_or_else(|| anyhow!("Registry requires authentication"))?
+                        .to_owned();
+                    let token = self.fetch_token(&challenge)?;
+                    self.tokens.insert(key.clone(), token);
+                    retried = true;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Returns the digest a tag currently points to
+    pub fn get_digest(&mut self, image: &ImageRef, tag: &str) -> Result<String> {
+        let response = self.request("HEAD", image, &format!("manifests/{}", tag))?;
+        response
+            .header("Docker-Content-Digest")
+            .map(|digest| digest.to_owned())
+            .ok_or_else(|| anyhow!("Registry did not return a digest"))
+    }
+
+    /// Fetches the manifest for a tag or digest
+    pub fn get_manifest(&mut self, image: &ImageRef, reference: &str) -> Result<Manifest> {
+        let response = self.request("GET", image, &format!("manifests/{}", reference))?;
+        Ok(serde_json::from_str(&response.into_string()?)?)
+    }
+
+    pub fn get_blob(&mut self, image: &ImageRef, digest: &str) -> Result<Vec<u8>> {
+        let response = self.request("GET", image, &format!("blobs/{}", digest))?;
+        let mut blob = Vec::new();
+        response.into_reader().read_to_end(&mut blob)?;
+        Ok(blob)
+    }
+
+    pub fn list_tags(&mut self, image: &ImageRef) -> Result<Vec<String>> {
+        let response = self.request("GET", image, "tags/list")?;
+        let tags: TagList = serde_json::from_str(&response.into_string()?)?;
+        Ok(tags.tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_image_refs() {
+        assert_eq!(
+            ImageRef::parse("nginx:1.25-alpine"),
+            ImageRef {
+                registry: None,
+                name: "nginx".to_owned(),
+                tag: Some("1.25-alpine".to_owned()),
+                digest: None,
+            }
+        );
+        let image = ImageRef::parse("localhost:5000/nirvati/app:v1@sha256:abcd");
+        assert_eq!(
+            image,
+            ImageRef {
+                registry: Some("localhost:5000".to_owned()),
+                name: "nirvati/app".to_owned(),
+                tag: Some("v1".to_owned()),
+                digest: Some("sha256:abcd".to_owned()),
+            }
+        );
+        assert_eq!(
+            image.purl(),
+            "pkg:docker/nirvati/app@sha256%3Aabcd?tag=v1&repository_url=localhost:5000"
+        );
+        assert_eq!(ImageRef::parse("nginx").purl(), "pkg:docker/library/nginx");
+    }
+    #[test]
+    fn parse_challenges() {
+        let params = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/nginx:pull,push");
+        assert!(parse_bearer_challenge(r#"Basic realm="registry""#).is_none());
+    }
+
+    #[test]
+    fn newer_version_tags() {
+        let tags = [
+            "1.24-alpine",
+            "1.26-alpine",
+            "1.25",
+            "1.27-alpine",
+            "latest",
+            "1.3-alpine",
+        ]
+        .map(|tag| tag.to_owned());
+        assert_eq!(
+            newer_tags("1.25-alpine", &tags),
+            vec!["1.26-alpine".to_owned(), "1.27-alpine".to_owned()]
+        );
+        assert!(newer_tags("latest", &tags).is_empty());
+        let tags = ["v1.2.3", "v1.10.0", "1.11.0", "v1.2"].map(|tag| tag.to_owned());
+        assert_eq!(newer_tags("v1.2.3", &tags), vec!["v1.10.0".to_owned()]);
+    }
+
+    #[test]
+    fn registry_requests_fail_offline() {
+        crate::offline::set_offline(true);
+        let mut client = RegistryClient::default();
+        let err = client
+            .get_digest(&ImageRef::parse("nginx:1.25-alpine"), "1.25-alpine")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Accessing the registry registry-1.docker.io needs network access, which is disabled in offline mode"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Apps installed multiple times under an instance suffix, like nextcloud@work
+//!
+//! Every instance gets its own copy of the app's dir in apps/<app>@<instance>, and is generated
+//! like a separate app, with its own data dir, ports, hostnames and permissions.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use super::prune::RENDERED_FILES;
+
+/// Splits an app id into the app and the instance
+pub fn split_instance_id(app: &str) -> (&str, Option<&str>) {
+    match app.split_once('@') {
+        Some((app, instance)) => (app, Some(instance)),
+        None => (app, None),
+    }
+}
+
+/// The app id with the instance separator replaced, for container names, hostnames and env vars
+pub fn sanitize_id(app: &str) -> String {
+    app.replace('@', "-")
+}
+
+/// The app id like it appears in env vars, where @, - and _ are all the same
+fn env_var_id(app: &str) -> String {
+    sanitize_id(app).to_uppercase().replace('-', "_")
+}
+
+/// Fails if another app would get the same hostnames and env vars as the instance, like nextcloud-work for nextcloud@work
+fn check_collisions(nirvati_dir: &Path, instance_id: &str) -> Result<()> {
+    let id = env_var_id(instance_id);
+    for entry in std::fs::read_dir(nirvati_dir.join("apps"))? {
+        let entry = entry?;
+        let app = entry.file_name().to_string_lossy().into_owned();
+        if app != instance_id && entry.file_type()?.is_dir() && env_var_id(&app) == id {
+            bail!(
+                "Instance {} would get the same hostnames and env vars as app {}",
+                instance_id,
+                app
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Copies an app's dir to the dir of one of its instances, without the app's rendered files
+fn copy_app_dir(nirvati_dir: &Path, app: &str, instance_id: &str) -> Result<()> {
+    let instance_dir = nirvati_dir.join("apps").join(instance_id);
+    crate::repos::copy_dir_all(&nirvati_dir.join("apps").join(app), &instance_dir)?;
+    for file in RENDERED_FILES {
+        let file = instance_dir.join(file);
+        if file.is_file() {
+            std::fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the dir of an instance from its app's dir, unless it exists already
+pub fn create_instance(nirvati_dir: &Path, instance_id: &str) -> Result<()> {
+    let (app, Some(instance)) = split_instance_id(instance_id) else {
+        return Ok(());
+    };
+    if nirvati_dir.join("apps").join(instance_id).exists() {
+        return Ok(());
+    }
+    if instance.is_empty()
+        || !instance
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        bail!("Instance names may only contain lowercase letters, digits and dashes");
+    }
+    if !nirvati_dir.join("apps").join(app).is_dir() {
+        bail!("App does not exist");
+    }
+    check_collisions(nirvati_dir, instance_id)?;
+    copy_app_dir(nirvati_dir, app, instance_id)
+}
+
+/// Whether an app exists, or the app of an instance that doesn't exist yet, so installs can be checked
+/// before create_instance copies anything
+pub fn app_exists(nirvati_dir: &Path, app_id: &str) -> bool {
+    let apps_dir = nirvati_dir.join("apps");
+    apps_dir.join(app_id).is_dir() || apps_dir.join(split_instance_id(app_id).0).is_dir()
+}
+
+/// Removes the dir create_instance created for an instance whose install failed
+pub fn remove_instance(nirvati_dir: &Path, instance_id: &str) -> Result<()> {
+    if split_instance_id(instance_id).1.is_none() {
+        return Ok(());
+    }
+    let instance_dir = nirvati_dir.join("apps").join(instance_id);
+    if instance_dir.is_dir() {
+        std::fs::remove_dir_all(instance_dir)?;
+    }
+    Ok(())
+}
+
+/// Updates the dirs of all instances from their app's dir, after the app has been synced
+pub fn update_instances(nirvati_dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(nirvati_dir.join("apps"))? {
+        let instance_id = entry?.file_name().to_string_lossy().into_owned();
+        let (app, Some(_)) = split_instance_id(&instance_id) else {
+            continue;
+        };
+        if nirvati_dir.join("apps").join(app).is_dir() {
+            copy_app_dir(nirvati_dir, app, &instance_id)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn checks_and_removes_instances() {
+        let nirvati_dir = TempDir::new("instances");
+        std::fs::create_dir_all(nirvati_dir.join("apps").join("notes")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("apps").join("notes").join("result.yml"),
+            "",
+        )
+        .unwrap();
+
+        assert!(app_exists(&nirvati_dir, "notes"));
+        assert!(app_exists(&nirvati_dir, "notes@work"));
+        assert!(!app_exists(&nirvati_dir, "missing@work"));
+        assert!(create_instance(&nirvati_dir, "missing@work").is_err());
+        assert!(!nirvati_dir.join("apps").join("missing@work").exists());
+
+        create_instance(&nirvati_dir, "notes@work").unwrap();
+        let instance_dir = nirvati_dir.join("apps").join("notes@work");
+        assert!(instance_dir.is_dir());
+        assert!(!instance_dir.join("result.yml").exists());
+        remove_instance(&nirvati_dir, "notes@work").unwrap();
+        assert!(!instance_dir.exists());
+        // Apps themselves are never removed
+        remove_instance(&nirvati_dir, "notes").unwrap();
+        assert!(nirvati_dir.join("apps").join("notes").is_dir());
+    }
+
+    #[test]
+    fn instances_cant_share_hostnames_with_other_apps() {
+        let nirvati_dir = TempDir::new("instances-collisions");
+        for app in ["nextcloud", "nextcloud-work", "nextcloud_home"] {
+            std::fs::create_dir_all(nirvati_dir.join("apps").join(app)).unwrap();
+        }
+        for instance in ["nextcloud@work", "nextcloud@home"] {
+            let err = create_instance(&nirvati_dir, instance).unwrap_err();
+            assert!(err.to_string().contains("same hostnames"), "{}", instance);
+            assert!(!nirvati_dir.join("apps").join(instance).exists());
+        }
+        create_instance(&nirvati_dir, "nextcloud@family").unwrap();
+        assert!(nirvati_dir.join("apps").join("nextcloud@family").is_dir());
+    }
+}
@@ -0,0 +1,406 @@
+//! Store policy checks for app reviews, run with `app-manager lint`
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::{
+    types::{AppYml, MetadataYml, ResultYml, Severity},
+    v1::{
+        helpers::is_valid_data_mount,
+        import::{ImportNote, NOTES_FILE},
+        types::StringOrMap,
+    },
+};
+
+use super::{
+    dirs::DataDir,
+    files::{parse_app_yml_with_unknown_keys, parse_metadata_yml},
+    images::{is_version_tag, ImageRef},
+    yaml,
+};
+
+/// How the findings of a rule are reported
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLevel {
+    Off,
+    Warning,
+    Error,
+}
+
+pub struct Rule {
+    pub id: &'static str,
+    pub description: &'static str,
+    pub level: RuleLevel,
+}
+
+pub const RULES: [Rule; 8] = [
+    Rule {
+        id: "pinned-image",
+        description: "Images use a digest or a version tag",
+        level: RuleLevel::Error,
+    },
+    Rule {
+        id: "root-justification",
+        description: "Apps with the root permission explain why in root_justification",
+        level: RuleLevel::Error,
+    },
+    Rule {
+        id: "caddy-disabled",
+        description: "The main service of HTTP apps is proxied by Caddy",
+        level: RuleLevel::Warning,
+    },
+    Rule {
+        id: "data-mount",
+        description: "Data mounts are inside the data dirs in dirs.yml",
+        level: RuleLevel::Error,
+    },
+    Rule {
+        id: "description-length",
+        description: "The tagline and description are within the configured lengths",
+        level: RuleLevel::Warning,
+    },
+    Rule {
+        id: "inferred-permission",
+        description:
+            "Permissions the app gets without requesting them, from the result.yml of Generate",
+        level: RuleLevel::Warning,
+    },
+    Rule {
+        id: "import-note",
+        description: "Fields dropped when converting the app from another store format on sync",
+        level: RuleLevel::Warning,
+    },
+    Rule {
+        id: "unknown-key",
+        description: "Keys the app.yml's version does not have, which are ignored",
+        level: RuleLevel::Warning,
+    },
+];
+
+/// The lint config, a YAML file passed with --lint-config
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    /// Overrides the level of rules, by rule id
+    pub rules: BTreeMap<String, RuleLevel>,
+    pub tagline_max_length: usize,
+    pub description_min_length: usize,
+    pub description_max_length: usize,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            rules: BTreeMap::new(),
+            tagline_max_length: 80,
+            description_min_length: 50,
+            description_max_length: 5000,
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?;
+        let config: Self = serde_yaml::from_str(&contents)
+            .map_err(|err| anyhow!("Invalid {}: {}", path.display(), err))?;
+        for rule in config.rules.keys() {
+            if !RULES.iter().any(|known| known.id == rule) {
+                bail!("Invalid {}: unknown rule {}", path.display(), rule);
+            }
+        }
+        Ok(config)
+    }
+
+    fn level(&self, rule: &Rule) -> RuleLevel {
+        self.rules.get(rule.id).copied().unwrap_or(rule.level)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LintFinding {
+    pub app: String,
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    /// The path of the app.yml or metadata.yml field, if it is about a single field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+/// Whether an image can't change without the app being updated
+pub fn is_pinned_image(image: &str) -> bool {
+    let image = ImageRef::parse(image);
+    image.digest.is_some() || image.tag.as_deref().is_some_and(is_version_tag)
+}
+
+/// Collects (rule id, message, field) for everything an app violates, regardless of the configured levels
+fn check_app(
+    app_yml: &AppYml,
+    metadata_yml: &MetadataYml,
+    data_dirs: &[DataDir],
+    config: &LintConfig,
+) -> Vec<(&'static str, String, Option<String>)> {
+    let mut violations = Vec::new();
+    let AppYml::V1(app_yml) = app_yml;
+    let MetadataYml::V1(metadata_yml) = metadata_yml;
+    let metadata = &metadata_yml.metadata;
+
+    let mut containers = app_yml
+        .services
+        .iter()
+        .map(|(name, container)| (format!("services.{}", name), container))
+        .collect::<Vec<_>>();
+    containers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    containers.extend(
+        app_yml
+            .init_containers
+            .iter()
+            .enumerate()
+            .map(|(idx, init)| (format!("init_containers.{}", idx), &init.container)),
+    );
+    for (field, container) in &containers {
+        if container.build.is_none() && !is_pinned_image(&container.image) {
+            violations.push((
+                "pinned-image",
+                format!(
+                    "Image {} has neither a digest nor a version tag",
+                    container.image
+                ),
+                Some(format!("{}.image", field)),
+            ));
+        }
+        if let Some(StringOrMap::Map(data_mounts)) = container.mounts.get("data") {
+            for (host_dir, container_dir) in data_mounts {
+                let relative = Path::new(host_dir.trim_start_matches('/'));
+                let in_data_dir = data_dirs.is_empty()
+                    || data_dirs.iter().any(|dir| {
+                        relative.starts_with(PathBuf::from(dir.path.trim_start_matches('/')))
+                    });
+                if !is_valid_data_mount(host_dir, container_dir) || !in_data_dir {
+                    violations.push((
+                        "data-mount",
+                        format!(
+                            "Data mount {} is not inside a data dir of the app",
+                            host_dir
+                        ),
+                        Some(format!("{}.mounts.data.{}", field, host_dir)),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(main) = app_yml.services.get("main") {
+        if main.port.is_some() && main.disable_caddy && !main.direct_tcp {
+            violations.push((
+                "caddy-disabled",
+                "The main service serves HTTP, but disables Caddy".to_owned(),
+                Some("services.main.disable_caddy".to_owned()),
+            ));
+        }
+    }
+
+    let justified = app_yml
+        .metadata
+        .root_justification
+        .as_deref()
+        .is_some_and(|justification| !justification.trim().is_empty());
+    if app_yml.metadata.has_permissions.iter().any(|p| p == "root") && !justified {
+        violations.push((
+            "root-justification",
+            "The app has the root permission without a root_justification".to_owned(),
+            Some("metadata.has_permissions".to_owned()),
+        ));
+    }
+
+    let tagline_length = metadata.tagline.chars().count();
+    if tagline_length > config.tagline_max_length {
+        violations.push((
+            "description-length",
+            format!(
+                "The tagline is {} characters long, at most {} are allowed",
+                tagline_length, config.tagline_max_length
+            ),
+            Some("metadata.tagline".to_owned()),
+        ));
+    }
+    let description_length = metadata.description.chars().count();
+    if !(config.description_min_length..=config.description_max_length)
+        .contains(&description_length)
+    {
+        violations.push((
+            "description-length",
+            format!(
+                "The description is {} characters long, it should have {} to {}",
+                description_length, config.description_min_length, config.description_max_length
+            ),
+            Some("metadata.description".to_owned()),
+        ));
+    }
+    violations
+}
+
+/// Lints the rendered app.yml and metadata.yml of an app in apps_dir, and its result.yml if it was generated
+pub fn lint_app(apps_dir: &Path, app_id: &str, config: &LintConfig) -> Result<Vec<LintFinding>> {
+    let app_dir = apps_dir.join(app_id);
+    let read = |file: &str| {
+        std::fs::read_to_string(app_dir.join(file)).map_err(|err| {
+            anyhow!(
+                "Failed to read {} of {}, generate it first: {}",
+                file,
+                app_id,
+                err
+            )
+        })
+    };
+    let (app_yml, unknown_keys) = parse_app_yml_with_unknown_keys(&read("app.yml")?)?;
+    let metadata_yml = parse_metadata_yml(&read("metadata.yml")?)?;
+    let data_dirs: Vec<DataDir> = if app_dir.join("dirs.yml").is_file() {
+        yaml::from_str("dirs.yml", &read("dirs.yml")?)?
+    } else {
+        Vec::new()
+    };
+    let mut violations = check_app(&app_yml, &metadata_yml, &data_dirs, config);
+    violations.extend(
+        unknown_keys
+            .into_iter()
+            .map(|diagnostic| ("unknown-key", diagnostic.message, diagnostic.field)),
+    );
+    if app_dir.join("result.yml").is_file() {
+        let result: ResultYml = yaml::from_str("result.yml", &read("result.yml")?)?;
+        violations.extend(
+            result
+                .metadata
+                .inferred_permissions
+                .into_iter()
+                .map(|inferred| {
+                    (
+                        "inferred-permission",
+                        format!(
+                            "Gets the {} permission because of {}, but does not request it",
+                            inferred.permission, inferred.source
+                        ),
+                        inferred.source.field(),
+                    )
+                }),
+        );
+    }
+    if app_dir.join(NOTES_FILE).is_file() {
+        let notes: Vec<ImportNote> = yaml::from_str(NOTES_FILE, &read(NOTES_FILE)?)?;
+        violations.extend(notes.into_iter().map(|note| {
+            let message = match note.permission {
+                Some(permission) => {
+                    format!("{} (needs the {} permission)", note.message, permission)
+                }
+                None => note.message,
+            };
+            ("import-note", message, Some(note.field))
+        }));
+    }
+    let findings = violations
+        .into_iter()
+        .filter_map(|(rule_id, message, field)| {
+            let rule = RULES.iter().find(|rule| rule.id == rule_id)?;
+            let severity = match config.level(rule) {
+                RuleLevel::Off => return None,
+                RuleLevel::Warning => Severity::Warning,
+                RuleLevel::Error => Severity::Error,
+            };
+            Some(LintFinding {
+                app: app_id.to_owned(),
+                rule: rule_id.to_owned(),
+                severity,
+                message,
+                field,
+            })
+        })
+        .collect();
+    Ok(findings)
+}
+
+/// Lints every app in apps_dir, sorted by app id
+pub fn lint_apps(apps_dir: &Path, config: &LintConfig) -> Result<Vec<LintFinding>> {
+    let mut apps = Vec::new();
+    for entry in std::fs::read_dir(apps_dir)? {
+        let path = entry?.path();
+        let Some(app_id) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if path.is_dir() && !app_id.starts_with('.') {
+            apps.push(app_id.to_owned());
+        }
+    }
+    apps.sort();
+    let mut findings = Vec::new();
+    for app in apps {
+        findings.extend(lint_app(apps_dir, &app, config)?);
+    }
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_images() {
+        assert!(is_pinned_image("ghcr.io/nirvati/app:1.2.3"));
+        assert!(is_pinned_image("nginx:v1.25-alpine"));
+        assert!(is_pinned_image("nginx@sha256:abc"));
+        assert!(is_pinned_image("localhost:5000/app:2@sha256:abc"));
+        assert!(!is_pinned_image("nginx"));
+        assert!(!is_pinned_image("nginx:latest"));
+        assert!(!is_pinned_image("localhost:5000/app"));
+    }
+
+    #[test]
+    fn reports_inferred_permissions_of_generated_apps() {
+        let fixture = crate::testing::Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let apps_dir = fixture.root().join("apps");
+        let app_yml_jinja_path = apps_dir.join("example").join("app.yml.jinja");
+        let app_yml_jinja = std::fs::read_to_string(&app_yml_jinja_path)
+            .unwrap()
+            .replace(
+                "    port: 80\n",
+                "    port: 80\n    cap_add:\n      - CAP_NET_RAW\n",
+            );
+        std::fs::write(&app_yml_jinja_path, app_yml_jinja).unwrap();
+        fixture.generate().unwrap();
+
+        let findings = lint_app(&apps_dir, "example", &LintConfig::default()).unwrap();
+        let inferred = findings
+            .iter()
+            .find(|finding| finding.rule == "inferred-permission")
+            .unwrap();
+        assert_eq!(inferred.severity, Severity::Warning);
+        assert_eq!(
+            inferred.message,
+            "Gets the network permission because of capability CAP_NET_RAW of service main, but does not request it"
+        );
+        assert_eq!(inferred.field.as_deref(), Some("services.main.cap_add"));
+
+        let config = LintConfig {
+            rules: BTreeMap::from([("inferred-permission".to_owned(), RuleLevel::Off)]),
+            ..Default::default()
+        };
+        assert!(lint_app(&apps_dir, "example", &config)
+            .unwrap()
+            .iter()
+            .all(|finding| finding.rule != "inferred-permission"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs::File,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use fs2::FileExt;
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another app manager process holds the lock
+    OperationInProgress,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::OperationInProgress => {
+                write!(f, "Another app manager operation is in progress")
+            }
+            LockError::Io(err) => write!(f, "Failed to lock the app manager: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::OperationInProgress => None,
+            LockError::Io(err) => Some(err),
+        }
+    }
+}
+
+/// An exclusive advisory lock on db/.appmgr.lock, released when dropped
+#[derive(Debug)]
+pub struct AppManagerLock {
+    file: File,
+}
+
+impl Drop for AppManagerLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.unlock() {
+            tracing::warn!("Failed to release the app manager lock: {:#}", err);
+        }
+    }
+}
+
+/// Acquires the lock all mutating operations need to hold, waiting up to `timeout` for other operations to finish
+pub fn acquire(nirvati_dir: &Path, timeout: Duration) -> Result<AppManagerLock, LockError> {
+    // db/ doesn't exist yet on a fresh Nirvati root
+    std::fs::create_dir_all(nirvati_dir.join("db")).map_err(LockError::Io)?;
+    let lock_path = nirvati_dir.join("db").join(".appmgr.lock");
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)
+        .map_err(LockError::Io)?;
+    let started = Instant::now();
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(AppManagerLock { file }),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                if started.elapsed() >= timeout {
+                    return Err(LockError::OperationInProgress);
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => return Err(LockError::Io(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn waits_for_other_operations_until_the_timeout() {
+        let nirvati_dir = TempDir::new("lock");
+        let lock = acquire(&nirvati_dir, Duration::ZERO).unwrap();
+        assert!(nirvati_dir.join("db").join(".appmgr.lock").is_file());
+
+        let started = Instant::now();
+        let contended = acquire(&nirvati_dir, Duration::from_millis(300));
+        assert!(matches!(contended, Err(LockError::OperationInProgress)));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // Released when dropped, while another operation waits for it
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+        let waited = acquire(&nirvati_dir, Duration::from_secs(5));
+        holder.join().unwrap();
+        assert!(waited.is_ok());
+    }
+}
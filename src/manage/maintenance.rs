@@ -0,0 +1,56 @@
+//! Apps the user took down for maintenance, listed in maintenanceApps of user.json
+//!
+//! An app in maintenance stays installed and keeps its data, but Caddy serves a static maintenance page on its
+//! HTTP routes instead of proxying them to the app, and closes connections to its TCP routes.
+
+use crate::composegenerator::types::ResultYml;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaintenanceState {
+    On,
+    Off,
+}
+
+impl MaintenanceState {
+    pub fn is_on(self) -> bool {
+        self == MaintenanceState::On
+    }
+}
+
+/// Marks the app's Caddy entries and registry entry
+pub fn apply_to_result(result: &mut ResultYml, in_maintenance: bool) {
+    result.metadata.maintenance = in_maintenance;
+    for entry in &mut result.caddy_entries {
+        entry.maintenance = in_maintenance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composegenerator::types::CaddyEntry;
+
+    #[test]
+    fn marks_all_entries() {
+        let mut result = ResultYml::default();
+        for (public_port, is_l4) in [(81, false), (8333, true)] {
+            result.caddy_entries.push(CaddyEntry {
+                public_port,
+                internal_port: public_port,
+                container_name: "main".to_owned(),
+                is_primary: !is_l4,
+                is_l4,
+                ipv6: false,
+                maintenance: false,
+            });
+        }
+        apply_to_result(&mut result, true);
+        assert!(result.metadata.maintenance);
+        assert!(result.caddy_entries[0].maintenance);
+        assert!(result.caddy_entries[1].maintenance);
+
+        apply_to_result(&mut result, false);
+        assert!(!result.metadata.maintenance);
+        assert!(!result.caddy_entries[0].maintenance);
+    }
+}
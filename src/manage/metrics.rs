@@ -0,0 +1,115 @@
+//! Prometheus scrape targets for the metrics endpoints of installed apps
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::ResultYml;
+
+use super::dns::DnsMap;
+
+/// An entry of apps/prometheus-scrape.yml, in Prometheus' file_sd format
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeTarget {
+    pub targets: Vec<String>,
+    /// Always contains app and service, and __metrics_path__ if it isn't /metrics
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ScrapeTarget {
+    pub fn app(&self) -> Option<&str> {
+        self.labels.get("app").map(String::as_str)
+    }
+}
+
+/// Returns the scrape targets of an app's services, which are reached by their hostnames on the app network
+pub fn get_targets(app: &str, result: &ResultYml, dns: &DnsMap) -> Vec<ScrapeTarget> {
+    result
+        .metrics
+        .iter()
+        .filter_map(|(service, endpoint)| {
+            let entry = dns.get(app)?.get(service)?;
+            let mut labels = BTreeMap::from([
+                ("app".to_owned(), app.to_owned()),
+                ("service".to_owned(), service.to_owned()),
+            ]);
+            if endpoint.path != "/metrics" {
+                labels.insert("__metrics_path__".to_owned(), endpoint.path.clone());
+            }
+            Some(ScrapeTarget {
+                targets: vec![format!("{}:{}", entry.hostname, endpoint.port)],
+                labels,
+            })
+        })
+        .collect()
+}
+
+/// Replaces the targets of the processed apps and removes those of apps that are not installed
+pub fn merge_targets(
+    previous: Vec<ScrapeTarget>,
+    processed_apps: &[String],
+    installed_apps: &[String],
+    new_targets: Vec<ScrapeTarget>,
+) -> Vec<ScrapeTarget> {
+    let mut targets = previous
+        .into_iter()
+        .filter(|target| {
+            target.app().is_some_and(|app| {
+                !processed_apps.iter().any(|processed| processed == app)
+                    && installed_apps.iter().any(|installed| installed == app)
+            })
+        })
+        .chain(new_targets.into_iter().filter(|target| {
+            target
+                .app()
+                .is_some_and(|app| installed_apps.iter().any(|installed| installed == app))
+        }))
+        .collect::<Vec<_>>();
+    targets.sort_by(|a, b| a.labels.cmp(&b.labels));
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{manage::files, testing::Fixture};
+
+    #[test]
+    fn collects_the_endpoints_of_installed_apps() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root();
+        let app_yml_jinja_path = root.join("apps").join("example").join("app.yml.jinja");
+        let app_yml_jinja = std::fs::read_to_string(&app_yml_jinja_path)
+            .unwrap()
+            .replace(
+                "    port: 80\n",
+                "    port: 80\n    metrics:\n      port: 9113\n      path: /stats\n",
+            );
+        std::fs::write(&app_yml_jinja_path, app_yml_jinja).unwrap();
+
+        // Apps that are not installed are not scraped
+        fixture.generate().unwrap();
+        assert!(files::get_scrape_targets(root).unwrap().is_empty());
+
+        files::add_installed_app("example", root).unwrap();
+        fixture.generate().unwrap();
+        let targets = files::get_scrape_targets(root).unwrap();
+        assert_eq!(targets.len(), 1);
+        let hostname = &files::get_dns_map(root).unwrap()["example"]["main"].hostname;
+        assert_eq!(targets[0].targets, vec![format!("{}:9113", hostname)]);
+        assert_eq!(targets[0].app(), Some("example"));
+        assert_eq!(targets[0].labels["service"], "main");
+        assert_eq!(targets[0].labels["__metrics_path__"], "/stats");
+
+        files::remove_installed_app("example", root).unwrap();
+        fixture.generate().unwrap();
+        assert!(files::get_scrape_targets(root).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+//! Explanations of the permissions an app has, for the consent prompt before installing it,
+//! and the apps that use a permission, before revoking it
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::types::{OutputMetadata, Permission, PermissionSource},
+    dependencies::{get_consumers, ReverseIndex},
+};
+
+use super::files;
+
+/// What a permission grants, with enough detail to ask the user for consent
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionExplanation {
+    /// Like lnd/admin, lnd or network
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// The app that exposes the permission, None for built-in permissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// Env vars the permission makes available
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env_vars: Vec<String>,
+    /// Values that are only available in templates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub template_variables: Vec<String>,
+    /// Files in the data dir of the app that exposes the permission
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    /// Other permissions that come with this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+    /// Whether the permission gives access to the host's network
+    pub host_network: bool,
+    /// Whether the permission is hidden from the UI
+    pub hidden: bool,
+}
+
+fn builtin(id: &str) -> Option<PermissionExplanation> {
+    let (name, description, host_network) = match id {
+        "root" => (
+            "Root access",
+            "Full access to the device, including all env vars, capabilities and the data of other apps",
+            true,
+        ),
+        "network" => (
+            "Host network",
+            "Uses the network of the device directly, including raw sockets",
+            true,
+        ),
+        "apps" => (
+            "Installed apps",
+            "Sees which apps are installed and which permissions they offer",
+            false,
+        ),
+        "local-build" => (
+            "Local builds",
+            "Builds its container images from source on this device",
+            false,
+        ),
+        "gpu" => (
+            "Graphics card",
+            "Uses the GPU of the device, for example for hardware-accelerated video transcoding",
+            false,
+        ),
+        "fuse" => (
+            "FUSE filesystems",
+            "Mounts FUSE filesystems like rclone or sshfs inside its containers",
+            false,
+        ),
+        _ => return None,
+    };
+    Some(PermissionExplanation {
+        id: id.to_owned(),
+        name: name.to_owned(),
+        description: description.to_owned(),
+        host_network,
+        ..Default::default()
+    })
+}
+
+/// Adds what a permission of an app grants to an explanation
+fn add_grants(explanation: &mut PermissionExplanation, permission: &Permission) {
+    for (name, value) in &permission.variables {
+        // Only strings are exposed as env vars
+        if value.is_string() {
+            explanation.env_vars.push(name.clone());
+        } else {
+            explanation.template_variables.push(name.clone());
+        }
+    }
+    explanation.files.extend(permission.files.iter().cloned());
+}
+
+/// Explains a permission string, app_names maps app ids to the names shown to users
+pub fn explain(
+    permission: &str,
+    available_permissions: &HashMap<String, Vec<Permission>>,
+    app_names: &HashMap<String, String>,
+) -> PermissionExplanation {
+    if let Some(explanation) = builtin(permission) {
+        return explanation;
+    }
+    let (app, permission_id) = match permission.split_once('/') {
+        Some((app, permission_id)) => (app, Some(permission_id)),
+        None => (permission, None),
+    };
+    let app_name = app_names.get(app).map(String::as_str).unwrap_or(app);
+    let app_permissions = available_permissions
+        .get(app)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut explanation = PermissionExplanation {
+        id: permission.to_owned(),
+        app: Some(app.to_owned()),
+        ..Default::default()
+    };
+    match permission_id {
+        Some(permission_id) => match app_permissions.iter().find(|perm| perm.id == permission_id) {
+            Some(app_permission) => {
+                explanation.name = app_permission.name.clone();
+                explanation.description = app_permission.description.clone();
+                explanation.includes = app_permission.includes.clone();
+                explanation.hidden = app_permission.hidden;
+                add_grants(&mut explanation, app_permission);
+            }
+            None => {
+                explanation.name = permission.to_owned();
+                explanation.description = format!("{} does not offer this permission", app_name);
+            }
+        },
+        None => {
+            explanation.name = app_name.to_owned();
+            explanation.description = format!(
+                "Full access to the data and variables {} offers to other apps",
+                app_name
+            );
+            for app_permission in app_permissions {
+                add_grants(&mut explanation, app_permission);
+            }
+        }
+    }
+    explanation.env_vars.sort();
+    explanation.env_vars.dedup();
+    explanation.template_variables.sort();
+    explanation.template_variables.dedup();
+    explanation.files.sort();
+    explanation.files.dedup();
+    explanation
+}
+
+/// Explains permissions using the permissions exposed by all apps in the registry, not only installed ones
+pub fn explain_all(
+    nirvati_dir: &Path,
+    permissions: &[String],
+) -> Result<BTreeMap<String, PermissionExplanation>> {
+    let registry = files::get_app_registry(nirvati_dir)?;
+    let app_names = registry
+        .iter()
+        .map(|app| (app.id.clone(), app.name.clone()))
+        .collect::<HashMap<_, _>>();
+    let apps = registry.into_iter().map(|app| app.id).collect::<Vec<_>>();
+    let available_permissions = super::get_exported_permissions(nirvati_dir, &apps);
+    Ok(permissions
+        .iter()
+        .map(|permission| {
+            (
+                permission.clone(),
+                explain(permission, &available_permissions, &app_names),
+            )
+        })
+        .collect())
+}
+
+/// How an app uses a permission
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PermissionUsage {
+    /// The app depends on the app of the permission
+    Dependency,
+    /// The app requests the permission in app_yml_jinja_permissions, so its templates use it
+    Jinja { permission: String },
+    /// The app gets the permission because of what its app.yml uses, like an env var or a mount
+    Inferred {
+        permission: String,
+        source: PermissionSource,
+    },
+}
+
+/// An installed app that uses a permission
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionUser {
+    pub app: String,
+    pub usages: Vec<PermissionUsage>,
+}
+
+/// An app matches all of its permissions, app/permission only matches itself
+fn matches(used: &str, permission: &str) -> bool {
+    used == permission || (!permission.contains('/') && used.split('/').next() == Some(permission))
+}
+
+/// Returns the installed apps that use an app or app/permission, and how they use it
+pub fn find_users(
+    registry: &[OutputMetadata],
+    index: &ReverseIndex,
+    installed_apps: &[String],
+    permission: &str,
+) -> Vec<PermissionUser> {
+    let consumers = match permission.split_once('/') {
+        Some(_) => index.get(permission).cloned().unwrap_or_default(),
+        None => get_consumers(index, permission),
+    };
+    consumers
+        .into_iter()
+        .filter(|consumer| installed_apps.contains(consumer))
+        .filter_map(|consumer| {
+            let entry = registry.iter().find(|entry| entry.id == consumer)?;
+            let mut usages = Vec::new();
+            if entry
+                .resolved_dependencies
+                .iter()
+                .flatten()
+                .any(|dep| dep == permission)
+            {
+                usages.push(PermissionUsage::Dependency);
+            }
+            usages.extend(
+                entry
+                    .requested_permissions
+                    .iter()
+                    .filter(|requested| matches(requested, permission))
+                    .map(|requested| PermissionUsage::Jinja {
+                        permission: requested.clone(),
+                    }),
+            );
+            usages.extend(
+                entry
+                    .inferred_permissions
+                    .iter()
+                    .filter(|inferred| matches(&inferred.permission, permission))
+                    .map(|inferred| PermissionUsage::Inferred {
+                        permission: inferred.permission.clone(),
+                        source: inferred.source.clone(),
+                    }),
+            );
+            if usages.is_empty() {
+                return None;
+            }
+            Some(PermissionUser {
+                app: consumer,
+                usages,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        composegenerator::types::{Dependency, InferredPermission},
+        dependencies::reverse_index,
+    };
+
+    #[test]
+    fn explains_permissions() {
+        let available = HashMap::from([(
+            "lnd".to_owned(),
+            vec![Permission {
+                id: "admin".to_owned(),
+                name: "Admin".to_owned(),
+                description: "Manage the node".to_owned(),
+                variables: BTreeMap::from([
+                    ("APP_LND_MACAROON".to_owned(), json!("$APP_LND_MACAROON")),
+                    ("APP_LND_CHANNELS".to_owned(), json!(["a", "b"])),
+                ]),
+                files: vec!["admin.macaroon".to_owned()],
+                ..Default::default()
+            }],
+        )]);
+        let names = HashMap::from([("lnd".to_owned(), "LND".to_owned())]);
+
+        let admin = explain("lnd/admin", &available, &names);
+        assert_eq!(admin.name, "Admin");
+        assert_eq!(admin.app.as_deref(), Some("lnd"));
+        assert_eq!(admin.env_vars, vec!["APP_LND_MACAROON"]);
+        assert_eq!(admin.template_variables, vec!["APP_LND_CHANNELS"]);
+        assert_eq!(admin.files, vec!["admin.macaroon"]);
+
+        let whole_app = explain("lnd", &available, &names);
+        assert_eq!(whole_app.name, "LND");
+        assert_eq!(whole_app.files, vec!["admin.macaroon"]);
+
+        assert!(explain("network", &available, &names).host_network);
+        assert_eq!(
+            explain("lnd/missing", &available, &names).name,
+            "lnd/missing"
+        );
+    }
+
+    #[test]
+    fn finds_users_of_permissions() {
+        let registry = vec![
+            OutputMetadata {
+                id: "rtl".to_owned(),
+                dependencies: vec![Dependency::OneDependency("lnd".to_owned())],
+                resolved_dependencies: vec![Some("lnd".to_owned())],
+                requested_permissions: vec!["lnd/admin".to_owned()],
+                has_permissions: vec!["lnd/admin".to_owned(), "lnd/invoices".to_owned()],
+                inferred_permissions: vec![InferredPermission {
+                    permission: "lnd/invoices".to_owned(),
+                    source: PermissionSource::EnvVar {
+                        name: "APP_LND_INVOICE_MACAROON".to_owned(),
+                    },
+                }],
+                ..Default::default()
+            },
+            OutputMetadata {
+                id: "thunderhub".to_owned(),
+                requested_permissions: vec!["lnd/admin".to_owned()],
+                has_permissions: vec!["lnd/admin".to_owned()],
+                ..Default::default()
+            },
+        ];
+        let index = reverse_index(&registry);
+        let installed = vec!["rtl".to_owned(), "lnd".to_owned()];
+
+        let users = find_users(&registry, &index, &installed, "lnd/invoices");
+        assert_eq!(
+            users,
+            vec![PermissionUser {
+                app: "rtl".to_owned(),
+                usages: vec![PermissionUsage::Inferred {
+                    permission: "lnd/invoices".to_owned(),
+                    source: PermissionSource::EnvVar {
+                        name: "APP_LND_INVOICE_MACAROON".to_owned(),
+                    },
+                }],
+            }]
+        );
+
+        // Thunderhub is not installed
+        let users = find_users(&registry, &index, &installed, "lnd");
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].usages.len(), 3);
+        assert_eq!(users[0].usages[0], PermissionUsage::Dependency);
+    }
+}
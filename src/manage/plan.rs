@@ -0,0 +1,403 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::types::{Dependency, OutputMetadata, ValidationMode},
+    config::Config,
+    dependencies::{get_conflicts, sort_deps, Node},
+};
+
+use super::{
+    events::{diff_ports, PortChange},
+    files,
+    instances::split_instance_id,
+    ports::{resolve_port_conflicts, PortMapEntry, PortPolicy},
+};
+
+/// The apps that are available, and the ports they request
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    pub apps: Vec<OutputMetadata>,
+    /// Ports as declared by the apps, before conflicts are resolved
+    pub requested_ports: Vec<PortMapEntry>,
+}
+
+impl Catalog {
+    pub fn load(nirvati_dir: &Path) -> Result<Self> {
+        let mut apps = files::get_app_registry(nirvati_dir)?;
+        // Instances created since the last generate are not in the registry yet
+        for entry in files::get_all_metadata_ymls(nirvati_dir)? {
+            if split_instance_id(&entry.id).1.is_some()
+                && !apps.iter().any(|app| app.id == entry.id)
+            {
+                apps.push(entry);
+            }
+        }
+        let mut requested_ports = Vec::new();
+        for app in &apps {
+            // Apps that require settings may not have an app.yml yet
+            let Ok(app_yml) = files::read_app_yml(nirvati_dir, &app.id) else {
+                continue;
+            };
+            match app_yml.get_ports(&app.id, app.implements.clone(), ValidationMode::Permissive) {
+                Ok(mut ports) => requested_ports.append(&mut ports),
+                Err(err) => tracing::warn!("Failed to get ports of app {}: {:#}", app.id, err),
+            }
+        }
+        Ok(Self {
+            apps,
+            requested_ports,
+        })
+    }
+
+    fn get(&self, app: &str) -> Option<&OutputMetadata> {
+        self.apps.iter().find(|entry| entry.id == app)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemState {
+    pub installed_apps: Vec<String>,
+    /// The current port map
+    pub ports: Vec<PortMapEntry>,
+    pub port_policy: PortPolicy,
+}
+
+impl SystemState {
+    pub fn load(nirvati_dir: &Path, port_policy: PortPolicy) -> Result<Self> {
+        Ok(Self {
+            installed_apps: files::get_installed_apps(nirvati_dir)?,
+            ports: files::get_port_map(nirvati_dir)?,
+            port_policy,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TargetState {
+    pub installed_apps: Vec<String>,
+}
+
+impl TargetState {
+    /// The installed apps after installing and uninstalling the given apps
+    pub fn from_changes(
+        current_state: &SystemState,
+        install: &[String],
+        uninstall: &[String],
+    ) -> Self {
+        let mut installed_apps = current_state.installed_apps.clone();
+        installed_apps.retain(|app| !uninstall.contains(app));
+        for app in install {
+            if !installed_apps.contains(app) {
+                installed_apps.push(app.clone());
+            }
+        }
+        Self { installed_apps }
+    }
+}
+
+/// The steps needed to get from the current to the target state
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallPlan {
+    /// Apps to install, dependencies first
+    pub install: Vec<String>,
+    /// Apps to uninstall, dependents first
+    pub uninstall: Vec<String>,
+    /// Apps that stay installed, but need to be regenerated
+    pub regenerate: Vec<String>,
+    pub port_changes: Vec<PortChange>,
+    /// Permissions the user has to grant to the apps that are installed
+    pub permission_grants: BTreeMap<String, Vec<String>>,
+}
+
+/// Returns the apps a dependency can be satisfied by
+fn dependency_apps(dependency: &Dependency) -> Vec<&String> {
+    match dependency {
+        Dependency::OneDependency(app) => vec![app],
+        Dependency::AlternativeDependency(apps) => apps.iter().collect(),
+    }
+}
+
+/// Sorts apps so every app comes after the apps of the set it depends on
+fn dependency_order(catalog: &Catalog, apps: &[String]) -> Vec<String> {
+    sort_deps(
+        apps.iter()
+            .map(|app| Node {
+                id: app.clone(),
+                dependencies: catalog
+                    .get(app)
+                    .map(|entry| {
+                        entry
+                            .dependencies
+                            .iter()
+                            .flat_map(dependency_apps)
+                            .filter(|dep| apps.contains(dep))
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect(),
+    )
+}
+
+pub fn compute_install_plan(
+    catalog: &Catalog,
+    current_state: &SystemState,
+    target_state: &TargetState,
+) -> Result<InstallPlan> {
+    let target = &target_state.installed_apps;
+    let current = &current_state.installed_apps;
+    let mut unmet = Vec::new();
+    for app in target {
+        let Some(entry) = catalog.get(app) else {
+            bail!("App {} does not exist", app);
+        };
+        for dependency in &entry.dependencies {
+            let options = dependency_apps(dependency);
+            if !options.iter().any(|dep| target.contains(dep)) {
+                unmet.push(format!(
+                    "{} needs {}",
+                    app,
+                    options
+                        .iter()
+                        .map(|dep| dep.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" or ")
+                ));
+            }
+        }
+    }
+    if !unmet.is_empty() {
+        bail!("Unmet dependencies: {}", unmet.join(", "));
+    }
+    for app in target {
+        let conflicts = get_conflicts(&catalog.apps, app, target);
+        if !conflicts.is_empty() {
+            bail!("{} conflicts with {}", app, conflicts.join(", "));
+        }
+    }
+
+    let to_install = target
+        .iter()
+        .filter(|app| !current.contains(app))
+        .cloned()
+        .collect::<Vec<_>>();
+    let to_uninstall = current
+        .iter()
+        .filter(|app| !target.contains(app))
+        .cloned()
+        .collect::<Vec<_>>();
+    let install = dependency_order(catalog, &to_install);
+    let mut uninstall = dependency_order(catalog, &to_uninstall);
+    uninstall.reverse();
+
+    let (new_ports, apps_with_conflicts) = resolve_port_conflicts(
+        catalog.requested_ports.clone(),
+        target,
+        &current_state.port_policy,
+    );
+    let blocked = apps_with_conflicts
+        .iter()
+        .filter(|app| target.contains(app))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !blocked.is_empty() {
+        bail!("Port conflicts for {}", blocked.join(", "));
+    }
+    let port_changes = diff_ports(&current_state.ports, &new_ports)
+        .into_iter()
+        .filter(|change| target.contains(&change.app))
+        .collect::<Vec<_>>();
+
+    let changed_apps = install.iter().chain(uninstall.iter()).collect::<Vec<_>>();
+    let mut regenerate = Vec::new();
+    for app in target.iter().filter(|app| current.contains(app)) {
+        let Some(entry) = catalog.get(app) else {
+            continue;
+        };
+        let uses_changed_app = entry
+            .dependencies
+            .iter()
+            .flat_map(dependency_apps)
+            .chain(entry.has_permissions.iter())
+            .any(|dep| {
+                let dep_app = dep.split('/').next().unwrap_or_default();
+                changed_apps
+                    .iter()
+                    .any(|changed| changed.as_str() == dep_app)
+            });
+        let ports_moved = port_changes.iter().any(|change| &change.app == app);
+        if uses_changed_app || ports_moved {
+            regenerate.push(app.clone());
+        }
+    }
+    regenerate.sort();
+
+    let permission_grants = install
+        .iter()
+        .filter_map(|app| {
+            let entry = catalog.get(app)?;
+            (!entry.has_permissions.is_empty())
+                .then(|| (app.clone(), entry.has_permissions.clone()))
+        })
+        .collect();
+
+    Ok(InstallPlan {
+        install,
+        uninstall,
+        regenerate,
+        port_changes,
+        permission_grants,
+    })
+}
+
+/// Updates the installed apps and regenerates once
+/// If generating fails, the previous installed apps are restored
+pub fn apply_plan(nirvati_dir: &Path, plan: &InstallPlan, config: &Config) -> Result<()> {
+    for app in &plan.uninstall {
+        files::remove_installed_app(app, nirvati_dir)?;
+    }
+    for app in &plan.install {
+        files::add_installed_app(app, nirvati_dir)?;
+    }
+    if let Err(err) = super::generate(nirvati_dir, config) {
+        for app in &plan.install {
+            files::remove_installed_app(app, nirvati_dir)?;
+        }
+        for app in &plan.uninstall {
+            files::add_installed_app(app, nirvati_dir)?;
+        }
+        if let Err(msg) = super::generate(nirvati_dir, config) {
+            tracing::error!("Failed to generate: {:#}", msg);
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manage::ports::PortPriority;
+    use crate::testing::Fixture;
+    use pretty_assertions::assert_eq;
+
+    fn app(id: &str, dependencies: &[&str], has_permissions: &[&str]) -> OutputMetadata {
+        OutputMetadata {
+            id: id.to_owned(),
+            dependencies: dependencies
+                .iter()
+                .map(|dep| Dependency::OneDependency(dep.to_string()))
+                .collect(),
+            has_permissions: has_permissions
+                .iter()
+                .map(|perm| perm.to_string())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn port(app: &str, public_port: u16) -> PortMapEntry {
+        PortMapEntry {
+            app: app.to_owned(),
+            internal_port: 80,
+            public_port,
+            container: "main".to_owned(),
+            implements: None,
+            priority: PortPriority::Optional,
+        }
+    }
+
+    #[test]
+    fn install_with_dependency() {
+        let catalog = Catalog {
+            apps: vec![
+                app("bitcoin", &[], &[]),
+                app("electrs", &["bitcoin"], &["bitcoin/rpc"]),
+                app("explorer", &[], &["electrs"]),
+            ],
+            requested_ports: vec![port("bitcoin", 8000), port("electrs", 8000)],
+        };
+        let current = SystemState {
+            installed_apps: vec!["explorer".to_owned()],
+            ports: vec![port("bitcoin", 8000), port("electrs", 8001)],
+            port_policy: PortPolicy::default(),
+        };
+        let target = TargetState {
+            installed_apps: vec![
+                "explorer".to_owned(),
+                "electrs".to_owned(),
+                "bitcoin".to_owned(),
+            ],
+        };
+        let plan = compute_install_plan(&catalog, &current, &target).unwrap();
+        assert_eq!(
+            plan,
+            InstallPlan {
+                install: vec!["bitcoin".to_owned(), "electrs".to_owned()],
+                uninstall: vec![],
+                regenerate: vec!["explorer".to_owned()],
+                port_changes: vec![],
+                permission_grants: BTreeMap::from([(
+                    "electrs".to_owned(),
+                    vec!["bitcoin/rpc".to_owned()]
+                )]),
+            }
+        );
+
+        let target = TargetState {
+            installed_apps: vec!["explorer".to_owned(), "electrs".to_owned()],
+        };
+        assert!(compute_install_plan(&catalog, &current, &target).is_err());
+    }
+
+    #[test]
+    fn target_state_from_changes() {
+        let current = SystemState {
+            installed_apps: vec!["a".to_owned(), "b".to_owned()],
+            ..Default::default()
+        };
+        let target = TargetState::from_changes(
+            &current,
+            &["c".to_owned(), "b".to_owned()],
+            &["a".to_owned()],
+        );
+        assert_eq!(target.installed_apps, vec!["b".to_owned(), "c".to_owned()]);
+    }
+
+    #[test]
+    fn failed_plans_restore_the_installed_apps() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures");
+        crate::offline::set_offline(true);
+
+        let fixture = Fixture::load(&fixtures.join("basic")).unwrap();
+        let plan = InstallPlan {
+            install: vec!["example".to_owned()],
+            ..Default::default()
+        };
+        apply_plan(fixture.root(), &plan, &Config::default()).unwrap();
+        assert_eq!(
+            files::get_installed_apps(fixture.root()).unwrap(),
+            vec!["example".to_owned()]
+        );
+
+        // Strict mode fails because of the broken app, so installing example is undone
+        let fixture = Fixture::load(&fixtures.join("failing-app")).unwrap();
+        let config = Config {
+            strict: true,
+            ..Default::default()
+        };
+        let err = apply_plan(fixture.root(), &plan, &config).unwrap_err();
+        assert!(err.to_string().contains("broken"));
+        assert_eq!(
+            files::get_installed_apps(fixture.root()).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -1,7 +1,14 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::RangeInclusive,
+};
+
+use crate::{composegenerator::types::ResultYml, utils::StringLike};
+
+use super::dns::{env_var_prefix, visible_apps};
 
 // A port map as used during creating the port map
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
@@ -20,6 +27,101 @@ pub static RESERVED_PORTS: [u16; 2] = [
     443, // HTTPS
 ];
 
+/// Which public ports may be assigned to apps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortPolicy {
+    /// Ports no app may use, always including RESERVED_PORTS
+    pub reserved: BTreeSet<u16>,
+    /// Ports entries are moved to when their public port is taken
+    pub dynamic_range: RangeInclusive<u16>,
+}
+
+impl Default for PortPolicy {
+    fn default() -> Self {
+        Self {
+            reserved: BTreeSet::from(RESERVED_PORTS),
+            dynamic_range: 1..=u16::MAX,
+        }
+    }
+}
+
+impl PortPolicy {
+    pub fn new(extra_reserved: &[u16], dynamic_range: RangeInclusive<u16>) -> Self {
+        let mut reserved = BTreeSet::from(RESERVED_PORTS);
+        reserved.extend(extra_reserved);
+        Self {
+            reserved,
+            dynamic_range,
+        }
+    }
+
+    pub fn is_reserved(&self, port: u16) -> bool {
+        self.reserved.contains(&port)
+    }
+
+    /// Finds the first free port after `port`, wrapping around at the end of the dynamic range
+    fn find_free_port(&self, port: u16, used: &HashMap<u16, PortMapEntry>) -> Option<u16> {
+        let (start, end) = (*self.dynamic_range.start(), *self.dynamic_range.end());
+        let first = port.saturating_add(1).clamp(start, end);
+        (first..=end)
+            .chain(start..first)
+            .find(|port| !used.contains_key(port) && !self.is_reserved(*port))
+    }
+}
+
+/// Returns the local ports of all sockets in the given state from a /proc/net/{tcp,udp}{,6} table
+fn parse_proc_net(table: &str, state: &str) -> Vec<u16> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace().skip(1);
+            let local_address = columns.next()?;
+            let socket_state = columns.nth(1)?;
+            if socket_state != state {
+                return None;
+            }
+            let (_, port) = local_address.rsplit_once(':')?;
+            u16::from_str_radix(port, 16).ok()
+        })
+        .collect()
+}
+
+/// Returns all ports that currently have a listening TCP socket or a bound UDP socket on the host
+pub fn get_host_ports_in_use() -> BTreeSet<u16> {
+    // TCP_LISTEN and TCP_CLOSE, which is what unconnected UDP sockets report
+    let tables = [
+        ("/proc/net/tcp", "0A"),
+        ("/proc/net/tcp6", "0A"),
+        ("/proc/net/udp", "07"),
+        ("/proc/net/udp6", "07"),
+    ];
+    let mut ports = BTreeSet::new();
+    for (path, state) in tables {
+        match std::fs::read_to_string(path) {
+            Ok(table) => ports.extend(parse_proc_net(&table, state)),
+            Err(err) => tracing::warn!("Failed to read {}: {:#}", path, err),
+        }
+    }
+    ports
+}
+
+/// Moves an entry to a free port, or marks its app as conflicting if the dynamic range is exhausted
+fn move_to_free_port(
+    mut entry: PortMapEntry,
+    cache: &mut HashMap<u16, PortMapEntry>,
+    apps_with_conflicts: &mut Vec<String>,
+    policy: &PortPolicy,
+) {
+    if let Some(new_port) = policy.find_free_port(entry.public_port, cache) {
+        entry.public_port = new_port;
+        cache.insert(new_port, entry);
+    } else {
+        cache.retain(|_, v| v.app != entry.app);
+        apps_with_conflicts.push(entry.app);
+    }
+}
+
 #[derive(
     Serialize_repr,
     Deserialize_repr,
@@ -48,6 +150,7 @@ pub enum PortPriority {
 pub fn resolve_port_conflicts(
     mut entries: Vec<PortMapEntry>,
     installed_apps: &[String],
+    policy: &PortPolicy,
 ) -> (Vec<PortMapEntry>, Vec<String>) {
     // Resolve any conflicts between apps public_port
     let mut cache = HashMap::new();
@@ -70,20 +173,14 @@ pub fn resolve_port_conflicts(
         if apps_with_conflicts.contains(&entry.app) {
             continue;
         }
-        if RESERVED_PORTS.contains(&entry.public_port) {
+        if policy.is_reserved(entry.public_port) {
             if entry.priority == PortPriority::Required {
                 apps_with_conflicts.push(entry.app.clone());
                 // Remove any existing entries from this app
                 cache.retain(|_, v: &mut PortMapEntry| v.app != entry.app);
             } else {
                 // Move the entry to a new, free port
-                let mut new_port = entry.public_port;
-                while cache.contains_key(&new_port) || RESERVED_PORTS.contains(&new_port) {
-                    new_port += 1;
-                }
-                let mut new_entry = entry.clone();
-                new_entry.public_port = new_port;
-                cache.insert(new_port, new_entry);
+                move_to_free_port(entry, &mut cache, &mut apps_with_conflicts, policy);
             }
         } else if cache.contains_key(&entry.public_port) {
             let other = cache.get(&entry.public_port).cloned().unwrap();
@@ -102,14 +199,8 @@ pub fn resolve_port_conflicts(
             }
             if entry.priority > other.priority {
                 // Move the other entry to a new, free port
-                let mut new_port = entry.public_port;
-                while cache.contains_key(&new_port) || RESERVED_PORTS.contains(&new_port) {
-                    new_port += 1;
-                }
-                let mut new_entry = other.clone();
-                new_entry.public_port = new_port;
-                cache.insert(new_port, new_entry);
                 cache.insert(entry.public_port, entry);
+                move_to_free_port(other, &mut cache, &mut apps_with_conflicts, policy);
             } else if entry.priority == PortPriority::Required {
                 apps_with_conflicts.push(entry.app.clone());
                 // Remove any existing entries from this app
@@ -118,33 +209,15 @@ pub fn resolve_port_conflicts(
                 // To make sorting more deterministic, we'll use the app name as a tiebreaker
                 if entry.app < other.app {
                     // Move the other entry to a new, free port
-                    let mut new_port = entry.public_port;
-                    while cache.contains_key(&new_port) || RESERVED_PORTS.contains(&new_port) {
-                        new_port += 1;
-                    }
-                    let mut new_entry = other.clone();
-                    new_entry.public_port = new_port;
-                    cache.insert(new_port, new_entry);
                     cache.insert(entry.public_port, entry);
+                    move_to_free_port(other, &mut cache, &mut apps_with_conflicts, policy);
                 } else {
                     // Move the entry to a new, free port
-                    let mut new_port = entry.public_port;
-                    while cache.contains_key(&new_port) || RESERVED_PORTS.contains(&new_port) {
-                        new_port += 1;
-                    }
-                    let mut new_entry = entry.clone();
-                    new_entry.public_port = new_port;
-                    cache.insert(new_port, new_entry);
+                    move_to_free_port(entry, &mut cache, &mut apps_with_conflicts, policy);
                 }
             } else {
                 // Move the entry to a new, free port
-                let mut new_port = entry.public_port;
-                while cache.contains_key(&new_port) || RESERVED_PORTS.contains(&new_port) {
-                    new_port += 1;
-                }
-                let mut new_entry = entry.clone();
-                new_entry.public_port = new_port;
-                cache.insert(new_port, new_entry);
+                move_to_free_port(entry, &mut cache, &mut apps_with_conflicts, policy);
             }
         } else {
             cache.insert(entry.public_port, entry);
@@ -163,12 +236,99 @@ pub fn resolve_port_conflicts(
     (result, apps_with_conflicts)
 }
 
+/// The public ports of containers, app -> container -> internal port -> public port
+pub type AssignedPorts = BTreeMap<String, BTreeMap<String, BTreeMap<u16, u16>>>;
+
+pub fn assigned_ports(port_map: &[PortMapEntry], apps: &[&str]) -> AssignedPorts {
+    let mut result = AssignedPorts::new();
+    for entry in port_map
+        .iter()
+        .filter(|entry| apps.contains(&entry.app.as_str()))
+    {
+        result
+            .entry(entry.app.clone())
+            .or_default()
+            .entry(entry.container.clone())
+            .or_default()
+            .insert(entry.internal_port, entry.public_port);
+    }
+    result
+}
+
+/// Exposes the public ports of an app's own containers and the apps it has permissions for as env vars,
+/// <prefix>_PUBLIC_PORT_<internal port> for every port and <prefix>_PUBLIC_PORT for the lowest internal port
+pub fn apply_to_result(result: &mut ResultYml, app: &str, port_map: &[PortMapEntry]) {
+    let assigned = assigned_ports(
+        port_map,
+        &visible_apps(app, &result.metadata.has_permissions),
+    );
+    let mut env_vars = BTreeMap::new();
+    for (visible_app, containers) in &assigned {
+        for (container, ports) in containers {
+            let prefix = env_var_prefix(visible_app, container);
+            if let Some(public_port) = ports.values().next() {
+                env_vars.insert(format!("{}_PUBLIC_PORT", prefix), public_port.to_string());
+            }
+            for (internal_port, public_port) in ports {
+                env_vars.insert(
+                    format!("{}_PUBLIC_PORT_{}", prefix, internal_port),
+                    public_port.to_string(),
+                );
+            }
+        }
+    }
+    for service in result.spec.services.values_mut() {
+        for (key, value) in &env_vars {
+            service
+                .environment
+                .entry(key.clone())
+                .or_insert_with(|| StringLike::String(value.clone()));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_proc_net_tables() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:BC8F 00000000:0000 0A 00000000:00000000 00:00000000 00000000 65534        0 1069 1
+   1: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 662 1
+   2: 0100007F:BC8F 0100007F:9C40 01 00000000:00000000 00:00000000 00000000     0        0 663 1";
+        assert_eq!(parse_proc_net(table, "0A"), vec![48271, 22]);
+        assert!(parse_proc_net(table, "07").is_empty());
+    }
+
+    #[test]
+    fn assigned_ports_of_visible_apps() {
+        let entry = |app: &str, public_port| PortMapEntry {
+            app: app.to_owned(),
+            internal_port: 80,
+            public_port,
+            container: "main".to_owned(),
+            implements: None,
+            priority: PortPriority::Optional,
+        };
+        let port_map = vec![entry("app1", 81), entry("app2", 82), entry("app3", 83)];
+        assert_eq!(
+            assigned_ports(&port_map, &["app1", "app3"]),
+            AssignedPorts::from([
+                (
+                    "app1".to_owned(),
+                    BTreeMap::from([("main".to_owned(), BTreeMap::from([(80, 81)]))])
+                ),
+                (
+                    "app3".to_owned(),
+                    BTreeMap::from([("main".to_owned(), BTreeMap::from([(80, 83)]))])
+                ),
+            ])
+        );
+    }
+
     mod resolve_port_conflicts {
-        use super::{resolve_port_conflicts, PortMapEntry, PortPriority};
+        use super::{resolve_port_conflicts, PortMapEntry, PortPolicy, PortPriority};
         use pretty_assertions::assert_eq;
         #[test]
         fn basic() {
@@ -198,7 +358,8 @@ mod tests {
                     priority: PortPriority::Optional,
                 },
             ];
-            let (resolved, conflicts) = resolve_port_conflicts(entries, &[]);
+            let (resolved, conflicts) =
+                resolve_port_conflicts(entries, &[], &PortPolicy::default());
             assert_eq!(
                 resolved,
                 vec![
@@ -258,7 +419,8 @@ mod tests {
                     priority: PortPriority::Optional,
                 },
             ];
-            let (resolved, conflicts) = resolve_port_conflicts(entries, &[]);
+            let (resolved, conflicts) =
+                resolve_port_conflicts(entries, &[], &PortPolicy::default());
             assert_eq!(
                 resolved,
                 vec![
@@ -311,7 +473,8 @@ mod tests {
                     priority: PortPriority::Required,
                 },
             ];
-            let (resolved, conflicts) = resolve_port_conflicts(entries, &[]);
+            let (resolved, conflicts) =
+                resolve_port_conflicts(entries, &[], &PortPolicy::default());
             assert_eq!(
                 resolved,
                 vec![PortMapEntry {
@@ -346,7 +509,8 @@ mod tests {
                     priority: PortPriority::Required,
                 },
             ];
-            let (resolved, conflicts) = resolve_port_conflicts(entries, &["app2".to_owned()]);
+            let (resolved, conflicts) =
+                resolve_port_conflicts(entries, &["app2".to_owned()], &PortPolicy::default());
             assert_eq!(
                 resolved,
                 vec![PortMapEntry {
@@ -381,9 +545,134 @@ mod tests {
                     priority: PortPriority::Required,
                 },
             ];
-            let (resolved, conflicts) = resolve_port_conflicts(entries, &[]);
+            let (resolved, conflicts) =
+                resolve_port_conflicts(entries, &[], &PortPolicy::default());
             assert!(resolved.is_empty());
             assert_eq!(conflicts, vec!["app1".to_owned(), "app2".to_owned()]);
         }
+
+        fn optional(app: &str, public_port: u16) -> PortMapEntry {
+            PortMapEntry {
+                app: app.to_owned(),
+                internal_port: 80,
+                public_port,
+                container: "main".to_owned(),
+                implements: None,
+                priority: PortPriority::Optional,
+            }
+        }
+
+        #[test]
+        fn configured_reserved_ports_and_range() {
+            let policy = PortPolicy::new(&[2000, 3001], 3000..=3002);
+            let entries = vec![optional("app1", 2000), optional("app2", 3002)];
+            let (resolved, conflicts) = resolve_port_conflicts(entries.clone(), &[], &policy);
+            // app1 is moved into the range, skipping the reserved 3001 and the taken 3002
+            assert_eq!(
+                resolved,
+                vec![optional("app1", 3000), optional("app2", 3002)]
+            );
+            assert!(conflicts.is_empty());
+
+            // Once the range is exhausted, the app that would have to move gets a conflict
+            let policy = PortPolicy::new(&[2000, 3000, 3001], 3000..=3002);
+            let (resolved, conflicts) = resolve_port_conflicts(entries, &[], &policy);
+            assert_eq!(resolved, vec![optional("app1", 3002)]);
+            assert_eq!(conflicts, vec!["app2".to_owned()]);
+        }
+    }
+
+    mod two_stages {
+        use std::path::Path;
+
+        use crate::{config::Config, manage::files, testing::Fixture};
+
+        /// An app that wants port 80 and gets its public port in the second stage
+        fn app_yml_jinja(app: &str) -> String {
+            let public_port = format!("{{{{ assigned_ports['{}'].main['80'] }}}}", app);
+            [
+                "version: 1",
+                "services:",
+                "  main:",
+                "    image: nginx:1.25-alpine",
+                "    port: 80",
+                "    environment:",
+                &format!(
+                    "      PUBLIC_PORT: \"{{% raw %}}{}{{% endraw %}}\"",
+                    public_port
+                ),
+                "",
+            ]
+            .join("\n")
+        }
+
+        fn fixture() -> Fixture {
+            let fixture = Fixture::load(
+                &Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests")
+                    .join("fixtures")
+                    .join("basic"),
+            )
+            .unwrap();
+            let apps_dir = fixture.root().join("apps");
+            std::fs::create_dir_all(apps_dir.join("other")).unwrap();
+            std::fs::copy(
+                apps_dir.join("example").join("metadata.yml"),
+                apps_dir.join("other").join("metadata.yml"),
+            )
+            .unwrap();
+            for app in ["example", "other"] {
+                std::fs::write(apps_dir.join(app).join("app.yml.jinja"), app_yml_jinja(app))
+                    .unwrap();
+            }
+            fixture
+        }
+
+        fn public_port(root: &Path, app: &str) -> u16 {
+            files::get_port_map(root)
+                .unwrap()
+                .into_iter()
+                .find(|entry| entry.app == app)
+                .unwrap_or_else(|| panic!("{} has no port", app))
+                .public_port
+        }
+
+        fn app_yml(root: &Path, app: &str) -> String {
+            std::fs::read_to_string(root.join("apps").join(app).join("app.yml")).unwrap()
+        }
+
+        #[test]
+        fn second_stage_gets_the_resolved_ports() {
+            let fixture = fixture();
+            let config = Config {
+                reserved_ports: vec![81],
+                ..Default::default()
+            };
+            let failed = crate::manage::generate(fixture.root(), &config).unwrap();
+            assert!(failed.is_empty());
+
+            let example = public_port(fixture.root(), "example");
+            let other = public_port(fixture.root(), "other");
+            // 80 is always reserved and 81 is reserved in the config
+            assert!(![80, 81].contains(&example));
+            assert!(![80, 81].contains(&other));
+            // Both apps want the same port, so one of them has to move again
+            assert_ne!(example, other);
+            for (app, port) in [("example", example), ("other", other)] {
+                assert!(
+                    app_yml(fixture.root(), app).contains(&format!("PUBLIC_PORT: \"{}\"", port))
+                );
+            }
+
+            // Another run keeps the ports and renders the same app.yml files
+            let port_map = files::get_port_map(fixture.root()).unwrap();
+            let app_ymls = ["example", "other"].map(|app| app_yml(fixture.root(), app));
+            crate::manage::generate(fixture.root(), &config).unwrap();
+            assert_eq!(files::get_port_map(fixture.root()).unwrap(), port_map);
+            assert_eq!(
+                ["example", "other"].map(|app| app_yml(fixture.root(), app)),
+                app_ymls
+            );
+        }
     }
 }
@@ -1,61 +1,222 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::bail;
+use serde::Serialize;
 
-use crate::{composegenerator::types::Permission, tera::process_app_yml_jinja};
+use crate::{
+    composegenerator::types::{
+        Diagnostic, DiagnosticCode, OutputMetadata, Permission, ValidationMode,
+    },
+    config::Config,
+    dependencies::{
+        mark_conflicts, mark_disabled_dependencies, resolve_dependencies, reverse_index,
+    },
+    tera::process_app_yml_jinja,
+};
 
 use super::{
-    files::{read_app_yml, read_metadata_yml},
-    ports::resolve_port_conflicts,
+    cancel, canonical, categories, changelog,
+    claims::resolve_claims,
+    compat, dirs, dns,
+    files::{
+        get_disk_quotas, get_dns_map, get_firewall_rules, get_port_map, get_quota_entries,
+        get_scrape_targets, get_shutdown_entries, get_traffic_entries, get_upnp_entries,
+        parse_app_yml, read_app_yml, read_app_yml_with_unknown_keys, read_metadata_yml,
+        save_data_dirs, save_dns_map, save_firewall_rules, save_port_map, save_quota_entries,
+        save_scrape_targets, save_shutdown_entries, save_snapshot_entries, save_tasks,
+        save_traffic_entries, save_upnp_entries,
+    },
+    firewall,
+    hooks::{notify, HookEvent},
+    maintenance, metrics,
+    ports::{self, resolve_port_conflicts, PortMapEntry},
+    profile, progress, quota, search, shutdown, snapshots, traffic, upnp, user_env,
 };
 
+/// An app that failed to generate, the other apps are generated anyway
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedApp {
+    pub app: String,
+    #[serde(flatten)]
+    pub diagnostic: Diagnostic,
+}
+
+impl FailedApp {
+    pub fn new(app: &str, code: DiagnosticCode, err: &anyhow::Error) -> Self {
+        FailedApp {
+            app: app.to_owned(),
+            diagnostic: Diagnostic::error(compat::failure_code(err, code), format!("{:#}", err)),
+        }
+    }
+}
+
+/// Returned by process_app_ymls if the kept ports of apps that are not processed would have to move
+#[derive(Debug)]
+pub struct KeptPortsMoved;
+
+impl std::fmt::Display for KeptPortsMoved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ports of apps that are not processed would have to move")
+    }
+}
+
+impl std::error::Error for KeptPortsMoved {}
+
+/// Generates the given apps and returns the ones that failed, which keep their last outputs
+/// kept_ports are the port map entries of apps that are not processed, which stay as they are
+/// Apps in earlier_failures failed before, for example while rendering their metadata, and are skipped
 pub fn process_app_ymls(
     nirvati_root: &Path,
     sorted_apps: &[String],
     mut available_permissions: HashMap<String, Vec<Permission>>,
-) -> anyhow::Result<()> {
+    kept_ports: Vec<PortMapEntry>,
+    earlier_failures: Vec<FailedApp>,
+    config: &Config,
+) -> anyhow::Result<Vec<FailedApp>> {
     let installed_apps = super::files::get_installed_apps(nirvati_root)?;
     let apps_dir = nirvati_root.join("apps");
     let mut new_registry_entries = Vec::new();
-    let mut available_permissions_strings = available_permissions
-        .iter()
-        .flat_map(|(k, v)| {
-            let mut permissions = v
-                .iter()
-                .map(|p| format!("{}/{}", k, p.id))
-                .collect::<Vec<_>>();
-            permissions.push(k.to_owned());
-            permissions
-        })
-        .collect::<Vec<_>>();
+    let mut available_permissions_strings = super::get_permission_strings(&available_permissions);
     let mut all_ports = Vec::new();
-    for app in sorted_apps {
+    let mode = config.validation_mode();
+    // Apps that failed to render or convert, with the reason
+    let mut failed_apps: Vec<(String, Diagnostic)> = earlier_failures
+        .into_iter()
+        .map(|failed| (failed.app, failed.diagnostic))
+        .collect();
+    // Apps that need a newer app manager, with the metadata to list them in the registry
+    let mut too_new_apps: Vec<OutputMetadata> = Vec::new();
+    // The second stages are rendered once ports are resolved
+    let mut first_stages = Vec::new();
+    // Claims of apps that are not processed are taken from the registry
+    let mut claims = super::files::get_app_registry(nirvati_root)?
+        .into_iter()
+        .map(|entry| (entry.id, entry.claims))
+        .collect::<BTreeMap<_, _>>();
+    for (index, app) in sorted_apps.iter().enumerate() {
+        cancel::check()?;
+        progress::report("stage1", Some(app), index + 1, sorted_apps.len());
+        if failed_apps.iter().any(|(failed_app, _)| failed_app == app) {
+            continue;
+        }
         let app_dir = apps_dir.join(app);
-        let Ok(metadata) = read_metadata_yml(&nirvati_root, app) else {
-            tracing::warn!("Failed to read metadata for app {}", app);
-            continue
-        };
-        let app_yml_jinja = app_dir.join("app.yml.jinja");
-        if app_yml_jinja.exists() {
-            if let Err(err) = process_app_yml_jinja(
-                app_yml_jinja,
-                metadata.get_app_yml_jinja_permissions(),
-                &installed_apps,
-                &available_permissions_strings,
-                &available_permissions,
-                nirvati_root,
-            ) {
-                tracing::error!("Failed to process app.yml.jinja for app {}: {:#}", app, err);
+        let metadata = match read_metadata_yml(nirvati_root, app) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                tracing::warn!("Failed to read metadata for app {}: {:#}", app, err);
+                failed_apps.push((
+                    app.to_owned(),
+                    Diagnostic::error(
+                        compat::failure_code(&err, DiagnosticCode::ConversionFailed),
+                        format!("{:#}", err),
+                    ),
+                ));
                 continue;
             }
+        };
+        let basic_metadata = metadata.get_basic_output_metadata(app.to_owned());
+        if let Some(diagnostic) =
+            compat::check_manager_version(basic_metadata.min_manager_version.as_deref())
+        {
+            tracing::warn!("Skipping app {}: {}", app, diagnostic.message);
+            failed_apps.push((app.to_owned(), diagnostic));
+            too_new_apps.push(basic_metadata);
+            continue;
         }
-        let app_yml = app_dir.join("app.yml");
-        if app_yml.exists() {
-            let app_yml = read_app_yml(&nirvati_root, app)?;
-            let mut ports = app_yml.get_ports(
+        claims.insert(app.to_owned(), basic_metadata.claims.clone());
+        let app_yml_jinja = app_dir.join("app.yml.jinja");
+        let app_yml = if app_yml_jinja.exists() {
+            let first_stage = profile::measure("stage1", Some(app), || {
+                process_app_yml_jinja(
+                    app_yml_jinja,
+                    &metadata,
+                    &installed_apps,
+                    &available_permissions_strings,
+                    &available_permissions,
+                    nirvati_root,
+                )
+            });
+            let rendered = first_stage.and_then(|first_stage| {
+                match parse_app_yml(&first_stage.rendered) {
+                    Ok(app_yml) => {
+                        first_stages.push((app.to_owned(), first_stage));
+                        Ok(Some(app_yml))
+                    }
+                    // Second stage blocks can make the first stage invalid YAML,
+                    // then the ports are taken from the app.yml rendered with the ports of the last generate
+                    Err(_) => {
+                        let app_yml =
+                            first_stage.render(nirvati_root, &get_port_map(nirvati_root)?)?;
+                        std::fs::write(app_dir.join("app.yml"), &app_yml)?;
+                        Ok(Some(parse_app_yml(&app_yml)?))
+                    }
+                }
+            });
+            match rendered {
+                Ok(app_yml) => app_yml,
+                Err(err) => {
+                    tracing::error!("Failed to process app.yml.jinja for app {}: {:#}", app, err);
+                    let code = compat::failure_code(&err, DiagnosticCode::RenderFailed);
+                    if code == DiagnosticCode::ManagerTooOld {
+                        too_new_apps.push(basic_metadata);
+                    }
+                    failed_apps.push((
+                        app.to_owned(),
+                        Diagnostic::error(code, format!("{:#}", err)),
+                    ));
+                    continue;
+                }
+            }
+        } else if app_dir.join("app.yml").exists() {
+            match read_app_yml(nirvati_root, app) {
+                Ok(app_yml) => Some(app_yml),
+                Err(err) => {
+                    tracing::error!("Failed to read app.yml for app {}: {:#}", app, err);
+                    let code = compat::failure_code(&err, DiagnosticCode::ConversionFailed);
+                    if code == DiagnosticCode::ManagerTooOld {
+                        too_new_apps.push(basic_metadata);
+                    }
+                    failed_apps.push((
+                        app.to_owned(),
+                        Diagnostic::error(code, format!("{:#}", err)),
+                    ));
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(app_yml) = app_yml {
+            if let Some(app_claims) = claims.get_mut(app) {
+                app_claims.extend(
+                    app_yml
+                        .get_network_aliases()
+                        .iter()
+                        .map(|alias| dns::alias_claim(alias)),
+                );
+            }
+            let ports = app_yml.get_ports(
                 app,
                 metadata
                     .get_basic_output_metadata(app.to_string())
                     .implements,
+                mode,
             );
+            let mut ports = match ports {
+                Ok(ports) => ports,
+                Err(err) => {
+                    tracing::error!("Invalid ports for app {}: {:#}", app, err);
+                    failed_apps.push((
+                        app.to_owned(),
+                        Diagnostic::error(DiagnosticCode::ConversionFailed, format!("{:#}", err)),
+                    ));
+                    continue;
+                }
+            };
             all_ports.append(&mut ports);
             let app_available_permissions = app_yml.into_exported_permissions();
             available_permissions.insert(app.to_owned(), app_available_permissions.clone());
@@ -80,40 +241,311 @@ pub fn process_app_ymls(
             tracing::warn!("App {} does not have an app.yml", app);
         }
     }
-    let (all_ports, apps_with_conflicts) = resolve_port_conflicts(all_ports, &installed_apps);
-    let apps_to_convert = sorted_apps.iter().filter(|app| {
-        let app_dir = apps_dir.join(app);
-        let app_yml = app_dir.join("app.yml");
-        app_yml.exists() && !apps_with_conflicts.contains(app)
+    all_ports.extend(kept_ports.iter().cloned());
+    let port_policy = super::get_port_policy(nirvati_root, config)?;
+    cancel::check()?;
+    progress::report("ports", None, 1, 1);
+    let (all_ports, apps_with_conflicts) = profile::measure("ports", None, || {
+        resolve_port_conflicts(all_ports, &installed_apps, &port_policy)
     });
+    if kept_ports.iter().any(|port| !all_ports.contains(port)) {
+        return Err(KeptPortsMoved.into());
+    }
+    save_port_map(nirvati_root, all_ports.clone())?;
+    let second_stages = first_stages.len();
+    for (index, (app, first_stage)) in first_stages.into_iter().enumerate() {
+        cancel::check()?;
+        progress::report("stage2", Some(&app), index + 1, second_stages);
+        let rendered = profile::measure("stage2", Some(&app), || {
+            first_stage.render(nirvati_root, &all_ports)
+        })
+        .and_then(|app_yml| {
+            Ok(std::fs::write(
+                apps_dir.join(&app).join("app.yml"),
+                app_yml,
+            )?)
+        });
+        if let Err(err) = rendered {
+            tracing::error!("Failed to process app.yml.jinja for app {}: {:#}", app, err);
+            failed_apps.push((
+                app,
+                Diagnostic::error(DiagnosticCode::RenderFailed, format!("{:#}", err)),
+            ));
+        }
+    }
+    let (granted_claims, apps_with_claim_conflicts) = resolve_claims(&claims, &installed_apps);
+    for app in sorted_apps
+        .iter()
+        .filter(|app| apps_with_claim_conflicts.contains(app))
+    {
+        let holders = claims[app]
+            .iter()
+            .filter_map(|claim| {
+                let holder = granted_claims.get(claim)?;
+                Some(format!("{} is claimed by {}", claim, holder))
+            })
+            .collect::<Vec<_>>();
+        tracing::warn!("App {} has conflicting claims", app);
+        failed_apps.push((
+            app.to_owned(),
+            Diagnostic::error(DiagnosticCode::ClaimConflict, holders.join(", ")),
+        ));
+    }
+    let apps_to_convert = sorted_apps
+        .iter()
+        .filter(|app| {
+            let app_dir = apps_dir.join(app);
+            let app_yml = app_dir.join("app.yml");
+            app_yml.exists()
+                && !apps_with_conflicts.contains(app)
+                && !failed_apps.iter().any(|(failed_app, _)| failed_app == *app)
+        })
+        .collect::<Vec<_>>();
     for app in &apps_with_conflicts {
         tracing::warn!("App {} has conflicting ports", app);
+        failed_apps.push((
+            app.to_owned(),
+            Diagnostic::error(
+                DiagnosticCode::PortConflict,
+                "A required port is reserved or used by another app".to_owned(),
+            ),
+        ));
     }
-    for app in apps_to_convert {
-        let app_dir = apps_dir.join(app);
-        let app_yml = read_app_yml(&nirvati_root, app)?;
-        let metadata = read_metadata_yml(&nirvati_root, app)?;
+    if !apps_with_conflicts.is_empty() {
+        notify(
+            nirvati_root,
+            HookEvent::PortConflict {
+                apps: apps_with_conflicts.clone(),
+            },
+        );
+    }
+    let digest_pins = super::files::get_digest_pins(nirvati_root)?;
+    let mut results = Vec::new();
+    for (index, app) in apps_to_convert.iter().copied().enumerate() {
+        cancel::check()?;
+        progress::report("convert", Some(app), index + 1, apps_to_convert.len());
+        let inputs = read_app_yml_with_unknown_keys(nirvati_root, app).and_then(
+            |(app_yml, unknown_keys)| {
+                Ok((app_yml, unknown_keys, read_metadata_yml(nirvati_root, app)?))
+            },
+        );
+        let (mut app_yml, unknown_keys, metadata) = match inputs {
+            Ok(inputs) => inputs,
+            Err(err) => {
+                tracing::error!("Failed to read app {}: {:#}", app, err);
+                failed_apps.push((
+                    app.to_owned(),
+                    Diagnostic::error(
+                        compat::failure_code(&err, DiagnosticCode::ConversionFailed),
+                        format!("{:#}", err),
+                    ),
+                ));
+                continue;
+            }
+        };
+        if let Some(pins) = digest_pins.get(app) {
+            app_yml.pin_images(pins);
+        }
         // TODO: Once drain_filter is stable, use that here
         let app_ports = all_ports
             .iter()
             .filter(|port| &port.app == app)
             .map(|port| port.to_owned())
             .collect::<Vec<_>>();
-        let result = app_yml.convert(app, &app_ports, metadata, &available_permissions);
-        let Ok(result) = result else {
-            tracing::error!("Failed to convert app.yml for app {}", app);
-            tracing::error!("{:#}", result.unwrap_err());
-            continue;
+        let result = profile::measure("convert", Some(app), || {
+            app_yml.convert(
+                app,
+                &app_ports,
+                metadata,
+                &available_permissions,
+                mode,
+                &config.logging,
+                config.ipv6_subnet.is_some(),
+            )
+        });
+        let mut result = match result {
+            Ok(result)
+                if !config.allow_local_builds
+                    && result
+                        .spec
+                        .services
+                        .values()
+                        .any(|service| service.build.is_some()) =>
+            {
+                tracing::warn!("App {} builds images locally, which is not allowed", app);
+                failed_apps.push((
+                    app.to_owned(),
+                    Diagnostic::error(
+                        DiagnosticCode::ConversionFailed,
+                        "Local builds are disabled, enable allow_local_builds to build this app"
+                            .to_owned(),
+                    ),
+                ));
+                continue;
+            }
+            Ok(result) => result,
+            Err(err) => {
+                tracing::error!("Failed to convert app.yml for app {}", app);
+                tracing::error!("{:#}", err);
+                failed_apps.push((
+                    app.to_owned(),
+                    Diagnostic::error(DiagnosticCode::ConversionFailed, format!("{:#}", err)),
+                ));
+                continue;
+            }
+        };
+        for diagnostic in &unknown_keys {
+            tracing::warn!("App {}: {}", app, diagnostic.message);
+        }
+        result.metadata.diagnostics.extend(unknown_keys);
+        let mut data_dirs = app_yml.get_data_dirs();
+        let pool_data_dir = match super::files::get_storage_pool(nirvati_root, app) {
+            Ok(Some(pool)) if pool != dirs::DEFAULT_POOL => {
+                Some(dirs::try_app_data_dir(nirvati_root, app))
+            }
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
         };
+        if let Some(data_dir) = pool_data_dir {
+            match data_dir {
+                Ok(data_dir) => dirs::apply_pool(&mut result, &mut data_dirs, &data_dir),
+                Err(err) => {
+                    tracing::error!("{:#}", err);
+                    failed_apps.push((
+                        app.to_owned(),
+                        Diagnostic::error(DiagnosticCode::ConversionFailed, format!("{:#}", err)),
+                    ));
+                    continue;
+                }
+            }
+        }
+        let written = profile::measure("write", Some(app), || -> anyhow::Result<()> {
+            save_data_dirs(nirvati_root, app, &data_dirs)?;
+            save_tasks(nirvati_root, app, &result.tasks)
+        });
+        if let Err(err) = written {
+            tracing::error!("Failed to write the outputs of app {}: {:#}", app, err);
+            failed_apps.push((
+                app.to_owned(),
+                Diagnostic::error(DiagnosticCode::ConversionFailed, format!("{:#}", err)),
+            ));
+            continue;
+        }
+        results.push((app, result));
+    }
+    let services = results
+        .iter()
+        .map(|(app, result)| {
+            let services = result.spec.services.keys().cloned().collect::<Vec<_>>();
+            (app.to_string(), services)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let store_ids = crate::repos::StoreIds::load(nirvati_root)?;
+    let known_categories = categories::known_categories(nirvati_root)?;
+    let maintenance_apps = super::files::get_maintenance_apps(nirvati_root)?;
+    let disabled_apps = super::files::get_disabled_apps(nirvati_root)?;
+    // Outputs host scripts use to run apps leave out disabled apps
+    let apps_to_run = super::files::get_apps_to_run(nirvati_root)?;
+    let mut dns_map = dns::assign_addresses(
+        &get_dns_map(nirvati_root)?,
+        &services,
+        &config.subnet,
+        config.ipv6_subnet.as_deref(),
+    )?;
+    for (app, result) in &results {
+        dns::set_aliases(&mut dns_map, app, result);
+    }
+    save_dns_map(nirvati_root, &dns_map)?;
+    let mut scrape_targets = Vec::new();
+    let mut firewall_rules = Vec::new();
+    let mut upnp_entries = Vec::new();
+    let mut shutdown_entries = Vec::new();
+    let mut traffic_entries = Vec::new();
+    for (app, mut result) in results {
+        scrape_targets.append(&mut metrics::get_targets(app, &result, &dns_map));
+        firewall_rules.append(&mut firewall::get_rules(app, &result));
+        upnp_entries.append(&mut upnp::get_entries(app, &result));
+        traffic_entries.append(&mut traffic::get_entries(app, &result, &dns_map));
+        dns::apply_to_result(&mut result, app, &dns_map);
+        shutdown_entries.push(shutdown::get_entry(app, &result));
+        ports::apply_to_result(&mut result, app, &all_ports);
+        let user_env = super::files::get_user_env(nirvati_root, app).and_then(|user_env| {
+            user_env::validate(
+                &user_env,
+                &result.metadata.has_permissions,
+                &available_permissions,
+            )?;
+            Ok(user_env)
+        });
+        match user_env {
+            Ok(user_env) => user_env::apply_to_result(&mut result, &user_env),
+            Err(err) => {
+                tracing::warn!("Ignoring user env of app {}: {:#}", app, err);
+                result.metadata.diagnostics.push(Diagnostic::warning(
+                    DiagnosticCode::InvalidUserEnv,
+                    format!("{:#}", err),
+                    None,
+                ));
+            }
+        }
+        maintenance::apply_to_result(&mut result, maintenance_apps.contains(app));
         #[cfg(debug_assertions)]
         {
-            let result_yml = app_dir.join("result.yml");
-            let result_writer = std::fs::File::create(&result_yml)?;
-            let mut result_writer = std::io::BufWriter::new(result_writer);
-            serde_yaml::to_writer(&mut result_writer, &result)?;
+            let result_yml = apps_dir.join(app).join("result.yml");
+            if let Err(err) = canonical::write_yaml(&result_yml, &result) {
+                tracing::warn!("Failed to write result.yml for app {}: {:#}", app, err);
+            }
         }
+        if let Err(err) = categories::check(&mut result.metadata, &known_categories, mode) {
+            failed_apps.push((
+                app.to_string(),
+                Diagnostic::error(DiagnosticCode::UnknownCategory, format!("{:#}", err)),
+            ));
+        }
+        result.metadata.disabled = disabled_apps.contains(app);
+        store_ids.apply_origin(&mut result.metadata);
+        resolve_dependencies(&mut result.metadata, &installed_apps);
         new_registry_entries.push(result.metadata);
     }
+    let scrape_targets = metrics::merge_targets(
+        get_scrape_targets(nirvati_root)?,
+        sorted_apps,
+        &apps_to_run,
+        scrape_targets,
+    );
+    save_scrape_targets(nirvati_root, &scrape_targets)?;
+    let firewall_rules = firewall::merge_rules(
+        get_firewall_rules(nirvati_root)?,
+        sorted_apps,
+        &apps_to_run,
+        firewall_rules,
+    );
+    save_firewall_rules(nirvati_root, &firewall_rules)?;
+    let upnp_entries = upnp::merge_entries(
+        get_upnp_entries(nirvati_root)?,
+        sorted_apps,
+        &apps_to_run,
+        upnp_entries,
+    );
+    save_upnp_entries(nirvati_root, &upnp_entries)?;
+    let traffic_entries = traffic::merge_entries(
+        get_traffic_entries(nirvati_root)?,
+        sorted_apps,
+        &apps_to_run,
+        traffic_entries,
+    );
+    save_traffic_entries(nirvati_root, &traffic_entries)?;
+    let quota_entries = quota::build_entries(
+        nirvati_root,
+        &get_quota_entries(nirvati_root)?,
+        &get_disk_quotas(nirvati_root)?,
+        &installed_apps,
+    );
+    save_quota_entries(nirvati_root, &quota_entries)?;
+    save_snapshot_entries(
+        nirvati_root,
+        &snapshots::build_entries(nirvati_root, &config.snapshots, &installed_apps),
+    )?;
     let current_registry = super::files::get_app_registry(nirvati_root)?;
     let new_app_ids = new_registry_entries
         .iter()
@@ -122,6 +554,121 @@ pub fn process_app_ymls(
     let mut new_registry = current_registry;
     new_registry.retain(|entry| !new_app_ids.contains(&entry.id));
     new_registry.append(&mut new_registry_entries.clone());
-    super::files::write_app_registry(nirvati_root, &new_registry)?;
-    Ok(())
+    for (app, diagnostic) in &failed_apps {
+        if let Some(entry) = new_registry.iter_mut().find(|entry| &entry.id == app) {
+            entry.diagnostics.push(diagnostic.clone());
+        }
+    }
+    // Apps that were never generated are listed too, so the dashboard can show they need an update of the app manager
+    for mut metadata in too_new_apps {
+        if new_registry.iter().all(|entry| entry.id != metadata.id) {
+            metadata.diagnostics.extend(
+                failed_apps
+                    .iter()
+                    .filter(|(app, _)| *app == metadata.id)
+                    .map(|(_, diagnostic)| diagnostic.clone()),
+            );
+            new_registry.push(metadata);
+        }
+    }
+    mark_conflicts(&mut new_registry, &installed_apps);
+    mark_disabled_dependencies(&mut new_registry, &disabled_apps);
+    let shutdown_entries = shutdown::merge_entries(
+        get_shutdown_entries(nirvati_root)?,
+        &new_registry,
+        sorted_apps,
+        &apps_to_run,
+        shutdown_entries,
+    );
+    save_shutdown_entries(nirvati_root, &shutdown_entries)?;
+    // Disabled apps still get their outputs, so enabling them again doesn't need their store
+    super::files::save_apps_to_run(nirvati_root, &apps_to_run)?;
+    cancel::check()?;
+    progress::report("write outputs", None, 1, 1);
+    profile::measure("write outputs", None, || -> anyhow::Result<()> {
+        super::files::write_app_registry(nirvati_root, &new_registry)?;
+        super::files::save_reverse_index(nirvati_root, &reverse_index(&new_registry))?;
+        super::files::save_categories(
+            nirvati_root,
+            &categories::count(&new_registry, &known_categories),
+        )?;
+        super::files::save_search_index(nirvati_root, &search::build_index(&new_registry))?;
+        let mut installed_versions = super::files::get_installed_versions(nirvati_root)?;
+        changelog::update_installed_versions(
+            &mut installed_versions,
+            &new_registry,
+            &installed_apps,
+        );
+        super::files::save_installed_versions(nirvati_root, &installed_versions)?;
+        super::files::save_changelog(
+            nirvati_root,
+            &changelog::build_changelog(&new_registry, &installed_versions),
+        )?;
+        Ok(())
+    })?;
+    if mode == ValidationMode::Strict && !failed_apps.is_empty() {
+        let errors = failed_apps
+            .iter()
+            .map(|(app, diagnostic)| format!("{}: {}", app, diagnostic.message))
+            .collect::<Vec<_>>();
+        bail!("Strict validation failed:\n{}", errors.join("\n"));
+    }
+    Ok(failed_apps
+        .into_iter()
+        .map(|(app, diagnostic)| FailedApp { app, diagnostic })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::testing::Fixture;
+
+    fn apps_to_run(root: &Path) -> Vec<String> {
+        let run_yml = std::fs::read_to_string(root.join("apps").join("run.yml")).unwrap();
+        serde_yaml::from_str(&run_yml).unwrap()
+    }
+
+    #[test]
+    fn disabled_apps_are_not_run() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let user_json = fixture.root().join("db").join("user.json");
+        std::fs::write(
+            &user_json,
+            r#"{"name": "Fixture", "password": "fixture", "installedApps": ["example"]}"#,
+        )
+        .unwrap();
+        fixture.generate().unwrap();
+        assert_eq!(apps_to_run(fixture.root()), vec!["example".to_owned()]);
+
+        std::fs::write(
+            &user_json,
+            r#"{"name": "Fixture", "password": "fixture", "installedApps": ["example"], "disabledApps": ["example"]}"#,
+        )
+        .unwrap();
+        fixture.generate().unwrap();
+        assert_eq!(apps_to_run(fixture.root()), Vec::<String>::new());
+        let registry = crate::manage::files::get_app_registry(fixture.root()).unwrap();
+        assert!(
+            registry
+                .iter()
+                .find(|entry| entry.id == "example")
+                .unwrap()
+                .disabled
+        );
+        // The app keeps its outputs, so it can be enabled again
+        assert!(fixture
+            .root()
+            .join("apps")
+            .join("example")
+            .join("app.yml")
+            .is_file());
+    }
 }
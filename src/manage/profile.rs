@@ -0,0 +1,158 @@
+//! Timings of the phases of Generate, recorded with `generate --profile`
+//!
+//! The profile is written in Chrome's trace event format, which speedscope, Perfetto and chrome://tracing show as flamegraph.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// A complete event in the trace event format
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: String,
+    /// Always X, an event with a duration
+    pub ph: &'static str,
+    /// Microseconds since profiling was enabled
+    pub ts: u64,
+    /// Microseconds
+    pub dur: u64,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events: &'a [TraceEvent],
+    display_time_unit: &'static str,
+}
+
+struct Profile {
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+/// None unless profiling is enabled
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Starts recording timings for this process
+pub fn enable() {
+    if let Ok(mut profile) = PROFILE.lock() {
+        *profile = Some(Profile {
+            start: Instant::now(),
+            events: Vec::new(),
+        });
+    }
+}
+
+/// Records a phase that started at start and took duration, does nothing if profiling is disabled
+pub fn record(phase: &str, app: Option<&str>, start: Instant, duration: Duration) {
+    let Ok(mut profile) = PROFILE.lock() else {
+        return;
+    };
+    let Some(profile) = profile.as_mut() else {
+        return;
+    };
+    let (name, args) = match app {
+        Some(app) => (
+            format!("{} {}", phase, app),
+            BTreeMap::from([("app".to_owned(), app.to_owned())]),
+        ),
+        None => (phase.to_owned(), BTreeMap::new()),
+    };
+    profile.events.push(TraceEvent {
+        name,
+        cat: phase.to_owned(),
+        ph: "X",
+        ts: start.saturating_duration_since(profile.start).as_micros() as u64,
+        dur: duration.as_micros() as u64,
+        pid: std::process::id(),
+        tid: 1,
+        args,
+    });
+}
+
+/// Runs an operation and records how long it took
+pub fn measure<T, F>(phase: &str, app: Option<&str>, operation: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let start = Instant::now();
+    let result = operation();
+    record(phase, app, start, start.elapsed());
+    result
+}
+
+/// Writes the recorded events to a file and stops profiling
+pub fn save(path: &Path) -> Result<()> {
+    let events = PROFILE
+        .lock()
+        .ok()
+        .and_then(|mut profile| profile.take())
+        .map(|profile| profile.events)
+        .unwrap_or_default();
+    let trace = Trace {
+        trace_events: &events,
+        display_time_unit: "ms",
+    };
+    std::fs::write(path, serde_json::to_string(&trace)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, TempDir};
+
+    #[test]
+    fn records_the_phases_of_every_app() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let out_dir = TempDir::new("profile");
+        let profile_path = out_dir.join("profile.json");
+
+        enable();
+        measure("generate", None, || fixture.generate()).unwrap();
+        save(&profile_path).unwrap();
+
+        let trace: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&profile_path).unwrap()).unwrap();
+        assert_eq!(trace["displayTimeUnit"], "ms");
+        let events = trace["traceEvents"].as_array().unwrap();
+        let has_event = |name: &str| events.iter().any(|event| event["name"] == name);
+        for name in [
+            "generate",
+            "ports",
+            "stage1 example",
+            "convert example",
+            "write example",
+        ] {
+            assert!(has_event(name), "{} is missing", name);
+        }
+        let convert = events
+            .iter()
+            .find(|event| event["name"] == "convert example")
+            .unwrap();
+        assert_eq!(convert["ph"], "X");
+        assert_eq!(convert["cat"], "convert");
+        assert_eq!(convert["args"]["app"], "example");
+
+        // Saving stops profiling
+        record("later", None, Instant::now(), Duration::ZERO);
+        assert!(PROFILE.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,121 @@
+//! Progress of Generate, so UIs can show which phase runs for which app instead of a spinner
+//!
+//! The phases are the ones `generate --profile` records. The CLI prints progress as JSON lines on stderr with
+//! `--progress`, the RPC mode sends it to the client as notifications.
+
+use std::{io::Write, sync::Mutex};
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub phase: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// The step that is starting, counting from 1
+    pub current: usize,
+    pub total: usize,
+    pub percent: u8,
+}
+
+pub type Sink = Box<dyn Fn(&Progress) + Send>;
+
+/// None unless progress is reported
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+/// Tests that set the sink hold this, so they don't replace each other's sink
+#[cfg(test)]
+pub static TEST_SINK_LOCK: Mutex<()> = Mutex::new(());
+
+/// Sends the progress of this process to sink, or stops reporting it
+pub fn set_sink(sink: Option<Sink>) {
+    if let Ok(mut current) = SINK.lock() {
+        *current = sink;
+    }
+}
+
+/// Prints progress as JSON lines on stderr
+pub fn to_stderr() -> Sink {
+    Box::new(|progress| {
+        if let Ok(line) = serde_json::to_string(progress) {
+            let _ = writeln!(std::io::stderr(), "{}", line);
+        }
+    })
+}
+
+/// Reports that step current of total in a phase starts, does nothing if progress isn't reported
+pub fn report(phase: &'static str, app: Option<&str>, current: usize, total: usize) {
+    let Ok(sink) = SINK.lock() else {
+        return;
+    };
+    let Some(sink) = sink.as_ref() else {
+        return;
+    };
+    sink(&Progress {
+        phase,
+        app: app.map(str::to_owned),
+        current,
+        total,
+        percent: (current * 100).checked_div(total).unwrap_or(100).min(100) as u8,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn progress_lines_are_json() {
+        let progress = Progress {
+            phase: "stage1",
+            app: Some("example".to_owned()),
+            current: 1,
+            total: 2,
+            percent: 50,
+        };
+        assert_eq!(
+            serde_json::to_string(&progress).unwrap(),
+            r#"{"phase":"stage1","app":"example","current":1,"total":2,"percent":50}"#
+        );
+        let progress = Progress {
+            app: None,
+            ..progress
+        };
+        assert_eq!(
+            serde_json::to_string(&progress).unwrap(),
+            r#"{"phase":"stage1","current":1,"total":2,"percent":50}"#
+        );
+    }
+
+    #[test]
+    fn reports_to_the_sink() {
+        let _sink = TEST_SINK_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let collected = Arc::clone(&reported);
+        set_sink(Some(Box::new(move |progress| {
+            // Generate in other tests reports to the same sink
+            if progress.phase == "test" {
+                collected.lock().unwrap().push(progress.clone());
+            }
+        })));
+        report("test", Some("example"), 1, 4);
+        report("test", None, 5, 4);
+        report("test", None, 0, 0);
+        set_sink(None);
+        report("test", None, 1, 1);
+
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 3);
+        assert_eq!(reported[0].app.as_deref(), Some("example"));
+        assert_eq!(
+            reported
+                .iter()
+                .map(|progress| progress.percent)
+                .collect::<Vec<_>>(),
+            vec![25, 100, 100]
+        );
+    }
+}
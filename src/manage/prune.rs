@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::files;
+
+/// Files the app manager or the host scripts write into an app's dir
+pub(crate) const RENDERED_FILES: [&str; 9] = [
+    "app.yml",
+    "app.yml.stage1",
+    "result.yml",
+    "dirs.yml",
+    "tasks.yml",
+    "docker-compose.yml",
+    ".env",
+    "Caddyfile",
+    "import-notes.yml",
+];
+
+/// Rendered outputs of apps that are neither in any app store nor installed
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Orphans {
+    pub files: Vec<PathBuf>,
+    pub data_dirs: Vec<PathBuf>,
+    /// Apps that still have entries in apps/ports.yml
+    pub port_map_apps: Vec<String>,
+    /// Apps that still have entries in apps/dns.yml
+    pub dns_apps: Vec<String>,
+}
+
+impl Orphans {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+            && self.data_dirs.is_empty()
+            && self.port_map_apps.is_empty()
+            && self.dns_apps.is_empty()
+    }
+}
+
+/// Whether an app dir still contains the app's source from its store
+fn is_in_store(app_dir: &Path) -> bool {
+    app_dir.join("metadata.yml").is_file() || app_dir.join("metadata.yml.jinja").is_file()
+}
+
+fn subdirs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut subdirs = Vec::new();
+    if !dir.is_dir() {
+        return Ok(subdirs);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // Apps from local stores can be symlinked
+        if entry.path().is_dir() {
+            subdirs.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+pub fn find_orphans(nirvati_dir: &Path) -> Result<Orphans> {
+    let installed_apps = files::get_installed_apps(nirvati_dir)?;
+    let app_dirs = subdirs(&nirvati_dir.join("apps"))?;
+    let known_apps = app_dirs
+        .iter()
+        .filter(|(_, path)| is_in_store(path))
+        .map(|(app, _)| app.clone())
+        .collect::<Vec<_>>();
+    let is_orphan = |app: &String| !known_apps.contains(app) && !installed_apps.contains(app);
+
+    let mut orphans = Orphans::default();
+    for (app, path) in &app_dirs {
+        if !is_orphan(app) {
+            continue;
+        }
+        for file in RENDERED_FILES {
+            if path.join(file).is_file() {
+                orphans.files.push(path.join(file));
+            }
+        }
+    }
+    let data_dir_names = files::get_data_dir_names(nirvati_dir)?;
+    for (dir_name, path) in subdirs(&nirvati_dir.join("app-data"))? {
+        // Renamed apps keep their old data dir
+        let app = data_dir_names
+            .iter()
+            .find(|(_, name)| **name == dir_name)
+            .map_or(dir_name.clone(), |(app, _)| app.clone());
+        if is_orphan(&app) {
+            orphans.data_dirs.push(path);
+        }
+    }
+    for entry in files::get_port_map(nirvati_dir)? {
+        if is_orphan(&entry.app) && !orphans.port_map_apps.contains(&entry.app) {
+            orphans.port_map_apps.push(entry.app);
+        }
+    }
+    orphans.dns_apps = files::get_dns_map(nirvati_dir)?
+        .into_keys()
+        .filter(is_orphan)
+        .collect();
+    Ok(orphans)
+}
+
+/// Removes the orphaned outputs, and app dirs that are empty afterwards
+pub fn remove_orphans(nirvati_dir: &Path, orphans: &Orphans) -> Result<()> {
+    for file in &orphans.files {
+        std::fs::remove_file(file)?;
+        if let Some(app_dir) = file.parent() {
+            if std::fs::read_dir(app_dir)?.next().is_none() {
+                std::fs::remove_dir(app_dir)?;
+            }
+        }
+    }
+    for dir in &orphans.data_dirs {
+        std::fs::remove_dir_all(dir)?;
+    }
+    if !orphans.port_map_apps.is_empty() {
+        let mut port_map = files::get_port_map(nirvati_dir)?;
+        port_map.retain(|entry| !orphans.port_map_apps.contains(&entry.app));
+        files::save_port_map(nirvati_dir, port_map)?;
+    }
+    if !orphans.dns_apps.is_empty() {
+        let mut dns = files::get_dns_map(nirvati_dir)?;
+        dns.retain(|app, _| !orphans.dns_apps.contains(app));
+        files::save_dns_map(nirvati_dir, &dns)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manage::{
+        dirs::app_data_dir,
+        ports::{PortMapEntry, PortPriority},
+    };
+    use crate::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn finds_and_removes_orphans() {
+        let nirvati_dir = TempDir::new("prune");
+        let apps_dir = nirvati_dir.join("apps");
+        std::fs::create_dir_all(apps_dir.join("kept")).unwrap();
+        std::fs::write(apps_dir.join("kept").join("metadata.yml"), "").unwrap();
+        std::fs::write(apps_dir.join("kept").join("result.yml"), "").unwrap();
+        std::fs::create_dir_all(apps_dir.join("removed")).unwrap();
+        std::fs::write(apps_dir.join("removed").join("result.yml"), "").unwrap();
+        std::fs::create_dir_all(app_data_dir(&nirvati_dir, "removed")).unwrap();
+        std::fs::create_dir_all(app_data_dir(&nirvati_dir, "kept")).unwrap();
+        let port = |app: &str| PortMapEntry {
+            app: app.to_owned(),
+            internal_port: 80,
+            public_port: 8080,
+            container: "main".to_owned(),
+            implements: None,
+            priority: PortPriority::Optional,
+        };
+        files::save_port_map(&nirvati_dir, vec![port("kept"), port("removed")]).unwrap();
+
+        let orphans = find_orphans(&nirvati_dir).unwrap();
+        assert_eq!(
+            orphans,
+            Orphans {
+                files: vec![apps_dir.join("removed").join("result.yml")],
+                data_dirs: vec![app_data_dir(&nirvati_dir, "removed")],
+                port_map_apps: vec!["removed".to_owned()],
+                dns_apps: vec![],
+            }
+        );
+        remove_orphans(&nirvati_dir, &orphans).unwrap();
+        assert!(!apps_dir.join("removed").exists());
+        assert!(apps_dir.join("kept").join("result.yml").exists());
+        assert!(find_orphans(&nirvati_dir).unwrap().is_empty());
+    }
+}
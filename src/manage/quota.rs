@@ -0,0 +1,201 @@
+//! Disk quotas users set per app, written to apps/quotas.yml, and the disk usage of app data dirs
+//!
+//! Host scripts apply the quotas as XFS or ext4 project quotas with the project id of an app,
+//! or as btrfs qgroup limits if the data dir is a subvolume.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::dirs::app_data_dir;
+
+/// Project ids below this are left for the host
+const FIRST_PROJECT_ID: u32 = 10000;
+
+/// An entry of apps/quotas.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaEntry {
+    pub app: String,
+    /// The data dir of the app
+    pub path: PathBuf,
+    /// The quota as set by the user, like 50G
+    pub limit: String,
+    pub bytes: u64,
+    /// The project id for XFS or ext4 project quotas, which stays the same as long as the app has a quota
+    pub project_id: u32,
+}
+
+/// The disk usage of an app, as reported by disk-usage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsage {
+    pub app: String,
+    pub path: PathBuf,
+    pub used_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+    /// How much of the quota is used, in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_used: Option<f64>,
+}
+
+/// Parses a size like 500M, 50G or 1T into bytes, with binary units
+pub fn parse_size(size: &str) -> Result<u64> {
+    let unit_start = size
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (value, unit) = size.split_at(unit_start);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow!("Size {} has to start with a number", size))?;
+    let shift = match unit.to_uppercase().trim_end_matches(['B', 'I']) {
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("Size {} needs a unit of K, M, G or T", size),
+    };
+    if value == 0 {
+        bail!("Size {} has to be more than 0", size);
+    }
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size {} is too large", size))
+}
+
+/// Builds the quota entries of installed apps, keeping the project ids apps had before
+pub fn build_entries(
+    nirvati_dir: &Path,
+    previous: &[QuotaEntry],
+    quotas: &HashMap<String, String>,
+    installed_apps: &[String],
+) -> Vec<QuotaEntry> {
+    let previous_ids = previous
+        .iter()
+        .map(|entry| (entry.app.as_str(), entry.project_id))
+        .collect::<HashMap<_, _>>();
+    let mut quotas = quotas
+        .iter()
+        .filter(|(app, _)| installed_apps.contains(app))
+        .filter_map(|(app, limit)| match parse_size(limit) {
+            Ok(bytes) => Some((app, limit, bytes)),
+            Err(err) => {
+                tracing::warn!("Ignoring the disk quota of {}: {:#}", app, err);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    quotas.sort();
+    let mut used_ids = quotas
+        .iter()
+        .filter_map(|(app, _, _)| previous_ids.get(app.as_str()).copied())
+        .collect::<BTreeSet<_>>();
+    let mut entries = Vec::new();
+    for (app, limit, bytes) in quotas {
+        let project_id = match previous_ids.get(app.as_str()) {
+            Some(project_id) => *project_id,
+            None => {
+                let project_id = (FIRST_PROJECT_ID..)
+                    .find(|id| !used_ids.contains(id))
+                    .expect("Ran out of project ids");
+                used_ids.insert(project_id);
+                project_id
+            }
+        };
+        entries.push(QuotaEntry {
+            app: app.clone(),
+            path: app_data_dir(nirvati_dir, app),
+            limit: limit.clone(),
+            bytes,
+            project_id,
+        });
+    }
+    entries
+}
+
+/// The size of all files in a dir, without following symlinks
+fn dir_size(path: &Path) -> Result<u64> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Scans the data dirs of apps and compares their size to their quota
+pub fn disk_usage(
+    nirvati_dir: &Path,
+    apps: &[String],
+    quotas: &HashMap<String, String>,
+) -> Result<Vec<DiskUsage>> {
+    let mut usage = Vec::new();
+    for app in apps {
+        let path = app_data_dir(nirvati_dir, app);
+        let used_bytes = if path.exists() {
+            dir_size(&path).map_err(|err| anyhow!("Failed to scan {}: {}", path.display(), err))?
+        } else {
+            0
+        };
+        let quota_bytes = quotas.get(app).and_then(|limit| parse_size(limit).ok());
+        usage.push(DiskUsage {
+            app: app.clone(),
+            path,
+            used_bytes,
+            quota_bytes,
+            quota_used: quota_bytes.map(|quota| used_bytes as f64 / quota as f64 * 100.0),
+        });
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("500M").unwrap(), 500 << 20);
+        assert_eq!(parse_size("50G").unwrap(), 50 << 30);
+        assert_eq!(parse_size("1TiB").unwrap(), 1 << 40);
+        assert!(parse_size("50").is_err());
+        assert!(parse_size("0G").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn project_ids_stay_stable() {
+        let dir = TempDir::new("quota");
+        let installed = vec!["app1".to_owned(), "app2".to_owned()];
+        let quotas = HashMap::from([
+            ("app1".to_owned(), "10G".to_owned()),
+            ("app2".to_owned(), "20G".to_owned()),
+            ("removed".to_owned(), "1G".to_owned()),
+        ]);
+        let entries = build_entries(&dir, &[], &quotas, &installed);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].project_id, 10000);
+        assert_eq!(entries[1].project_id, 10001);
+
+        let quotas = HashMap::from([("app2".to_owned(), "30G".to_owned())]);
+        let entries = build_entries(&dir, &entries, &quotas, &installed);
+        assert_eq!(entries[0].project_id, 10001);
+        assert_eq!(entries[0].bytes, 30 << 30);
+
+        std::fs::create_dir_all(dir.join("app-data").join("app2")).unwrap();
+        std::fs::write(dir.join("app-data").join("app2").join("file"), [0; 100]).unwrap();
+        let usage = disk_usage(&dir, &installed, &quotas);
+        let usage = usage.unwrap();
+        assert_eq!(usage[0].used_bytes, 0);
+        assert_eq!(usage[1].used_bytes, 100);
+        assert_eq!(usage[1].quota_bytes, Some(30 << 30));
+    }
+}
@@ -0,0 +1,463 @@
+//! A long-running mode for the dashboard backend, run with `app-manager rpc`
+//!
+//! It accepts JSON-RPC 2.0 requests on a unix socket, one per line, so dashboard actions don't have to spawn a process.
+//! Mutating requests are queued and run one after another, each holding the app manager lock while it runs.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    cancel::{self, Checkpoint},
+    files,
+    lock::LockError,
+    progress,
+};
+
+/// Requests with a longer line are rejected
+const MAX_REQUEST_LENGTH: u64 = 1024 * 1024;
+
+/// Methods that modify the Nirvati root, these are queued behind the app manager lock
+pub const MUTATING_METHODS: [&str; 2] = ["generate", "install"];
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+/// Another app manager process held the lock for longer than the lock timeout
+const OPERATION_IN_PROGRESS: i64 = -32001;
+/// The request was cancelled and its changes were rolled back
+const CANCELLED: i64 = -32002;
+
+/// Runs generate, install and plan, which are implemented by the CLI
+pub type Dispatch = dyn Fn(&str, Value) -> Result<Value> + Send + Sync;
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// The params of a request didn't match what the method expects
+    InvalidParams(String),
+    MethodNotFound(String),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::InvalidParams(err) => write!(f, "Invalid params: {}", err),
+            RpcError::MethodNotFound(method) => write!(f, "Unknown method {}", method),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Deserializes a request's params, missing params are treated like an empty object
+pub fn params<T: DeserializeOwned>(params: Value) -> Result<T> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|err| RpcError::InvalidParams(err.to_string()).into())
+}
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    jsonrpc: String,
+    /// Requests without an id are notifications, which don't get a response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub installed_apps: Vec<String>,
+    /// How many apps the registry has
+    pub apps: usize,
+    /// Whether a mutating request is running
+    pub busy: bool,
+    /// Mutating requests waiting for the running one
+    pub queued: usize,
+}
+
+/// Serializes the mutating requests of all connections
+struct Queue {
+    running: Mutex<()>,
+    waiting: AtomicUsize,
+    busy: AtomicUsize,
+}
+
+pub struct Server {
+    nirvati_dir: PathBuf,
+    lock_timeout: Duration,
+    queue: Queue,
+    dispatch: Box<Dispatch>,
+}
+
+impl Server {
+    pub fn new(nirvati_dir: &Path, lock_timeout: Duration, dispatch: Box<Dispatch>) -> Self {
+        Server {
+            nirvati_dir: nirvati_dir.to_owned(),
+            lock_timeout,
+            queue: Queue {
+                running: Mutex::new(()),
+                waiting: AtomicUsize::new(0),
+                busy: AtomicUsize::new(0),
+            },
+            dispatch,
+        }
+    }
+
+    fn status(&self) -> Result<Status> {
+        Ok(Status {
+            installed_apps: files::get_installed_apps(&self.nirvati_dir)?,
+            apps: files::get_app_registry(&self.nirvati_dir)?.len(),
+            busy: self.queue.busy.load(Ordering::SeqCst) > 0,
+            queued: self.queue.waiting.load(Ordering::SeqCst),
+        })
+    }
+
+    /// Waits for the mutating requests queued before this one, then runs it with the app manager lock held
+    fn run_mutating(
+        &self,
+        method: &str,
+        params: Value,
+        progress: Option<progress::Sink>,
+    ) -> Result<Value> {
+        self.queue.waiting.fetch_add(1, Ordering::SeqCst);
+        let running = self
+            .queue
+            .running
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.queue.waiting.fetch_sub(1, Ordering::SeqCst);
+        self.queue.busy.fetch_add(1, Ordering::SeqCst);
+        progress::set_sink(progress);
+        cancel::reset();
+        let result = super::lock::acquire(&self.nirvati_dir, self.lock_timeout)
+            .map_err(anyhow::Error::from)
+            .and_then(|_lock| {
+                let checkpoint = Checkpoint::take(&self.nirvati_dir)?;
+                let result = (self.dispatch)(method, params);
+                match &result {
+                    Err(err) if cancel::is_cancelled(err) => checkpoint.restore()?,
+                    _ => checkpoint.discard()?,
+                }
+                result
+            });
+        progress::set_sink(None);
+        self.queue.busy.fetch_sub(1, Ordering::SeqCst);
+        drop(running);
+        result
+    }
+
+    fn call(&self, method: &str, params: Value, progress: Option<progress::Sink>) -> Result<Value> {
+        match method {
+            "status" => Ok(serde_json::to_value(self.status()?)?),
+            // Stops the running generate or install, queued requests still run
+            "cancel" => {
+                let running = self.queue.busy.load(Ordering::SeqCst) > 0;
+                if running {
+                    cancel::request();
+                }
+                Ok(json!({ "cancelled": running }))
+            }
+            "plan" => (self.dispatch)(method, params),
+            method if MUTATING_METHODS.contains(&method) => {
+                self.run_mutating(method, params, progress)
+            }
+            method => Err(RpcError::MethodNotFound(method.to_owned()).into()),
+        }
+    }
+
+    /// Answers a line of a connection, without any socket access, so the framing can be tested
+    /// The progress of generate and install is sent to progress
+    pub fn handle_line(&self, line: &str, progress: Option<progress::Sink>) -> Option<String> {
+        let request = match serde_json::from_str::<Value>(line) {
+            Ok(request) => request,
+            Err(err) => return Some(error_response(Value::Null, PARSE_ERROR, &err.to_string())),
+        };
+        let id = request.get("id").cloned().unwrap_or_default();
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(_) => {
+                return Some(error_response(
+                    id,
+                    INVALID_REQUEST,
+                    "Only JSON-RPC 2.0 is supported",
+                ))
+            }
+            Err(err) => return Some(error_response(id, INVALID_REQUEST, &err.to_string())),
+        };
+        let id = request.id?;
+        Some(match self.call(&request.method, request.params, progress) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }).to_string(),
+            Err(err) => {
+                let code = match err.downcast_ref::<RpcError>() {
+                    Some(RpcError::InvalidParams(_)) => INVALID_PARAMS,
+                    Some(RpcError::MethodNotFound(_)) => METHOD_NOT_FOUND,
+                    None if cancel::is_cancelled(&err) => CANCELLED,
+                    None => match err.downcast_ref::<LockError>() {
+                        Some(LockError::OperationInProgress) => OPERATION_IN_PROGRESS,
+                        _ => SERVER_ERROR,
+                    },
+                };
+                error_response(id, code, &format!("{:#}", err))
+            }
+        })
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let mut line = String::new();
+            let read = reader
+                .by_ref()
+                .take(MAX_REQUEST_LENGTH)
+                .read_line(&mut line)?;
+            if read == 0 {
+                return Ok(());
+            }
+            if !line.ends_with('\n') && read as u64 == MAX_REQUEST_LENGTH {
+                writeln!(
+                    writer,
+                    "{}",
+                    error_response(Value::Null, INVALID_REQUEST, "Request is too long")
+                )?;
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Progress is sent as notifications before the response
+            let notifications = writer.try_clone()?;
+            let progress: progress::Sink = Box::new(move |progress| {
+                let notification =
+                    json!({ "jsonrpc": "2.0", "method": "progress", "params": progress });
+                let _ = writeln!(&notifications, "{}", notification);
+            });
+            if let Some(response) = self.handle_line(line.trim(), Some(progress)) {
+                writeln!(writer, "{}", response)?;
+            }
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+    .to_string()
+}
+
+/// Removes a socket left behind by a server that was killed, but fails if another server still listens on it
+fn remove_stale_socket(socket: &Path) -> Result<()> {
+    let Ok(file_type) = std::fs::symlink_metadata(socket).map(|metadata| metadata.file_type())
+    else {
+        return Ok(());
+    };
+    if !file_type.is_socket() {
+        return Err(anyhow!("{} exists and is not a socket", socket.display()));
+    }
+    if UnixStream::connect(socket).is_ok() {
+        return Err(anyhow!(
+            "Another app manager already listens on {}",
+            socket.display()
+        ));
+    }
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+/// Answers requests until the process is stopped, every connection on its own thread
+pub fn listen(socket: &Path, server: Server) -> Result<()> {
+    remove_stale_socket(socket)?;
+    let listener = UnixListener::bind(socket)
+        .map_err(|err| anyhow!("Failed to listen on {}: {}", socket.display(), err))?;
+    // Only root and the dashboard's group may run commands
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o660))?;
+    tracing::info!("Listening on {}", socket.display());
+    let server = Arc::new(server);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            if let Err(err) = server.handle_connection(stream) {
+                tracing::warn!("Failed to answer a request: {:#}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, TempDir};
+
+    #[test]
+    fn answers_json_rpc_requests() {
+        let _sink = progress::TEST_SINK_LOCK
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let nirvati_dir = TempDir::new("rpc");
+        std::fs::create_dir_all(nirvati_dir.join("db")).unwrap();
+        std::fs::create_dir_all(nirvati_dir.join("apps")).unwrap();
+        let server = Server::new(
+            &nirvati_dir,
+            Duration::ZERO,
+            Box::new(|method, request_params| {
+                #[derive(Deserialize)]
+                struct Install {
+                    app: String,
+                }
+                match method {
+                    "install" => Ok(json!(params::<Install>(request_params)?.app)),
+                    _ => Err(anyhow!("{} failed", method)),
+                }
+            }),
+        );
+
+        let response = |line: &str| -> Value {
+            serde_json::from_str(&server.handle_line(line, None).unwrap()).unwrap()
+        };
+        let install =
+            response(r#"{"jsonrpc":"2.0","id":1,"method":"install","params":{"app":"notes"}}"#);
+        let invalid = response(r#"{"jsonrpc":"2.0","id":2,"method":"install","params":{}}"#);
+        let failed = response(r#"{"jsonrpc":"2.0","id":"3","method":"generate"}"#);
+        let unknown = response(r#"{"jsonrpc":"2.0","id":4,"method":"uninstall"}"#);
+        let garbage = response("{");
+        let notification = server.handle_line(r#"{"jsonrpc":"2.0","method":"generate"}"#, None);
+
+        assert_eq!(install["id"], 1);
+        assert_eq!(install["result"], "notes");
+        assert_eq!(invalid["error"]["code"], INVALID_PARAMS);
+        assert_eq!(failed["id"], "3");
+        assert_eq!(failed["error"]["code"], SERVER_ERROR);
+        assert_eq!(failed["error"]["message"], "generate failed");
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(garbage["error"]["code"], PARSE_ERROR);
+        assert_eq!(notification, None);
+    }
+
+    #[test]
+    fn cancelled_install_rolls_back_new_instances() {
+        let _sink = progress::TEST_SINK_LOCK
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let nirvati_dir = TempDir::new("rpc-cancel");
+        std::fs::create_dir_all(nirvati_dir.join("db")).unwrap();
+        std::fs::create_dir_all(nirvati_dir.join("apps").join("notes")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("apps").join("notes").join("metadata.yml"),
+            "",
+        )
+        .unwrap();
+        std::fs::write(nirvati_dir.join("db").join("user.json"), "{}").unwrap();
+        let root = nirvati_dir.to_path_buf();
+        let server = Server::new(
+            &nirvati_dir,
+            Duration::ZERO,
+            Box::new(move |_, _| {
+                // Cancelled after the instance was created and its settings were saved
+                crate::manage::instances::create_instance(&root, "notes@work")?;
+                std::fs::write(root.join("db").join("user.json"), "{\"installedApps\":[]}")?;
+                std::fs::write(root.join("db").join("ports.yml"), "")?;
+                Err(cancel::Cancelled.into())
+            }),
+        );
+
+        let response = server
+            .handle_line(
+                r#"{"jsonrpc":"2.0","id":1,"method":"install","params":{"app":"notes@work"}}"#,
+                None,
+            )
+            .unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+
+        assert_eq!(response["error"]["code"], CANCELLED);
+        assert!(!nirvati_dir.join("apps").join("notes@work").exists());
+        assert!(!nirvati_dir.join("db").join("ports.yml").exists());
+        assert!(!nirvati_dir.join("db").join(".checkpoint").exists());
+        assert!(nirvati_dir
+            .join("apps")
+            .join("notes")
+            .join("metadata.yml")
+            .is_file());
+        assert_eq!(
+            std::fs::read_to_string(nirvati_dir.join("db").join("user.json")).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn sends_generate_progress_as_notifications() {
+        let _sink = progress::TEST_SINK_LOCK
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root().to_path_buf();
+        let server = Server::new(
+            &root,
+            Duration::ZERO,
+            Box::new(move |_, _| Ok(json!(fixture.generate()?.len()))),
+        );
+        let (client, connection) = UnixStream::pair().unwrap();
+        let handler = std::thread::spawn(move || server.handle_connection(connection));
+        writeln!(&client, r#"{{"jsonrpc":"2.0","id":1,"method":"generate"}}"#).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let lines = BufReader::new(&client)
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        handler.join().unwrap().unwrap();
+
+        // Notifications come before the response
+        let (response, notifications) = lines.split_last().unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], 0);
+        assert!(notifications.iter().all(|notification| {
+            notification["method"] == "progress" && notification.get("id").is_none()
+        }));
+        let reported = |phase: &str, app: Option<&str>| {
+            notifications.iter().any(|notification| {
+                let progress = &notification["params"];
+                progress["phase"] == phase
+                    && progress.get("app").and_then(Value::as_str) == app
+                    && progress["current"].as_u64() <= progress["total"].as_u64()
+            })
+        };
+        assert!(reported("stage1", Some("example")));
+        assert!(reported("convert", Some("example")));
+        assert!(reported("write outputs", None));
+    }
+}
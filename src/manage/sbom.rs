@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Result;
+use rand::RngCore;
+use serde::Serialize;
+
+use super::{files, images::ImageRef};
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hash {
+    pub alg: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Component {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    #[serde(rename = "bom-ref")]
+    pub bom_ref: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub purl: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hashes: Vec<Hash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tool {
+    pub vendor: String,
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BomMetadata {
+    pub tools: Vec<Tool>,
+}
+
+/// A CycloneDX 1.5 bill of materials
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Bom {
+    pub bom_format: String,
+    pub spec_version: String,
+    pub serial_number: String,
+    pub version: u32,
+    pub metadata: BomMetadata,
+    pub components: Vec<Component>,
+}
+
+fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    // Version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Builds a bill of materials of the images of the given apps
+/// Images used by multiple services are listed once, with one nirvati:service property per service
+pub fn build_bom(nirvati_dir: &Path, apps: &[String]) -> Result<Bom> {
+    let digest_pins = files::get_digest_pins(nirvati_dir)?;
+    let mut users: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for app in apps {
+        let mut app_yml = files::read_app_yml(nirvati_dir, app)?;
+        if let Some(pins) = digest_pins.get(app) {
+            app_yml.pin_images(pins);
+        }
+        for (service, image) in app_yml.get_images() {
+            users
+                .entry(image)
+                .or_default()
+                .push(format!("{}/{}", app, service));
+        }
+    }
+    let components = users
+        .into_iter()
+        .map(|(image, services)| {
+            let image_ref = ImageRef::parse(&image);
+            let hashes = image_ref
+                .digest
+                .as_deref()
+                .and_then(|digest| digest.strip_prefix("sha256:"))
+                .map(|content| {
+                    vec![Hash {
+                        alg: "SHA-256".to_owned(),
+                        content: content.to_owned(),
+                    }]
+                })
+                .unwrap_or_default();
+            Component {
+                component_type: "container".to_owned(),
+                bom_ref: image.clone(),
+                name: image_ref.name.clone(),
+                version: image_ref.tag.clone().or_else(|| image_ref.digest.clone()),
+                purl: image_ref.purl(),
+                hashes,
+                properties: services
+                    .into_iter()
+                    .map(|service| Property {
+                        name: "nirvati:service".to_owned(),
+                        value: service,
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok(Bom {
+        bom_format: "CycloneDX".to_owned(),
+        spec_version: "1.5".to_owned(),
+        serial_number: format!("urn:uuid:{}", random_uuid()),
+        version: 1,
+        metadata: BomMetadata {
+            tools: vec![Tool {
+                vendor: "Nirvati".to_owned(),
+                name: env!("CARGO_PKG_NAME").to_owned(),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+            }],
+        },
+        components,
+    })
+}
@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::composegenerator::v1::{
+    import::{self, ImportNote},
+    RESERVED_NAMES,
+};
+
+use super::yaml;
+
+// The templates are embedded into the binary
+// __APP_ID__, __APP_NAME__ and __CATEGORY__ are replaced when scaffolding
+const METADATA_YML_JINJA: &str = include_str!("scaffold/metadata.yml.jinja");
+const SETTINGS_YML: &str = include_str!("scaffold/settings.yml");
+const ICON_SVG: &str = include_str!("scaffold/icon.svg");
+const TERA_HELPERS: &str = include_str!("scaffold/helpers.ts");
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppTemplate {
+    /// A single web app proxied through Caddy
+    Web,
+    /// A background service that exposes raw TCP ports
+    Daemon,
+    /// A web app with a separate database container
+    Db,
+}
+
+impl AppTemplate {
+    fn app_yml_jinja(self) -> &'static str {
+        match self {
+            AppTemplate::Web => include_str!("scaffold/web.app.yml.jinja"),
+            AppTemplate::Daemon => include_str!("scaffold/daemon.app.yml.jinja"),
+            AppTemplate::Db => include_str!("scaffold/db.app.yml.jinja"),
+        }
+    }
+
+    fn category(self) -> &'static str {
+        match self {
+            AppTemplate::Web => "Productivity",
+            AppTemplate::Daemon => "Networking",
+            AppTemplate::Db => "Productivity",
+        }
+    }
+}
+
+/// Turns an app id like "my-app" into a display name like "My App"
+fn app_name_from_id(app_id: &str) -> String {
+    app_id
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn fill_template(template: &str, app_id: &str, app_template: AppTemplate) -> String {
+    template
+        .replace("__APP_ID__", app_id)
+        .replace("__APP_NAME__", &app_name_from_id(app_id))
+        .replace("__CATEGORY__", app_template.category())
+}
+
+/// Checks that an app id can be used for a new app and returns the app's dir
+fn new_app_dir(nirvati_dir: &Path, app_id: &str) -> Result<PathBuf> {
+    if app_id.is_empty()
+        || !app_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        bail!("App ids may only contain lowercase letters, digits and dashes");
+    }
+    if RESERVED_NAMES.contains(&app_id) {
+        bail!("{} is a reserved name", app_id);
+    }
+    let app_dir = nirvati_dir.join("apps").join(app_id);
+    if app_dir.exists() {
+        bail!("App {} already exists", app_id);
+    }
+    Ok(app_dir)
+}
+
+/// Creates a new app directory from a template and returns its path
+pub fn scaffold_app(nirvati_dir: &Path, app_id: &str, template: AppTemplate) -> Result<PathBuf> {
+    let app_dir = new_app_dir(nirvati_dir, app_id)?;
+    std::fs::create_dir_all(app_dir.join("_tera"))?;
+    let files = [
+        ("metadata.yml.jinja", METADATA_YML_JINJA),
+        ("app.yml.jinja", template.app_yml_jinja()),
+        ("settings.yml", SETTINGS_YML),
+        ("icon.svg", ICON_SVG),
+        ("_tera/helpers.ts", TERA_HELPERS),
+    ];
+    for (file_name, contents) in files {
+        std::fs::write(
+            app_dir.join(file_name),
+            fill_template(contents, app_id, template),
+        )?;
+    }
+    Ok(app_dir)
+}
+
+/// Creates a new app directory with a draft app.yml and metadata.yml converted from a compose file
+/// Returns its path and what has to be reviewed
+pub fn import_compose(
+    nirvati_dir: &Path,
+    compose_file: &Path,
+    app_id: &str,
+) -> Result<(PathBuf, Vec<ImportNote>)> {
+    let app_dir = new_app_dir(nirvati_dir, app_id)?;
+    let contents = std::fs::read_to_string(compose_file)
+        .with_context(|| format!("Failed to read {}", compose_file.display()))?;
+    let compose = yaml::parse_value("docker-compose.yml", &contents)?;
+    let mut imported = import::import_compose(&compose, &app_name_from_id(app_id), None)?;
+    imported.notes.push(ImportNote {
+        field: "metadata".to_owned(),
+        message: "Fill in the category, tagline, description, developers, repo and support link in metadata.yml".to_owned(),
+        permission: None,
+    });
+    std::fs::create_dir_all(&app_dir)?;
+    std::fs::write(
+        app_dir.join("app.yml"),
+        serde_yaml::to_string(&imported.app_yml)?,
+    )?;
+    std::fs::write(
+        app_dir.join("metadata.yml"),
+        serde_yaml::to_string(&imported.metadata)?,
+    )?;
+    std::fs::write(app_dir.join("icon.svg"), ICON_SVG)?;
+    Ok((app_dir, imported.notes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, manage::validate::validate_app, testing::TempDir};
+
+    #[test]
+    fn scaffolded_apps_validate() {
+        let nirvati_dir = TempDir::new("scaffold");
+        std::fs::create_dir_all(nirvati_dir.join("db").join("nirvati-seed")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("db").join("nirvati-seed").join("seed"),
+            "seed",
+        )
+        .unwrap();
+        for (app_id, template) in [
+            ("my-web", AppTemplate::Web),
+            ("my-daemon", AppTemplate::Daemon),
+            ("my-db", AppTemplate::Db),
+        ] {
+            let app_dir = scaffold_app(&nirvati_dir, app_id, template).unwrap();
+            assert_eq!(app_dir, nirvati_dir.join("apps").join(app_id));
+            let result = validate_app(&nirvati_dir, app_id, &Config::default())
+                .unwrap_or_else(|err| panic!("{} doesn't validate: {:#}", app_id, err));
+            let name = app_name_from_id(app_id);
+            assert_eq!(result.metadata.name, name);
+            // The tagline comes from the JS helper
+            assert_eq!(
+                result.metadata.tagline,
+                format!("{}, running on your own server", name)
+            );
+        }
+
+        let existing = scaffold_app(&nirvati_dir, "my-web", AppTemplate::Web).unwrap_err();
+        assert_eq!(existing.to_string(), "App my-web already exists");
+        assert!(scaffold_app(&nirvati_dir, "My_App", AppTemplate::Web).is_err());
+        assert!(scaffold_app(&nirvati_dir, RESERVED_NAMES[0], AppTemplate::Web).is_err());
+    }
+}
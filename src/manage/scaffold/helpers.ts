@@ -0,0 +1,5 @@
+// Top-level functions with exactly one parameter can be called from this app's templates,
+// for example {{ tagline(name="My app") }}. All arguments are passed as a single object.
+function tagline(args: { name: string }): string {
+  return `${args.name}, running on your own server`;
+}
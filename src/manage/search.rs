@@ -0,0 +1,109 @@
+//! The search index in apps/search.json, so the dashboard can search apps without loading the whole registry
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::OutputMetadata;
+
+const NAME_WEIGHT: u32 = 10;
+const CATEGORY_WEIGHT: u32 = 5;
+const TAGLINE_WEIGHT: u32 = 3;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+/// Words that match almost every app
+const STOP_WORDS: [&str; 20] = [
+    "an", "and", "are", "as", "at", "be", "by", "for", "from", "in", "is", "it", "of", "on", "or",
+    "that", "the", "to", "with", "your",
+];
+
+/// Contents of apps/search.json
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchIndex {
+    /// The ids of the indexed apps, sorted
+    pub apps: Vec<String>,
+    /// Token -> (index in apps, weight) for every app containing the token, highest weight first
+    pub terms: BTreeMap<String, Vec<(usize, u32)>>,
+}
+
+/// Splits text into lowercase words, without stop words and single characters
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+/// Builds the index from the name, category, tagline and description of the apps in the registry
+pub fn build_index(registry: &[OutputMetadata]) -> SearchIndex {
+    let mut apps = registry
+        .iter()
+        .map(|app| app.id.clone())
+        .collect::<Vec<_>>();
+    apps.sort();
+    apps.dedup();
+    let mut weights = BTreeMap::<String, BTreeMap<usize, u32>>::new();
+    for app in registry {
+        let Ok(index) = apps.binary_search(&app.id) else {
+            continue;
+        };
+        for (text, weight) in [
+            (&app.name, NAME_WEIGHT),
+            (&app.category, CATEGORY_WEIGHT),
+            (&app.tagline, TAGLINE_WEIGHT),
+            (&app.description, DESCRIPTION_WEIGHT),
+        ] {
+            for token in tokenize(text) {
+                *weights.entry(token).or_default().entry(index).or_default() += weight;
+            }
+        }
+    }
+    let terms = weights
+        .into_iter()
+        .map(|(token, apps)| {
+            let mut apps = apps.into_iter().collect::<Vec<_>>();
+            apps.sort_by_key(|(index, weight)| (std::cmp::Reverse(*weight), *index));
+            (token, apps)
+        })
+        .collect();
+    SearchIndex { apps, terms }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn app(id: &str, name: &str, category: &str, tagline: &str) -> OutputMetadata {
+        OutputMetadata {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            category: category.to_owned(),
+            tagline: tagline.to_owned(),
+            description: "The best app for your notes".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn weights_matches_by_field() {
+        let index = build_index(&[
+            app("notes", "Notes", "Productivity", "Write notes"),
+            app("bitcoin", "Bitcoin Node", "Finance", "A full node"),
+        ]);
+        assert_eq!(index.apps, vec!["bitcoin".to_owned(), "notes".to_owned()]);
+        // Names weigh more than taglines and descriptions
+        assert_eq!(
+            index.terms["notes"],
+            vec![
+                (1, NAME_WEIGHT + TAGLINE_WEIGHT + DESCRIPTION_WEIGHT),
+                (0, DESCRIPTION_WEIGHT)
+            ]
+        );
+        assert_eq!(index.terms["node"], vec![(0, NAME_WEIGHT + TAGLINE_WEIGHT)]);
+        assert_eq!(index.terms["finance"], vec![(0, CATEGORY_WEIGHT)]);
+        for left_out in ["the", "for", "your", "a"] {
+            assert!(!index.terms.contains_key(left_out), "{}", left_out);
+        }
+    }
+}
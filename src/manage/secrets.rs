@@ -0,0 +1,306 @@
+//! Settings and permission variables that reference secrets in external stores instead of containing them
+//!
+//! A reference looks like `secretRef:<scheme>:<reference>`, for example `secretRef:file:lnd/password`
+//! or `secretRef:exec:lnd/password`, and is resolved while rendering app.yml.jinja.
+//! Settings are set by the user and can reference any secret, but permission variables come from store apps,
+//! so they can only reference secrets under `<app>/` of the app that exports them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Component, Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::files::SimpleValue;
+
+pub const SECRET_REF_PREFIX: &str = "secretRef:";
+
+/// Resolves the references of one scheme, which get the part after `secretRef:<scheme>:`
+pub trait SecretResolver: Send + Sync {
+    fn resolve(&self, reference: &str) -> Result<String>;
+}
+
+/// Reads secrets from files in a dir, for example one a secret store decrypts its secrets to
+pub struct FileResolver {
+    dir: PathBuf,
+}
+
+impl FileResolver {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl SecretResolver for FileResolver {
+    fn resolve(&self, reference: &str) -> Result<String> {
+        let path = Path::new(reference);
+        if reference.is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!(
+                "Secret file {} has to be a relative path without ..",
+                reference
+            );
+        }
+        let contents = std::fs::read_to_string(self.dir.join(path))
+            .map_err(|err| anyhow!("Failed to read secret file {}: {}", reference, err))?;
+        Ok(contents.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+/// Runs a command with `--` and the reference as its last arguments and uses what it prints,
+/// like `pass show`, `vault kv get -field=value` or `sops -d --extract`
+pub struct ExecResolver {
+    command: Vec<String>,
+    /// Commands can be slow or ask for a passphrase, so every reference is only resolved once
+    cache: Mutex<HashMap<String, String>>,
+}
+
+impl ExecResolver {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl SecretResolver for ExecResolver {
+    fn resolve(&self, reference: &str) -> Result<String> {
+        let mut cache = self.cache.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(secret) = cache.get(reference) {
+            return Ok(secret.clone());
+        }
+        let Some((program, args)) = self.command.split_first() else {
+            bail!("No secret command set in [secrets] of the config");
+        };
+        let output = Command::new(program)
+            .args(args)
+            .arg("--")
+            .arg(reference)
+            .output()
+            .map_err(|err| anyhow!("Failed to run {}: {}", program, err))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed to resolve secret {}: {}",
+                program,
+                reference,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let secret = String::from_utf8(output.stdout)
+            .map_err(|_| anyhow!("Secret {} is not valid UTF-8", reference))?
+            .trim_end_matches(['\r', '\n'])
+            .to_owned();
+        cache.insert(reference.to_owned(), secret.clone());
+        Ok(secret)
+    }
+}
+
+/// The built-in resolvers, set in [secrets] of the config
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// The dir of secretRef:file:, relative to the Nirvati root unless absolute, defaults to secrets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// The command of secretRef:exec:, which is disabled if this is empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+}
+
+static RESOLVERS: Mutex<BTreeMap<String, Arc<dyn SecretResolver>>> = Mutex::new(BTreeMap::new());
+
+/// Secrets that were rendered into templates, secret -> a name for it, so export can redact them
+static RENDERED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Remembers a secret rendered into a template, named like an env var after where it came from
+pub fn record_rendered(name: &str, secret: &str) {
+    let name = name
+        .to_uppercase()
+        .replace(|char: char| !char.is_ascii_alphanumeric(), "_");
+    RENDERED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(secret.to_owned(), name);
+}
+
+/// The secrets rendered so far, secret -> name
+pub fn rendered() -> BTreeMap<String, String> {
+    RENDERED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// Registers a resolver for secretRef:<scheme>:, replacing any previous one
+pub fn register(scheme: &str, resolver: Arc<dyn SecretResolver>) {
+    RESOLVERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(scheme.to_owned(), resolver);
+}
+
+/// Registers the built-in file and exec resolvers
+pub fn configure(nirvati_dir: &Path, config: &SecretsConfig) {
+    let dir = config
+        .dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("secrets"));
+    register("file", Arc::new(FileResolver::new(nirvati_dir.join(dir))));
+    if !config.command.is_empty() {
+        register("exec", Arc::new(ExecResolver::new(config.command.clone())));
+    }
+}
+
+/// Splits a secret reference into its scheme and reference, and returns None if the value isn't one
+fn parse(value: &str) -> Result<Option<(&str, &str)>> {
+    let Some(secret_ref) = value.strip_prefix(SECRET_REF_PREFIX) else {
+        return Ok(None);
+    };
+    let (scheme, reference) = secret_ref.split_once(':').ok_or_else(|| {
+        anyhow!(
+            "Invalid secret reference {}, use secretRef:<scheme>:<reference>",
+            value
+        )
+    })?;
+    // Resolvers pass references to commands, which would parse them as options
+    if reference.starts_with('-') {
+        bail!("Secret reference {} can't start with -", reference);
+    }
+    Ok(Some((scheme, reference)))
+}
+
+/// Resolves a value if it is a secret reference, and returns None otherwise
+pub fn resolve(value: &str) -> Result<Option<String>> {
+    let Some((scheme, reference)) = parse(value)? else {
+        return Ok(None);
+    };
+    let resolver = RESOLVERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(scheme)
+        .cloned()
+        .ok_or_else(|| anyhow!("No secret resolver for {}{}", SECRET_REF_PREFIX, scheme))?;
+    let secret = resolver.resolve(reference)?;
+    record_rendered(reference, &secret);
+    Ok(Some(secret))
+}
+
+/// Replaces the secret references in an app's settings with the secrets
+pub fn resolve_settings(settings: &mut HashMap<String, SimpleValue>) -> Result<()> {
+    for (key, value) in settings.iter_mut() {
+        if let SimpleValue::String(string) = value {
+            if let Some(secret) =
+                resolve(string).map_err(|err| anyhow!("Setting {}: {:#}", key, err))?
+            {
+                *string = secret;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the secret references in a permission variable app exports with the secrets,
+/// which have to be under `<app>/`, so an app can't export the secrets of other apps
+pub fn resolve_exported(value: &mut serde_json::Value, app: &str) -> Result<()> {
+    match value {
+        serde_json::Value::String(string) => {
+            if let Some((_, reference)) = parse(string)? {
+                let in_namespace = reference
+                    .strip_prefix(app)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .is_some_and(|rest| !rest.is_empty());
+                if !in_namespace {
+                    bail!(
+                        "Secret reference {} is not under {}/, apps can only export their own secrets",
+                        reference,
+                        app
+                    );
+                }
+            }
+            if let Some(secret) = resolve(string)? {
+                *string = secret;
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                resolve_exported(value, app)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                resolve_exported(value, app).map_err(|err| anyhow!("{}: {:#}", key, err))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn resolves_secret_refs() {
+        let dir = TempDir::new("secrets");
+        std::fs::create_dir_all(dir.join("lnd")).unwrap();
+        std::fs::write(dir.join("lnd").join("password"), "hunter2\n").unwrap();
+        register("test-file", Arc::new(FileResolver::new(dir.to_path_buf())));
+
+        let secret = resolve("secretRef:test-file:lnd/password");
+        let mut variables = json!({
+            "APP_LND_PASSWORD": "secretRef:test-file:lnd/password",
+            "APP_LND_PORTS": [10009],
+        });
+        let resolved = resolve_exported(&mut variables, "lnd");
+
+        assert_eq!(secret.unwrap().as_deref(), Some("hunter2"));
+        resolved.unwrap();
+        assert_eq!(variables["APP_LND_PASSWORD"], "hunter2");
+        assert_eq!(variables["APP_LND_PORTS"], json!([10009]));
+        assert_eq!(resolve("plain value").unwrap(), None);
+        assert!(resolve("secretRef:test-file:../password").is_err());
+        assert!(resolve("secretRef:missing:password").is_err());
+        assert!(resolve("secretRef:test-file:--help").is_err());
+
+        let exec = ExecResolver::new(vec!["echo".to_owned(), "secret".to_owned()]);
+        assert_eq!(
+            exec.resolve("nirvati/lnd").unwrap(),
+            "secret -- nirvati/lnd"
+        );
+    }
+
+    #[test]
+    fn exported_variables_only_resolve_own_secrets() {
+        register(
+            "test-exported",
+            Arc::new(ExecResolver::new(vec!["echo".to_owned()])),
+        );
+        let mut own = json!({ "APP_LND_PASSWORD": "secretRef:test-exported:lnd/password" });
+        resolve_exported(&mut own, "lnd").unwrap();
+        assert_eq!(own["APP_LND_PASSWORD"], "-- lnd/password");
+
+        // A store app that exports another app's secret to the apps that get its permission
+        for reference in [
+            "secretRef:test-exported:bitcoin/password",
+            "secretRef:test-exported:lnd-evil/password",
+            "secretRef:test-exported:lnd/",
+            "secretRef:test-exported:-lnd/password",
+        ] {
+            let mut variables = json!({ "APP_EVIL_PASSWORD": [reference] });
+            assert!(resolve_exported(&mut variables, "lnd").is_err());
+            assert_eq!(variables["APP_EVIL_PASSWORD"][0], reference);
+        }
+    }
+}
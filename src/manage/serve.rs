@@ -0,0 +1,339 @@
+//! A read-only HTTP server for the dashboard backend, run with `app-manager serve`
+//!
+//! It serves the files the app manager writes to apps/ as JSON, so the dashboard doesn't have to read them itself.
+//! Responses have an ETag, requests with a matching If-None-Match get a 304 without a body.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use hyper_util::{
+    rt::{TokioIo, TokioTimer},
+    service::TowerToHyperService,
+};
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::Semaphore};
+
+use crate::{composegenerator::types::OutputMetadata, dependencies::get_consumers};
+
+use super::files;
+
+/// The whole request head has to arrive within this time, so slow clients can't hold a connection
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections answered at the same time, others get a 503 right away
+const MAX_CONNECTIONS: usize = 16;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AppInfo {
+    #[serde(flatten)]
+    pub metadata: OutputMetadata,
+    pub installed: bool,
+    /// Installed apps that depend on the app or use its permissions
+    pub required_by: Vec<String>,
+}
+
+/// An app's registry entry, whether it is installed and the installed apps that depend on it
+pub fn app_info(nirvati_dir: &Path, app: &str) -> Result<Option<AppInfo>> {
+    let Some(metadata) = files::get_app_registry(nirvati_dir)?
+        .into_iter()
+        .find(|entry| entry.id == app)
+    else {
+        return Ok(None);
+    };
+    let installed_apps = files::get_installed_apps(nirvati_dir)?;
+    let rdeps = files::get_reverse_index(nirvati_dir)?;
+    Ok(Some(AppInfo {
+        installed: installed_apps.iter().any(|installed| installed == app),
+        required_by: get_consumers(&rdeps, app)
+            .into_iter()
+            .filter(|consumer| installed_apps.contains(consumer))
+            .collect(),
+        metadata,
+    }))
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// A 200 with an ETag, or a 304 if the client already has this body
+fn cached(body: Vec<u8>, headers: &HeaderMap) -> Response {
+    let etag = format!("\"{}\"", &hex::encode(hmac_sha256::Hash::hash(&body))[..32]);
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+    let headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "no-cache".to_owned()),
+    ];
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, headers).into_response()
+    } else {
+        (headers, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+    }
+}
+
+/// A file in apps/, or a 404 naming the command that writes it
+async fn file_response(path: PathBuf, written_by: &str, headers: &HeaderMap) -> Response {
+    match tokio::fs::read(path).await {
+        Ok(body) => cached(body, headers),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            error(StatusCode::NOT_FOUND, &format!("Run {} first", written_by))
+        }
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+type NirvatiDir = State<Arc<PathBuf>>;
+
+async fn registry(State(nirvati_dir): NirvatiDir, headers: HeaderMap) -> Response {
+    let path = nirvati_dir.join("apps").join("registry.json");
+    file_response(path, "generate", &headers).await
+}
+
+async fn search(State(nirvati_dir): NirvatiDir, headers: HeaderMap) -> Response {
+    let path = nirvati_dir.join("apps").join("search.json");
+    file_response(path, "generate", &headers).await
+}
+
+async fn updates(State(nirvati_dir): NirvatiDir, headers: HeaderMap) -> Response {
+    let path = nirvati_dir.join("apps").join("updates.json");
+    file_response(path, "check-updates", &headers).await
+}
+
+async fn app(
+    State(nirvati_dir): NirvatiDir,
+    UrlPath(app): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    let info = tokio::task::spawn_blocking(move || app_info(&nirvati_dir, &app)).await;
+    match info {
+        Ok(Ok(Some(info))) => match serde_json::to_vec(&info) {
+            Ok(body) => cached(body, &headers),
+            Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        },
+        Ok(Ok(None)) => error(StatusCode::NOT_FOUND, "App does not exist"),
+        Ok(Err(err)) => error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{:#}", err)),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+/// The routes of the server, GET routes answer HEAD requests too
+pub fn router(nirvati_dir: &Path) -> Router {
+    Router::new()
+        .route("/registry", get(registry))
+        .route("/search", get(search))
+        .route("/updates", get(updates))
+        .route("/apps/:app", get(app))
+        .fallback(|| async { error(StatusCode::NOT_FOUND, "Unknown endpoint") })
+        .with_state(Arc::new(nirvati_dir.to_owned()))
+}
+
+/// Answers the connections of a listener until it fails, up to MAX_CONNECTIONS at a time
+async fn serve_listener(
+    listener: TcpListener,
+    router: Router,
+    head_timeout: Duration,
+) -> Result<()> {
+    let busy = Router::new()
+        .fallback(|| async { error(StatusCode::SERVICE_UNAVAILABLE, "Too many connections") });
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+        let slot = Arc::clone(&connections).try_acquire_owned().ok();
+        let service = if slot.is_some() {
+            router.clone()
+        } else {
+            busy.clone()
+        };
+        tokio::spawn(async move {
+            let _slot = slot;
+            let connection = hyper::server::conn::http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(head_timeout)
+                .keep_alive(false)
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+            if let Err(err) = connection.await {
+                tracing::warn!("Failed to answer a request: {}", err);
+            }
+        });
+    }
+}
+
+/// Serves requests until the process is stopped
+pub fn serve(nirvati_dir: &Path, listen: SocketAddr) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(listen)
+            .await
+            .map_err(|err| anyhow!("Failed to listen on {}: {}", listen, err))?;
+        tracing::info!("Listening on {}", listen);
+        serve_listener(listener, router(nirvati_dir), HEAD_TIMEOUT).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::{body::Body, http::Request};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    async fn get(router: &Router, method: &str, uri: &str, etag: Option<&str>) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn serves_files_with_etags() {
+        let nirvati_dir = TempDir::new("serve");
+        std::fs::create_dir_all(nirvati_dir.join("apps")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("apps").join("search.json"),
+            "{\"apps\":[]}",
+        )
+        .unwrap();
+        std::fs::write(nirvati_dir.join("apps").join("registry.json"), "[]").unwrap();
+        let router = router(&nirvati_dir);
+
+        let response = get(&router, "GET", "/search?q=notes", None).await;
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let updates = get(&router, "GET", "/updates", None).await;
+        let post = get(&router, "POST", "/search", None).await;
+        let unknown = get(&router, "GET", "/secrets", None).await;
+        let missing_app = get(&router, "GET", "/apps/notes", None).await;
+        let cached = get(&router, "GET", "/search", Some(&etag)).await;
+        let head = get(&router, "HEAD", "/search", None).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, b"{\"apps\":[]}");
+        assert_eq!(updates.status(), StatusCode::NOT_FOUND);
+        assert_eq!(post.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        assert_eq!(missing_app.status(), StatusCode::NOT_FOUND);
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[header::ETAG], etag.as_str());
+        assert!(body(cached).await.is_empty());
+        assert_eq!(head.status(), StatusCode::OK);
+        assert!(body(head).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_heads_time_out() {
+        let nirvati_dir = TempDir::new("serve-timeout");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(
+            listener,
+            router(&nirvati_dir),
+            Duration::from_millis(300),
+        ));
+
+        // A client that never ends the head is disconnected after the timeout
+        let mut slow = tokio::net::TcpStream::connect(address).await.unwrap();
+        slow.write_all(b"GET /registry HTTP/1.1\r\n").await.unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        slow.read_to_end(&mut response).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let mut complete = tokio::net::TcpStream::connect(address).await.unwrap();
+        complete
+            .write_all(b"GET /registry HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        complete.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_connections() {
+        let nirvati_dir = TempDir::new("serve-limit");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(
+            listener,
+            router(&nirvati_dir),
+            Duration::from_secs(5),
+        ));
+
+        // Clients that didn't finish their head yet hold their connection
+        let mut waiting = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+            client
+                .write_all(b"GET /registry HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            waiting.push(client);
+        }
+        let mut rejected = tokio::net::TcpStream::connect(address).await.unwrap();
+        rejected
+            .write_all(b"GET /registry HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        rejected.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 503"));
+
+        // Finished connections free their slot
+        drop(waiting);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut accepted = tokio::net::TcpStream::connect(address).await.unwrap();
+        accepted
+            .write_all(b"GET /registry HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        accepted.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 404"));
+    }
+}
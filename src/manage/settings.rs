@@ -0,0 +1,275 @@
+//! Validation of app settings against an app's settings.yml, and which templates a change affects
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::files::SimpleValue;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SettingType {
+    String,
+    Number,
+    Float,
+    /// Types the app manager can't validate
+    #[default]
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct SettingDefinition {
+    #[serde(rename = "type", default)]
+    pub setting_type: SettingType,
+    #[serde(default)]
+    pub default: Option<SimpleValue>,
+}
+
+/// The schema of an app's settings, from its settings.yml
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct SettingsYml {
+    pub settings: BTreeMap<String, SettingDefinition>,
+}
+
+impl SettingsYml {
+    /// Returns the settings that have a default value, with that value
+    pub fn defaults(&self) -> BTreeMap<String, SimpleValue> {
+        self.settings
+            .iter()
+            .filter_map(|(key, definition)| Some((key.clone(), definition.default.clone()?)))
+            .collect()
+    }
+}
+
+pub fn read_settings_yml(nirvati_dir: &Path, app: &str) -> Result<Option<SettingsYml>> {
+    let settings_yml_path = nirvati_dir.join("apps").join(app).join("settings.yml");
+    if !settings_yml_path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(super::yaml::from_str(
+        "settings.yml",
+        &std::fs::read_to_string(settings_yml_path)?,
+    )?))
+}
+
+/// Checks that every setting is declared in the schema and has the declared type
+pub fn validate_settings(
+    schema: &SettingsYml,
+    settings: &HashMap<String, SimpleValue>,
+) -> Result<()> {
+    for (key, value) in settings {
+        let Some(definition) = schema.settings.get(key) else {
+            bail!("Unknown setting {}", key);
+        };
+        let valid = matches!(
+            (definition.setting_type, value),
+            (SettingType::String, SimpleValue::String(_))
+                | (SettingType::Number, SimpleValue::Number(_))
+                | (
+                    SettingType::Float,
+                    SimpleValue::Float(_) | SimpleValue::Number(_)
+                )
+                | (SettingType::Other, _)
+        );
+        if !valid {
+            bail!("Setting {} has to be a {:?}", key, definition.setting_type);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the keys whose values differ between the old and new settings
+pub fn changed_keys(
+    old: &HashMap<String, SimpleValue>,
+    new: &HashMap<String, SimpleValue>,
+) -> Vec<String> {
+    let as_json = |value: Option<&SimpleValue>| value.and_then(|v| serde_json::to_value(v).ok());
+    let mut keys = old
+        .keys()
+        .chain(new.keys())
+        .filter(|key| as_json(old.get(*key)) != as_json(new.get(*key)))
+        .cloned()
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// The templates of an app that use changed settings
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingsImpact {
+    pub changed: Vec<String>,
+    /// Whether app.yml.jinja or metadata.yml.jinja uses a changed setting, so the app has to be regenerated
+    pub compose: bool,
+    /// Config templates that use a changed setting
+    pub config_templates: Vec<String>,
+}
+
+/// The expressions and statements of a template, where variables can be used
+fn template_tags(template: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let end = match after.chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            _ => {
+                rest = after;
+                continue;
+            }
+        };
+        let Some(len) = after[1..].find(end) else {
+            // An unclosed tag fails to render anyway, count the rest as used
+            tags.push(&after[1..]);
+            break;
+        };
+        tags.push(&after[1..len + 1]);
+        rest = &after[len + 1 + end.len()..];
+    }
+    tags
+}
+
+/// Whether a template uses one of the keys, or settings as a whole,
+/// like in a loop, with json_encode or as argument of a JS helper
+fn uses_settings(template: &str, keys: &[String]) -> bool {
+    let is_ident = |char: char| char.is_ascii_alphanumeric() || char == '_';
+    for tag in template_tags(template) {
+        if tag.contains("__tera_context") {
+            return true;
+        }
+        for (index, _) in tag.match_indices("settings") {
+            // Other variables, like app_settings, or attributes, like app.settings
+            if tag[..index]
+                .chars()
+                .next_back()
+                .is_some_and(|char| is_ident(char) || char == '.')
+            {
+                continue;
+            }
+            let rest = &tag[index + "settings".len()..];
+            if rest.chars().next().is_some_and(is_ident) {
+                continue;
+            }
+            let key = if let Some(rest) = rest.strip_prefix('.') {
+                rest.split(|char: char| !is_ident(char)).next()
+            } else if let Some(rest) = rest.strip_prefix("[\"").or_else(|| rest.strip_prefix("['"))
+            {
+                rest.split(['"', '\'']).next()
+            } else {
+                None
+            };
+            match key {
+                Some(key) if !key.is_empty() => {
+                    if keys.iter().any(|changed| changed == key) {
+                        return true;
+                    }
+                }
+                _ => return true,
+            }
+        }
+    }
+    false
+}
+
+pub fn get_impact(nirvati_dir: &Path, app: &str, changed: Vec<String>) -> Result<SettingsImpact> {
+    let mut impact = SettingsImpact::default();
+    for entry in std::fs::read_dir(nirvati_dir.join("apps").join(app))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(".jinja") || !entry.path().is_file() {
+            continue;
+        }
+        let template = std::fs::read_to_string(entry.path())?;
+        if !uses_settings(&template, &changed) {
+            continue;
+        }
+        if name == "app.yml.jinja" || name == "metadata.yml.jinja" {
+            impact.compose = true;
+        } else {
+            impact.config_templates.push(name);
+        }
+    }
+    impact.config_templates.sort();
+    impact.changed = changed;
+    Ok(impact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_used_settings() {
+        let changed = keys(&["port"]);
+        for used in [
+            "{{ settings.port }}",
+            "{{ settings['port'] }}",
+            "{{ settings[\"port\"] | default(value=80) }}",
+            "{{ settings | json_encode() }}",
+            "{% for key, value in settings %}{{ key }}{% endfor %}",
+            "{{ my_helper(values=settings) }}",
+            "{{ settings[name] }}",
+            "{{ __tera_context }}",
+        ] {
+            assert!(uses_settings(used, &changed), "{}", used);
+        }
+        for unused in [
+            "{{ settings.name }}",
+            "{{ settings.port_range }}",
+            "{{ app_settings.port }}",
+            "{{ app.settings }}",
+            "settings: {{ app.id }}",
+            "",
+        ] {
+            assert!(!uses_settings(unused, &changed), "{}", unused);
+        }
+    }
+
+    #[test]
+    fn gets_affected_templates() {
+        let nirvati_dir = TempDir::new("settings-impact");
+        let app_dir = nirvati_dir.join("apps").join("notes");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(app_dir.join("app.yml.jinja"), "version: 1\n").unwrap();
+        std::fs::write(
+            app_dir.join("metadata.yml.jinja"),
+            "name: {{ settings.name }}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("config.toml.jinja"),
+            "{{ settings | json_encode() }}",
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("nginx.conf.jinja"),
+            "listen {{ settings.port }};",
+        )
+        .unwrap();
+
+        let impact = get_impact(&nirvati_dir, "notes", keys(&["name"])).unwrap();
+        assert!(impact.compose);
+        assert_eq!(impact.config_templates, vec!["config.toml.jinja"]);
+
+        let impact = get_impact(&nirvati_dir, "notes", keys(&["port"])).unwrap();
+        assert!(!impact.compose);
+        assert_eq!(
+            impact.config_templates,
+            vec!["config.toml.jinja", "nginx.conf.jinja"]
+        );
+        assert_eq!(impact.changed, vec!["port"]);
+    }
+}
@@ -0,0 +1,213 @@
+//! The order in which the host's shutdown script stops apps, written to apps/shutdown.yml
+//!
+//! Apps are stopped before the apps they depend on or use permissions of, so databases and other
+//! shared services are stopped last, after everything that could still write to them.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::{
+        output::types::DependsOn,
+        types::{OutputMetadata, ResultYml},
+        v1::helpers::parse_duration,
+    },
+    dependencies::{sort_deps, Node},
+};
+
+use super::dns::hostname;
+
+/// Docker's stop timeout for containers without a stop_grace_period, in seconds
+pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+/// An entry of apps/shutdown.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownEntry {
+    pub app: String,
+    /// The app's containers in the order they are stopped
+    pub containers: Vec<String>,
+    /// How long to wait for the app to stop in seconds, its longest stop_grace_period
+    pub stop_timeout: u64,
+}
+
+/// Sorts ids so everything comes after what it depends on
+/// Ids in a dependency cycle are appended alphabetically
+fn start_order(dependencies: BTreeMap<String, Vec<String>>) -> Vec<String> {
+    let ids = dependencies.keys().cloned().collect::<Vec<_>>();
+    let mut sorted = sort_deps(
+        dependencies
+            .into_iter()
+            .map(|(id, dependencies)| Node {
+                dependencies: dependencies
+                    .into_iter()
+                    .filter(|dependency| dependency != &id && ids.contains(dependency))
+                    .collect(),
+                id,
+            })
+            .collect(),
+    );
+    for id in ids {
+        if !sorted.contains(&id) {
+            sorted.push(id);
+        }
+    }
+    sorted
+}
+
+/// Returns the entry of an app, after its container names are set
+pub fn get_entry(app: &str, result: &ResultYml) -> ShutdownEntry {
+    let services = &result.spec.services;
+    let mut containers = start_order(
+        services
+            .iter()
+            .map(|(name, service)| {
+                let dependencies = match &service.depends_on {
+                    Some(DependsOn::List(dependencies)) => dependencies.clone(),
+                    Some(DependsOn::Conditions(conditions)) => conditions.keys().cloned().collect(),
+                    None => Vec::new(),
+                };
+                (name.clone(), dependencies)
+            })
+            .collect(),
+    )
+    .into_iter()
+    .map(|name| {
+        services[&name]
+            .container_name
+            .clone()
+            .unwrap_or_else(|| hostname(app, &name))
+    })
+    .collect::<Vec<_>>();
+    containers.reverse();
+    let stop_timeout = services
+        .values()
+        .map(|service| {
+            service
+                .stop_grace_period
+                .as_deref()
+                .and_then(parse_duration)
+                .map_or(DEFAULT_STOP_TIMEOUT, |duration| {
+                    duration.as_secs_f64().ceil() as u64
+                })
+        })
+        .max()
+        .unwrap_or(DEFAULT_STOP_TIMEOUT);
+    ShutdownEntry {
+        app: app.to_owned(),
+        containers,
+        stop_timeout,
+    }
+}
+
+/// Replaces the entries of the processed apps, removes those of apps that are not installed
+/// and orders them so apps are stopped before the apps they use
+pub fn merge_entries(
+    previous: Vec<ShutdownEntry>,
+    registry: &[OutputMetadata],
+    processed_apps: &[String],
+    installed_apps: &[String],
+    new_entries: Vec<ShutdownEntry>,
+) -> Vec<ShutdownEntry> {
+    let mut entries = previous
+        .into_iter()
+        .filter(|entry| !processed_apps.contains(&entry.app))
+        .chain(new_entries)
+        .filter(|entry| installed_apps.contains(&entry.app))
+        .map(|entry| (entry.app.clone(), entry))
+        .collect::<BTreeMap<_, _>>();
+    let dependencies = entries
+        .keys()
+        .map(|app| {
+            let used_apps = registry
+                .iter()
+                .find(|metadata| &metadata.id == app)
+                .map(|metadata| {
+                    metadata
+                        .resolved_dependencies
+                        .iter()
+                        .flatten()
+                        .cloned()
+                        .chain(metadata.has_permissions.iter().filter_map(|permission| {
+                            Some(permission.split('/').next()?.to_owned())
+                        }))
+                        .collect()
+                })
+                .unwrap_or_default();
+            (app.clone(), used_apps)
+        })
+        .collect();
+    let mut order = start_order(dependencies);
+    order.reverse();
+    order
+        .into_iter()
+        .filter_map(|app| entries.remove(&app))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composegenerator::output::types::Service;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn databases_stop_last() {
+        let mut result = ResultYml::default();
+        result.spec.services.insert(
+            "main".to_owned(),
+            Service {
+                depends_on: Some(DependsOn::List(vec!["db".to_owned()])),
+                ..Default::default()
+            },
+        );
+        result.spec.services.insert(
+            "db".to_owned(),
+            Service {
+                stop_grace_period: Some("1m30s".to_owned()),
+                ..Default::default()
+            },
+        );
+        let entry = get_entry("nextcloud", &result);
+        assert_eq!(entry.containers, vec!["nextcloud_main", "nextcloud_db"]);
+        assert_eq!(entry.stop_timeout, 90);
+
+        let registry = vec![
+            OutputMetadata {
+                id: "nextcloud".to_owned(),
+                resolved_dependencies: vec![Some("postgres".to_owned())],
+                ..Default::default()
+            },
+            OutputMetadata {
+                id: "backup".to_owned(),
+                has_permissions: vec!["nextcloud/files".to_owned()],
+                ..Default::default()
+            },
+        ];
+        let postgres = ShutdownEntry {
+            app: "postgres".to_owned(),
+            containers: vec!["postgres_main".to_owned()],
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+        };
+        let backup = ShutdownEntry {
+            app: "backup".to_owned(),
+            ..postgres.clone()
+        };
+        let entries = merge_entries(
+            vec![postgres, backup],
+            &registry,
+            &["nextcloud".to_owned()],
+            &[
+                "backup".to_owned(),
+                "nextcloud".to_owned(),
+                "postgres".to_owned(),
+            ],
+            vec![entry],
+        );
+        let order = entries
+            .iter()
+            .map(|entry| entry.app.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["backup", "nextcloud", "postgres"]);
+    }
+}
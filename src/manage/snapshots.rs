@@ -0,0 +1,239 @@
+//! Which ZFS datasets or btrfs subvolumes belong to an app, written to apps/snapshots.yml,
+//! so host tooling can snapshot apps, for example before updating them
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{dirs, files};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotBackend {
+    Zfs,
+    Btrfs,
+}
+
+/// How app data dirs can be snapshotted, set in [snapshots] of the config
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<SnapshotBackend>,
+    /// Storage pool -> the ZFS dataset of its dir, whose child datasets are the data dirs of apps
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub datasets: BTreeMap<String, String>,
+}
+
+/// An entry of apps/snapshots.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub app: String,
+    /// The data dir of the app
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<SnapshotBackend>,
+    /// The ZFS dataset or btrfs subvolume to snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Whether the app keeps data a failed update could break, so it should be snapshotted before updates
+    pub pre_update: bool,
+}
+
+/// What host tooling needs to snapshot an app before updating it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PreUpdateManifest {
+    #[serde(flatten)]
+    pub entry: SnapshotEntry,
+    /// The installed version, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// A name for the snapshot, pass the ID of the snapshot to the update with --snapshot-id
+    pub snapshot_name: String,
+}
+
+/// Builds the snapshot entry of an app
+pub fn entry(nirvati_dir: &Path, config: &SnapshotConfig, app: &str) -> Result<SnapshotEntry> {
+    let path = dirs::app_data_dir(nirvati_dir, app);
+    let target = match config.backend {
+        Some(SnapshotBackend::Zfs) => {
+            let pool = files::get_storage_pool(nirvati_dir, app)?
+                .unwrap_or_else(|| dirs::DEFAULT_POOL.to_owned());
+            let dataset = config.datasets.get(&pool).ok_or_else(|| {
+                anyhow!(
+                    "Storage pool {} has no dataset in [snapshots] of the config",
+                    pool
+                )
+            })?;
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Data dir of {} has no name", app))?;
+            Some(format!("{}/{}", dataset, name.to_string_lossy()))
+        }
+        Some(SnapshotBackend::Btrfs) => Some(path.to_string_lossy().into_owned()),
+        None => None,
+    };
+    Ok(SnapshotEntry {
+        app: app.to_owned(),
+        backend: config.backend,
+        target,
+        pre_update: !files::get_data_dirs(nirvati_dir, app)?.is_empty(),
+        path,
+    })
+}
+
+/// Builds the snapshot entries of installed apps, skipping apps whose entry can't be built
+pub fn build_entries(
+    nirvati_dir: &Path,
+    config: &SnapshotConfig,
+    installed_apps: &[String],
+) -> Vec<SnapshotEntry> {
+    installed_apps
+        .iter()
+        .filter_map(|app| match entry(nirvati_dir, config, app) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                tracing::warn!("No snapshot entry for {}: {:#}", app, err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn pre_update_manifest(
+    nirvati_dir: &Path,
+    config: &SnapshotConfig,
+    app: &str,
+) -> Result<PreUpdateManifest> {
+    let version = files::get_installed_versions(nirvati_dir)?.remove(app);
+    // ZFS only allows some characters in snapshot names, and @ separates them from the dataset
+    let snapshot_name = format!(
+        "nirvati-{}-{}-{}",
+        app,
+        version.as_deref().unwrap_or("unknown"),
+        super::events::now()
+    )
+    .replace(
+        |char: char| !char.is_ascii_alphanumeric() && !matches!(char, '-' | '_' | '.'),
+        "-",
+    );
+    Ok(PreUpdateManifest {
+        entry: entry(nirvati_dir, config, app)?,
+        version,
+        snapshot_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn setup(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        std::fs::create_dir_all(dir.join("apps").join("nextcloud")).unwrap();
+        std::fs::write(
+            dir.join("db").join("user.json"),
+            r#"{"name":"","password":"","installedApps":["nextcloud","notes@work"],"installedVersions":{"nextcloud":"28.0.1","notes@work":"1.0+build/2"}}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("apps").join("nextcloud").join("dirs.yml"),
+            "- path: data\n  uid: 33\n  gid: 33\n  mode: \"750\"\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    fn zfs() -> SnapshotConfig {
+        SnapshotConfig {
+            backend: Some(SnapshotBackend::Zfs),
+            datasets: BTreeMap::from([("default".to_owned(), "tank/app-data".to_owned())]),
+        }
+    }
+
+    #[test]
+    fn maps_data_dirs_to_zfs_datasets() {
+        let dir = setup("snapshots-zfs");
+        let entry = entry(&dir, &zfs(), "nextcloud").unwrap();
+        assert_eq!(entry.path, dir.join("app-data").join("nextcloud"));
+        assert_eq!(entry.backend, Some(SnapshotBackend::Zfs));
+        assert_eq!(entry.target.as_deref(), Some("tank/app-data/nextcloud"));
+        assert!(entry.pre_update);
+
+        // Renamed apps keep the dataset of their old data dir
+        std::fs::write(
+            dir.join("db").join("data-dirs.json"),
+            r#"{"nextcloud":"owncloud"}"#,
+        )
+        .unwrap();
+        let entry = super::entry(&dir, &zfs(), "nextcloud").unwrap();
+        assert_eq!(entry.target.as_deref(), Some("tank/app-data/owncloud"));
+    }
+
+    #[test]
+    fn pools_without_a_dataset_are_skipped() {
+        let dir = setup("snapshots-missing");
+        let config = SnapshotConfig {
+            backend: Some(SnapshotBackend::Zfs),
+            datasets: BTreeMap::new(),
+        };
+        let err = entry(&dir, &config, "nextcloud").unwrap_err();
+        assert!(err.to_string().contains("Storage pool default"));
+        assert!(build_entries(&dir, &config, &["nextcloud".to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn btrfs_targets_are_the_data_dir() {
+        let dir = setup("snapshots-btrfs");
+        let config = SnapshotConfig {
+            backend: Some(SnapshotBackend::Btrfs),
+            datasets: BTreeMap::new(),
+        };
+        let entries = build_entries(
+            &dir,
+            &config,
+            &["nextcloud".to_owned(), "notes@work".to_owned()],
+        );
+        assert_eq!(entries.len(), 2);
+        let path = dir.join("app-data").join("notes@work");
+        assert_eq!(entries[1].path, path);
+        assert_eq!(
+            entries[1].target.as_deref(),
+            Some(path.to_string_lossy().as_ref())
+        );
+        // Without data dirs, there's nothing an update could break
+        assert!(entries[0].pre_update);
+        assert!(!entries[1].pre_update);
+
+        let entry = entry(&dir, &SnapshotConfig::default(), "nextcloud").unwrap();
+        assert_eq!(entry.backend, None);
+        assert_eq!(entry.target, None);
+    }
+
+    #[test]
+    fn snapshot_names_only_use_allowed_characters() {
+        let dir = setup("snapshots-names");
+        let manifest = pre_update_manifest(&dir, &zfs(), "notes@work").unwrap();
+        assert_eq!(manifest.version.as_deref(), Some("1.0+build/2"));
+        assert_eq!(manifest.entry.app, "notes@work");
+        assert!(manifest
+            .snapshot_name
+            .starts_with("nirvati-notes-work-1.0-build-2-"));
+        assert!(manifest
+            .snapshot_name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | '.')));
+
+        let manifest = pre_update_manifest(&dir, &zfs(), "bitcoin").unwrap();
+        assert_eq!(manifest.version, None);
+        assert!(manifest
+            .snapshot_name
+            .starts_with("nirvati-bitcoin-unknown-"));
+    }
+}
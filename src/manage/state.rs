@@ -0,0 +1,99 @@
+//! Export and import of the state needed to move a Nirvati install to new hardware
+//!
+//! Apps are synced from their stores again after importing, so the bundle only contains
+//! the user's choices and what was assigned to their apps.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::events;
+
+const BUNDLE_VERSION: u32 = 1;
+
+/// Files that make up the state, relative to the Nirvati root
+/// This includes the seed app passwords are derived from, so bundles need to be kept secret
+const STATE_FILES: [&str; 7] = [
+    "db/user.json",
+    "db/sources.yml",
+    "db/hooks.yml",
+    "db/data-dirs.json",
+    "db/nirvati-seed/seed",
+    "apps/ports.yml",
+    "apps/dns.yml",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StateBundle {
+    pub version: u32,
+    pub exported_at: u64,
+    /// Path relative to the Nirvati root -> contents, for the state files that exist
+    pub files: BTreeMap<String, String>,
+}
+
+pub fn export_state(nirvati_dir: &Path) -> Result<StateBundle> {
+    let mut files = BTreeMap::new();
+    for file in STATE_FILES {
+        let path = nirvati_dir.join(file);
+        if path.is_file() {
+            files.insert(file.to_owned(), std::fs::read_to_string(path)?);
+        }
+    }
+    Ok(StateBundle {
+        version: BUNDLE_VERSION,
+        exported_at: events::now(),
+        files,
+    })
+}
+
+/// Writes the files of a bundle to the Nirvati root, replacing existing ones
+pub fn import_state(nirvati_dir: &Path, bundle: &StateBundle) -> Result<()> {
+    if bundle.version != BUNDLE_VERSION {
+        bail!("Unsupported state bundle version {}", bundle.version);
+    }
+    if let Some(file) = bundle
+        .files
+        .keys()
+        .find(|file| !STATE_FILES.contains(&file.as_str()))
+    {
+        bail!("Unexpected file {} in state bundle", file);
+    }
+    for (file, contents) in &bundle.files {
+        let path = nirvati_dir.join(file);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn export_and_import() {
+        let old_dir = TempDir::new("state-old");
+        let new_dir = TempDir::new("state-new");
+        std::fs::create_dir_all(old_dir.join("db").join("nirvati-seed")).unwrap();
+        std::fs::write(old_dir.join("db").join("user.json"), "{}").unwrap();
+        std::fs::write(old_dir.join("db").join("nirvati-seed").join("seed"), "seed").unwrap();
+
+        let bundle = export_state(&old_dir).unwrap();
+        assert_eq!(
+            bundle.files.keys().collect::<Vec<_>>(),
+            vec!["db/nirvati-seed/seed", "db/user.json"]
+        );
+        import_state(&new_dir, &bundle).unwrap();
+        assert_eq!(export_state(&new_dir).unwrap().files, bundle.files);
+
+        let mut invalid = bundle.clone();
+        invalid.files.insert("../outside".to_owned(), String::new());
+        assert!(import_state(&new_dir, &invalid).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Traffic shaping hints for the host, written to apps/traffic.yml
+//!
+//! Host scripts mark the traffic of every address with its DSCP class, which cake's diffserv4 mode sorts into tins,
+//! and cap services with tc, so a backup app can't starve interactive apps on a household uplink.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::{BandwidthPriority, ResultYml};
+
+use super::dns::DnsMap;
+
+/// An entry of apps/traffic.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrafficEntry {
+    pub app: String,
+    pub service: String,
+    /// The container, which host scripts can look up the veth interface of
+    pub container: String,
+    pub ip: Ipv4Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+    pub priority: BandwidthPriority,
+    /// The DSCP class to mark traffic with, like CS1
+    pub dscp: String,
+    /// The cake diffserv4 tin the DSCP class ends up in
+    pub tin: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload_kbit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_kbit: Option<u64>,
+}
+
+/// Parses a rate like 10mbit into kbit/s
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let unit_start = rate
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(rate.len());
+    let (value, unit) = rate.split_at(unit_start);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow!("Rate {} has to start with a number", rate))?;
+    let multiplier = match unit.to_lowercase().as_str() {
+        "kbit" => 1,
+        "mbit" => 1_000,
+        "gbit" => 1_000_000,
+        _ => bail!("Rate {} needs a unit of kbit, mbit or gbit", rate),
+    };
+    if value == 0 {
+        bail!("Rate {} has to be more than 0", rate);
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Rate {} is too large", rate))
+}
+
+fn dscp_and_tin(priority: BandwidthPriority) -> (&'static str, &'static str) {
+    match priority {
+        BandwidthPriority::Bulk => ("CS1", "bulk"),
+        BandwidthPriority::Normal => ("CS0", "besteffort"),
+        BandwidthPriority::Interactive => ("AF41", "video"),
+    }
+}
+
+/// Returns the shaping hints of an app's services, which are found by their addresses on the app network
+pub fn get_entries(app: &str, result: &ResultYml, dns: &DnsMap) -> Vec<TrafficEntry> {
+    result
+        .bandwidth
+        .iter()
+        .filter_map(|(service, bandwidth)| {
+            let entry = dns.get(app)?.get(service)?;
+            let (dscp, tin) = dscp_and_tin(bandwidth.priority);
+            Some(TrafficEntry {
+                app: app.to_owned(),
+                service: service.to_owned(),
+                container: entry.hostname.clone(),
+                ip: entry.ip,
+                ipv6: entry.ipv6,
+                priority: bandwidth.priority,
+                dscp: dscp.to_owned(),
+                tin: tin.to_owned(),
+                // Rates were validated when converting the app
+                max_upload_kbit: bandwidth
+                    .max_upload
+                    .as_deref()
+                    .and_then(|rate| parse_rate(rate).ok()),
+                max_download_kbit: bandwidth
+                    .max_download
+                    .as_deref()
+                    .and_then(|rate| parse_rate(rate).ok()),
+            })
+        })
+        .collect()
+}
+
+/// Replaces the entries of the processed apps and removes those of apps that are not installed
+pub fn merge_entries(
+    previous: Vec<TrafficEntry>,
+    processed_apps: &[String],
+    installed_apps: &[String],
+    new_entries: Vec<TrafficEntry>,
+) -> Vec<TrafficEntry> {
+    let mut entries = previous
+        .into_iter()
+        .filter(|entry| !processed_apps.contains(&entry.app))
+        .chain(new_entries)
+        .filter(|entry| installed_apps.contains(&entry.app))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.ip);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500kbit").unwrap(), 500);
+        assert_eq!(parse_rate("10mbit").unwrap(), 10_000);
+        assert_eq!(parse_rate("1Gbit").unwrap(), 1_000_000);
+        assert!(parse_rate("10").is_err());
+        assert!(parse_rate("mbit").is_err());
+        assert!(parse_rate("0mbit").is_err());
+        assert!(parse_rate("10mb").is_err());
+    }
+}
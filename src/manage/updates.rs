@@ -0,0 +1,222 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    files,
+    images::{newer_tags, ImageRef, RegistryClient},
+};
+
+/// An available update for one container, listed in apps/updates.json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerUpdate {
+    pub service: String,
+    pub image: String,
+    /// The digest the image is pinned to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_digest: Option<String>,
+    /// The digest the image's tag points to now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest_digest: Option<String>,
+    /// Tags with a newer version, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub newer_tags: Vec<String>,
+}
+
+impl ContainerUpdate {
+    /// Whether the pinned digest is outdated
+    pub fn digest_changed(&self) -> bool {
+        self.current_digest.is_some()
+            && self.latest_digest.is_some()
+            && self.current_digest != self.latest_digest
+    }
+}
+
+/// Contents of apps/updates.json, app -> updates
+pub type Updates = BTreeMap<String, Vec<ContainerUpdate>>;
+
+fn check_image(
+    client: &mut RegistryClient,
+    service: &str,
+    image: &str,
+) -> Result<Option<ContainerUpdate>> {
+    let image_ref = ImageRef::parse(image);
+    let Some(tag) = image_ref.tag.as_deref() else {
+        return Ok(None);
+    };
+    let latest_digest = client.get_digest(&image_ref, tag)?;
+    let newer_tags = newer_tags(tag, &client.list_tags(&image_ref)?);
+    let update = ContainerUpdate {
+        service: service.to_owned(),
+        image: image.to_owned(),
+        current_digest: image_ref.digest.clone(),
+        latest_digest: Some(latest_digest),
+        newer_tags,
+    };
+    if update.digest_changed() || !update.newer_tags.is_empty() {
+        Ok(Some(update))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks the containers listed in update_containers of all installed apps for updates
+/// Registry errors are logged and the container is skipped
+pub fn check_updates(nirvati_dir: &Path) -> Result<Updates> {
+    let installed_apps = files::get_installed_apps(nirvati_dir)?;
+    let registry = files::get_app_registry(nirvati_dir)?;
+    let digest_pins = files::get_digest_pins(nirvati_dir)?;
+    let mut client = RegistryClient::default();
+    let mut updates = Updates::new();
+    for entry in registry
+        .iter()
+        .filter(|entry| installed_apps.contains(&entry.id))
+    {
+        let Some(update_containers) = &entry.update_containers else {
+            continue;
+        };
+        let mut app_yml = files::read_app_yml(nirvati_dir, &entry.id)?;
+        if let Some(pins) = digest_pins.get(&entry.id) {
+            app_yml.pin_images(pins);
+        }
+        for (service, image) in app_yml.get_images() {
+            if !update_containers.contains(&service) {
+                continue;
+            }
+            match check_image(&mut client, &service, &image) {
+                Ok(Some(update)) => updates.entry(entry.id.clone()).or_default().push(update),
+                Ok(None) => {}
+                Err(err) => tracing::warn!(
+                    "Failed to check {} of app {} for updates: {:#}",
+                    image,
+                    entry.id,
+                    err
+                ),
+            }
+        }
+    }
+    Ok(updates)
+}
+
+/// Pins images with an outdated digest to the latest one in db/digest-pins.json, which Generate applies
+/// Returns the apps whose pins were changed
+pub fn apply_digest_updates(nirvati_dir: &Path, updates: &Updates) -> Result<Vec<String>> {
+    let mut digest_pins = files::get_digest_pins(nirvati_dir)?;
+    let mut changed_apps = Vec::new();
+    for (app, app_updates) in updates {
+        let pins = digest_pins.entry(app.clone()).or_default();
+        let mut changed = false;
+        for update in app_updates.iter().filter(|update| update.digest_changed()) {
+            let new_image = ImageRef::parse(&update.image)
+                .to_string_with_digest(update.latest_digest.as_deref());
+            // The image may already be pinned, then the pin of the image in the app.yml is replaced
+            let source = pins
+                .iter()
+                .find(|(_, pinned)| **pinned == update.image)
+                .map_or_else(|| update.image.clone(), |(source, _)| source.clone());
+            if pins.get(&source) != Some(&new_image) {
+                pins.insert(source, new_image);
+                changed = true;
+            }
+        }
+        if changed {
+            changed_apps.push(app.clone());
+        }
+    }
+    digest_pins.retain(|_, pins| !pins.is_empty());
+    if !changed_apps.is_empty() {
+        files::save_digest_pins(nirvati_dir, &digest_pins)?;
+    }
+    Ok(changed_apps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    fn digest(c: char) -> String {
+        format!("sha256:{}", c.to_string().repeat(64))
+    }
+
+    fn update(image: &str, latest_digest: &str) -> Updates {
+        Updates::from([(
+            "example".to_owned(),
+            vec![ContainerUpdate {
+                service: "main".to_owned(),
+                image: image.to_owned(),
+                current_digest: ImageRef::parse(image).digest,
+                latest_digest: Some(latest_digest.to_owned()),
+                newer_tags: Vec::new(),
+            }],
+        )])
+    }
+
+    #[test]
+    fn pins_survive_syncing_the_store() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root();
+        let app_yml_jinja_path = root.join("apps").join("example").join("app.yml.jinja");
+        let image = format!("nginx:1.25-alpine@{}", digest('a'));
+        let from_store = std::fs::read_to_string(&app_yml_jinja_path)
+            .unwrap()
+            .replace("nginx:1.25-alpine", &image);
+        std::fs::write(&app_yml_jinja_path, &from_store).unwrap();
+        let generated_image = || {
+            fixture.generate().unwrap();
+            let app_yml = files::read_app_yml(root, "example").unwrap();
+            let result_yml =
+                std::fs::read_to_string(root.join("apps").join("example").join("result.yml"))
+                    .unwrap();
+            let mut images = app_yml.get_images();
+            let (_, image) = images.pop().unwrap();
+            (image, result_yml)
+        };
+
+        assert_eq!(
+            apply_digest_updates(root, &update(&image, &digest('b'))).unwrap(),
+            vec!["example".to_owned()]
+        );
+        // The app's own files are left as they are
+        assert_eq!(
+            std::fs::read_to_string(&app_yml_jinja_path).unwrap(),
+            from_store
+        );
+        let (app_yml_image, result_yml) = generated_image();
+        assert_eq!(app_yml_image, image);
+        assert!(result_yml.contains(&format!("nginx:1.25-alpine@{}", digest('b'))));
+
+        // Updating a pinned image replaces the pin of the image in the app.yml
+        let pinned = format!("nginx:1.25-alpine@{}", digest('b'));
+        apply_digest_updates(root, &update(&pinned, &digest('c'))).unwrap();
+        assert_eq!(
+            files::get_digest_pins(root).unwrap()["example"],
+            BTreeMap::from([(image.clone(), format!("nginx:1.25-alpine@{}", digest('c')))])
+        );
+        // Nothing changes if the pin is up to date
+        let pinned = format!("nginx:1.25-alpine@{}", digest('c'));
+        assert!(apply_digest_updates(root, &update(&pinned, &digest('c')))
+            .unwrap()
+            .is_empty());
+
+        // A sync writes the store's app.yml.jinja again
+        std::fs::write(&app_yml_jinja_path, &from_store).unwrap();
+        let (_, result_yml) = generated_image();
+        assert!(result_yml.contains(&format!("nginx:1.25-alpine@{}", digest('c'))));
+
+        // Once the store updates the image, the pin no longer applies
+        let from_store = from_store.replace(&image, "nginx:1.27-alpine");
+        std::fs::write(&app_yml_jinja_path, from_store).unwrap();
+        let (_, result_yml) = generated_image();
+        assert!(result_yml.contains("nginx:1.27-alpine"));
+        assert!(!result_yml.contains(&digest('c')));
+    }
+}
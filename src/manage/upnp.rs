@@ -0,0 +1,163 @@
+//! Ports the host should forward on the router via UPnP or NAT-PMP, written to apps/upnp.yml
+//!
+//! Host scripts write the result to apps/upnp-status.yml, which templates get as `upnp_status`.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::ResultYml;
+
+use super::firewall::Protocol;
+
+/// Routers drop mappings when their lease expires, so hosts should renew them after about half of it
+pub const DEFAULT_LEASE_DURATION: u32 = 3600;
+
+/// An entry of apps/upnp.yml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpnpEntry {
+    pub app: String,
+    pub service: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    /// The suggested lease in seconds
+    pub lease_duration: u32,
+    /// Shown in the router's list of port mappings
+    pub description: String,
+}
+
+/// An entry of apps/upnp-status.yml, written by the host after trying to forward a port
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardingStatus {
+    pub app: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub forwarded: bool,
+    /// The port on the router, if it differs from the public port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+/// Returns the ports of an app that should be forwarded, none for apps that are only reachable through Tor
+pub fn get_entries(app: &str, result: &ResultYml) -> Vec<UpnpEntry> {
+    if result.metadata.tor_only {
+        return Vec::new();
+    }
+    result
+        .wan_ports
+        .iter()
+        .map(|wan_port| UpnpEntry {
+            app: app.to_owned(),
+            service: wan_port.service.clone(),
+            port: wan_port.public_port,
+            protocol: wan_port.protocol,
+            lease_duration: DEFAULT_LEASE_DURATION,
+            description: format!(
+                "Nirvati {} {}/{}",
+                result.metadata.name,
+                wan_port.public_port,
+                protocol_name(wan_port.protocol)
+            ),
+        })
+        .collect()
+}
+
+/// Replaces the entries of the processed apps and removes those of apps that are not installed
+pub fn merge_entries(
+    previous: Vec<UpnpEntry>,
+    processed_apps: &[String],
+    installed_apps: &[String],
+    new_entries: Vec<UpnpEntry>,
+) -> Vec<UpnpEntry> {
+    let mut entries = previous
+        .into_iter()
+        .filter(|entry| !processed_apps.contains(&entry.app))
+        .chain(new_entries)
+        .filter(|entry| installed_apps.contains(&entry.app))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| (entry.port, entry.protocol));
+    entries
+}
+
+/// The forwarding status of an app's ports for templates, keyed like 8333/tcp
+pub fn status_of_app(status: &[ForwardingStatus], app: &str) -> BTreeMap<String, ForwardingStatus> {
+    status
+        .iter()
+        .filter(|entry| entry.app == app)
+        .map(|entry| {
+            (
+                format!("{}/{}", entry.port, protocol_name(entry.protocol)),
+                entry.clone(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{manage::files, testing::Fixture};
+
+    #[test]
+    fn lists_wan_ports_and_passes_their_status_to_templates() {
+        let fixture = Fixture::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("basic"),
+        )
+        .unwrap();
+        let root = fixture.root();
+        let example_dir = root.join("apps").join("example");
+        let app_yml_jinja = std::fs::read_to_string(example_dir.join("app.yml.jinja"))
+            .unwrap()
+            .replace(
+                "    port: 80\n    command:\n",
+                "    port: 80\n    required_ports:\n      udp:\n        8333:\n          port: 8333\n          wan_required: true\n    command:\n      - \"--forwarded={% if '8333/udp' in upnp_status %}{{ upnp_status['8333/udp'].externalPort }}{% endif %}\"\n",
+            );
+        std::fs::write(example_dir.join("app.yml.jinja"), app_yml_jinja).unwrap();
+
+        // Apps that are not installed are not forwarded
+        fixture.generate().unwrap();
+        assert!(files::get_upnp_entries(root).unwrap().is_empty());
+
+        files::add_installed_app("example", root).unwrap();
+        fixture.generate().unwrap();
+        assert_eq!(
+            files::get_upnp_entries(root).unwrap(),
+            vec![UpnpEntry {
+                app: "example".to_owned(),
+                service: "main".to_owned(),
+                port: 8333,
+                protocol: Protocol::Udp,
+                lease_duration: DEFAULT_LEASE_DURATION,
+                description: "Nirvati Example 8333/udp".to_owned(),
+            }]
+        );
+        let app_yml = || std::fs::read_to_string(example_dir.join("app.yml")).unwrap();
+        assert!(app_yml().contains("--forwarded="));
+        assert!(!app_yml().contains("--forwarded=18333"));
+
+        std::fs::write(
+            root.join("apps").join("upnp-status.yml"),
+            "- app: example\n  port: 8333\n  protocol: udp\n  forwarded: true\n  externalPort: 18333\n",
+        )
+        .unwrap();
+        fixture.generate().unwrap();
+        assert!(app_yml().contains("--forwarded=18333"));
+    }
+}
@@ -0,0 +1,91 @@
+//! Env vars the user sets for an app in user.json, to toggle things like debug flags without changing its app.yml
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    composegenerator::{
+        types::{Permission, ResultYml},
+        v1::convert::env_var_permission,
+    },
+    utils::{find_env_vars, StringLike},
+};
+
+/// Checks that the values only use env vars the app already has permission to access
+pub fn validate(
+    user_env: &BTreeMap<String, String>,
+    has_permissions: &[String],
+    available_permissions: &HashMap<String, Vec<Permission>>,
+) -> Result<()> {
+    if has_permissions.iter().any(|perm| perm == "root") {
+        return Ok(());
+    }
+    for (key, value) in user_env {
+        for env_var in find_env_vars(value) {
+            let Some(permission) =
+                env_var_permission(env_var, has_permissions, available_permissions)
+            else {
+                continue;
+            };
+            if !has_permissions.contains(&permission) {
+                bail!(
+                    "{} uses {}, which requires the permission {}",
+                    key,
+                    env_var,
+                    permission
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets the env vars in every service of the app, replacing the values from the app.yml
+pub fn apply_to_result(result: &mut ResultYml, user_env: &BTreeMap<String, String>) {
+    for service in result.spec.services.values_mut() {
+        for (key, value) in user_env {
+            service
+                .environment
+                .insert(key.clone(), StringLike::String(value.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_vars_of_other_apps_without_permission() {
+        let available_permissions = HashMap::from([(
+            "bitcoind".to_owned(),
+            vec![Permission {
+                id: "rpc".to_owned(),
+                name: "RPC".to_owned(),
+                description: String::new(),
+                includes: Vec::new(),
+                files: Vec::new(),
+                variables: BTreeMap::from([(
+                    "APP_bitcoind_PASSWORD".to_owned(),
+                    serde_json::Value::String("$APP_bitcoind_PASSWORD".to_owned()),
+                )]),
+                hidden: false,
+            }],
+        )]);
+        let user_env = BTreeMap::from([(
+            "RPC_URL".to_owned(),
+            "http://user:${APP_bitcoind_PASSWORD}@${DEVICE_IP}".to_owned(),
+        )]);
+        assert!(validate(&user_env, &[], &available_permissions).is_err());
+        assert!(validate(
+            &user_env,
+            &["bitcoind/rpc".to_owned()],
+            &available_permissions
+        )
+        .is_ok());
+        assert!(validate(&user_env, &["root".to_owned()], &available_permissions).is_ok());
+        let debug_env = BTreeMap::from([("RUST_LOG".to_owned(), "debug".to_owned())]);
+        assert!(validate(&debug_env, &[], &available_permissions).is_ok());
+    }
+}
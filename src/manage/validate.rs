@@ -0,0 +1,297 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    composegenerator::{
+        types::{Diagnostic, DiagnosticCode, ResultYml, ValidationMode},
+        v1::RESERVED_NAMES,
+    },
+    config::Config,
+    tera::{render_app_yml_jinja, render_metadata_yml_jinja},
+};
+
+use super::{
+    categories, dns,
+    files::{self, SimpleValue},
+    ports::resolve_port_conflicts,
+};
+
+/// Overrides for the state an app is previewed in
+#[derive(Debug, Clone, Default)]
+pub struct PreviewOptions {
+    /// Apps to treat as installed in addition to the actually installed ones
+    pub pretend_installed: Vec<String>,
+    /// Settings to use instead of the ones saved in user.json
+    pub settings: Option<HashMap<String, SimpleValue>>,
+    /// Render every template twice and report templates that render differently
+    pub check_determinism: bool,
+}
+
+/// Describes where two renders of a template differ, None if they're the same
+fn compare_renders(template: &str, first: &str, second: &str) -> Option<String> {
+    if first == second {
+        return None;
+    }
+    let line = first
+        .lines()
+        .zip(second.lines())
+        .position(|(first, second)| first != second)
+        .unwrap_or_else(|| first.lines().count().min(second.lines().count()))
+        + 1;
+    Some(format!(
+        "{} renders differently every time, starting at line {}, use derive_entropy instead of random values or the current time",
+        template, line
+    ))
+}
+
+/// Renders a template a second time if determinism is checked, and adds a diagnostic if the result differs
+fn check_rerender<F>(
+    template: &str,
+    first: &str,
+    render: F,
+    options: &PreviewOptions,
+    mode: ValidationMode,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<()>
+where
+    F: FnOnce() -> Result<String>,
+{
+    if !options.check_determinism {
+        return Ok(());
+    }
+    let Some(message) = compare_renders(template, first, &render()?) else {
+        return Ok(());
+    };
+    if mode == ValidationMode::Strict {
+        bail!(message);
+    }
+    tracing::warn!("{}", message);
+    diagnostics.push(Diagnostic::warning(
+        DiagnosticCode::NondeterministicTemplate,
+        message,
+        None,
+    ));
+    Ok(())
+}
+
+/// Renders and converts a single app in memory to check whether it would generate successfully
+/// Generated files are not written, and ports are only checked for conflicts within the app itself
+pub fn validate_app(nirvati_root: &Path, app_id: &str, config: &Config) -> Result<ResultYml> {
+    let options = PreviewOptions {
+        check_determinism: true,
+        ..PreviewOptions::default()
+    };
+    preview_app(nirvati_root, app_id, config, options)
+}
+
+/// Renders and converts a single app in memory, optionally simulating a different system state
+pub fn preview_app(
+    nirvati_root: &Path,
+    app_id: &str,
+    config: &Config,
+    options: PreviewOptions,
+) -> Result<ResultYml> {
+    let app_dir = nirvati_root.join("apps").join(app_id);
+    if !app_dir.is_dir() {
+        bail!("App {} does not exist", app_id);
+    }
+    let mut installed_apps = files::get_installed_apps(nirvati_root)?;
+    for app in &options.pretend_installed {
+        if !installed_apps.contains(app) {
+            installed_apps.push(app.clone());
+        }
+    }
+    let settings = match &options.settings {
+        Some(settings) => Some(settings.clone()),
+        None => files::get_app_settings(nirvati_root, app_id)?,
+    };
+    let available_permissions = super::get_exported_permissions(nirvati_root, &installed_apps);
+    let available_permissions_list = super::get_permission_strings(&available_permissions);
+
+    let mut diagnostics = Vec::new();
+    let metadata_yml_jinja = app_dir.join("metadata.yml.jinja");
+    let metadata = if metadata_yml_jinja.is_file() {
+        let mut metadata_permissions = available_permissions_list.clone();
+        metadata_permissions.extend(RESERVED_NAMES.iter().map(|name| name.to_string()));
+        let render = || {
+            render_metadata_yml_jinja(
+                &metadata_yml_jinja,
+                &installed_apps,
+                &metadata_permissions,
+                nirvati_root,
+            )
+        };
+        let rendered = render()?;
+        check_rerender(
+            "metadata.yml.jinja",
+            &rendered,
+            render,
+            &options,
+            config.validation_mode(),
+            &mut diagnostics,
+        )?;
+        files::parse_metadata_yml(&rendered)?
+    } else {
+        files::read_metadata_yml(nirvati_root, app_id)?
+    };
+
+    let app_yml_jinja = app_dir.join("app.yml.jinja");
+    let (app_yml, unknown_keys) = if app_yml_jinja.is_file() {
+        let render = || {
+            render_app_yml_jinja(
+                &app_yml_jinja,
+                &metadata,
+                &installed_apps,
+                &available_permissions_list,
+                &available_permissions,
+                settings.as_ref(),
+                nirvati_root,
+            )
+        };
+        let rendered = render()?;
+        check_rerender(
+            "app.yml.jinja",
+            &rendered,
+            render,
+            &options,
+            config.validation_mode(),
+            &mut diagnostics,
+        )?;
+        files::parse_app_yml_with_unknown_keys(&rendered)?
+    } else {
+        files::read_app_yml_with_unknown_keys(nirvati_root, app_id)?
+    };
+    diagnostics.extend(unknown_keys);
+
+    let implements = metadata
+        .get_basic_output_metadata(app_id.to_owned())
+        .implements;
+    let (ports, apps_with_conflicts) = resolve_port_conflicts(
+        app_yml.get_ports(app_id, implements, config.validation_mode())?,
+        &installed_apps,
+        &super::get_port_policy(nirvati_root, config)?,
+    );
+    if !apps_with_conflicts.is_empty() {
+        bail!("App {} has conflicting ports", app_id);
+    }
+    let mut result = app_yml.convert(
+        app_id,
+        &ports,
+        metadata,
+        &available_permissions,
+        config.validation_mode(),
+        &config.logging,
+        config.ipv6_subnet.is_some(),
+    )?;
+    let services = BTreeMap::from([(
+        app_id.to_owned(),
+        result.spec.services.keys().cloned().collect(),
+    )]);
+    let dns_map = dns::assign_addresses(
+        &files::get_dns_map(nirvati_root)?,
+        &services,
+        &config.subnet,
+        config.ipv6_subnet.as_deref(),
+    )?;
+    dns::apply_to_result(&mut result, app_id, &dns_map);
+    result.metadata.diagnostics.extend(diagnostics);
+    categories::check(
+        &mut result.metadata,
+        &categories::known_categories(nirvati_root)?,
+        config.validation_mode(),
+    )?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{compare_renders, preview_app, PreviewOptions};
+    use crate::{config::Config, manage::files::SimpleValue, testing::TempDir};
+
+    const METADATA_YML: &str = "version: 1
+metadata:
+  name: Demo
+  version: \"1.0.0\"
+  category: Productivity
+  tagline: An app used to test previews
+  developers:
+    Nirvati: https://nirvati.org
+  description: This app only exists in tests.
+  repo:
+    Source code: https://example.com/demo
+  support: https://example.com/demo/issues
+  gallery: []
+";
+
+    /// A Nirvati root with the app demo
+    fn root_with_app(app_yml_jinja: &str) -> TempDir {
+        let nirvati_dir = TempDir::new("preview");
+        let app_dir = nirvati_dir.join("apps").join("demo");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::create_dir_all(nirvati_dir.join("db").join("nirvati-seed")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("db").join("nirvati-seed").join("seed"),
+            "seed",
+        )
+        .unwrap();
+        std::fs::write(app_dir.join("metadata.yml"), METADATA_YML).unwrap();
+        std::fs::write(app_dir.join("app.yml.jinja"), app_yml_jinja).unwrap();
+        nirvati_dir
+    }
+
+    #[test]
+    fn finds_differing_line() {
+        assert_eq!(compare_renders("app.yml.jinja", "a\nb", "a\nb"), None);
+        let message = compare_renders("app.yml.jinja", "a\nb\nc", "a\nx\nc").unwrap();
+        assert!(message.contains("line 2"));
+        let message = compare_renders("app.yml.jinja", "a", "a\nb").unwrap();
+        assert!(message.contains("line 2"));
+    }
+
+    #[test]
+    fn previews_with_other_settings_without_writing_files() {
+        let nirvati_dir = root_with_app(
+            "version: 1
+services:
+  main:
+    image: nginx:1.25-alpine
+    command:
+      - \"--greeting={{ settings.greeting | default(value='none') }}\"
+",
+        );
+        let preview = |settings| {
+            let options = PreviewOptions {
+                settings,
+                ..PreviewOptions::default()
+            };
+            let result = preview_app(&nirvati_dir, "demo", &Config::default(), options).unwrap();
+            serde_yaml::to_string(&result.spec).unwrap()
+        };
+
+        assert!(preview(None).contains("--greeting=none"));
+        let settings = HashMap::from([(
+            "greeting".to_owned(),
+            SimpleValue::String("hello".to_owned()),
+        )]);
+        assert!(preview(Some(settings)).contains("--greeting=hello"));
+        // Previews are only rendered in memory
+        assert!(!nirvati_dir
+            .join("apps")
+            .join("demo")
+            .join("app.yml")
+            .exists());
+        assert!(preview_app(
+            &nirvati_dir,
+            "missing",
+            &Config::default(),
+            PreviewOptions::default()
+        )
+        .is_err());
+    }
+}
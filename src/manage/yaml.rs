@@ -0,0 +1,192 @@
+//! Parsing of the YAML files app authors write, with the key path and line of errors
+//!
+//! Anchors and aliases are resolved by serde_yaml itself, merge keys (`<<: *defaults`) are applied before deserializing.
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+
+/// Formats a serde_yaml error of a file, with the line it happened at if known
+fn describe(file: &str, path: Option<&str>, err: &serde_yaml::Error) -> String {
+    let mut location = Vec::new();
+    if let Some(path) = path.filter(|path| *path != ".") {
+        location.push(path.to_owned());
+    }
+    if let Some(position) = err.location() {
+        location.push(format!("line {}", position.line()));
+    }
+    // serde_yaml adds the path and position to its messages, which are already part of the location
+    let mut message = err.to_string();
+    if let Some(idx) = message
+        .rfind(" at line ")
+        .filter(|_| err.location().is_some())
+    {
+        message.truncate(idx);
+    }
+    if let Some(path) = path {
+        if let Some(rest) = message.strip_prefix(&format!("{}: ", path)) {
+            message = rest.to_owned();
+        }
+    }
+    if location.is_empty() {
+        format!("Invalid {}: {}", file, message)
+    } else {
+        format!("Invalid {} at {}: {}", file, location.join(", "), message)
+    }
+}
+
+/// Parses a file into a value, with merge keys applied
+pub fn parse_value(file: &str, contents: &str) -> Result<Value> {
+    let mut value: Value =
+        serde_yaml::from_str(contents).map_err(|err| anyhow!(describe(file, None, &err)))?;
+    value.apply_merge().map_err(|err| {
+        anyhow!(
+            "Invalid {}: {}, merge keys (<<) need a mapping or a list of mappings",
+            file,
+            err
+        )
+    })?;
+    Ok(value)
+}
+
+/// The key path of a value serde ignored, without the markers serde_ignored adds for options and newtypes
+fn key_path(path: &serde_ignored::Path, segments: &mut Vec<String>) {
+    match path {
+        serde_ignored::Path::Root => {}
+        serde_ignored::Path::Seq { parent, index } => {
+            key_path(parent, segments);
+            segments.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            key_path(parent, segments);
+            segments.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => key_path(parent, segments),
+    }
+}
+
+/// Deserializes a value parsed with parse_value, reporting the key path of errors
+/// contents is only used to find the line of errors
+pub fn from_value<T: DeserializeOwned>(file: &str, value: Value, contents: &str) -> Result<T> {
+    from_value_with_unknown_keys(file, value, contents).map(|(parsed, _)| parsed)
+}
+
+/// Like from_value, but also returns the paths of the keys that were ignored because T has no field for them
+/// Keys starting with `x-` are meant to be ignored, for example to hold anchors, and are not returned
+pub fn from_value_with_unknown_keys<T: DeserializeOwned>(
+    file: &str,
+    value: Value,
+    contents: &str,
+) -> Result<(T, Vec<String>)> {
+    let mut unknown_keys = Vec::new();
+    let mut record = |path: serde_ignored::Path| {
+        let mut segments = Vec::new();
+        key_path(&path, &mut segments);
+        if !segments.iter().any(|segment| segment.starts_with("x-")) {
+            unknown_keys.push(segments.join("."));
+        }
+    };
+    let parsed = serde_path_to_error::deserialize::<_, T>(serde_ignored::Deserializer::new(
+        value,
+        &mut record,
+    ));
+    let err = match parsed {
+        Ok(parsed) => return Ok((parsed, unknown_keys)),
+        Err(err) => err,
+    };
+    let path = err.path().to_string();
+    // Values have no positions, but without merge keys the file fails at the same path when deserialized directly
+    let located =
+        serde_path_to_error::deserialize::<_, T>(serde_yaml::Deserializer::from_str(contents))
+            .err()
+            .filter(|direct| {
+                direct.path().to_string() == path && direct.inner().location().is_some()
+            });
+    Err(anyhow!(match located {
+        Some(direct) => describe(file, Some(&path), direct.inner()),
+        None => describe(file, Some(&path), err.inner()),
+    }))
+}
+
+/// Parses and deserializes a file
+pub fn from_str<T: DeserializeOwned>(file: &str, contents: &str) -> Result<T> {
+    from_value(file, parse_value(file, contents)?, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Service {
+        image: String,
+        port: Option<u16>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct File {
+        services: BTreeMap<String, Service>,
+    }
+
+    #[test]
+    fn applies_merge_keys() {
+        let contents = "
+x-defaults: &defaults
+  image: nginx:1.25
+services:
+  main:
+    <<: *defaults
+    port: 80
+  other: *defaults
+";
+        let parsed: File = from_str("app.yml", contents).unwrap();
+        assert_eq!(parsed.services["main"].image, "nginx:1.25");
+        assert_eq!(parsed.services["main"].port, Some(80));
+        assert_eq!(parsed.services["other"].port, None);
+    }
+
+    #[test]
+    fn reports_path_and_line() {
+        let contents = "services:\n  main:\n    image: nginx\n    port: eighty\n";
+        let err = from_str::<File>("app.yml", contents).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid app.yml at services.main.port, line 4: invalid type: string \"eighty\", expected u16"
+        );
+        let err = from_str::<File>("app.yml", "services:\n  main: [\n").unwrap_err();
+        assert!(err.to_string().starts_with("Invalid app.yml at line 3: "));
+        let err = from_str::<File>("app.yml", "services:\n  <<: 1\n").unwrap_err();
+        assert!(err.to_string().contains("merge keys"));
+    }
+
+    #[test]
+    fn collects_unknown_keys() {
+        let contents = "
+x-defaults: &defaults
+  image: nginx:1.25
+services:
+  main:
+    <<: *defaults
+    port: 80
+    restart: always
+  other:
+    image: nginx
+    x-note: shared
+healthcheck: {}
+";
+        let (parsed, unknown_keys) = from_value_with_unknown_keys::<File>(
+            "app.yml",
+            parse_value("app.yml", contents).unwrap(),
+            contents,
+        )
+        .unwrap();
+        assert_eq!(parsed.services["main"].image, "nginx:1.25");
+        assert_eq!(unknown_keys, vec!["services.main.restart", "healthcheck"]);
+    }
+}
@@ -0,0 +1,45 @@
+//! Offline mode, in which the app manager never accesses the network, for air-gapped devices
+//!
+//! Everything that accesses the network calls ensure_online first, so offline mode can't be bypassed by new callers.
+//! Generate never needs the network: templates render from local files and the JS helpers have no network APIs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfflineError {
+    /// What needed the network, like "Downloading https://example.com/apps.tar.gz"
+    pub action: String,
+}
+
+impl std::fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} needs network access, which is disabled in offline mode",
+            self.action
+        )
+    }
+}
+
+impl std::error::Error for OfflineError {}
+
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::SeqCst);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::SeqCst)
+}
+
+/// Fails if offline mode is on, has to be called before every network access
+pub fn ensure_online(action: impl Into<String>) -> Result<(), OfflineError> {
+    if is_offline() {
+        Err(OfflineError {
+            action: action.into(),
+        })
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! The HTTP or SOCKS5 proxy all network access goes through, if one is configured
+//!
+//! Like offline mode, the proxy is set once at startup, and every HTTP client is built with agent_builder,
+//! so new callers can't bypass it.
+
+use std::{sync::RwLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+static PROXY: RwLock<Option<String>> = RwLock::new(None);
+
+/// Sets the proxy for this process, like http://proxy.lan:3128 or socks5://127.0.0.1:9050
+pub fn set_proxy(proxy: Option<String>) -> Result<()> {
+    if let Some(proxy) = &proxy {
+        ureq::Proxy::new(proxy).map_err(|err| anyhow!("Invalid proxy {}: {}", proxy, err))?;
+    }
+    if let Ok(mut current) = PROXY.write() {
+        *current = proxy;
+    }
+    Ok(())
+}
+
+pub fn get_proxy() -> Option<String> {
+    PROXY.read().ok().and_then(|proxy| proxy.clone())
+}
+
+/// An HTTP client builder that uses proxy, or the configured proxy if none is given
+pub fn agent_builder(timeout: Duration, proxy: Option<&str>) -> Result<ureq::AgentBuilder> {
+    let builder = ureq::AgentBuilder::new().timeout(timeout);
+    let Some(proxy) = proxy.map(str::to_owned).or_else(get_proxy) else {
+        return Ok(builder);
+    };
+    Ok(builder.proxy(ureq::Proxy::new(&proxy)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_proxies() {
+        assert!(set_proxy(Some("socks5://127.0.0.1:9050".to_owned())).is_ok());
+        assert_eq!(get_proxy().as_deref(), Some("socks5://127.0.0.1:9050"));
+        assert!(set_proxy(Some("ftp://proxy.lan".to_owned())).is_err());
+        assert!(set_proxy(None).is_ok());
+        assert_eq!(get_proxy(), None);
+    }
+}
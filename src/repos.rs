@@ -1 +1,790 @@
+//! App stores configured in db/sources.yml
+//!
+//! Every store is checked out to repos/<store>, with one dir per app, except local stores,
+//! which are read from a developer's checkout.
+//! Apps are installed from apps/<app>, so if multiple stores contain an app with the same id,
+//! the store with the highest priority provides it.
+//! Within metadata.yml, apps can be referenced as <store>/<app> to require the app from a specific store.
 
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composegenerator::{
+        types::OutputMetadata,
+        v1::{
+            import::{ImportNote, NOTES_FILE},
+            types::{AppYml, MetadataYml},
+        },
+    },
+    manage::{events, files, instances::split_instance_id},
+    utils::is_false,
+};
+
+pub mod casaos;
+pub mod citadel;
+pub mod fetch;
+pub mod git;
+pub mod runtipi;
+pub mod umbrel;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceType {
+    /// A git repository, checked out by the host
+    #[default]
+    Git,
+    /// A gzipped tarball downloaded over HTTPS
+    Tarball,
+    /// An OCI artifact with a single gzipped tarball layer, url is the artifact reference
+    Oci,
+    /// A developer checkout, url is its path
+    /// Apps from local stores bypass store review and are marked as dev apps
+    Local,
+}
+
+/// The format of the apps in a store, apps in other formats are converted on sync
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreFormat {
+    #[default]
+    Nirvati,
+    /// Apps with an umbrel-app.yml and a docker-compose.yml
+    Umbrel,
+    /// Apps with a docker-compose.yml with an x-casaos section, in Apps/
+    Casaos,
+    /// Apps with a config.json and a docker-compose.yml, in apps/
+    Runtipi,
+    /// Apps with a legacy Citadel app.yml, of version 3 or 4
+    /// Nirvati stores can contain them too, they are converted the same way
+    Citadel,
+}
+
+impl StoreFormat {
+    fn is_app_dir(self, path: &Path) -> bool {
+        match self {
+            StoreFormat::Nirvati => {
+                path.join("metadata.yml").is_file()
+                    || path.join("metadata.yml.jinja").is_file()
+                    || citadel::is_app_dir(path)
+            }
+            StoreFormat::Umbrel => umbrel::is_app_dir(path),
+            StoreFormat::Casaos => casaos::is_app_dir(path),
+            StoreFormat::Runtipi => runtipi::is_app_dir(path),
+            StoreFormat::Citadel => citadel::is_app_dir(path),
+        }
+    }
+
+    /// The dir of a checkout the apps are in, if it isn't the root
+    fn apps_subdir(self) -> Option<&'static str> {
+        match self {
+            StoreFormat::Nirvati | StoreFormat::Umbrel | StoreFormat::Citadel => None,
+            StoreFormat::Casaos => Some("Apps"),
+            StoreFormat::Runtipi => Some("apps"),
+        }
+    }
+
+    /// Writes app.yml and metadata.yml of an app copied from a store in this format,
+    /// and returns what has to be reviewed
+    fn convert_app(self, app_dir: &Path, app: &str) -> Result<Vec<ImportNote>> {
+        match self {
+            StoreFormat::Nirvati => Ok(Vec::new()),
+            StoreFormat::Umbrel => umbrel::convert_app(app_dir, app),
+            StoreFormat::Casaos => casaos::convert_app(app_dir, app),
+            StoreFormat::Runtipi => runtipi::convert_app(app_dir, app),
+            StoreFormat::Citadel => citadel::convert_app(app_dir, app),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoreSource {
+    pub id: String,
+    /// The name shown in the UI, defaults to the id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", default)]
+    pub source_type: SourceType,
+    #[serde(default, skip_serializing_if = "is_default_format")]
+    pub format: StoreFormat,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Expected sha256 of the archive of tarball and OCI stores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Stores with a higher priority win if multiple stores contain the same app
+    #[serde(default)]
+    pub priority: i32,
+    /// Symlink the apps of a local store into apps/ instead of copying them,
+    /// so changes in the checkout apply without syncing again
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub symlink: bool,
+    /// Other URLs of tarball and OCI stores, tried in order if url fails, like an onion mirror
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+}
+
+impl StoreSource {
+    /// The URLs to fetch the store from, in the order they are tried
+    /// With prefer_tor, onion mirrors come first, otherwise url is tried first
+    pub fn urls(&self, prefer_tor: bool) -> Vec<&str> {
+        let mut urls = std::iter::once(&self.url)
+            .chain(&self.mirrors)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if prefer_tor {
+            // Stable, so the order within onion and clearnet URLs is kept
+            urls.sort_by_key(|url| !is_onion(url));
+        }
+        urls
+    }
+
+    /// The dir the store's apps are read from
+    pub fn checkout_dir(&self, nirvati_dir: &Path) -> PathBuf {
+        match self.source_type {
+            SourceType::Local => PathBuf::from(&self.url),
+            _ => store_dir(nirvati_dir, &self.id),
+        }
+    }
+
+    /// The dir the store's apps are in, the checkout or a subdir of it, depending on the format
+    pub fn apps_dir(&self, nirvati_dir: &Path) -> PathBuf {
+        let checkout_dir = self.checkout_dir(nirvati_dir);
+        match self.format.apps_subdir() {
+            Some(subdir) if checkout_dir.join(subdir).is_dir() => checkout_dir.join(subdir),
+            _ => checkout_dir,
+        }
+    }
+}
+
+/// Contents of db/sources.yml
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sources {
+    #[serde(default)]
+    pub stores: Vec<StoreSource>,
+}
+
+/// App id -> id of the store it was synced from, stored in apps/origins.json
+pub type Origins = BTreeMap<String, String>;
+
+pub fn store_dir(nirvati_dir: &Path, store: &str) -> PathBuf {
+    nirvati_dir.join("repos").join(store)
+}
+
+pub fn qualified_id(store: &str, app: &str) -> String {
+    format!("{}/{}", store, app)
+}
+
+/// Whether a URL or OCI reference points to an onion service
+fn is_onion(url: &str) -> bool {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme
+        .split(['/', ':'])
+        .next()
+        .is_some_and(|host| host.ends_with(".onion"))
+}
+
+fn is_default_format(format: &StoreFormat) -> bool {
+    *format == StoreFormat::Nirvati
+}
+
+/// Writes the result of converting an app from another format, templated apps get an app.yml.jinja
+fn write_converted(
+    app_dir: &Path,
+    app_yml: &AppYml,
+    metadata: &MetadataYml,
+    templated: bool,
+) -> Result<()> {
+    let (app_yml_file, stale) = if templated {
+        ("app.yml.jinja", "app.yml")
+    } else {
+        ("app.yml", "app.yml.jinja")
+    };
+    std::fs::write(app_dir.join(app_yml_file), serde_yaml::to_string(app_yml)?)?;
+    if app_dir.join(stale).is_file() {
+        std::fs::remove_file(app_dir.join(stale))?;
+    }
+    std::fs::write(
+        app_dir.join("metadata.yml"),
+        serde_yaml::to_string(metadata)?,
+    )?;
+    Ok(())
+}
+
+/// The ids of the apps in a store checkout, sorted
+fn list_store_apps(dir: &Path, format: StoreFormat) -> Result<Vec<String>> {
+    let mut apps = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && format.is_app_dir(&entry.path()) {
+            apps.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    apps.sort();
+    Ok(apps)
+}
+
+/// Decides which store provides each app
+pub fn resolve_apps(nirvati_dir: &Path, sources: &Sources) -> Result<Origins> {
+    let mut stores = sources.stores.iter().collect::<Vec<_>>();
+    // Stable, so stores listed first win ties
+    stores.sort_by_key(|store| -store.priority);
+    let mut origins = Origins::new();
+    for store in stores {
+        let dir = store.apps_dir(nirvati_dir);
+        if !dir.is_dir() {
+            tracing::warn!(
+                "Store {} has not been checked out to {}",
+                store.id,
+                dir.display()
+            );
+            continue;
+        }
+        let apps = match list_store_apps(&dir, store.format) {
+            Ok(apps) => apps,
+            Err(err) => {
+                tracing::warn!("Failed to list the apps of store {}: {:#}", store.id, err);
+                continue;
+            }
+        };
+        for app in apps {
+            if sources.stores.iter().any(|store| store.id == app) {
+                tracing::warn!(
+                    "App {} has the same id as a store, so references to its permissions are ambiguous",
+                    qualified_id(&store.id, &app)
+                );
+            }
+            match origins.get(&app) {
+                Some(provider) => tracing::warn!(
+                    "Ignoring {}, because {} has a higher priority",
+                    qualified_id(&store.id, &app),
+                    qualified_id(provider, &app)
+                ),
+                None => {
+                    origins.insert(app, store.id.clone());
+                }
+            }
+        }
+    }
+    Ok(origins)
+}
+
+pub(crate) fn copy_dir_all(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    Unsigned,
+    /// The signature could not be checked, for example because the key is unknown
+    Unknown,
+}
+
+/// An entry in apps/stores.json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreSummary {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    /// Seconds since epoch
+    pub last_sync: u64,
+    /// The commit the checkout is at, or sha256:<hash> of the archive of tarball and OCI stores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Number of apps in the store, including apps provided by another store
+    pub apps: usize,
+    /// Status of the signature on the checked out commit
+    pub signature: SignatureStatus,
+    /// The URL or mirror a tarball or OCI store was downloaded from on the last sync
+    /// Unset if the archive was taken from the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_from: Option<String>,
+}
+
+fn git(repo_dir: &Path, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo_dir)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Maps the %G? placeholder of git log to a signature status
+fn parse_signature_status(status: &str) -> SignatureStatus {
+    match status {
+        "G" | "U" => SignatureStatus::Valid,
+        "N" => SignatureStatus::Unsigned,
+        "B" | "X" | "Y" | "R" => SignatureStatus::Invalid,
+        _ => SignatureStatus::Unknown,
+    }
+}
+
+/// Summarizes a store checkout, fetched is what sync fetched of the store
+pub fn summarize_store(
+    nirvati_dir: &Path,
+    store: &StoreSource,
+    last_sync: u64,
+    fetched: Option<&fetch::FetchedStore>,
+) -> Result<StoreSummary> {
+    let dir = store.checkout_dir(nirvati_dir);
+    let apps = if dir.is_dir() {
+        list_store_apps(&store.apps_dir(nirvati_dir), store.format)?.len()
+    } else {
+        0
+    };
+    // Without this check, git would use a repository the Nirvati root is in
+    let is_git_checkout = matches!(store.source_type, SourceType::Git | SourceType::Local)
+        && dir.join(".git").exists();
+    let (commit, signature) = if is_git_checkout {
+        (
+            git(&dir, &["rev-parse", "HEAD"]),
+            git(&dir, &["log", "-1", "--format=%G?"])
+                .map(|status| parse_signature_status(&status))
+                .unwrap_or(SignatureStatus::Unknown),
+        )
+    } else {
+        (
+            fetched
+                .and_then(|fetched| fetched.sha256.as_ref())
+                .map(|sha256| format!("sha256:{}", sha256)),
+            SignatureStatus::Unknown,
+        )
+    };
+    Ok(StoreSummary {
+        id: store.id.clone(),
+        name: store.name.clone().unwrap_or_else(|| store.id.clone()),
+        url: store.url.clone(),
+        last_sync,
+        commit,
+        apps,
+        signature,
+        fetched_from: fetched.and_then(|fetched| fetched.url.clone()),
+    })
+}
+
+/// What syncing did to a store, in the output of sync
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreSyncResult {
+    pub store: String,
+    /// Apps the store provides that were added or changed
+    pub updated_apps: Vec<String>,
+    /// Apps the store provided before, but no longer does, because it removed them
+    /// or a store with a higher priority provides them now
+    pub removed_apps: Vec<String>,
+    /// Why the store could not be fetched or some of its apps could not be copied,
+    /// the existing checkout and apps are kept then
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+/// The result of sync_apps
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncReport {
+    /// The new apps/origins.json
+    #[serde(skip)]
+    pub origins: Origins,
+    pub stores: Vec<StoreSyncResult>,
+}
+
+/// A hash of the paths and contents of the files in a dir, None if it can't be read
+fn dir_fingerprint(dir: &Path) -> Option<String> {
+    fn hash_dir(dir: &Path, relative: &Path, hasher: &mut hmac_sha256::Hash) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            if entry.path().is_dir() {
+                hash_dir(&entry.path(), &path, hasher)?;
+            } else {
+                hasher.update(path.to_string_lossy().as_bytes());
+                hasher.update([0]);
+                hasher.update(std::fs::read(entry.path())?);
+                hasher.update([0]);
+            }
+        }
+        Ok(())
+    }
+    let mut hasher = hmac_sha256::Hash::new();
+    hash_dir(dir, Path::new(""), &mut hasher).ok()?;
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// Copies or symlinks an app from a store checkout to apps/ and converts it if it isn't in the Nirvati format
+fn sync_app(nirvati_dir: &Path, store: &StoreSource, app: &str) -> Result<()> {
+    let source = store.apps_dir(nirvati_dir).join(app);
+    let target = nirvati_dir.join("apps").join(app);
+    if target.is_symlink() {
+        std::fs::remove_file(&target)?;
+    }
+    let format = if store.format == StoreFormat::Nirvati && citadel::is_app_dir(&source) {
+        StoreFormat::Citadel
+    } else {
+        store.format
+    };
+    // Converted apps are written to apps/, so they can't be symlinked
+    if store.symlink && store.source_type == SourceType::Local && format == StoreFormat::Nirvati {
+        if target.exists() {
+            std::fs::remove_dir_all(&target)?;
+        }
+        std::os::unix::fs::symlink(source.canonicalize()?, &target)?;
+    } else {
+        copy_dir_all(&source, &target)?;
+    }
+    if format != StoreFormat::Nirvati {
+        match format.convert_app(&target, app) {
+            Ok(notes) => {
+                for note in &notes {
+                    tracing::warn!(
+                        "{}: {}: {}",
+                        qualified_id(&store.id, app),
+                        note.field,
+                        note.message
+                    );
+                }
+                std::fs::write(target.join(NOTES_FILE), serde_yaml::to_string(&notes)?)?;
+            }
+            Err(err) => tracing::error!(
+                "Failed to convert app {}: {:#}",
+                qualified_id(&store.id, app),
+                err
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Fetches tarball and OCI stores, copies the apps from the store checkouts to apps/
+/// and writes apps/origins.json and apps/stores.json
+/// Stores are fetched in parallel. If a store can't be fetched, its existing checkout is used,
+/// and the other stores are synced anyway.
+/// With a tor_proxy, stores are downloaded through it, from their onion mirrors first
+pub fn sync_apps(nirvati_dir: &Path, tor_proxy: Option<&str>) -> Result<SyncReport> {
+    let sources = files::get_sources(nirvati_dir)?;
+    if crate::offline::is_offline() {
+        let downloads = sources
+            .stores
+            .iter()
+            .filter(|store| fetch::needs_download(nirvati_dir, store))
+            .map(|store| store.id.as_str())
+            .collect::<Vec<_>>();
+        if !downloads.is_empty() {
+            bail!(
+                "Can't sync in offline mode, the stores {} would have to be downloaded. Pin them to a sha256 whose archive is in repos/cache, or sync while online",
+                downloads.join(", ")
+            );
+        }
+    }
+    let mut results = sources
+        .stores
+        .iter()
+        .map(|store| StoreSyncResult {
+            store: store.id.clone(),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let mut archives = BTreeMap::new();
+    for ((store, fetched), result) in fetch::fetch_stores(nirvati_dir, &sources.stores, tor_proxy)?
+        .into_iter()
+        .zip(&mut results)
+    {
+        match fetched {
+            Ok(Some(archive)) => {
+                archives.insert(store, archive);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!("Failed to fetch store {}: {:#}", store, err);
+                result.errors.push(format!("{:#}", err));
+            }
+        }
+    }
+    let previous_origins = files::get_app_origins(nirvati_dir)?;
+    let origins = resolve_apps(nirvati_dir, &sources)?;
+    for (app, store_id) in &origins {
+        let Some(index) = sources
+            .stores
+            .iter()
+            .position(|store| &store.id == store_id)
+        else {
+            continue;
+        };
+        let target = nirvati_dir.join("apps").join(app);
+        let before = dir_fingerprint(&target);
+        if let Err(err) = sync_app(nirvati_dir, &sources.stores[index], app) {
+            tracing::error!(
+                "Failed to sync app {}: {:#}",
+                qualified_id(store_id, app),
+                err
+            );
+            results[index]
+                .errors
+                .push(format!("Failed to sync app {}: {:#}", app, err));
+        } else if dir_fingerprint(&target) != before {
+            results[index].updated_apps.push(app.clone());
+        }
+    }
+    for (app, store_id) in &previous_origins {
+        if origins.get(app) == Some(store_id) {
+            continue;
+        }
+        if let Some(result) = results.iter_mut().find(|result| &result.store == store_id) {
+            result.removed_apps.push(app.clone());
+        }
+    }
+    files::save_app_origins(nirvati_dir, &origins)?;
+    crate::manage::instances::update_instances(nirvati_dir)?;
+    let last_sync = events::now();
+    let mut summaries = Vec::new();
+    for (store, result) in sources.stores.iter().zip(&mut results) {
+        match summarize_store(nirvati_dir, store, last_sync, archives.get(&store.id)) {
+            Ok(summary) => summaries.push(summary),
+            Err(err) => {
+                tracing::warn!("Failed to summarize store {}: {:#}", store.id, err);
+                result.errors.push(format!("{:#}", err));
+            }
+        }
+    }
+    files::save_store_summaries(nirvati_dir, &summaries)?;
+    Ok(SyncReport {
+        origins,
+        stores: results,
+    })
+}
+
+/// Translates store-qualified app references into the app ids used in apps/
+#[derive(Debug, Clone, Default)]
+pub struct StoreIds {
+    stores: Vec<String>,
+    /// Local stores
+    dev_stores: Vec<String>,
+    origins: Origins,
+}
+
+impl StoreIds {
+    pub fn new(sources: &Sources, origins: Origins) -> Self {
+        Self {
+            stores: sources
+                .stores
+                .iter()
+                .map(|store| store.id.clone())
+                .collect(),
+            dev_stores: sources
+                .stores
+                .iter()
+                .filter(|store| store.source_type == SourceType::Local)
+                .map(|store| store.id.clone())
+                .collect(),
+            origins,
+        }
+    }
+
+    pub fn load(nirvati_dir: &Path) -> Result<Self> {
+        Ok(Self::new(
+            &files::get_sources(nirvati_dir)?,
+            files::get_app_origins(nirvati_dir)?,
+        ))
+    }
+
+    /// Sets the store a registry entry comes from, and whether it is a dev app
+    pub fn apply_origin(&self, metadata: &mut OutputMetadata) {
+        let (app, _) = split_instance_id(&metadata.id);
+        metadata.store = self.origins.get(app).cloned();
+        metadata.dev = metadata
+            .store
+            .as_ref()
+            .is_some_and(|store| self.dev_stores.contains(store));
+    }
+
+    /// Turns <store>/<app> and <store>/<app>/<permission> into <app> and <app>/<permission>
+    /// References to an app the store does not provide are kept, so they stay unmet
+    pub fn localize(&self, id: &str) -> String {
+        let Some((store, rest)) = id.split_once('/') else {
+            return id.to_owned();
+        };
+        if !self.stores.iter().any(|known| known == store) {
+            return id.to_owned();
+        }
+        let app = rest.split('/').next().unwrap_or_default();
+        if self.origins.get(app).map(String::as_str) == Some(store) {
+            rest.to_owned()
+        } else {
+            id.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{store, TempDir};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn higher_priority_store_wins() {
+        let nirvati_dir = TempDir::new("repos");
+        for (store, app) in [
+            ("official", "bitcoin"),
+            ("community", "bitcoin"),
+            ("community", "electrs"),
+        ] {
+            let app_dir = store_dir(&nirvati_dir, store).join(app);
+            std::fs::create_dir_all(&app_dir).unwrap();
+            std::fs::write(app_dir.join("metadata.yml"), "").unwrap();
+        }
+        let store = |id: &str, priority| StoreSource {
+            priority,
+            ..store(id, &format!("https://example.com/{}.git", id))
+        };
+        let sources = Sources {
+            stores: vec![store("community", 0), store("official", 10)],
+        };
+        let origins = resolve_apps(&nirvati_dir, &sources).unwrap();
+        assert_eq!(
+            origins,
+            Origins::from([
+                ("bitcoin".to_owned(), "official".to_owned()),
+                ("electrs".to_owned(), "community".to_owned()),
+            ])
+        );
+
+        let ids = StoreIds::new(&sources, origins);
+        assert_eq!(ids.localize("official/bitcoin"), "bitcoin");
+        assert_eq!(ids.localize("official/bitcoin/rpc"), "bitcoin/rpc");
+        assert_eq!(ids.localize("community/bitcoin"), "community/bitcoin");
+        assert_eq!(ids.localize("bitcoin/rpc"), "bitcoin/rpc");
+        assert_eq!(ids.localize("electrs"), "electrs");
+    }
+
+    #[test]
+    fn onion_mirrors_first_with_prefer_tor() {
+        let store = StoreSource {
+            source_type: SourceType::Tarball,
+            mirrors: vec![
+                "https://mirror.example.com/apps.tar.gz".to_owned(),
+                "http://exampleabc.onion/apps.tar.gz".to_owned(),
+                "registry.exampleabc.onion:5000/apps:latest".to_owned(),
+            ],
+            ..store("official", "https://example.com/apps.tar.gz")
+        };
+        assert_eq!(
+            store.urls(false),
+            vec![
+                "https://example.com/apps.tar.gz",
+                "https://mirror.example.com/apps.tar.gz",
+                "http://exampleabc.onion/apps.tar.gz",
+                "registry.exampleabc.onion:5000/apps:latest",
+            ]
+        );
+        assert_eq!(
+            store.urls(true),
+            vec![
+                "http://exampleabc.onion/apps.tar.gz",
+                "registry.exampleabc.onion:5000/apps:latest",
+                "https://example.com/apps.tar.gz",
+                "https://mirror.example.com/apps.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn signature_statuses() {
+        assert_eq!(parse_signature_status("G"), SignatureStatus::Valid);
+        assert_eq!(parse_signature_status("N"), SignatureStatus::Unsigned);
+        assert_eq!(parse_signature_status("B"), SignatureStatus::Invalid);
+        assert_eq!(parse_signature_status("E"), SignatureStatus::Unknown);
+    }
+
+    #[test]
+    fn sync_fails_offline_if_stores_need_downloads() {
+        crate::offline::set_offline(true);
+        let nirvati_dir = TempDir::new("repos-offline");
+        let sources = Sources {
+            stores: vec![StoreSource {
+                source_type: SourceType::Tarball,
+                ..store("remote", "https://invalid.example/apps.tar.gz")
+            }],
+        };
+        std::fs::create_dir_all(nirvati_dir.join("db")).unwrap();
+        std::fs::write(
+            nirvati_dir.join("db").join("sources.yml"),
+            serde_yaml::to_string(&sources).unwrap(),
+        )
+        .unwrap();
+        let err = sync_apps(&nirvati_dir, None).unwrap_err().to_string();
+        assert!(err.starts_with("Can't sync in offline mode, the stores remote"));
+        assert!(!store_dir(&nirvati_dir, "remote").exists());
+    }
+
+    #[test]
+    fn local_stores_provide_dev_apps() {
+        let nirvati_dir = TempDir::new("repos-local");
+        let checkout = TempDir::new("repos-checkout");
+        std::fs::create_dir_all(checkout.join("notes")).unwrap();
+        std::fs::write(checkout.join("notes").join("metadata.yml"), "version: 1\n").unwrap();
+        std::fs::create_dir_all(nirvati_dir.join("apps")).unwrap();
+        let local = |symlink| StoreSource {
+            source_type: SourceType::Local,
+            symlink,
+            ..store("dev", checkout.to_str().unwrap())
+        };
+        let target = nirvati_dir.join("apps").join("notes");
+
+        sync_app(&nirvati_dir, &local(true), "notes").unwrap();
+        assert!(target.is_symlink());
+        // Edits in the checkout apply without syncing again
+        std::fs::write(checkout.join("notes").join("app.yml"), "version: 1\n").unwrap();
+        assert!(target.join("app.yml").is_file());
+        sync_app(&nirvati_dir, &local(false), "notes").unwrap();
+        assert!(!target.is_symlink());
+        assert!(target.join("app.yml").is_file());
+
+        let sources = Sources {
+            stores: vec![
+                local(false),
+                store("official", "https://example.com/official.git"),
+            ],
+        };
+        let ids = StoreIds::new(
+            &sources,
+            Origins::from([
+                ("notes".to_owned(), "dev".to_owned()),
+                ("bitcoin".to_owned(), "official".to_owned()),
+            ]),
+        );
+        for (app, store, dev) in [("notes", "dev", true), ("bitcoin", "official", false)] {
+            let mut metadata = OutputMetadata {
+                id: app.to_owned(),
+                ..Default::default()
+            };
+            ids.apply_origin(&mut metadata);
+            assert_eq!(metadata.store.as_deref(), Some(store));
+            assert_eq!(metadata.dev, dev);
+        }
+    }
+}
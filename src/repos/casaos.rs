@@ -0,0 +1,194 @@
+//! Converts apps from CasaOS app stores, a docker-compose.yml with an x-casaos section per app, into Nirvati apps on sync
+//!
+//! The metadata is read from the x-casaos section of the compose file, which also names the main service
+//! and the port of its web UI. Volumes in /DATA/AppData/$AppID become data dirs of the app.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde_yaml::Value;
+
+use crate::{
+    composegenerator::v1::{
+        import::{import_compose, replace_env_vars, unknown_keys, ImportNote, WebUi},
+        types::MetadataYml,
+    },
+    manage::{categories, yaml},
+};
+
+/// Fields of x-casaos that are converted, or only matter to the CasaOS dashboard
+const KNOWN_FIELDS: [&str; 16] = [
+    "architectures",
+    "main",
+    "author",
+    "category",
+    "description",
+    "developer",
+    "icon",
+    "tagline",
+    "thumbnail",
+    "title",
+    "index",
+    "port_map",
+    "scheme",
+    "screenshot_link",
+    "store_app_id",
+    "hostname",
+];
+
+/// Whether a dir in a store checkout is a CasaOS app
+pub fn is_app_dir(path: &Path) -> bool {
+    let compose_yml = path.join("docker-compose.yml");
+    compose_yml.is_file()
+        && std::fs::read_to_string(compose_yml)
+            .is_ok_and(|compose_yml| compose_yml.contains("x-casaos:"))
+}
+
+/// A localized field like title, in English if available
+fn localized(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(string)) => string.clone(),
+        Some(Value::Mapping(mapping)) => mapping
+            .get("en_us")
+            .or_else(|| mapping.values().next())
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        _ => String::new(),
+    }
+}
+
+fn as_string(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// The main service and the container port port_map is published on
+fn web_ui(compose: &Value, casaos: &Value) -> Option<WebUi> {
+    let service = as_string(casaos.get("main"))?;
+    let published = as_string(casaos.get("port_map"))?;
+    let ports = compose
+        .get("services")?
+        .get(service.as_str())?
+        .get("ports")?
+        .as_sequence()?;
+    let port = ports.iter().find_map(|port| match port {
+        Value::Mapping(mapping) => (as_string(mapping.get("published")).as_ref()
+            == Some(&published))
+        .then(|| as_string(mapping.get("target")))
+        .flatten(),
+        port => {
+            let port = as_string(Some(port))?;
+            let port = port.split('/').next()?;
+            let (host, target) = port.rsplit_once(':')?;
+            (host.rsplit(':').next() == Some(published.as_str())).then(|| target.to_owned())
+        }
+    })?;
+    Some(WebUi {
+        service,
+        port: port.parse().ok()?,
+    })
+}
+
+/// Moves volumes in the app's AppData dir into the app's data dir, and removes bridge networking, which is the default
+fn map_services(compose: &mut Value, casaos_id: &str) {
+    let app_data = format!("/DATA/AppData/{}", casaos_id);
+    let to_data_dir = |source: &str| {
+        source
+            .strip_prefix(&app_data)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .map(|rest| format!(".{}", rest))
+    };
+    let Some(services) = compose.get_mut("services").and_then(Value::as_mapping_mut) else {
+        return;
+    };
+    for service in services.values_mut().filter_map(Value::as_mapping_mut) {
+        if service.get("network_mode").and_then(Value::as_str) == Some("bridge") {
+            service.remove("network_mode");
+        }
+        for volume in service
+            .get_mut("volumes")
+            .and_then(Value::as_sequence_mut)
+            .into_iter()
+            .flatten()
+        {
+            match volume {
+                Value::String(volume) => {
+                    let Some((source, rest)) = volume.split_once(':') else {
+                        continue;
+                    };
+                    if let Some(source) = to_data_dir(source) {
+                        *volume = format!("{}:{}", source, rest);
+                    }
+                }
+                Value::Mapping(volume) => {
+                    if let Some(source) = volume
+                        .get("source")
+                        .and_then(Value::as_str)
+                        .and_then(to_data_dir)
+                    {
+                        volume.insert("source".into(), source.into());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn metadata(casaos: &Value, metadata: &mut MetadataYml) {
+    let metadata = &mut metadata.metadata;
+    let title = localized(casaos.get("title"));
+    if !title.is_empty() {
+        metadata.name = title;
+    }
+    metadata.category = categories::canonical(&localized(casaos.get("category")));
+    metadata.tagline = localized(casaos.get("tagline"));
+    metadata.description = localized(casaos.get("description"));
+    if let Some(developer) = as_string(casaos.get("developer")) {
+        metadata.developers = BTreeMap::from([(developer, String::new())]);
+    }
+    metadata.path = as_string(casaos.get("index")).filter(|path| path != "/");
+    metadata.gallery = Some(
+        casaos
+            .get("screenshot_link")
+            .and_then(Value::as_sequence)
+            .into_iter()
+            .flatten()
+            .filter_map(|link| as_string(Some(link)))
+            .collect(),
+    );
+}
+
+/// Writes metadata.yml and app.yml into a CasaOS app's dir and returns what has to be reviewed
+pub fn convert_app(app_dir: &Path, app: &str) -> Result<Vec<ImportNote>> {
+    let compose_yml = std::fs::read_to_string(app_dir.join("docker-compose.yml"))?;
+    let mut compose = yaml::parse_value("docker-compose.yml", &compose_yml)?;
+    let casaos = compose
+        .get("x-casaos")
+        .cloned()
+        .ok_or_else(|| anyhow!("docker-compose.yml has no x-casaos section"))?;
+    let mut notes = unknown_keys(&casaos, "x-casaos", &KNOWN_FIELDS);
+    let web_ui = web_ui(&compose, &casaos);
+    // CasaOS sets $AppID to the name of the compose project
+    let casaos_id = as_string(compose.get("name")).unwrap_or_else(|| app.to_owned());
+    replace_env_vars(
+        &mut compose,
+        &[
+            ("AppID", &casaos_id),
+            ("PUID", "1000"),
+            ("PGID", "1000"),
+            ("TZ", "UTC"),
+        ],
+    );
+    map_services(&mut compose, &casaos_id);
+    let mut imported = import_compose(&compose, app, web_ui.as_ref())
+        .map_err(|err| anyhow!("Failed to convert docker-compose.yml: {:#}", err))?;
+    metadata(&casaos, &mut imported.metadata);
+    super::write_converted(app_dir, &imported.app_yml, &imported.metadata, false)?;
+    notes.extend(imported.notes);
+    Ok(notes)
+}
@@ -0,0 +1,552 @@
+//! Converts legacy Citadel apps, with an app.yml of version 3 or 4 and no metadata.yml, into Nirvati apps on sync
+//!
+//! Citadel's app.yml has the metadata in the same file, and v3 lists its services as `containers`.
+//! Citadel's dependencies on bitcoind, lnd, electrum and c-lightning become dependencies on the Nirvati apps,
+//! their env vars are renamed to the APP_<APP>_<VAR> variables of those apps' permissions,
+//! and hidden services make the app depend on tor.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    composegenerator::{
+        types::Dependency,
+        v1::{
+            import::{import_compose, replace_env_vars, unknown_keys, ImportNote, WebUi},
+            types::{InputMetadata, MetadataYml, PortTarget, StringOrMap},
+        },
+    },
+    manage::yaml,
+    utils::find_env_vars,
+};
+
+/// Citadel's name of an app it provides -> the Nirvati app, and the prefix of its env vars
+const DEPENDENCIES: [(&str, &str, &str); 5] = [
+    ("bitcoind", "bitcoin", "BITCOIN"),
+    ("lnd", "lnd", "LND"),
+    ("electrum", "electrs", "ELECTRUM"),
+    ("c-lightning", "core-ln", "C_LIGHTNING"),
+    ("tor", "tor", "TOR"),
+];
+
+/// Citadel env vars -> what they are replaced with
+const ENV_VARS: [(&str, &str); 3] = [
+    ("APP_SEED", "{{ derive_entropy(identifier=\"app-seed\") }}"),
+    ("APP_DOMAIN", "${DEVICE_HOSTNAME}.local"),
+    ("DEVICE_DOMAIN_NAME", "${DEVICE_HOSTNAME}.local"),
+];
+
+/// Fields of Citadel's metadata, in snake case, that are converted or only matter to Citadel
+const KNOWN_METADATA: [&str; 22] = [
+    "id",
+    "name",
+    "version",
+    "category",
+    "tagline",
+    "developers",
+    "description",
+    "dependencies",
+    "permissions",
+    "optional_dependencies",
+    "conflicts",
+    "repo",
+    "support",
+    "gallery",
+    "path",
+    "default_username",
+    "default_password",
+    "deterministic_password",
+    "tor_only",
+    "update_containers",
+    "implements",
+    "release_notes",
+];
+
+/// Service keys that are not in compose files
+const CITADEL_SERVICE_KEYS: [&str; 6] = [
+    "port",
+    "mounts",
+    "requiredPorts",
+    "requiredUdpPorts",
+    "hiddenServicePorts",
+    "hiddenServices",
+];
+
+/// The version of a legacy app.yml, if it is one
+fn legacy_version(app_yml: &Value) -> Option<u64> {
+    app_yml
+        .get("version")
+        .and_then(Value::as_u64)
+        .filter(|version| matches!(version, 3 | 4))
+}
+
+/// Whether a dir in a store checkout is a legacy Citadel app
+pub fn is_app_dir(path: &Path) -> bool {
+    !path.join("metadata.yml").is_file()
+        && !path.join("metadata.yml.jinja").is_file()
+        && std::fs::read_to_string(path.join("app.yml"))
+            .ok()
+            .and_then(|app_yml| serde_yaml::from_str::<Value>(&app_yml).ok())
+            .and_then(|app_yml| legacy_version(&app_yml))
+            .is_some()
+}
+
+/// The Nirvati app for a Citadel dependency or mount name like bitcoind, bitcoin or c_lightning
+fn dependency(name: &str) -> Option<(&'static str, &'static str)> {
+    let name = name.replace('_', "-");
+    DEPENDENCIES
+        .iter()
+        .find(|(citadel, nirvati, prefix)| {
+            name == *citadel || name == *nirvati || name == prefix.to_lowercase().replace('_', "-")
+        })
+        .map(|(_, nirvati, prefix)| (*nirvati, *prefix))
+}
+
+fn snake_case(key: &str) -> String {
+    let mut snake = String::new();
+    for char in key.chars() {
+        if char.is_ascii_uppercase() {
+            snake.push('_');
+        }
+        snake.push(char.to_ascii_lowercase());
+    }
+    snake
+}
+
+fn collect_env_vars(value: &Value, env_vars: &mut BTreeSet<String>) {
+    match value {
+        Value::String(string) => {
+            env_vars.extend(find_env_vars(string).into_iter().map(str::to_owned));
+        }
+        Value::Sequence(sequence) => sequence
+            .iter()
+            .for_each(|value| collect_env_vars(value, env_vars)),
+        Value::Mapping(mapping) => mapping
+            .values()
+            .for_each(|value| collect_env_vars(value, env_vars)),
+        _ => {}
+    }
+}
+
+/// Ports from requiredPorts, a list of ports in v3 and a map of protocols to port maps in v4
+fn required_ports(value: &Value) -> Vec<(u16, u16)> {
+    match value {
+        Value::Sequence(ports) => ports
+            .iter()
+            .filter_map(|port| port.as_u64()?.try_into().ok())
+            .map(|port| (port, port))
+            .collect(),
+        Value::Mapping(ports) => ports
+            .iter()
+            .filter_map(|(public, target)| {
+                Some((
+                    public.as_u64()?.try_into().ok()?,
+                    target.as_u64()?.try_into().ok()?,
+                ))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The app's needs from Citadel, the Nirvati apps it depends on and the permissions it requests
+#[derive(Default)]
+struct Requirements {
+    dependencies: BTreeSet<String>,
+    permissions: BTreeSet<String>,
+}
+
+impl Requirements {
+    fn add(&mut self, name: &str, field: &str, notes: &mut Vec<ImportNote>) {
+        match name {
+            "root" | "network" => {
+                self.permissions.insert(name.to_owned());
+            }
+            "hw" => {
+                self.permissions.insert("root".to_owned());
+                notes.push(ImportNote {
+                    field: field.to_owned(),
+                    message: "Hardware access needs the root permission on Nirvati".to_owned(),
+                    permission: Some("root".to_owned()),
+                });
+            }
+            name => {
+                let app = dependency(name).map_or(name, |(app, _)| app);
+                self.dependencies.insert(app.to_owned());
+                self.permissions.insert(app.to_owned());
+            }
+        }
+    }
+}
+
+fn metadata(
+    citadel: &Value,
+    requirements: &Requirements,
+    alternatives: Vec<Vec<String>>,
+    notes: &mut Vec<ImportNote>,
+) -> Result<MetadataYml> {
+    let Some(Value::Mapping(citadel)) = citadel.get("metadata") else {
+        bail!("app.yml has no metadata");
+    };
+    let mut metadata = citadel
+        .iter()
+        .filter_map(|(key, value)| Some((Value::String(snake_case(key.as_str()?)), value.clone())))
+        .collect::<Mapping>();
+    notes.extend(unknown_keys(
+        &Value::Mapping(metadata.clone()),
+        "metadata",
+        &KNOWN_METADATA,
+    ));
+    metadata.retain(|key, _| {
+        key.as_str()
+            .is_some_and(|key| KNOWN_METADATA.contains(&key) && key != "permissions")
+    });
+    for key in ["id", "deterministic_password"] {
+        metadata.remove(key);
+    }
+    if let Some(Value::String(repo)) = metadata.get("repo").cloned() {
+        metadata.insert(
+            "repo".into(),
+            Value::Mapping(Mapping::from_iter([("Source code".into(), repo.into())])),
+        );
+    }
+    metadata.remove("dependencies");
+    let mut metadata: InputMetadata = serde_yaml::from_value(Value::Mapping(metadata))
+        .map_err(|err| anyhow!("Invalid metadata in app.yml: {}", err))?;
+    metadata.dependencies = requirements
+        .dependencies
+        .iter()
+        .cloned()
+        .map(Dependency::OneDependency)
+        .chain(
+            alternatives
+                .into_iter()
+                .map(Dependency::AlternativeDependency),
+        )
+        .collect();
+    metadata.app_yml_jinja_permissions = requirements.permissions.iter().cloned().collect();
+    // Citadel shows the derived password, which the dashboard can't know
+    metadata.default_password = metadata
+        .default_password
+        .filter(|password| !password.contains("APP_SEED"));
+    metadata.legacy = true;
+    Ok(MetadataYml {
+        version: 1,
+        metadata,
+    })
+}
+
+/// Writes metadata.yml and app.yml or app.yml.jinja into a legacy Citadel app's dir
+/// and returns what has to be reviewed
+pub fn convert_app(app_dir: &Path, app: &str) -> Result<Vec<ImportNote>> {
+    let app_yml = std::fs::read_to_string(app_dir.join("app.yml"))?;
+    let citadel = yaml::parse_value("app.yml", &app_yml)?;
+    let version =
+        legacy_version(&citadel).ok_or_else(|| anyhow!("app.yml is not of version 3 or 4"))?;
+    let mut notes = unknown_keys(
+        &citadel,
+        "app.yml",
+        &["version", "metadata", "services", "containers"],
+    );
+
+    let mut requirements = Requirements::default();
+    let mut alternatives = Vec::new();
+    // v3 lists dependencies, v4 permissions, both can have alternatives
+    for key in ["dependencies", "permissions"] {
+        let field = format!("metadata.{}", key);
+        for entry in citadel
+            .get("metadata")
+            .and_then(|metadata| metadata.get(key))
+            .and_then(Value::as_sequence)
+            .into_iter()
+            .flatten()
+        {
+            match entry {
+                Value::String(name) => requirements.add(name, &field, &mut notes),
+                Value::Sequence(options) => alternatives.push(
+                    options
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(|name| dependency(name).map_or(name, |(app, _)| app).to_owned())
+                        .collect(),
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    // v3 has a list of containers with a name, v4 a map of services
+    let services = match (version, citadel.get("containers")) {
+        (3, Some(Value::Sequence(containers))) => containers
+            .iter()
+            .filter_map(|container| {
+                let name = container.get("name")?.as_str()?.to_owned();
+                let mut container = container.as_mapping()?.clone();
+                container.remove("name");
+                Some((Value::String(name), Value::Mapping(container)))
+            })
+            .collect::<Mapping>(),
+        _ => citadel
+            .get("services")
+            .and_then(Value::as_mapping)
+            .cloned()
+            .ok_or_else(|| anyhow!("app.yml has no services"))?,
+    };
+
+    // Dependencies' env vars are renamed to the variables of their Nirvati apps
+    let mut env_vars = BTreeSet::new();
+    collect_env_vars(&Value::Mapping(services.clone()), &mut env_vars);
+    let mut renamed = Vec::new();
+    for env_var in &env_vars {
+        let Some((app, rest)) = DEPENDENCIES.iter().find_map(|(_, app, prefix)| {
+            Some((*app, env_var.strip_prefix(prefix)?.strip_prefix('_')?))
+        }) else {
+            continue;
+        };
+        renamed.push((
+            env_var.clone(),
+            format!(
+                "${{APP_{}_{}}}",
+                app.replace('-', "").to_uppercase(),
+                rest.replace('_', "")
+            ),
+        ));
+        if requirements.dependencies.insert(app.to_owned()) {
+            requirements.permissions.insert(app.to_owned());
+            notes.push(ImportNote {
+                field: "metadata.dependencies".to_owned(),
+                message: format!("Depends on {} because the app uses its env vars", app),
+                permission: Some(app.to_owned()),
+            });
+        }
+    }
+    for (env_var, replacement) in &renamed {
+        notes.push(ImportNote::new(
+            "services",
+            format!(
+                "${} was renamed to {}, check that a permission of the app provides it",
+                env_var, replacement
+            ),
+        ));
+    }
+
+    let mut compose_services = Mapping::new();
+    let mut citadel_services = BTreeMap::new();
+    for (name, service) in services {
+        let Some(name) = name.as_str().map(str::to_owned) else {
+            continue;
+        };
+        let Value::Mapping(mut service) = service else {
+            bail!("Service {} is not a map", name);
+        };
+        let citadel_keys = CITADEL_SERVICE_KEYS
+            .iter()
+            .filter_map(|key| Some((*key, service.remove(*key)?)))
+            .collect::<BTreeMap<_, _>>();
+        compose_services.insert(name.clone().into(), Value::Mapping(service));
+        citadel_services.insert(name, citadel_keys);
+    }
+    let mut compose = Value::Mapping(Mapping::from_iter([(
+        "services".into(),
+        Value::Mapping(compose_services),
+    )]));
+    let replacements = renamed
+        .iter()
+        .map(|(env_var, replacement)| (env_var.as_str(), replacement.as_str()))
+        .chain(ENV_VARS)
+        .collect::<Vec<_>>();
+    let templated = replace_env_vars(&mut compose, &replacements);
+
+    let web_ui = citadel_services
+        .get("main")
+        .and_then(|keys| keys.get("port")?.as_u64()?.try_into().ok())
+        .map(|port| WebUi {
+            service: "main".to_owned(),
+            port,
+        });
+    let mut imported = import_compose(&compose, app, web_ui.as_ref())
+        .map_err(|err| anyhow!("Failed to convert the services of app.yml: {:#}", err))?;
+    for (name, keys) in citadel_services {
+        let container = imported
+            .app_yml
+            .services
+            .get_mut(&name)
+            .ok_or_else(|| anyhow!("Service {} is missing after converting", name))?;
+        for (key, value) in keys {
+            let field = format!("services.{}.{}", name, key);
+            match key {
+                "port" => container.port = value.as_u64().and_then(|port| port.try_into().ok()),
+                "mounts" => {
+                    let mounts: BTreeMap<String, StringOrMap> = serde_yaml::from_value(value)
+                        .map_err(|err| anyhow!("Invalid mounts of service {}: {}", name, err))?;
+                    for (mount, target) in mounts {
+                        let mount = match dependency(&mount) {
+                            Some((app, _)) if mount != "data" => {
+                                requirements.add(app, &field, &mut notes);
+                                app.to_owned()
+                            }
+                            _ => mount,
+                        };
+                        container.mounts.insert(mount, target);
+                    }
+                }
+                "requiredPorts" => {
+                    let (tcp, udp) = match &value {
+                        Value::Mapping(protocols)
+                            if protocols.contains_key("tcp") || protocols.contains_key("udp") =>
+                        {
+                            (
+                                protocols.get("tcp").map(required_ports).unwrap_or_default(),
+                                protocols.get("udp").map(required_ports).unwrap_or_default(),
+                            )
+                        }
+                        value => (required_ports(value), Vec::new()),
+                    };
+                    for (public, target) in tcp {
+                        container
+                            .required_ports
+                            .tcp
+                            .insert(public, PortTarget::Port(target));
+                    }
+                    for (public, target) in udp {
+                        container
+                            .required_ports
+                            .udp
+                            .insert(public, PortTarget::Port(target));
+                    }
+                }
+                "requiredUdpPorts" => {
+                    for (public, target) in required_ports(&value) {
+                        container
+                            .required_ports
+                            .udp
+                            .insert(public, PortTarget::Port(target));
+                    }
+                }
+                _ => {
+                    requirements.add("tor", &field, &mut notes);
+                    notes.push(ImportNote {
+                        field,
+                        message: "Hidden services aren't supported by app.yml, they were dropped"
+                            .to_owned(),
+                        permission: Some("tor".to_owned()),
+                    });
+                }
+            }
+        }
+    }
+
+    let metadata = metadata(&citadel, &requirements, alternatives, &mut notes)?;
+    super::write_converted(app_dir, &imported.app_yml, &metadata, templated)?;
+    // Env vars of dependencies are expected, they were renamed above
+    notes.extend(imported.notes.into_iter().filter(|note| {
+        !note.permission.as_ref().is_some_and(|permission| {
+            requirements
+                .dependencies
+                .iter()
+                .any(|app| app.replace('-', "").eq_ignore_ascii_case(permission))
+        })
+    }));
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{composegenerator::v1::types::AppYml, utils::StringLike};
+
+    #[test]
+    fn converts_citadel_v4_app() {
+        let app_dir = TempDir::new("citadel");
+        std::fs::write(
+            app_dir.join("app.yml"),
+            r#"
+version: 4
+metadata:
+  id: explorer
+  name: Explorer
+  version: 3.3.0
+  category: Bitcoin
+  tagline: Explore the blockchain
+  developers:
+    Example: https://example.com
+  description: A block explorer
+  permissions:
+    - lnd
+    - [electrum, fulcrum]
+  repo: https://github.com/example/explorer
+  support: https://github.com/example/explorer/issues
+  gallery: []
+  defaultPassword: $APP_SEED
+  torOnly: false
+  widgets: []
+services:
+  main:
+    image: ghcr.io/example/explorer:3.3.0
+    user: 1000:1000
+    port: 3002
+    environment:
+      RPC_HOST: $BITCOIN_IP
+      RPC_PASS: ${BITCOIN_RPC_PASS}
+      LND_DIR: /lnd
+    mounts:
+      data:
+        data: /data
+      lnd: /lnd
+      c_lightning: /cln
+    requiredPorts:
+      tcp:
+        9735: 9735
+    hiddenServicePorts:
+      explorer: [80]
+"#,
+        )
+        .unwrap();
+        assert!(is_app_dir(&app_dir));
+        let notes = convert_app(&app_dir, "explorer");
+        let app_yml = std::fs::read_to_string(app_dir.join("app.yml"));
+        let metadata_yml = std::fs::read_to_string(app_dir.join("metadata.yml"));
+        let notes = notes.unwrap();
+        let app_yml: AppYml = serde_yaml::from_str(&app_yml.unwrap()).unwrap();
+        let main = &app_yml.services["main"];
+        assert_eq!(main.port, Some(3002));
+        assert_eq!(
+            main.environment["RPC_PASS"],
+            StringLike::String("${APP_BITCOIN_RPCPASS}".to_owned())
+        );
+        assert_eq!(
+            main.mounts.keys().collect::<Vec<_>>(),
+            vec!["core-ln", "data", "lnd"]
+        );
+        assert_eq!(main.required_ports.tcp[&9735], PortTarget::Port(9735));
+        assert!(notes.iter().any(|note| note.field == "metadata.widgets"));
+        assert!(notes
+            .iter()
+            .any(|note| note.field == "services.main.hiddenServicePorts"));
+
+        let metadata: MetadataYml = serde_yaml::from_str(&metadata_yml.unwrap()).unwrap();
+        let metadata = metadata.metadata;
+        assert!(metadata.legacy);
+        assert_eq!(metadata.default_password, None);
+        assert_eq!(
+            metadata.dependencies,
+            vec![
+                Dependency::OneDependency("bitcoin".to_owned()),
+                Dependency::OneDependency("core-ln".to_owned()),
+                Dependency::OneDependency("lnd".to_owned()),
+                Dependency::OneDependency("tor".to_owned()),
+                Dependency::AlternativeDependency(vec!["electrs".to_owned(), "fulcrum".to_owned()]),
+            ]
+        );
+        assert_eq!(
+            metadata.repo["Source code"],
+            "https://github.com/example/explorer"
+        );
+    }
+}
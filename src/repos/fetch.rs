@@ -0,0 +1,353 @@
+//! Downloads of app stores that are distributed as tarballs or OCI artifacts
+//!
+//! Archives are cached in repos/cache/<sha256>.tar.gz, so a store with a pinned sha256
+//! can be synced without network access once its archive has been placed there.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::read::GzDecoder;
+
+use super::{git, is_onion, store_dir, SourceType, StoreSource};
+use crate::manage::{
+    images::{ImageRef, RegistryClient},
+    progress,
+};
+
+/// How many stores are downloaded at the same time
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+pub fn cache_dir(nirvati_dir: &Path) -> PathBuf {
+    nirvati_dir.join("repos").join("cache")
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(hmac_sha256::Hash::hash(data))
+}
+
+fn read_cached(nirvati_dir: &Path, sha256: &str) -> Option<Vec<u8>> {
+    let archive = std::fs::read(cache_dir(nirvati_dir).join(format!("{}.tar.gz", sha256))).ok()?;
+    (sha256_hex(&archive) == sha256).then_some(archive)
+}
+
+fn download_tarball(url: &str, proxy: Option<&str>) -> Result<Vec<u8>> {
+    // Onion services are end-to-end encrypted, so plain HTTP is fine for them
+    if !url.starts_with("https://") && !(proxy.is_some() && is_onion(url)) {
+        bail!("Tarball stores must be downloaded over HTTPS");
+    }
+    crate::offline::ensure_online(format!("Downloading {}", url))?;
+    let agent = crate::proxy::agent_builder(Duration::from_secs(300), proxy)?.build();
+    let mut archive = Vec::new();
+    agent
+        .get(url)
+        .call()?
+        .into_reader()
+        .read_to_end(&mut archive)?;
+    Ok(archive)
+}
+
+/// Downloads the single layer of an OCI artifact and verifies it against its digest
+fn download_artifact(client: &mut RegistryClient, reference: &str) -> Result<Vec<u8>> {
+    let image = ImageRef::parse(reference);
+    let manifest_ref = image
+        .digest
+        .clone()
+        .or_else(|| image.tag.clone())
+        .unwrap_or_else(|| "latest".to_owned());
+    let manifest = client.get_manifest(&image, &manifest_ref)?;
+    let [layer] = manifest.layers.as_slice() else {
+        bail!(
+            "Expected the artifact to have one layer, found {}",
+            manifest.layers.len()
+        );
+    };
+    let expected = layer
+        .digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("Unsupported layer digest {}", layer.digest))?;
+    let blob = client.get_blob(&image, &layer.digest)?;
+    if sha256_hex(&blob) != expected {
+        bail!("Layer {} does not match its digest", layer.digest);
+    }
+    Ok(blob)
+}
+
+/// Extracts a gzipped tarball to a store's checkout dir, replacing it
+/// If the archive only contains a single dir, like GitHub tarballs, that dir is used as the store root
+fn extract(archive: &[u8], target: &Path) -> Result<()> {
+    let tmp_dir = target.with_extension("tmp");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    std::fs::create_dir_all(&tmp_dir)?;
+    tar::Archive::new(GzDecoder::new(archive)).unpack(&tmp_dir)?;
+    let entries = std::fs::read_dir(&tmp_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    let root = match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => entry.path(),
+        _ => tmp_dir.clone(),
+    };
+    if target.exists() {
+        std::fs::remove_dir_all(target)?;
+    }
+    std::fs::rename(root, target)?;
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    Ok(())
+}
+
+fn expected_sha256(store: &StoreSource) -> Option<&str> {
+    store
+        .sha256
+        .as_deref()
+        .map(|sha256| sha256.trim_start_matches("sha256:"))
+}
+
+/// Whether fetching a store downloads it, because it is a tarball or OCI store that is not pinned to a cached archive
+pub fn needs_download(nirvati_dir: &Path, store: &StoreSource) -> bool {
+    matches!(store.source_type, SourceType::Tarball | SourceType::Oci)
+        && expected_sha256(store)
+            .and_then(|sha256| read_cached(nirvati_dir, sha256))
+            .is_none()
+}
+
+/// What was fetched of a store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedStore {
+    /// The sha256 of the archive a tarball or OCI store was extracted from, None for git stores
+    pub sha256: Option<String>,
+    /// The URL or mirror it was fetched from, None if the archive was taken from the cache
+    pub url: Option<String>,
+}
+
+/// Downloads the archive of a store from the first of its URLs that works and has the expected sha256
+/// tor_proxy is the SOCKS5 proxy to download through, onion mirrors are tried first then
+fn download(
+    store: &StoreSource,
+    client: &mut RegistryClient,
+    tor_proxy: Option<&str>,
+) -> Result<(Vec<u8>, String)> {
+    let mut errors = Vec::new();
+    for url in store.urls(tor_proxy.is_some()) {
+        let archive = match store.source_type {
+            SourceType::Oci => download_artifact(client, url),
+            _ => download_tarball(url, tor_proxy),
+        };
+        let archive = archive.and_then(|archive| match expected_sha256(store) {
+            Some(expected) if sha256_hex(&archive) != expected => bail!(
+                "Checksum mismatch for store {}: expected {}, got {}",
+                store.id,
+                expected,
+                sha256_hex(&archive)
+            ),
+            _ => Ok(archive),
+        });
+        match archive {
+            Ok(archive) => return Ok((archive, url.to_owned())),
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to download store {} from {}: {:#}",
+                    store.id,
+                    url,
+                    err
+                );
+                errors.push(format!("{}: {:#}", url, err));
+            }
+        }
+    }
+    bail!("{}", errors.join("; "))
+}
+
+/// Downloads a tarball or OCI store, or takes it from the cache, and extracts it to repos/<store>,
+/// or fetches a git store, see the git module
+/// Returns None for local stores and git stores the host checks out itself
+pub fn fetch_store(
+    nirvati_dir: &Path,
+    store: &StoreSource,
+    client: &mut RegistryClient,
+    tor_proxy: Option<&str>,
+) -> Result<Option<FetchedStore>> {
+    let cached = expected_sha256(store).and_then(|sha256| read_cached(nirvati_dir, sha256));
+    let (archive, url) = match (cached, store.source_type) {
+        (_, SourceType::Local) => return Ok(None),
+        (_, SourceType::Git) => {
+            let url = git::sync_store(nirvati_dir, store, tor_proxy, tor_proxy.is_some())?;
+            return Ok(url.map(|url| FetchedStore {
+                sha256: None,
+                url: Some(url),
+            }));
+        }
+        (Some(archive), _) => (archive, None),
+        (None, _) => {
+            let (archive, url) = download(store, client, tor_proxy)?;
+            (archive, Some(url))
+        }
+    };
+    let sha256 = sha256_hex(&archive);
+    std::fs::create_dir_all(cache_dir(nirvati_dir))?;
+    std::fs::write(
+        cache_dir(nirvati_dir).join(format!("{}.tar.gz", sha256)),
+        &archive,
+    )?;
+    extract(&archive, &store_dir(nirvati_dir, &store.id))?;
+    Ok(Some(FetchedStore {
+        sha256: Some(sha256),
+        url,
+    }))
+}
+
+/// Fetches stores in parallel, returning the result of fetch_store for every store, in the order of stores
+/// A store that fails doesn't stop the others
+pub fn fetch_stores(
+    nirvati_dir: &Path,
+    stores: &[StoreSource],
+    tor_proxy: Option<&str>,
+) -> Result<Vec<(String, Result<Option<FetchedStore>>)>> {
+    let queue = Mutex::new(stores.iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(stores.len()));
+    // Every worker has its own client, the tokens it caches are per repository anyway
+    let clients = (0..stores.len().min(MAX_CONCURRENT_FETCHES))
+        .map(|_| RegistryClient::new(tor_proxy))
+        .collect::<Result<Vec<_>>>()?;
+    std::thread::scope(|scope| {
+        let (queue, results) = (&queue, &results);
+        for mut client in clients {
+            scope.spawn(move || loop {
+                let Some((index, store)) = queue.lock().ok().and_then(|mut queue| queue.next())
+                else {
+                    break;
+                };
+                progress::report("fetch", Some(&store.id), index + 1, stores.len());
+                let result = fetch_store(nirvati_dir, store, &mut client, tor_proxy);
+                if let Ok(mut results) = results.lock() {
+                    results.push((index, store.id.clone(), result));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_default();
+    results.sort_by_key(|(index, _, _)| *index);
+    Ok(results
+        .into_iter()
+        .map(|(_, store, result)| (store, result))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{store, TempDir};
+    use flate2::{write::GzEncoder, Compression};
+
+    fn build_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let metadata = b"version: 1\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "apps-main/example/metadata.yml", &metadata[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn fetches_pinned_archive_from_cache() {
+        let nirvati_dir = TempDir::new("fetch");
+        let archive = build_archive();
+        let sha256 = sha256_hex(&archive);
+        std::fs::create_dir_all(cache_dir(&nirvati_dir)).unwrap();
+        std::fs::write(
+            cache_dir(&nirvati_dir).join(format!("{}.tar.gz", sha256)),
+            &archive,
+        )
+        .unwrap();
+        let store = StoreSource {
+            source_type: SourceType::Tarball,
+            sha256: Some(format!("sha256:{}", sha256)),
+            // Never contacted, because the archive is cached
+            ..store("mirror", "https://invalid.example/apps.tar.gz")
+        };
+        let mut client = RegistryClient::default();
+        let fetched = fetch_store(&nirvati_dir, &store, &mut client, None).unwrap();
+        let extracted = store_dir(&nirvati_dir, "mirror")
+            .join("example")
+            .join("metadata.yml")
+            .is_file();
+        assert_eq!(
+            fetched,
+            Some(FetchedStore {
+                sha256: Some(sha256),
+                url: None
+            })
+        );
+        assert!(extracted);
+    }
+
+    #[test]
+    fn failing_stores_do_not_stop_others() {
+        let nirvati_dir = TempDir::new("fetch-all");
+        let archive = build_archive();
+        let sha256 = sha256_hex(&archive);
+        std::fs::create_dir_all(cache_dir(&nirvati_dir)).unwrap();
+        std::fs::write(
+            cache_dir(&nirvati_dir).join(format!("{}.tar.gz", sha256)),
+            &archive,
+        )
+        .unwrap();
+        let store = |id: &str, url: &str, sha256: Option<String>| StoreSource {
+            source_type: SourceType::Tarball,
+            sha256,
+            ..store(id, url)
+        };
+        let stores = [
+            store("insecure", "http://invalid.example/apps.tar.gz", None),
+            store(
+                "mirror",
+                "https://invalid.example/apps.tar.gz",
+                Some(sha256.clone()),
+            ),
+        ];
+        let results = fetch_stores(&nirvati_dir, &stores, None).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "insecure");
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].0, "mirror");
+        assert_eq!(
+            results[1].1.as_ref().unwrap().as_ref().unwrap().sha256,
+            Some(sha256)
+        );
+    }
+
+    #[test]
+    fn downloads_fail_offline() {
+        crate::offline::set_offline(true);
+        let nirvati_dir = TempDir::new("fetch-offline");
+        let stores = [
+            StoreSource {
+                source_type: SourceType::Tarball,
+                ..store("tarball", "https://invalid.example/apps.tar.gz")
+            },
+            StoreSource {
+                source_type: SourceType::Oci,
+                ..store("oci", "registry.invalid.example/apps:latest")
+            },
+        ];
+        let mut client = RegistryClient::default();
+        for store in stores {
+            let err = fetch_store(&nirvati_dir, &store, &mut client, None).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("disabled in offline mode"),
+                "{:#}",
+                err
+            );
+            assert!(!store_dir(&nirvati_dir, &store.id).exists());
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Shallow, sparse checkouts of git stores
+//!
+//! Only the newest commit of the branch is fetched, and blobs are only downloaded for the files that are checked out.
+//! The git dir is kept in repos/cache/git/<store>.git, so its packs survive if the checkout is removed,
+//! and every sync only downloads the objects that changed.
+//! A store can list its app dirs in apps.txt at its root, then only those dirs and the files at the root are checked out.
+//! Stores the host cloned itself, with a .git dir in the checkout, are left to the host.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Result};
+
+use super::{fetch::cache_dir, store_dir, StoreSource};
+
+/// The file at the root of a store that lists its app dirs, one per line
+pub const INDEX_FILE: &str = "apps.txt";
+
+pub fn git_dir(nirvati_dir: &Path, store: &str) -> PathBuf {
+    cache_dir(nirvati_dir)
+        .join("git")
+        .join(format!("{}.git", store))
+}
+
+/// Whether the host checks out the store itself, in a clone with its own .git dir
+pub fn is_host_managed(checkout: &Path) -> bool {
+    checkout.join(".git").is_dir()
+}
+
+/// The proxy for git's http.proxy, with SOCKS5 proxies resolving hostnames through the proxy
+/// libcurl resolves socks5:// hostnames locally, which leaks them to the resolver and can't resolve onion hosts
+fn git_proxy(proxy: &str) -> String {
+    match proxy.strip_prefix("socks5://") {
+        Some(rest) => format!("socks5h://{}", rest),
+        None => proxy.to_owned(),
+    }
+}
+
+/// Whether git fetches a URL over HTTP(S), the only transports http.proxy applies to
+fn is_http(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// Runs git in a checkout, going through proxy if one is given
+fn git(checkout: &Path, proxy: Option<&str>, args: &[&str]) -> Result<String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(checkout);
+    if let Some(proxy) = proxy {
+        command
+            .arg("-c")
+            .arg(format!("http.proxy={}", git_proxy(proxy)));
+    }
+    let output = command.args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The app dirs listed in an index file, skipping empty lines, comments and paths outside the store
+pub fn parse_index(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim().trim_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| line.split('/').all(|part| !part.is_empty() && part != ".."))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The patterns of a cone mode sparse checkout of the files at the root and the given dirs, like git writes them
+fn cone_patterns(dirs: &[String]) -> String {
+    let mut parents = BTreeSet::new();
+    for dir in dirs {
+        let parts = dir.split('/').collect::<Vec<_>>();
+        for end in 1..parts.len() {
+            parents.insert(parts[..end].join("/"));
+        }
+    }
+    let mut patterns = vec!["/*".to_owned(), "!/*/".to_owned()];
+    for parent in &parents {
+        patterns.push(format!("/{}/", parent));
+        patterns.push(format!("!/{}/*/", parent));
+    }
+    let dirs = dirs
+        .iter()
+        .filter(|dir| !parents.contains(*dir))
+        .collect::<BTreeSet<_>>();
+    patterns.extend(dirs.into_iter().map(|dir| format!("/{}/", dir)));
+    patterns.join("\n") + "\n"
+}
+
+/// Checks out only the app dirs the fetched commit lists in its index file, or everything if it has none
+fn apply_index(
+    checkout: &Path,
+    git_dir: &Path,
+    store: &StoreSource,
+    proxy: Option<&str>,
+) -> Result<()> {
+    // The blob of the index is downloaded on demand, like the ones of the checkout
+    let index = git(
+        checkout,
+        proxy,
+        &["show", &format!("FETCH_HEAD:{}", INDEX_FILE)],
+    )
+    .ok()
+    .map(|index| parse_index(&index));
+    match index {
+        Some(apps) => {
+            let dirs = apps
+                .into_iter()
+                .map(|app| match store.format.apps_subdir() {
+                    Some(subdir) => format!("{}/{}", subdir, app),
+                    None => app,
+                })
+                .collect::<Vec<_>>();
+            std::fs::create_dir_all(git_dir.join("info"))?;
+            std::fs::write(
+                git_dir.join("info").join("sparse-checkout"),
+                cone_patterns(&dirs),
+            )?;
+            git(checkout, None, &["config", "core.sparseCheckout", "true"])?;
+            git(
+                checkout,
+                None,
+                &["config", "core.sparseCheckoutCone", "true"],
+            )?;
+        }
+        None => {
+            git(checkout, None, &["config", "core.sparseCheckout", "false"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetches the newest commit of a git store into its cached git dir and checks it out to repos/<store>
+/// Returns the URL or mirror it was fetched from, or None if the host manages the checkout or the device is offline
+pub fn sync_store(
+    nirvati_dir: &Path,
+    store: &StoreSource,
+    proxy: Option<&str>,
+    prefer_tor: bool,
+) -> Result<Option<String>> {
+    let checkout = store_dir(nirvati_dir, &store.id);
+    if is_host_managed(&checkout) {
+        return Ok(None);
+    }
+    if let Err(err) = crate::offline::ensure_online(format!("Fetching {}", store.url)) {
+        tracing::warn!("{}, using the existing checkout of store {}", err, store.id);
+        return Ok(None);
+    }
+    let git_dir = git_dir(nirvati_dir, &store.id);
+    if !checkout.join(".git").is_file() {
+        std::fs::create_dir_all(&checkout)?;
+        std::fs::create_dir_all(git_dir.parent().unwrap_or(&git_dir))?;
+        // Reinitializing keeps the objects of an existing git dir, so its packs are reused
+        git(
+            &checkout,
+            None,
+            &[
+                "init",
+                "-q",
+                &format!("--separate-git-dir={}", git_dir.display()),
+            ],
+        )?;
+    }
+    let proxy = proxy.map(str::to_owned).or_else(crate::proxy::get_proxy);
+    let branch = store.branch.as_deref().unwrap_or("HEAD");
+    let mut errors = Vec::new();
+    let mut fetched_from = None;
+    for url in store.urls(prefer_tor) {
+        if proxy.is_some() && !is_http(url) {
+            tracing::warn!(
+                "Not fetching store {} from {}, only HTTP(S) URLs can go through the proxy",
+                store.id,
+                url
+            );
+            errors.push(format!(
+                "{}: not an HTTP(S) URL, which a proxy requires",
+                url
+            ));
+            continue;
+        }
+        // Missing blobs are fetched from origin on checkout, so it has to be the mirror that works
+        let _ = git(&checkout, None, &["remote", "remove", "origin"]);
+        git(&checkout, None, &["remote", "add", "--", "origin", url])?;
+        git(
+            &checkout,
+            None,
+            &["config", "remote.origin.promisor", "true"],
+        )?;
+        git(
+            &checkout,
+            None,
+            &["config", "remote.origin.partialclonefilter", "blob:none"],
+        )?;
+        let fetched = git(
+            &checkout,
+            proxy.as_deref(),
+            &[
+                "fetch",
+                "-q",
+                "--depth=1",
+                "--filter=blob:none",
+                "--",
+                "origin",
+                branch,
+            ],
+        );
+        match fetched {
+            Ok(_) => {
+                fetched_from = Some(url.to_owned());
+                break;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to fetch store {} from {}: {:#}", store.id, url, err);
+                errors.push(format!("{}: {:#}", url, err));
+            }
+        }
+    }
+    let Some(fetched_from) = fetched_from else {
+        bail!("{}", errors.join("; "));
+    };
+    apply_index(&checkout, &git_dir, store, proxy.as_deref())?;
+    // Blobs of the new commit are downloaded on checkout, through the proxy as well
+    git(
+        &checkout,
+        proxy.as_deref(),
+        &["reset", "-q", "--hard", "FETCH_HEAD"],
+    )?;
+    // Removes the files of dirs that are no longer in the index
+    git(&checkout, proxy.as_deref(), &["read-tree", "-mu", "HEAD"])?;
+    Ok(Some(fetched_from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_index() {
+        let index = "# Apps of the store\nbitcoin\n\nelectrs/\n../etc\nApps/nextcloud\n";
+        assert_eq!(
+            parse_index(index),
+            vec!["bitcoin", "electrs", "Apps/nextcloud"]
+        );
+    }
+
+    #[test]
+    fn resolves_hostnames_through_socks_proxies() {
+        assert_eq!(
+            git_proxy("socks5://127.0.0.1:9050"),
+            "socks5h://127.0.0.1:9050"
+        );
+        assert_eq!(
+            git_proxy("socks5h://127.0.0.1:9050"),
+            "socks5h://127.0.0.1:9050"
+        );
+        assert_eq!(git_proxy("http://proxy.lan:3128"), "http://proxy.lan:3128");
+        assert!(is_http("http://store.onion/apps.git"));
+        assert!(!is_http("ssh://git@github.com/nirvati/apps.git"));
+        assert!(!is_http("git://github.com/nirvati/apps.git"));
+    }
+
+    #[test]
+    fn writes_cone_patterns() {
+        assert_eq!(
+            cone_patterns(&["bitcoin".to_owned(), "Apps/nextcloud".to_owned()]),
+            "/*\n!/*/\n/Apps/\n!/Apps/*/\n/Apps/nextcloud/\n/bitcoin/\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_checkout_offline() {
+        crate::offline::set_offline(true);
+        let nirvati_dir = crate::testing::TempDir::new("git-offline");
+        let store = crate::testing::store("apps", "https://invalid.example/apps.git");
+        assert_eq!(sync_store(&nirvati_dir, &store, None, false).unwrap(), None);
+        assert!(!store_dir(&nirvati_dir, "apps").exists());
+        assert!(!git_dir(&nirvati_dir, "apps").exists());
+    }
+}
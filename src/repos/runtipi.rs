@@ -0,0 +1,362 @@
+//! Converts apps from Runtipi app stores, with a config.json and a docker-compose.yml, into Nirvati apps on sync
+//!
+//! The port Runtipi publishes as ${APP_PORT} is the web UI. The form fields of config.json become
+//! settings in settings.yml, except random ones, which are derived from the Nirvati seed.
+//! Apps with form fields get an app.yml.jinja.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    composegenerator::v1::{
+        import::{import_compose, replace_env_vars, unknown_keys, ImportNote, WebUi},
+        types::MetadataYml,
+    },
+    manage::{categories, yaml},
+};
+
+/// Runtipi env vars -> what they are replaced with
+const ENV_VARS: [(&str, &str); 6] = [
+    ("TZ", "UTC"),
+    ("APP_DOMAIN", "${DEVICE_HOSTNAME}.local"),
+    ("LOCAL_DOMAIN", "${DEVICE_HOSTNAME}.local"),
+    ("APP_PROTOCOL", "http"),
+    ("APP_EXPOSED", "false"),
+    // So its subdirs are reported as host paths instead of becoming data dirs
+    ("ROOT_FOLDER_HOST", "/runtipi"),
+];
+
+/// Fields of config.json that are converted, or only matter to Runtipi's app store
+const KNOWN_FIELDS: [&str; 24] = [
+    "$schema",
+    "name",
+    "id",
+    "port",
+    "available",
+    "exposable",
+    "dynamic_config",
+    "tipi_version",
+    "version",
+    "categories",
+    "description",
+    "short_desc",
+    "author",
+    "source",
+    "website",
+    "form_fields",
+    "supported_architectures",
+    "created_at",
+    "updated_at",
+    "min_tipi_version",
+    "https",
+    "force_expose",
+    "no_gui",
+    "url_suffix",
+];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FormField {
+    #[serde(rename = "type")]
+    pub field_type: String,
+    pub label: String,
+    pub hint: Option<String>,
+    pub env_variable: String,
+    pub default: Option<Value>,
+}
+
+/// The fields of config.json Nirvati uses
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RuntipiApp {
+    pub name: String,
+    pub version: String,
+    pub categories: Vec<String>,
+    pub description: String,
+    pub short_desc: String,
+    pub author: String,
+    pub source: String,
+    pub website: String,
+    pub form_fields: Vec<FormField>,
+    pub no_gui: bool,
+    pub url_suffix: Option<String>,
+}
+
+/// Whether a dir in a store checkout is a Runtipi app
+pub fn is_app_dir(path: &Path) -> bool {
+    path.join("config.json").is_file() && path.join("docker-compose.yml").is_file()
+}
+
+fn is_app_port(value: &str) -> bool {
+    value == "${APP_PORT}" || value == "$APP_PORT"
+}
+
+/// Removes the port published as ${APP_PORT} and returns its service and container port
+fn take_app_port(compose: &mut Value) -> Option<WebUi> {
+    let services = compose.get_mut("services")?.as_mapping_mut()?;
+    for (service, definition) in services.iter_mut() {
+        let Some(ports) = definition.get_mut("ports").and_then(Value::as_sequence_mut) else {
+            continue;
+        };
+        let target = ports.iter().enumerate().find_map(|(index, port)| {
+            let target = match port {
+                Value::Mapping(mapping) => mapping
+                    .get("published")
+                    .and_then(Value::as_str)
+                    .filter(|published| is_app_port(published))
+                    .and(mapping.get("target"))
+                    .and_then(|target| match target {
+                        Value::Number(number) => number.as_u64().map(|port| port.to_string()),
+                        target => target.as_str().map(str::to_owned),
+                    }),
+                port => port
+                    .as_str()
+                    .and_then(|port| port.rsplit_once(':'))
+                    .filter(|(published, _)| is_app_port(published))
+                    .map(|(_, target)| target.trim_end_matches("/tcp").to_owned()),
+            }?;
+            Some((index, target.parse::<u16>().ok()?))
+        });
+        if let Some((index, port)) = target {
+            ports.remove(index);
+            return Some(WebUi {
+                service: service.as_str()?.to_owned(),
+                port,
+            });
+        }
+    }
+    None
+}
+
+/// The Tera expression an env var of a form field is replaced with, and its settings.yml entry unless it's random
+fn form_field(field: &FormField) -> (String, Option<Value>) {
+    let key = field.env_variable.to_lowercase();
+    if field.field_type == "random" {
+        return (
+            format!("{{{{ derive_entropy(identifier=\"{}\") }}}}", key),
+            None,
+        );
+    }
+    let default = field.default.as_ref().and_then(|default| match default {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(bool) => Some(bool.to_string()),
+        _ => None,
+    });
+    let expression = format!(
+        "{{{{ settings.{} | default(value=\"{}\") }}}}",
+        key,
+        default
+            .as_deref()
+            .unwrap_or_default()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    );
+    let mut setting = Mapping::new();
+    setting.insert("name".into(), field.label.clone().into());
+    if let Some(hint) = &field.hint {
+        setting.insert("description".into(), hint.clone().into());
+    }
+    let setting_type = match field.field_type.as_str() {
+        "number" => "number",
+        "boolean" => "boolean",
+        _ => "string",
+    };
+    setting.insert("type".into(), setting_type.into());
+    if let Some(default) = &field.default {
+        setting.insert("default".into(), default.clone());
+    }
+    (expression, Some(Value::Mapping(setting)))
+}
+
+fn metadata(runtipi_app: RuntipiApp, app_dir: &Path, metadata: &mut MetadataYml) {
+    let metadata = &mut metadata.metadata;
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    metadata.name = runtipi_app.name;
+    if !runtipi_app.version.is_empty() {
+        metadata.version = runtipi_app.version;
+    }
+    metadata.category = categories::canonical(
+        runtipi_app
+            .categories
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default(),
+    );
+    metadata.tagline = runtipi_app.short_desc;
+    // Runtipi shows metadata/description.md instead of the description in config.json
+    metadata.description = std::fs::read_to_string(app_dir.join("metadata").join("description.md"))
+        .unwrap_or(runtipi_app.description);
+    metadata.developers = BTreeMap::from([(
+        runtipi_app.author,
+        non_empty(runtipi_app.website).unwrap_or_else(|| runtipi_app.source.clone()),
+    )]);
+    metadata.repo = non_empty(runtipi_app.source.clone())
+        .map(|repo| BTreeMap::from([("Source code".to_owned(), repo)]))
+        .unwrap_or_default();
+    metadata.support = runtipi_app.source;
+    metadata.path = runtipi_app.url_suffix;
+}
+
+/// Writes metadata.yml, app.yml or app.yml.jinja and settings.yml into a Runtipi app's dir
+/// and returns what has to be reviewed
+pub fn convert_app(app_dir: &Path, app: &str) -> Result<Vec<ImportNote>> {
+    let config_json = std::fs::read_to_string(app_dir.join("config.json"))?;
+    let config: Value = serde_json::from_str(&config_json)
+        .map_err(|err| anyhow!("Invalid config.json: {}", err))?;
+    let mut notes = unknown_keys(&config, "config.json", &KNOWN_FIELDS);
+    let runtipi_app: RuntipiApp =
+        serde_yaml::from_value(config).map_err(|err| anyhow!("Invalid config.json: {}", err))?;
+    let compose_yml = std::fs::read_to_string(app_dir.join("docker-compose.yml"))?;
+    let mut compose = yaml::parse_value("docker-compose.yml", &compose_yml)?;
+
+    let web_ui = take_app_port(&mut compose).filter(|_| !runtipi_app.no_gui);
+    let mut settings = Mapping::new();
+    let mut replacements = vec![("APP_ID".to_owned(), app.to_owned())];
+    for field in &runtipi_app.form_fields {
+        if field.env_variable.is_empty() {
+            continue;
+        }
+        let (expression, setting) = form_field(field);
+        if let Some(setting) = setting {
+            settings.insert(field.env_variable.to_lowercase().into(), setting);
+        }
+        replacements.push((field.env_variable.clone(), expression));
+    }
+    let replacements = replacements
+        .iter()
+        .map(|(env_var, replacement)| (env_var.as_str(), replacement.as_str()))
+        .chain(ENV_VARS)
+        .collect::<Vec<_>>();
+    let templated = replace_env_vars(&mut compose, &replacements);
+
+    let mut imported = import_compose(&compose, app, web_ui.as_ref())
+        .map_err(|err| anyhow!("Failed to convert docker-compose.yml: {:#}", err))?;
+    metadata(runtipi_app, app_dir, &mut imported.metadata);
+    super::write_converted(app_dir, &imported.app_yml, &imported.metadata, templated)?;
+    if !settings.is_empty() {
+        let settings_yml = Mapping::from_iter([
+            ("version".into(), 1.into()),
+            ("settings".into(), Value::Mapping(settings)),
+        ]);
+        std::fs::write(
+            app_dir.join("settings.yml"),
+            serde_yaml::to_string(&settings_yml)?,
+        )?;
+    }
+    notes.extend(imported.notes);
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{
+        composegenerator::v1::types::{AppYml, StringOrMap},
+        manage::settings::SettingsYml,
+        utils::StringLike,
+    };
+
+    #[test]
+    fn converts_runtipi_app() {
+        let app_dir = TempDir::new("runtipi");
+        std::fs::create_dir_all(app_dir.join("metadata")).unwrap();
+        std::fs::write(
+            app_dir.join("config.json"),
+            r#"{
+  "$schema": "../schema.json",
+  "name": "Notes",
+  "id": "notes",
+  "port": 8765,
+  "available": true,
+  "tipi_version": 3,
+  "version": "1.2.0",
+  "categories": ["development"],
+  "description": "",
+  "short_desc": "Take notes",
+  "author": "Example",
+  "source": "https://github.com/example/notes",
+  "form_fields": [
+    {"type": "text", "label": "Username", "env_variable": "NOTES_USER", "default": "admin"},
+    {"type": "random", "label": "Secret", "env_variable": "NOTES_SECRET", "min": 32}
+  ],
+  "generate_vapid_keys": true
+}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("metadata").join("description.md"),
+            "# Notes\nA note taking app",
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("docker-compose.yml"),
+            r#"
+services:
+  notes:
+    image: ghcr.io/example/notes:1.2.0
+    ports:
+      - ${APP_PORT}:3000
+    volumes:
+      - ${APP_DATA_DIR}/data:/data
+    environment:
+      USER: ${NOTES_USER}
+      SECRET: ${NOTES_SECRET}
+      TZ: ${TZ}
+    networks:
+      - tipi_main_network
+    labels:
+      traefik.enable: true
+networks:
+  tipi_main_network:
+    name: runtipi_tipi_main_network
+    external: true
+"#,
+        )
+        .unwrap();
+        let notes = convert_app(&app_dir, "notes");
+        let app_yml = std::fs::read_to_string(app_dir.join("app.yml.jinja"));
+        let metadata_yml = std::fs::read_to_string(app_dir.join("metadata.yml"));
+        let settings_yml = std::fs::read_to_string(app_dir.join("settings.yml"));
+        let notes = notes.unwrap();
+        let app_yml: AppYml = serde_yaml::from_str(&app_yml.unwrap()).unwrap();
+        let main = &app_yml.services["main"];
+        assert_eq!(main.port, Some(3000));
+        assert!(main.required_ports.tcp.is_empty());
+        assert_eq!(
+            main.mounts["data"],
+            StringOrMap::Map(BTreeMap::from([("data".to_owned(), "/data".to_owned())]))
+        );
+        assert_eq!(
+            main.environment["USER"],
+            StringLike::String("{{ settings.notes_user | default(value=\"admin\") }}".to_owned())
+        );
+        assert_eq!(
+            main.environment["SECRET"],
+            StringLike::String("{{ derive_entropy(identifier=\"notes_secret\") }}".to_owned())
+        );
+        assert_eq!(main.environment["TZ"], StringLike::String("UTC".to_owned()));
+        assert_eq!(
+            notes
+                .iter()
+                .filter(|note| note.field.starts_with("config.json"))
+                .map(|note| note.field.as_str())
+                .collect::<Vec<_>>(),
+            vec!["config.json.generate_vapid_keys"]
+        );
+
+        let metadata: MetadataYml = serde_yaml::from_str(&metadata_yml.unwrap()).unwrap();
+        assert_eq!(metadata.metadata.category, "Developer tools");
+        assert_eq!(metadata.metadata.description, "# Notes\nA note taking app");
+
+        let settings: SettingsYml = serde_yaml::from_str(&settings_yml.unwrap()).unwrap();
+        assert_eq!(
+            settings.settings.keys().collect::<Vec<_>>(),
+            vec!["notes_user"]
+        );
+    }
+}
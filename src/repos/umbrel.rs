@@ -0,0 +1,268 @@
+//! Converts apps from Umbrel app stores, with an umbrel-app.yml and a docker-compose.yml, into Nirvati apps on sync
+//!
+//! Umbrel's app_proxy service is replaced by Caddy, and the env vars Umbrel sets for apps are mapped to their
+//! Nirvati equivalents. APP_PASSWORD and APP_SEED are derived from the Nirvati seed, so the app gets an app.yml.jinja.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::{
+    composegenerator::{
+        types::Dependency,
+        v1::{
+            import::{import_compose, replace_env_vars, unknown_keys, ImportNote, WebUi},
+            types::{InputMetadata, MetadataYml},
+        },
+    },
+    manage::{categories, yaml},
+};
+
+/// Umbrel env vars -> what they are replaced with
+/// Single quotes would be doubled in the YAML output, which Tera can't parse
+const ENV_VARS: [(&str, &str); 4] = [
+    (
+        "APP_PASSWORD",
+        "{{ derive_entropy(identifier=\"app-password\") }}",
+    ),
+    ("APP_SEED", "{{ derive_entropy(identifier=\"app-seed\") }}"),
+    ("DEVICE_DOMAIN_NAME", "${DEVICE_HOSTNAME}.local"),
+    ("APP_DOMAIN", "${DEVICE_HOSTNAME}.local"),
+];
+
+/// Fields of umbrel-app.yml that are converted, or only matter to Umbrel's app store
+const KNOWN_FIELDS: [&str; 23] = [
+    "manifestVersion",
+    "id",
+    "name",
+    "version",
+    "category",
+    "tagline",
+    "description",
+    "developer",
+    "website",
+    "dependencies",
+    "repo",
+    "support",
+    "port",
+    "gallery",
+    "path",
+    "defaultUsername",
+    "defaultPassword",
+    "deterministicPassword",
+    "releaseNotes",
+    "torOnly",
+    "implements",
+    "submitter",
+    "submission",
+];
+
+/// The fields of umbrel-app.yml Nirvati uses
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UmbrelApp {
+    pub name: String,
+    pub version: String,
+    pub category: String,
+    pub tagline: String,
+    pub description: String,
+    pub developer: String,
+    pub website: String,
+    pub dependencies: Vec<String>,
+    pub repo: String,
+    pub support: String,
+    pub gallery: Vec<String>,
+    pub path: String,
+    pub default_username: String,
+    pub default_password: String,
+    pub release_notes: String,
+    pub tor_only: bool,
+    pub implements: Option<String>,
+}
+
+/// Whether a dir in a store checkout is an Umbrel app
+pub fn is_app_dir(path: &Path) -> bool {
+    path.join("umbrel-app.yml").is_file() && path.join("docker-compose.yml").is_file()
+}
+
+/// Removes the app_proxy service and returns the service and port it proxies
+/// APP_HOST is the container name, like <app>_<service>_1
+fn take_app_proxy(compose: &mut Value, app: &str) -> Option<WebUi> {
+    let services = compose.get_mut("services")?.as_mapping_mut()?;
+    let app_proxy = services.remove("app_proxy")?;
+    let environment = app_proxy.get("environment")?;
+    let get = |key: &str| match environment {
+        Value::Mapping(mapping) => mapping.get(key).and_then(|value| match value {
+            Value::Number(number) => Some(number.to_string()),
+            value => value.as_str().map(str::to_owned),
+        }),
+        Value::Sequence(sequence) => sequence
+            .iter()
+            .filter_map(Value::as_str)
+            .find_map(|entry| entry.strip_prefix(&format!("{}=", key)).map(str::to_owned)),
+        _ => None,
+    };
+    let host = get("APP_HOST")?;
+    let port = get("APP_PORT")?.parse().ok()?;
+    let service = host
+        .strip_prefix(&format!("{}_", app))
+        .unwrap_or(&host)
+        .trim_end_matches(|char: char| char.is_ascii_digit())
+        .trim_end_matches('_')
+        .to_owned();
+    services
+        .contains_key(service.as_str())
+        .then_some(WebUi { service, port })
+}
+
+fn metadata(umbrel_app: UmbrelApp) -> MetadataYml {
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    MetadataYml {
+        version: 1,
+        metadata: InputMetadata {
+            category: categories::canonical(&umbrel_app.category),
+            tagline: umbrel_app.tagline,
+            developers: BTreeMap::from([(umbrel_app.developer, umbrel_app.website)]),
+            description: umbrel_app.description,
+            dependencies: umbrel_app
+                .dependencies
+                .into_iter()
+                .map(Dependency::OneDependency)
+                .collect(),
+            repo: non_empty(umbrel_app.repo)
+                .map(|repo| BTreeMap::from([("Source code".to_owned(), repo)]))
+                .unwrap_or_default(),
+            support: umbrel_app.support,
+            gallery: Some(umbrel_app.gallery),
+            path: non_empty(umbrel_app.path),
+            default_username: non_empty(umbrel_app.default_username),
+            // Umbrel shows the derived password, which the dashboard can't know
+            default_password: non_empty(umbrel_app.default_password)
+                .filter(|password| !password.contains("APP_PASSWORD")),
+            release_notes: non_empty(umbrel_app.release_notes)
+                .map(|notes| BTreeMap::from([(umbrel_app.version.clone(), notes)]))
+                .unwrap_or_default(),
+            tor_only: umbrel_app.tor_only,
+            implements: umbrel_app.implements,
+            name: umbrel_app.name,
+            version: umbrel_app.version,
+            ..Default::default()
+        },
+    }
+}
+
+/// Writes metadata.yml and app.yml or app.yml.jinja into an Umbrel app's dir and returns what has to be reviewed
+pub fn convert_app(app_dir: &Path, app: &str) -> Result<Vec<ImportNote>> {
+    let umbrel_app_yml = std::fs::read_to_string(app_dir.join("umbrel-app.yml"))?;
+    let umbrel_app: UmbrelApp = yaml::from_str("umbrel-app.yml", &umbrel_app_yml)?;
+    let mut notes = unknown_keys(
+        &yaml::parse_value("umbrel-app.yml", &umbrel_app_yml)?,
+        "umbrel-app.yml",
+        &KNOWN_FIELDS,
+    );
+    let compose_yml = std::fs::read_to_string(app_dir.join("docker-compose.yml"))?;
+    let mut compose = yaml::parse_value("docker-compose.yml", &compose_yml)?;
+    let web_ui = take_app_proxy(&mut compose, app);
+    let derived = replace_env_vars(&mut compose, &ENV_VARS);
+    let imported = import_compose(&compose, &umbrel_app.name, web_ui.as_ref())
+        .map_err(|err| anyhow!("Failed to convert docker-compose.yml: {:#}", err))?;
+    super::write_converted(app_dir, &imported.app_yml, &metadata(umbrel_app), derived)?;
+    notes.extend(imported.notes);
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::{
+        composegenerator::v1::types::{AppYml, StringOrMap},
+        utils::StringLike,
+    };
+
+    #[test]
+    fn converts_umbrel_app() {
+        let app_dir = TempDir::new("umbrel");
+        std::fs::write(
+            app_dir.join("umbrel-app.yml"),
+            r#"
+manifestVersion: 1
+id: notes
+category: developer
+name: Notes
+version: "1.2.0"
+tagline: Take notes
+description: A note taking app
+developer: Example
+website: https://example.com
+dependencies: [bitcoin]
+repo: https://github.com/example/notes
+support: https://github.com/example/notes/issues
+port: 8765
+gallery: [1.jpg]
+defaultUsername: admin
+defaultPassword: $APP_PASSWORD
+submitter: Someone
+widgets: []
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("docker-compose.yml"),
+            r#"
+version: "3.7"
+services:
+  app_proxy:
+    environment:
+      APP_HOST: notes_web_1
+      APP_PORT: 3000
+  web:
+    image: ghcr.io/example/notes:1.2.0
+    volumes:
+      - ${APP_DATA_DIR}/data:/data
+    environment:
+      ADMIN_PASSWORD: $APP_PASSWORD
+      SECRET: ${APP_SEED}
+      OTHER: $APP_SEED_OTHER
+      RPC_HOST: $APP_BITCOIN_NODE_IP
+"#,
+        )
+        .unwrap();
+        let notes = convert_app(&app_dir, "notes");
+        let app_yml = std::fs::read_to_string(app_dir.join("app.yml.jinja"));
+        let metadata_yml = std::fs::read_to_string(app_dir.join("metadata.yml"));
+        let notes = notes.unwrap();
+        let app_yml: AppYml = serde_yaml::from_str(&app_yml.unwrap()).unwrap();
+        assert_eq!(app_yml.services.len(), 1);
+        let main = &app_yml.services["main"];
+        assert_eq!(main.port, Some(3000));
+        assert_eq!(
+            main.mounts["data"],
+            StringOrMap::Map(BTreeMap::from([("data".to_owned(), "/data".to_owned())]))
+        );
+        assert_eq!(
+            main.environment["ADMIN_PASSWORD"],
+            StringLike::String("{{ derive_entropy(identifier=\"app-password\") }}".to_owned())
+        );
+        assert_eq!(
+            main.environment["OTHER"],
+            StringLike::String("$APP_SEED_OTHER".to_owned())
+        );
+        assert!(notes
+            .iter()
+            .any(|note| note.field == "services.web.environment.RPC_HOST"));
+        assert!(notes
+            .iter()
+            .any(|note| note.field == "umbrel-app.yml.widgets"));
+
+        let metadata: MetadataYml = serde_yaml::from_str(&metadata_yml.unwrap()).unwrap();
+        assert_eq!(metadata.metadata.category, "Developer tools");
+        assert_eq!(metadata.metadata.default_password, None);
+        assert_eq!(
+            metadata.metadata.dependencies,
+            vec![Dependency::OneDependency("bitcoin".to_owned())]
+        );
+    }
+}
@@ -3,25 +3,58 @@ use std::{
     path::{Path, PathBuf},
     rc::Rc,
     sync::Arc,
-    time::Duration,
+    time::Instant,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use tera::Tera;
 
-use crate::{composegenerator::types::Permission, manage::files::get_app_settings};
+use crate::{
+    composegenerator::types::{DiagnosticCode, MetadataYml, Permission},
+    manage::{
+        cancel,
+        dirs::app_data_dir,
+        dns::visible_apps,
+        files::{get_app_settings, get_dns_map, get_port_map, get_upnp_status, SimpleValue},
+        instances::split_instance_id,
+        ports::{assigned_ports, PortMapEntry},
+        processing::FailedApp,
+        profile, progress, secrets,
+        settings::read_settings_yml,
+        upnp,
+    },
+};
 
 mod builtins;
 pub mod js;
+mod pool;
 pub mod second_stage;
 
-#[allow(unused_must_use)]
+/// Sets the limits of the sandbox the JS helpers run in, before any template is rendered
+pub fn configure_sandbox(options: js::SandboxOptions) {
+    pool::configure(options);
+}
+
 pub fn process_metadata_yml_jinja(
     file: PathBuf,
     installed_apps: &[String],
     available_permissions: &[String],
     nirvati_root: &Path,
 ) -> Result<()> {
+    let rendered =
+        render_metadata_yml_jinja(&file, installed_apps, available_permissions, nirvati_root)?;
+    std::fs::write(file.with_extension(""), rendered)?;
+    Ok(())
+}
+
+/// Renders a metadata.yml.jinja file without writing the result anywhere
+#[allow(unused_must_use)]
+pub fn render_metadata_yml_jinja(
+    file: &Path,
+    installed_apps: &[String],
+    available_permissions: &[String],
+    nirvati_root: &Path,
+) -> Result<String> {
     let app_id = file
         .parent()
         .ok_or_else(|| anyhow!("Failed to get parent dir"))?
@@ -29,8 +62,7 @@ pub fn process_metadata_yml_jinja(
         .ok_or_else(|| anyhow!("Failed to get file name"))?
         .to_str()
         .ok_or_else(|| anyhow!("Failed to convert to str"))?;
-    let contents = std::fs::read_to_string(&file)?;
-    let out_file = file.with_extension("");
+    let contents = std::fs::read_to_string(file)?;
     let dir = file
         .parent()
         .ok_or_else(|| anyhow!("Failed to get parent dir"))?;
@@ -48,55 +80,78 @@ pub fn process_metadata_yml_jinja(
     let mut code = String::new();
     let mut functions = Vec::new();
     if tera_dir.is_dir() {
-        (code, functions) = js::parse_tera_helpers(&dir.join("_tera"))?;
+        (code, functions) = profile::measure("js transpile", Some(app_id), || {
+            js::parse_tera_helpers(&dir.join("_tera"), &js::cache_dir(nirvati_root))
+        })?;
     }
 
-    let (tx, rx) = std::sync::mpsc::channel();
-    let thread = std::thread::spawn(move || -> Result<()> {
-        // This may execute JS code, so we need to sandbox it
-        extrasafe::SafetyContext::new()
-            .enable(
-                extrasafe::builtins::SystemIO::nothing()
-                    .allow_stdout()
-                    .allow_stderr(),
-            )
-            .unwrap()
-            .apply_to_current_thread()?;
+    render_sandboxed(tera, code, functions, contents, Arc::new(tera_ctx), app_id)
+}
 
-        let mut tera = js::declare_js_functions(tera, &code, &functions)?;
-        let result = tera.render_str(&contents, &tera_ctx);
-        tx.send(result)?;
-        Ok(())
-    });
-    let rendered = rx.recv_timeout(Duration::from_secs(2));
-    thread.join().unwrap()?;
-    let rendered = rendered
-        .ok()
-        .ok_or_else(|| anyhow!("Rendering timed out!"))??;
-    std::fs::write(out_file, rendered)?;
-    Ok(())
+/// Declares the JS helpers and renders a template on a sandboxed worker, which has to finish within 2 seconds
+fn render_sandboxed(
+    tera: Tera,
+    code: String,
+    functions: Vec<String>,
+    contents: String,
+    tera_ctx: Arc<tera::Context>,
+    app_id: &str,
+) -> Result<String> {
+    let start = Instant::now();
+    let job = pool::RenderJob {
+        app_id: app_id.to_owned(),
+        tera,
+        code,
+        functions,
+        contents,
+        tera_ctx,
+    };
+    pool::render(job, || {
+        profile::record("js init", Some(app_id), start, start.elapsed());
+    })
 }
 
+/// Renders all metadata.yml.jinja files and returns the apps that failed to render
 pub fn process_metadata_yml_jinjas(
     nirvati_root: &Path,
     installed_apps: &[String],
     available_permissions: &[String],
-) -> Result<()> {
+) -> Result<Vec<FailedApp>> {
     // Loop through all subdirs, and process all metadata.yml.jinja files
+    let mut metadata_ymls = Vec::new();
     for entry in std::fs::read_dir(nirvati_root.join("apps"))? {
         let entry = entry?;
-        let path = entry.path();
-        let metadata_yml = path.join("metadata.yml.jinja");
+        let metadata_yml = entry.path().join("metadata.yml.jinja");
         if metadata_yml.is_file() {
+            metadata_ymls.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata_yml,
+            ));
+        }
+    }
+    let total = metadata_ymls.len();
+    let mut failed_apps = Vec::new();
+    for (index, (app_id, metadata_yml)) in metadata_ymls.into_iter().enumerate() {
+        cancel::check()?;
+        progress::report("metadata", Some(&app_id), index + 1, total);
+        let rendered = profile::measure("metadata", Some(&app_id), || {
             process_metadata_yml_jinja(
                 metadata_yml,
                 installed_apps,
                 available_permissions,
                 nirvati_root,
-            )?;
+            )
+        });
+        if let Err(err) = rendered {
+            tracing::error!(
+                "Failed to process metadata.yml.jinja for app {}: {:#}",
+                app_id,
+                err
+            );
+            failed_apps.push(FailedApp::new(&app_id, DiagnosticCode::RenderFailed, &err));
         }
     }
-    Ok(())
+    Ok(failed_apps)
 }
 
 pub fn assign_permission(
@@ -111,8 +166,15 @@ pub fn assign_permission(
         if map.contains_key(key) {
             tracing::warn!("Duplicate variable in permissions of app {}", from_app);
         }
+        let mut value = value.to_owned();
+        secrets::resolve_exported(&mut value, from_app).with_context(|| {
+            format!(
+                "Failed to resolve permission variable {} of {}",
+                key, from_app
+            )
+        })?;
         // Insert returns None if the key was not present
-        assert!(map.insert(key.to_owned(), value.to_owned()).is_none());
+        assert!(map.insert(key.to_owned(), value).is_none());
     }
     if handle_recursion {
         let mut handled_values = Rc::new(handled_values.unwrap_or_default());
@@ -144,15 +206,92 @@ pub fn assign_permission(
     Ok(())
 }
 
-#[allow(unused_must_use)]
+/// Renders the first stage of an app's app.yml.jinja with its settings
 pub fn process_app_yml_jinja(
     file: PathBuf,
-    permissions: &[String],
+    metadata: &MetadataYml,
     installed_apps: &[String],
     available_permissions_list: &[String],
     available_permissions: &HashMap<String, Vec<Permission>>,
     nirvati_root: &Path,
-) -> Result<()> {
+) -> Result<FirstStage> {
+    let app_id = file
+        .parent()
+        .ok_or_else(|| anyhow!("Failed to get parent dir"))?
+        .file_name()
+        .ok_or_else(|| anyhow!("Failed to get file name"))?
+        .to_str()
+        .ok_or_else(|| anyhow!("Failed to convert to str"))?;
+    let settings = get_app_settings(nirvati_root, app_id)?;
+    render_first_stage(
+        &file,
+        metadata,
+        installed_apps,
+        available_permissions_list,
+        available_permissions,
+        settings.as_ref(),
+        nirvati_root,
+    )
+}
+
+/// An app.yml.jinja after the first stage, the second stage is rendered once ports are assigned
+pub struct FirstStage {
+    pub rendered: String,
+    app_id: String,
+    visible_apps: Vec<String>,
+    tera_ctx: tera::Context,
+    available_files: Vec<PathBuf>,
+}
+
+impl FirstStage {
+    /// Renders the second stage, with the public ports from the port map available as `assigned_ports`
+    pub fn render(mut self, nirvati_root: &Path, port_map: &[PortMapEntry]) -> Result<String> {
+        let visible_apps = self
+            .visible_apps
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        self.tera_ctx
+            .insert("assigned_ports", &assigned_ports(port_map, &visible_apps));
+        let mut tera = second_stage::get_tera(nirvati_root.to_path_buf(), self.available_files);
+        tera.render_str(&self.rendered, &self.tera_ctx)
+            .with_context(|| format!("Failed to render the second stage for {}", self.app_id))
+    }
+}
+
+/// Renders both stages of an app.yml.jinja file without writing the result anywhere,
+/// with the ports of the last generate
+pub fn render_app_yml_jinja(
+    file: &Path,
+    metadata: &MetadataYml,
+    installed_apps: &[String],
+    available_permissions_list: &[String],
+    available_permissions: &HashMap<String, Vec<Permission>>,
+    settings: Option<&HashMap<String, SimpleValue>>,
+    nirvati_root: &Path,
+) -> Result<String> {
+    render_first_stage(
+        file,
+        metadata,
+        installed_apps,
+        available_permissions_list,
+        available_permissions,
+        settings,
+        nirvati_root,
+    )?
+    .render(nirvati_root, &get_port_map(nirvati_root)?)
+}
+
+#[allow(unused_must_use)]
+fn render_first_stage(
+    file: &Path,
+    metadata: &MetadataYml,
+    installed_apps: &[String],
+    available_permissions_list: &[String],
+    available_permissions: &HashMap<String, Vec<Permission>>,
+    settings: Option<&HashMap<String, SimpleValue>>,
+    nirvati_root: &Path,
+) -> Result<FirstStage> {
     let app_id = file
         .parent()
         .ok_or_else(|| anyhow!("Failed to get parent dir"))?
@@ -160,17 +299,31 @@ pub fn process_app_yml_jinja(
         .ok_or_else(|| anyhow!("Failed to get file name"))?
         .to_str()
         .ok_or_else(|| anyhow!("Failed to convert to str"))?;
-    let contents = std::fs::read_to_string(&file)?;
-    let out_file = file.with_extension("");
+    let contents = std::fs::read_to_string(file)?;
     let dir = file
         .parent()
         .ok_or_else(|| anyhow!("Failed to get parent dir"))?;
 
+    let permissions = &metadata.get_usable_app_yml_jinja_permissions(installed_apps);
     let mut tera_ctx = tera::Context::new();
     if permissions.contains(&"apps".to_string()) {
         tera_ctx.insert("installed_apps", &installed_apps);
         tera_ctx.insert("available_permissions", &available_permissions_list);
     }
+    tera_ctx.insert("instance_id", &split_instance_id(app_id).1);
+    // The app's own metadata, so templates don't have to repeat values from it
+    let mut own_metadata =
+        serde_json::to_value(metadata.get_basic_output_metadata(app_id.to_owned()))?;
+    own_metadata["settingDefaults"] = serde_json::to_value(
+        read_settings_yml(nirvati_root, app_id)?
+            .map(|settings_yml| settings_yml.defaults())
+            .unwrap_or_default(),
+    )?;
+    tera_ctx.insert("app", &own_metadata);
+    tera_ctx.insert(
+        "satisfied_optional_deps",
+        &metadata.get_satisfied_optional_deps(installed_apps),
+    );
 
     let mut app_metadata_obj = Rc::new(serde_json::Map::new());
 
@@ -189,12 +342,12 @@ pub fn process_app_yml_jinja(
     for (app, perms) in available_permissions.iter() {
         if permissions.contains(app) {
             for perm in perms {
-                assign_permission(app, perm, false);
+                assign_permission(app, perm, false)?;
             }
         } else {
             for perm in perms {
                 if permissions.contains(&format!("{}/{}", app, perm.id)) {
-                    assign_permission(app, perm, true);
+                    assign_permission(app, perm, true)?;
                 }
             }
         }
@@ -202,10 +355,21 @@ pub fn process_app_yml_jinja(
 
     tera_ctx.insert("app_metadata", &Rc::try_unwrap(app_metadata_obj).unwrap());
 
-    if let Some(settings) = get_app_settings(nirvati_root, app_id)? {
+    if let Some(settings) = settings {
+        let mut settings = settings.clone();
+        secrets::resolve_settings(&mut settings)
+            .with_context(|| format!("Failed to resolve a setting of {}", app_id))?;
         tera_ctx.insert("settings", &settings);
     }
 
+    // Hostnames and IPs of all app containers, as of the last generate
+    tera_ctx.insert("dns", &get_dns_map(nirvati_root)?);
+    // Whether the router forwards the app's wan_required ports, as reported by the host
+    tera_ctx.insert(
+        "upnp_status",
+        &upnp::status_of_app(&get_upnp_status(nirvati_root)?, app_id),
+    );
+
     let mut tera = Tera::default();
     tera.functions
         .remove("get_env")
@@ -215,34 +379,20 @@ pub fn process_app_yml_jinja(
     let mut code = String::new();
     let mut functions = Vec::new();
     if tera_dir.is_dir() {
-        (code, functions) = js::parse_tera_helpers(&dir.join("_tera"))?;
+        (code, functions) = profile::measure("js transpile", Some(app_id), || {
+            js::parse_tera_helpers(&dir.join("_tera"), &js::cache_dir(nirvati_root))
+        })?;
     }
 
     let tera_ctx = Arc::new(tera_ctx);
-    let ctx_arc_2 = Arc::clone(&tera_ctx);
-
-    let (tx, rx) = std::sync::mpsc::channel();
-    let thread = std::thread::spawn(move || -> Result<()> {
-        // This may execute JS code, so we need to sandbox it
-        extrasafe::SafetyContext::new()
-            .enable(
-                extrasafe::builtins::SystemIO::nothing()
-                    .allow_stdout()
-                    .allow_stderr(),
-            )
-            .unwrap()
-            .apply_to_current_thread()?;
-
-        let mut tera = js::declare_js_functions(tera, &code, &functions)?;
-        let result = tera.render_str(&contents, &ctx_arc_2);
-        tx.send(result)?;
-        Ok(())
-    });
-    let rendered = rx.recv_timeout(Duration::from_secs(2));
-    thread.join().unwrap()?;
-    let rendered = rendered
-        .ok()
-        .ok_or_else(|| anyhow!("Rendering timed out!"))??;
+    let rendered = render_sandboxed(
+        tera,
+        code,
+        functions,
+        contents,
+        Arc::clone(&tera_ctx),
+        app_id,
+    )?;
     #[cfg(debug_assertions)]
     {
         let out_file = file.with_extension("stage1");
@@ -261,17 +411,24 @@ pub fn process_app_yml_jinja(
                 .find(|p| p.id == perm)
             {
                 for dir in &perm.files {
-                    available_files.push(nirvati_root.join("app-data").join(app).join(dir));
+                    available_files.push(app_data_dir(nirvati_root, app).join(dir));
                 }
             }
         } else {
             debug_assert!(split.len() == 1);
             let app = split[0];
-            available_files.push(nirvati_root.join("app-data").join(app));
+            available_files.push(app_data_dir(nirvati_root, app));
         }
     }
-    let mut tera = second_stage::get_tera(nirvati_root.to_path_buf(), available_files);
-    let rendered = tera.render_str(&rendered, &tera_ctx)?;
-    std::fs::write(out_file, rendered)?;
-    Ok(())
+    Ok(FirstStage {
+        rendered,
+        app_id: app_id.to_owned(),
+        visible_apps: visible_apps(app_id, permissions)
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+        // The pool worker drops the job before it sends the result, so this is the only reference left
+        tera_ctx: Arc::try_unwrap(tera_ctx).unwrap_or_else(|ctx| ctx.as_ref().clone()),
+        available_files,
+    })
 }
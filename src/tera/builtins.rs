@@ -18,8 +18,9 @@ pub fn register_builtins(tera: &mut Tera, nirvati_root: &Path, app_id: &str) ->
                 .ok_or_else(|| tera::Error::msg("identifier is not a string"))?;
             let mut hasher = HMAC::new(&nirvati_seed);
             hasher.update(format!("{}:{}", app_id, identifier).as_bytes());
-            let result = hasher.finalize();
-            Ok(tera::Value::String(hex::encode(result)))
+            let result = hex::encode(hasher.finalize());
+            crate::manage::secrets::record_rendered(identifier, &result);
+            Ok(tera::Value::String(result))
         },
     );
     // This can only be used during stage 2
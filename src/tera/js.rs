@@ -2,16 +2,44 @@ use anyhow::{anyhow, bail, Result};
 use deno_ast::{EmitOptions, ParseParams, SourceTextInfo};
 use quick_js::{Context as QuickJSContext, JsValue};
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
     collections::HashMap,
     marker::PhantomData,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 use tera::{Context, Tera};
 
-pub fn transpile_js_ts_in_thread(path: &Path) -> Result<(String, Vec<String>)> {
+/// Where transpiled helpers are cached, in files named after the hash of their source
+pub fn cache_dir(nirvati_root: &Path) -> PathBuf {
+    nirvati_root.join("apps").join(".tera-cache")
+}
+
+/// A transpiled helper file in the cache
+#[derive(Serialize, Deserialize)]
+struct CachedHelpers {
+    code: String,
+    exported_funcs: Vec<String>,
+}
+
+fn cache_key(contents: &str, ext: &str) -> String {
+    // The version is part of the key, so a new transpiler doesn't use the output of the old one
+    let key = format!("{}\0{}\0{}", env!("CARGO_PKG_VERSION"), ext, contents);
+    hex::encode(hmac_sha256::Hash::hash(key.as_bytes()))
+}
+
+fn write_cache(cache_file: &Path, cached: &CachedHelpers) -> Result<()> {
+    if let Some(cache_dir) = cache_file.parent() {
+        std::fs::create_dir_all(cache_dir)?;
+    }
+    std::fs::write(cache_file, serde_json::to_vec(cached)?)?;
+    Ok(())
+}
+
+/// Transpiles a helper file, or takes the result from the cache if the file didn't change
+fn transpile_js_ts_cached(path: &Path, cache_dir: &Path) -> Result<(String, Vec<String>)> {
     let contents = std::fs::read_to_string(path)?;
     let ext = path
         .extension()
@@ -19,6 +47,30 @@ pub fn transpile_js_ts_in_thread(path: &Path) -> Result<(String, Vec<String>)> {
         .to_str()
         .ok_or_else(|| anyhow!("Failed to get extension of file"))?
         .to_string();
+    let cache_file = cache_dir.join(format!("{}.json", cache_key(&contents, &ext)));
+    if let Some(cached) = std::fs::read(&cache_file)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<CachedHelpers>(&json).ok())
+    {
+        return Ok((cached.code, cached.exported_funcs));
+    }
+    let (code, exported_funcs) = transpile_js_ts_in_thread(path, contents, ext)?;
+    let cached = CachedHelpers {
+        code,
+        exported_funcs,
+    };
+    // Failing to write the cache only makes the next run slower
+    if let Err(err) = write_cache(&cache_file, &cached) {
+        tracing::warn!("Failed to cache transpiled {}: {:#}", path.display(), err);
+    }
+    Ok((cached.code, cached.exported_funcs))
+}
+
+pub fn transpile_js_ts_in_thread(
+    path: &Path,
+    contents: String,
+    ext: String,
+) -> Result<(String, Vec<String>)> {
     let specifier = format!("file://{}", path.display());
     let transpile_result = std::thread::spawn(move || -> Result<(String, Vec<String>)> {
         // This may execute JS code, so we need to sandbox it
@@ -68,7 +120,7 @@ pub fn transpile_js_ts_in_thread(path: &Path) -> Result<(String, Vec<String>)> {
     Ok(result)
 }
 
-pub fn parse_tera_helpers(dir: &Path) -> anyhow::Result<(String, Vec<String>)> {
+pub fn parse_tera_helpers(dir: &Path, cache_dir: &Path) -> anyhow::Result<(String, Vec<String>)> {
     let mut code = String::new();
     let mut exported_funcs = Vec::new();
     // Loop through all files in dir that end in .js or .ts.
@@ -83,20 +135,13 @@ pub fn parse_tera_helpers(dir: &Path) -> anyhow::Result<(String, Vec<String>)> {
                 .ok_or_else(|| anyhow!("Failed to get extension of file"))?;
             if ext == "js" || ext == "ts" {
                 // I haven't audited the code of the transpiler, so run it in a separate thread without any FS access to prevent it from doing anything malicious
-                let (code_additions, exported_func_additions) = transpile_js_ts_in_thread(&path)?;
+                let (code_additions, exported_func_additions) =
+                    transpile_js_ts_cached(&path, cache_dir)?;
                 code.push_str(&code_additions);
                 exported_funcs.extend(exported_func_additions);
             }
         }
     }
-    // Put the polyfills at the top of the file
-    // They're in OUT_DIR because build.rs transpiles and minifies them for production
-    code = format!(
-        "{}\n{}\n{}",
-        include_str!(concat!(env!("OUT_DIR"), "/polyfills/textencoder.js")),
-        include_str!(concat!(env!("OUT_DIR"), "/polyfills/webcrypto.js")),
-        code
-    );
     Ok((code, exported_funcs))
 }
 
@@ -118,8 +163,7 @@ fn js_val_to_serde_val(val: JsValue) -> Result<Value> {
         JsValue::Object(obj) => Value::Object(serde_json::Map::from_iter(
             obj.into_iter()
                 .map(|(key, val)| Ok((key, js_val_to_serde_val(val)?)))
-                .collect::<Result<Vec<(String, Value)>>>()?
-                .into_iter(),
+                .collect::<Result<Vec<(String, Value)>>>()?,
         )),
         JsValue::Date(date) => Value::String(date.to_rfc3339()),
         JsValue::BigInt(bigint) => Value::String(bigint.to_string()),
@@ -158,60 +202,259 @@ impl TeraWithJs {
 //impl !Send for TeraWithJs {}
 //impl !Sync for TeraWithJs {}
 
-pub fn declare_js_functions(
-    mut tera: Tera,
-    code: &str,
-    exported_funcs: &[String],
-) -> Result<TeraWithJs> {
-    let ctx = QuickJSContext::new()?;
-    ctx.add_callback("_nirvati_getRandomValues", |len: i32| -> JsValue {
-        let mut rng = rand::thread_rng();
-        let mut bytes = vec![0u8; len as usize];
-        rng.fill_bytes(&mut bytes);
-        JsValue::String(hex::encode(bytes))
-    })?;
-    ctx.add_callback("_nirvati_dbg", |msg: String| -> JsValue {
-        tracing::debug!("[JS] {}", msg);
-        JsValue::Undefined
-    })?;
-    ctx.eval(code)?;
-    let ctx_arc = Arc::new(Mutex::new(CtxWrapper { ctx }));
-
-    for func in exported_funcs {
-        let ctx = ctx_arc.clone();
-        let fn_name = func.clone();
-        tera.register_function(func, move |args: &HashMap<String, Value>| {
-            let arg = serde_json::to_string(args)?;
-            let ctx = ctx.as_ref().lock();
-            let Ok(ctx) = ctx else {
-                return Err("Failed to lock context".into());
-            };
-            let result = ctx.ctx.eval(&format!("{}({})", fn_name, arg));
-            if let Ok(result) = result {
-                let result = js_val_to_serde_val(result);
+/// Limits of the QuickJS contexts that run the helpers in _tera, the `[sandbox]` table in config.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxOptions {
+    /// The max heap of a context, in MiB
+    pub max_heap_mib: usize,
+    /// Make Date and performance.now() return the Unix epoch, so helpers can't measure time
+    pub freeze_time: bool,
+    /// Log every call of a helper or callback with its arguments, for reviewing apps
+    pub audit: bool,
+}
+
+impl Default for SandboxOptions {
+    fn default() -> Self {
+        Self {
+            max_heap_mib: 64,
+            freeze_time: false,
+            audit: false,
+        }
+    }
+}
+
+/// The most bytes crypto.getRandomValues() fills at once, like in browsers
+const MAX_RANDOM_BYTES: i32 = 65536;
+
+/// Replaces the clock with the Unix epoch, while dates with explicit values keep working
+const FREEZE_TIME: &str = r#"
+(() => {
+    const RealDate = Date;
+    function FrozenDate(...args) {
+        if (new.target === undefined) {
+            return new RealDate(0).toString();
+        }
+        return args.length === 0 ? new RealDate(0) : new RealDate(...args);
+    }
+    FrozenDate.prototype = RealDate.prototype;
+    FrozenDate.now = () => 0;
+    FrozenDate.parse = RealDate.parse;
+    FrozenDate.UTC = RealDate.UTC;
+    Object.defineProperty(RealDate.prototype, "constructor", { value: FrozenDate });
+    globalThis.Date = FrozenDate;
+    if (typeof performance !== "undefined") {
+        globalThis.performance = Object.freeze({ now: () => 0, timeOrigin: 0 });
+    }
+})();
+"#;
+
+/// Freezes everything reachable from the global object and the polyfills, except the globals quick_js needs,
+/// so helpers can't change builtins or polyfills used by the helpers of the next app
+const LOCKDOWN: &str = r#"
+let __nirvati_helpers = {};
+// quick_js resolves promises through these globals, so they stay writable
+var __promiseResult = 0;
+var __promiseValue = 0;
+var __resolvePromise = 0;
+(() => {
+    // The polyfills keep their own references, helpers shouldn't call the callbacks directly
+    for (const callback of ["_nirvati_getRandomValues", "_nirvati_dbg"]) {
+        delete globalThis[callback];
+    }
+    const promiseGlobals = ["__promiseResult", "__promiseValue", "__resolvePromise"];
+    const seen = new Set([globalThis]);
+    const harden = (value) => {
+        if ((typeof value !== "object" && typeof value !== "function") || value === null || seen.has(value)) {
+            return;
+        }
+        seen.add(value);
+        Object.freeze(value);
+        harden(Object.getPrototypeOf(value));
+        for (const key of Reflect.ownKeys(value)) {
+            const descriptor = Object.getOwnPropertyDescriptor(value, key);
+            harden(descriptor.value);
+            harden(descriptor.get);
+            harden(descriptor.set);
+        }
+    };
+    for (const key of Reflect.ownKeys(globalThis)) {
+        if (promiseGlobals.includes(key)) {
+            continue;
+        }
+        const descriptor = Object.getOwnPropertyDescriptor(globalThis, key);
+        Object.defineProperty(
+            globalThis,
+            key,
+            "value" in descriptor ? { writable: false, configurable: false } : { configurable: false },
+        );
+        harden(descriptor.value);
+        harden(descriptor.get);
+        harden(descriptor.set);
+    }
+    harden(Object.getPrototypeOf(globalThis));
+    Object.preventExtensions(globalThis);
+    for (const polyfill of [
+        typeof crypto !== "undefined" && crypto,
+        typeof TextEncoder !== "undefined" && TextEncoder,
+        typeof TextDecoder !== "undefined" && TextDecoder,
+    ]) {
+        harden(polyfill);
+    }
+})();
+"#;
+
+/// Resets the globals quick_js resolves promises through, which helpers can write to,
+/// and returns whether they could be reset
+const RESET_PROMISE_GLOBALS: &str = r#"
+(() => {
+    const promiseGlobals = ["__promiseResult", "__promiseValue", "__resolvePromise"];
+    for (const key of promiseGlobals) {
+        globalThis[key] = 0;
+    }
+    return promiseGlobals.every((key) => globalThis[key] === 0);
+})()
+"#;
+
+/// A QuickJS context with the polyfills, which can run the helpers of one app after another
+pub struct JsRuntime {
+    ctx: Arc<Mutex<CtxWrapper>>,
+    options: SandboxOptions,
+}
+
+impl JsRuntime {
+    pub fn new(options: &SandboxOptions) -> Result<Self> {
+        Ok(Self {
+            ctx: Arc::new(Mutex::new(CtxWrapper {
+                ctx: Self::new_context(options)?,
+            })),
+            options: options.clone(),
+        })
+    }
+
+    fn new_context(options: &SandboxOptions) -> Result<QuickJSContext> {
+        let ctx = QuickJSContext::builder()
+            .memory_limit(options.max_heap_mib * 1024 * 1024)
+            .build()?;
+        let audit = options.audit;
+        ctx.add_callback("_nirvati_getRandomValues", move |len: i32| {
+            if audit {
+                tracing::info!("[sandbox audit] _nirvati_getRandomValues({})", len);
+            }
+            if !(0..=MAX_RANDOM_BYTES).contains(&len) {
+                return Err(format!(
+                    "Can't generate {} random bytes, the limit is {}",
+                    len, MAX_RANDOM_BYTES
+                ));
+            }
+            let mut rng = rand::thread_rng();
+            let mut bytes = vec![0u8; len as usize];
+            rng.fill_bytes(&mut bytes);
+            Ok(JsValue::String(hex::encode(bytes)))
+        })?;
+        ctx.add_callback("_nirvati_dbg", move |msg: String| -> JsValue {
+            if audit {
+                tracing::info!("[sandbox audit] _nirvati_dbg({:?})", msg);
+            }
+            tracing::debug!("[JS] {}", msg);
+            JsValue::Undefined
+        })?;
+        // They're in OUT_DIR because build.rs transpiles and minifies them for production
+        ctx.eval(include_str!(concat!(
+            env!("OUT_DIR"),
+            "/polyfills/textencoder.js"
+        )))?;
+        ctx.eval(include_str!(concat!(
+            env!("OUT_DIR"),
+            "/polyfills/webcrypto.js"
+        )))?;
+        if options.freeze_time {
+            ctx.eval(FREEZE_TIME)?;
+        }
+        ctx.eval(LOCKDOWN)?;
+        Ok(ctx)
+    }
+
+    /// Replaces the helpers of the previous app
+    /// The code runs in its own function scope, so only the exported functions stay reachable
+    /// If the previous app made the promise globals read-only, the context is replaced with a new one
+    pub fn load_helpers(&self, code: &str, exported_funcs: &[String]) -> Result<()> {
+        let exports = exported_funcs
+            .iter()
+            .map(|func| format!("{0}: typeof {0} === \"function\" ? {0} : undefined", func))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let ctx = self.ctx.as_ref().lock();
+        let Ok(mut ctx) = ctx else {
+            return Err(anyhow!("Failed to lock context"));
+        };
+        if !matches!(ctx.ctx.eval(RESET_PROMISE_GLOBALS), Ok(JsValue::Bool(true))) {
+            tracing::warn!("The previous helpers changed the sandbox, creating a new context");
+            ctx.ctx = Self::new_context(&self.options)?;
+        }
+        ctx.ctx.eval(&format!(
+            "__nirvati_helpers = (function () {{\n{}\nreturn {{ {} }};\n}})();",
+            code, exports
+        ))?;
+        Ok(())
+    }
+
+    /// Makes the exported functions of the loaded helpers callable from templates
+    pub fn register_functions(&self, mut tera: Tera, exported_funcs: &[String]) -> TeraWithJs {
+        for func in exported_funcs {
+            let ctx = self.ctx.clone();
+            let fn_name = func.clone();
+            let audit = self.options.audit;
+            tera.register_function(func, move |args: &HashMap<String, Value>| {
+                let arg = serde_json::to_string(args)?;
+                if audit {
+                    tracing::info!("[sandbox audit] {}({})", fn_name, arg);
+                }
+                let ctx = ctx.as_ref().lock();
+                let Ok(ctx) = ctx else {
+                    return Err("Failed to lock context".into());
+                };
+                let result = ctx
+                    .ctx
+                    .eval(&format!("__nirvati_helpers.{}({})", fn_name, arg));
                 if let Ok(result) = result {
-                    Ok(result)
+                    let result = js_val_to_serde_val(result);
+                    if let Ok(result) = result {
+                        Ok(result)
+                    } else {
+                        Err("Failed to convert JS value to serde value".into())
+                    }
                 } else {
-                    Err("Failed to convert JS value to serde value".into())
+                    eprintln!("{:#?}", result.err());
+                    Err(format!("Failed to call JS function {}", fn_name).into())
                 }
-            } else {
-                eprintln!("{:#?}", result.err());
-                Err(format!("Failed to call JS function {}", fn_name).into())
-            }
-        });
+            });
+        }
+        TeraWithJs {
+            tera,
+            quickjs_ctx: self.ctx.clone(),
+            _not_sync: PhantomData,
+        }
     }
-    Ok(TeraWithJs {
-        tera,
-        quickjs_ctx: ctx_arc,
-        _not_sync: PhantomData,
-    })
+}
+
+/// Declares the helpers in a new context, use a JsRuntime to reuse the context for multiple apps
+pub fn declare_js_functions(
+    tera: Tera,
+    code: &str,
+    exported_funcs: &[String],
+) -> Result<TeraWithJs> {
+    let runtime = JsRuntime::new(&SandboxOptions::default())?;
+    runtime.load_helpers(code, exported_funcs)?;
+    Ok(runtime.register_functions(tera, exported_funcs))
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
-    use super::declare_js_functions;
+    use super::{declare_js_functions, parse_tera_helpers, JsRuntime, SandboxOptions};
+    use crate::testing::TempDir;
     use quick_js::JsValue;
     use serde_json::Value;
     use tera::Tera;
@@ -247,6 +490,116 @@ mod tests {
         assert_eq!(result, "12");
     }
 
+    #[test]
+    fn test_helpers_are_isolated_between_apps() {
+        let runtime = JsRuntime::new(&SandboxOptions::default()).unwrap();
+        runtime
+            .load_helpers(
+                "function first(args) { return 1; }\nMath.max = () => 0;\nglobalThis.leaked = 1;",
+                &["first".to_string()],
+            )
+            .unwrap();
+        let code = r#"
+            function second(args) {
+                return [typeof first, Math.max(1, 2), typeof leaked].join(",");
+            }"#;
+        runtime.load_helpers(code, &["second".to_string()]).unwrap();
+        let mut tera = runtime.register_functions(Tera::default(), &["second".to_string()]);
+        let result = tera
+            .render_str("{{ second() }}", &tera::Context::new())
+            .unwrap();
+        assert_eq!(result, "undefined,2,undefined");
+    }
+
+    #[test]
+    fn test_promise_globals_are_reset_between_apps() {
+        let runtime = JsRuntime::new(&SandboxOptions::default()).unwrap();
+        let code = r#"
+            function read(args) {
+                return [__promiseResult, __promiseValue, __resolvePromise].join(",");
+            }"#;
+        for leaking in [
+            "__promiseResult = \"leaked\"; __promiseValue = \"leaked\"; __resolvePromise = () => \"leaked\";",
+            "Object.defineProperty(globalThis, \"__promiseValue\", { value: \"leaked\", writable: false });",
+        ] {
+            runtime
+                .load_helpers(
+                    &format!("function leak(args) {{ {} }}\nleak();", leaking),
+                    &["leak".to_string()],
+                )
+                .unwrap();
+            runtime.load_helpers(code, &["read".to_string()]).unwrap();
+            let mut tera = runtime.register_functions(Tera::default(), &["read".to_string()]);
+            let result = tera
+                .render_str("{{ read() }}", &tera::Context::new())
+                .unwrap();
+            assert_eq!(result, "0,0,0");
+        }
+    }
+
+    #[test]
+    fn test_sandbox_limits() {
+        let options = SandboxOptions {
+            max_heap_mib: 16,
+            ..SandboxOptions::default()
+        };
+        let runtime = JsRuntime::new(&options).unwrap();
+        let code = r#"
+            function recurse(args) { return recurse(args) + 1; }
+            function allocate(args) { return new Array(64 * 1024 * 1024).fill(1).length; }
+            function callbacks(args) { return [typeof _nirvati_getRandomValues, typeof _nirvati_dbg].join(","); }"#;
+        let functions = ["recurse", "allocate", "callbacks"].map(str::to_string);
+        runtime.load_helpers(code, &functions).unwrap();
+        let mut tera = runtime.register_functions(Tera::default(), &functions);
+        let ctx = tera::Context::new();
+        assert!(tera.render_str("{{ recurse() }}", &ctx).is_err());
+        assert!(tera.render_str("{{ allocate() }}", &ctx).is_err());
+        assert_eq!(
+            tera.render_str("{{ callbacks() }}", &ctx).unwrap(),
+            "undefined,undefined"
+        );
+    }
+
+    #[test]
+    fn test_frozen_time() {
+        let options = SandboxOptions {
+            freeze_time: true,
+            ..SandboxOptions::default()
+        };
+        let runtime = JsRuntime::new(&options).unwrap();
+        let code = r#"
+            function times(args) {
+                return [Date.now(), new Date().getTime(), new Date(1000).getTime(), new Date() instanceof Date].join(",");
+            }"#;
+        runtime.load_helpers(code, &["times".to_string()]).unwrap();
+        let mut tera = runtime.register_functions(Tera::default(), &["times".to_string()]);
+        let result = tera
+            .render_str("{{ times() }}", &tera::Context::new())
+            .unwrap();
+        assert_eq!(result, "0,0,1000,true");
+    }
+
+    #[test]
+    fn test_cached_helpers_skip_transpiling() {
+        let root = TempDir::new("tera-cache");
+        let tera_dir = root.join("_tera");
+        let cache_dir = root.join("cache");
+        std::fs::create_dir_all(&tera_dir).unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let contents = "function helper(args: { name: string }) { return args.name; }";
+        std::fs::write(tera_dir.join("helpers.ts"), contents).unwrap();
+        // A cache entry that can't be the transpiler's output, to see that it is used
+        std::fs::write(
+            cache_dir.join(format!("{}.json", super::cache_key(contents, "ts"))),
+            r#"{"code":"function cached(args) {}","exported_funcs":["cached"]}"#,
+        )
+        .unwrap();
+        let result = parse_tera_helpers(&tera_dir, &cache_dir);
+        let (code, functions) = result.unwrap();
+        assert_eq!(code, "function cached(args) {}");
+        assert_eq!(functions, vec!["cached".to_string()]);
+    }
+
     #[test]
     fn test_js_val_to_serde_val() {
         use super::js_val_to_serde_val;
@@ -1,13 +1,18 @@
-const crypto = {
-    getRandomValues(uint8Array: Uint8Array) {
-        const randomHex = _nirvati_getRandomValues(uint8Array.byteLength);
-        // Every byte is two hex characters
-        for (let i = 0; i < uint8Array.byteLength; i++) {
-            uint8Array[i] = parseInt(randomHex.substring(i * 2, i * 2 + 2), 16);
+const crypto = (() => {
+    // The sandbox removes the callbacks from the global object once the polyfills are loaded
+    const getRandomValues = _nirvati_getRandomValues;
+    const dbg = _nirvati_dbg;
+    return {
+        getRandomValues(uint8Array: Uint8Array) {
+            const randomHex = getRandomValues(uint8Array.byteLength);
+            // Every byte is two hex characters
+            for (let i = 0; i < uint8Array.byteLength; i++) {
+                uint8Array[i] = parseInt(randomHex.substring(i * 2, i * 2 + 2), 16);
+            }
+        },
+        get subtle() {
+            dbg("SubtleCrypto is not (yet) supported in Nirvati's JS engine.");
+            return false;
         }
-    },
-    get subtle() {
-        _nirvati_dbg("SubtleCrypto is not (yet) supported in Nirvati's JS engine.");
-        return false;
-    }
-};
+    };
+})();
@@ -0,0 +1,200 @@
+//! Sandboxed worker threads that render templates with JS helpers
+//!
+//! Applying the sandbox and evaluating the polyfills is slow, so every worker does it once
+//! and then renders the templates of one app after another with the same JsRuntime.
+
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use tera::Tera;
+
+use super::js::{JsRuntime, SandboxOptions};
+
+/// Renders must finish within this time, including waiting for a free worker
+const RENDER_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_WORKERS: usize = 4;
+/// QuickJS throws a RangeError once JS code uses 256 KiB of stack, but only if the thread has more than that,
+/// and Tera's recursion runs on the same stack, so workers get a fixed stack well above it.
+/// Otherwise deep recursion would overflow the thread, which aborts the whole process instead of failing the render.
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// A template to render with the JS helpers of its app
+pub struct RenderJob {
+    pub app_id: String,
+    pub tera: Tera,
+    pub code: String,
+    pub functions: Vec<String>,
+    pub contents: String,
+    pub tera_ctx: Arc<tera::Context>,
+}
+
+enum Reply {
+    /// The app's helpers are loaded and the template is being rendered
+    Initialized,
+    Rendered(Result<String>),
+}
+
+struct QueuedJob {
+    job: RenderJob,
+    reply: Sender<Reply>,
+}
+
+struct Pool {
+    jobs: Sender<QueuedJob>,
+    queue: Arc<Mutex<Receiver<QueuedJob>>>,
+}
+
+static POOL: OnceLock<Mutex<Pool>> = OnceLock::new();
+static OPTIONS: OnceLock<SandboxOptions> = OnceLock::new();
+
+/// Sets the options of the workers, which only has an effect before the first render
+pub fn configure(options: SandboxOptions) {
+    let _ = OPTIONS.set(options);
+}
+
+impl Pool {
+    fn new() -> Self {
+        let (jobs, queue) = channel();
+        let pool = Self {
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+        };
+        let workers = std::thread::available_parallelism()
+            .map(|workers| workers.get())
+            .unwrap_or(1)
+            .min(MAX_WORKERS);
+        for _ in 0..workers {
+            pool.spawn_worker();
+        }
+        pool
+    }
+
+    fn spawn_worker(&self) {
+        let queue = Arc::clone(&self.queue);
+        let options = OPTIONS.get().cloned().unwrap_or_default();
+        let spawned = std::thread::Builder::new()
+            .name("render-worker".to_owned())
+            .stack_size(WORKER_STACK_SIZE)
+            .spawn(move || run_worker(queue, options));
+        if let Err(err) = spawned {
+            tracing::error!("Failed to start a render worker: {}", err);
+        }
+    }
+}
+
+fn apply_sandbox() -> Result<()> {
+    // Templates may execute JS code, so we need to sandbox it
+    extrasafe::SafetyContext::new()
+        .enable(
+            extrasafe::builtins::SystemIO::nothing()
+                .allow_stdout()
+                .allow_stderr(),
+        )?
+        .apply_to_current_thread()?;
+    Ok(())
+}
+
+fn render_job(
+    runtime: &JsRuntime,
+    job: RenderJob,
+    reply: &Sender<Reply>,
+    options: &SandboxOptions,
+) -> Result<String> {
+    if options.audit {
+        tracing::info!("[sandbox audit] Loading the helpers of {}", job.app_id);
+    }
+    runtime.load_helpers(&job.code, &job.functions)?;
+    let mut tera = runtime.register_functions(job.tera, &job.functions);
+    // The caller may have given up already, which only matters for the result
+    let _ = reply.send(Reply::Initialized);
+    tera.render_str(&job.contents, &job.tera_ctx)
+}
+
+fn run_worker(queue: Arc<Mutex<Receiver<QueuedJob>>>, options: SandboxOptions) {
+    let runtime = apply_sandbox().and_then(|()| JsRuntime::new(&options));
+    loop {
+        let queued = {
+            let Ok(queue) = queue.lock() else {
+                return;
+            };
+            let Ok(queued) = queue.recv() else {
+                return;
+            };
+            queued
+        };
+        let result = match &runtime {
+            Ok(runtime) => render_job(runtime, queued.job, &queued.reply, &options),
+            Err(err) => Err(anyhow!("Failed to start the render worker: {:#}", err)),
+        };
+        let _ = queued.reply.send(Reply::Rendered(result));
+    }
+}
+
+/// Renders a template on a worker, on_initialized is called once the app's helpers are loaded
+pub fn render<F>(job: RenderJob, on_initialized: F) -> Result<String>
+where
+    F: FnOnce(),
+{
+    let start = Instant::now();
+    let pool = POOL.get_or_init(|| Mutex::new(Pool::new()));
+    let (reply, replies) = channel();
+    pool.lock()
+        .map_err(|_| anyhow!("Failed to lock the render pool"))?
+        .jobs
+        .send(QueuedJob { job, reply })?;
+    let mut on_initialized = Some(on_initialized);
+    loop {
+        match replies.recv_timeout(RENDER_TIMEOUT.saturating_sub(start.elapsed())) {
+            Ok(Reply::Initialized) => {
+                if let Some(on_initialized) = on_initialized.take() {
+                    on_initialized();
+                }
+            }
+            Ok(Reply::Rendered(result)) => return result,
+            // The worker is stuck or crashed and can't be reused, so replace it
+            Err(RecvTimeoutError::Timeout) => {
+                if let Ok(pool) = pool.lock() {
+                    pool.spawn_worker();
+                }
+                bail!("Rendering timed out!");
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                if let Ok(pool) = pool.lock() {
+                    pool.spawn_worker();
+                }
+                bail!("The render worker stopped unexpectedly");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(code: &str, function: &str) -> RenderJob {
+        RenderJob {
+            app_id: "recursion".to_owned(),
+            tera: Tera::default(),
+            code: code.to_owned(),
+            functions: vec![function.to_owned()],
+            contents: format!("{{{{ {}() }}}}", function),
+            tera_ctx: Arc::new(tera::Context::new()),
+        }
+    }
+
+    #[test]
+    fn unbounded_recursion_fails_the_render() {
+        let recursion = "function recurse(args) { return recurse(args) + 1; }";
+        assert!(render(job(recursion, "recurse"), || {}).is_err());
+        // The worker is still alive and renders the next template
+        let answer = "function answer(args) { return 42; }";
+        assert_eq!(render(job(answer, "answer"), || {}).unwrap(), "42");
+    }
+}
@@ -1,10 +1,103 @@
 // A minimal processor that doesn't include many tools (Most notably, no JS), but does support reading UTF-8 text files
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
 
 use tera::Tera;
 
 use crate::manage::files::set_next_app_regenerate;
 
+/// Why read_file didn't return the contents of a file
+#[derive(Debug)]
+pub enum ReadFileError {
+    /// The path is outside the files the app may read, or tries to escape them
+    Denied(PathBuf),
+    /// The path is allowed, but doesn't exist
+    Missing(PathBuf),
+    /// The path is allowed, but can't be read as UTF-8 text
+    Unreadable(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for ReadFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadFileError::Denied(path) => {
+                write!(f, "Path {} is not in can_read_files", path.display())
+            }
+            ReadFileError::Missing(path) => write!(f, "File {} does not exist", path.display()),
+            ReadFileError::Unreadable(path, err) => {
+                write!(f, "Failed to read file {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadFileError::Denied(_) | ReadFileError::Missing(_) => None,
+            ReadFileError::Unreadable(_, err) => Some(err),
+        }
+    }
+}
+
+/// Resolves all symlinks in the part of the path that exists, the rest can't contain any
+fn canonicalize_existing(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                return Ok(missing
+                    .into_iter()
+                    .rev()
+                    .fold(canonical, |path, component| path.join(component)))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let (Some(file_name), Some(parent)) = (existing.file_name(), existing.parent())
+                else {
+                    return Err(err);
+                };
+                missing.push(file_name);
+                existing = parent;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads a file relative to the Nirvati root, if it is one of can_read_files or inside of them after resolving symlinks
+pub fn read_allowed_file(
+    nirvati_root: &Path,
+    can_read_files: &[PathBuf],
+    path: &str,
+) -> Result<String, ReadFileError> {
+    let relative = Path::new(path);
+    // Absolute paths would replace the root when joined
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(ReadFileError::Denied(relative.to_path_buf()));
+    }
+    let path = nirvati_root.join(relative);
+    let canonical =
+        canonicalize_existing(&path).map_err(|_| ReadFileError::Denied(path.clone()))?;
+    let allowed = can_read_files.iter().any(|allowed| {
+        canonicalize_existing(allowed).is_ok_and(|allowed| canonical.starts_with(allowed))
+    });
+    if !allowed {
+        return Err(ReadFileError::Denied(path));
+    }
+    std::fs::read_to_string(&canonical).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => ReadFileError::Missing(path),
+        _ => ReadFileError::Unreadable(path, err),
+    })
+}
+
 pub fn get_tera(nirvati_root: PathBuf, can_read_files: Vec<PathBuf>) -> Tera {
     let mut tera = Tera::default();
     tera.functions
@@ -20,37 +113,20 @@ pub fn get_tera(nirvati_root: PathBuf, can_read_files: Vec<PathBuf>) -> Tera {
                 .ok_or_else(|| tera::Error::msg("Missing path argument"))?
                 .as_str()
                 .ok_or_else(|| tera::Error::msg("Path argument is not a string"))?;
-            let path = nirvati_root.join(path);
-            // Check if can_read_files includes path or any of its parents
-            let mut check_path = path.clone();
-            let mut found = false;
-            while &check_path != nirvati_root.as_ref() {
-                if can_read_files.iter().any(|p| p == &path) {
-                    found = true;
-                    break;
-                }
-                check_path = path.parent().unwrap().to_path_buf();
-            }
-            if !found {
-                return Err(tera::Error::msg(format!(
-                    "Path {} is not in can_read_files",
-                    path.display()
-                )));
-            }
-            let contents = std::fs::read_to_string(&path).or_else(|_| {
-                // if args.fallback is set, return that instead of an error
-                if let Some(fallback) = args.get("fallback") {
-                    Ok(fallback
+            let contents = match read_allowed_file(&nirvati_root, &can_read_files, path) {
+                Ok(contents) => contents,
+                // if args.fallback is set, return that for files that can't be read, but not for denied ones
+                Err(err @ (ReadFileError::Missing(_) | ReadFileError::Unreadable(..))) => {
+                    let Some(fallback) = args.get("fallback") else {
+                        return Err(tera::Error::msg(err.to_string()));
+                    };
+                    fallback
                         .as_str()
                         .ok_or(tera::Error::msg("Fallback is not a string"))?
-                        .to_owned())
-                } else {
-                    Err(tera::Error::msg(format!(
-                        "Failed to read file {}",
-                        path.display()
-                    )))
+                        .to_owned()
                 }
-            })?;
+                Err(err) => return Err(tera::Error::msg(err.to_string())),
+            };
             Ok(tera::Value::String(contents))
         },
     );
@@ -80,3 +156,42 @@ pub fn get_tera(nirvati_root: PathBuf, can_read_files: Vec<PathBuf>) -> Tera {
     );
     tera
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn read_file_stays_in_allowed_paths() {
+        let root = TempDir::new("read-file");
+        let allowed = root.join("app-data").join("app");
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::create_dir_all(root.join("app-data").join("app2")).unwrap();
+        std::fs::write(allowed.join("file"), "allowed").unwrap();
+        std::fs::write(root.join("app-data").join("app2").join("file"), "other").unwrap();
+        std::fs::write(root.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), allowed.join("link")).unwrap();
+        std::os::unix::fs::symlink(root.join("app-data"), allowed.join("dir-link")).unwrap();
+        let can_read_files = vec![allowed.clone()];
+        let read = |path: &str| read_allowed_file(&root, &can_read_files, path);
+
+        let result = read("app-data/app/file");
+        let denied = [
+            "secret",
+            "app-data/app/../../secret",
+            "app-data/app/link",
+            "app-data/app/dir-link/app2/file",
+            "app-data/app2/file",
+            "app-data/app2/missing",
+        ]
+        .map(|path| matches!(read(path), Err(ReadFileError::Denied(_))));
+        let absolute = read(root.join("secret").to_str().unwrap());
+        let missing = read("app-data/app/missing");
+
+        assert_eq!(result.unwrap(), "allowed");
+        assert_eq!(denied, [true; 6]);
+        assert!(matches!(absolute, Err(ReadFileError::Denied(_))));
+        assert!(matches!(missing, Err(ReadFileError::Missing(_))));
+    }
+}
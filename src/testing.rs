@@ -0,0 +1,256 @@
+//! Golden file tests for app stores
+//!
+//! A fixture is a directory containing a `root` directory (A Nirvati root with apps, db/user.json and db/nirvati-seed/seed)
+//! and a `golden` directory, which mirrors the files in `root` that are expected after running Generate.
+//! Only files present in `golden` are compared, so fixtures can choose which outputs they want to pin.
+//! Set `NIRVATI_UPDATE_GOLDEN=1` to overwrite the golden files with the current output instead of comparing.
+//!
+//! It also has the helpers the unit tests share, temporary Nirvati roots and store sources.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+
+use crate::{
+    manage::processing::FailedApp,
+    repos::{SourceType, StoreFormat, StoreSource},
+};
+
+/// A copy of a fixture's Nirvati root in a temporary directory
+pub struct Fixture {
+    fixture_dir: PathBuf,
+    root: PathBuf,
+}
+
+/// A file that differs from its golden file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The path relative to the Nirvati root
+    pub path: PathBuf,
+    pub expected: String,
+    /// None if the file was not generated
+    pub actual: Option<String>,
+}
+
+fn random_suffix() -> String {
+    let mut suffix = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut suffix);
+    hex::encode(suffix)
+}
+
+/// An empty temporary directory for a test, removed when dropped, so failing asserts don't leak it
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates nirvati-<name>-<random suffix> in the temporary directory, so parallel tests never share one
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("nirvati-{}-{}", name, random_suffix()));
+        std::fs::create_dir_all(&path).expect("Failed to create a temporary directory");
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A git store in the Nirvati format with only an id and URL, override other fields with `..store(id, url)`
+pub fn store(id: &str, url: &str) -> StoreSource {
+    StoreSource {
+        id: id.to_owned(),
+        name: None,
+        source_type: SourceType::Git,
+        format: StoreFormat::Nirvati,
+        url: url.to_owned(),
+        branch: None,
+        sha256: None,
+        priority: 0,
+        symlink: false,
+        mirrors: Vec::new(),
+    }
+}
+
+fn copy_dir_all(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+fn list_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let relative = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &relative, files)?;
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+impl Fixture {
+    /// Copies the fixture's root into a new temporary directory, so the fixture itself is never modified
+    pub fn load(fixture_dir: &Path) -> Result<Self> {
+        let source = fixture_dir.join("root");
+        if !source.is_dir() {
+            return Err(anyhow!("{} has no root directory", fixture_dir.display()));
+        }
+        let root = std::env::temp_dir().join(format!("nirvati-fixture-{}", random_suffix()));
+        copy_dir_all(&source, &root)?;
+        Ok(Self {
+            fixture_dir: fixture_dir.to_path_buf(),
+            root,
+        })
+    }
+
+    /// The temporary Nirvati root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Runs Generate in offline mode, so fixtures also check that generating never needs the network,
+    /// and returns the apps that failed
+    pub fn generate(&self) -> Result<Vec<FailedApp>> {
+        crate::offline::set_offline(true);
+        crate::manage::generate(&self.root, &crate::config::Config::default())
+    }
+
+    /// Compares the generated files to the golden files and returns all differences
+    pub fn compare_with_golden(&self) -> Result<Vec<Mismatch>> {
+        let golden_dir = self.fixture_dir.join("golden");
+        let mut golden_files = Vec::new();
+        list_files(&golden_dir, Path::new(""), &mut golden_files)?;
+        golden_files.sort();
+        let update = std::env::var("NIRVATI_UPDATE_GOLDEN").is_ok_and(|value| value == "1");
+        let mut mismatches = Vec::new();
+        for path in golden_files {
+            let expected = std::fs::read_to_string(golden_dir.join(&path))?;
+            let actual = std::fs::read_to_string(self.root.join(&path)).ok();
+            if actual.as_ref() == Some(&expected) {
+                continue;
+            }
+            if update {
+                if let Some(actual) = &actual {
+                    std::fs::write(golden_dir.join(&path), actual)?;
+                    continue;
+                }
+            }
+            mismatches.push(Mismatch {
+                path,
+                expected,
+                actual,
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.root) {
+            tracing::warn!("Failed to remove {}: {:#}", self.root.display(), err);
+        }
+    }
+}
+
+/// Runs Generate on a fixture and returns an error describing every file that differs from its golden file
+pub fn check_fixture(fixture_dir: &Path) -> Result<()> {
+    let fixture = Fixture::load(fixture_dir)?;
+    fixture.generate()?;
+    let mismatches = fixture.compare_with_golden()?;
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let mut message = format!("{} file(s) differ from the golden files:", mismatches.len());
+    for mismatch in mismatches {
+        message.push_str(&format!(
+            "\n--- {}\n+++ expected:\n{}\n+++ actual:\n{}",
+            mismatch.path.display(),
+            mismatch.expected,
+            mismatch.actual.as_deref().unwrap_or("<not generated>")
+        ));
+    }
+    Err(anyhow!(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Fixture;
+    use crate::composegenerator::types::{DiagnosticCode, Severity};
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+    }
+
+    #[test]
+    fn fixtures_match_golden_files() {
+        for entry in std::fs::read_dir(fixtures_dir()).unwrap() {
+            let entry = entry.unwrap();
+            if let Err(err) = super::check_fixture(&entry.path()) {
+                panic!("Fixture {}: {:#}", entry.path().display(), err);
+            }
+        }
+    }
+
+    #[test]
+    fn failing_apps_dont_stop_the_others() {
+        let fixture = Fixture::load(&fixtures_dir().join("failing-app")).unwrap();
+        let failed = fixture.generate().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].app, "broken");
+        assert_eq!(failed[0].diagnostic.code, DiagnosticCode::RenderFailed);
+        // The other app is generated anyway
+        assert_eq!(fixture.compare_with_golden().unwrap(), Vec::new());
+
+        let registry = crate::manage::files::get_app_registry(fixture.root()).unwrap();
+        let diagnostics = |app: &str| {
+            registry
+                .iter()
+                .find(|entry| entry.id == app)
+                .unwrap_or_else(|| panic!("{} is not in the registry", app))
+                .diagnostics
+                .clone()
+        };
+        assert!(diagnostics("broken").contains(&failed[0].diagnostic));
+        assert_eq!(failed[0].diagnostic.severity, Severity::Error);
+        assert!(diagnostics("example")
+            .iter()
+            .all(|diagnostic| diagnostic.severity != Severity::Error));
+    }
+}
@@ -1,13 +1,7 @@
-use lazy_static::lazy_static;
-use regex::Regex;
-use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-lazy_static! {
-    // This should have been the following regex originally: \$(\{.*?}|[A-z1-9]+)
-    // However, it lead to a double match of ${VAR} and {VAR} getting matched for some reason
-    static ref ENV_VAR_REGEX: Regex = Regex::new(r"\$\{.*?}|\$[A-z1-9]+").unwrap();
-}
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 
 // A helper for skipping deserialization of values that default to false
 #[inline]
@@ -16,43 +10,318 @@ pub fn is_false(v: &bool) -> bool {
 }
 
 /// A type that can be serialized into a string, but can also be various other types
+/// Untagged variants are tried in order, so String comes last and quoted values like "42" or "true" stay strings
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(untagged)]
 pub enum StringLike {
-    String(String),
-    Int(i64),
     Bool(bool),
+    Int(i64),
     Float(f64),
+    String(String),
+}
+
+impl From<String> for StringLike {
+    fn from(value: String) -> Self {
+        StringLike::String(value)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(untagged)]
 pub enum StringOrNumber {
-    String(String),
     Int(i64),
     Float(f64),
+    String(String),
+}
+
+impl From<String> for StringOrNumber {
+    fn from(value: String) -> Self {
+        StringOrNumber::String(value)
+    }
 }
 
+/// Deserializes a map that compose also accepts as a list of KEY=VALUE strings, like environment and sysctls
+/// Values from the list are strings, entries without a value would be passed through from the host and are rejected
+pub fn key_value_map<'de, D, T>(deserializer: D) -> Result<BTreeMap<String, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + From<String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MapOrList<T> {
+        Map(BTreeMap<String, T>),
+        List(Vec<String>),
+    }
+
+    match MapOrList::deserialize(deserializer)? {
+        MapOrList::Map(map) => Ok(map),
+        MapOrList::List(list) => list
+            .into_iter()
+            .map(|entry| match entry.split_once('=') {
+                Some((key, value)) => Ok((key.to_owned(), T::from(value.to_owned()))),
+                None => Err(serde::de::Error::custom(format!(
+                    "{} has no value, set it as {}=<value>",
+                    entry, entry
+                ))),
+            })
+            .collect(),
+    }
+}
+
+/// Returns the env vars a string references, using compose's interpolation syntax
+/// Supports $VAR, ${VAR}, and ${VAR:-default}, ${VAR-default}, ${VAR:?error}, ${VAR?error}, ${VAR:+other}
+/// and ${VAR+other}, whose values can reference env vars too. $$ is a literal $ and references nothing.
+/// Unterminated ${ still return the var, as permissions are inferred from this
 pub fn find_env_vars(string: &str) -> Vec<&str> {
-    let mut result: Vec<&str> = Vec::new();
-    let matches = ENV_VAR_REGEX.captures_iter(string);
-    for captures in matches {
-        for element in captures.iter().flatten() {
-            let matched = element.as_str();
-            // If the env var starts with ${, remove it and the closing }
-            // Otherwise, just remove the $
-            if matched.starts_with("${") {
-                let simplified = &matched[2..matched.len() - 1];
-                // Split it at :-, : or -, depending on which of these exist
-                let split = simplified.splitn(2, '-').collect::<Vec<&str>>();
-                let main_var = split[0].split(':').collect::<Vec<&str>>()[0];
-                result.push(main_var);
-                if split.len() > 1 {
-                    let mut env_vars_in_default = find_env_vars(split[1]);
-                    result.append(&mut env_vars_in_default);
-                }
-            } else {
-                result.push(&matched[1..matched.len()]);
+    let mut result = Vec::new();
+    find_env_vars_in(string, &mut result);
+    result
+}
+
+/// Turns the $$ in a value without env vars into the literal $ compose passes to the container
+pub fn unescape_env_value(value: &str) -> String {
+    value.replace("$$", "$")
+}
+
+/// Whether a string ends with an unpaired $, which turns a $ after it into $$, a literal $
+pub fn ends_with_escaping_dollar(string: &str) -> bool {
+    let dollars = string
+        .bytes()
+        .rev()
+        .take_while(|byte| *byte == b'$')
+        .count();
+    dollars % 2 == 1
+}
+
+fn find_env_vars_in<'a>(string: &'a str, result: &mut Vec<&'a str>) {
+    let mut rest = string;
+    while let Some(index) = rest.find('$') {
+        rest = &rest[index + 1..];
+        if let Some(after_escape) = rest.strip_prefix('$') {
+            rest = after_escape;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let name_len = env_var_name_len(braced);
+            if name_len > 0 {
+                result.push(&braced[..name_len]);
+            }
+            let end = closing_brace(braced);
+            // The modifier itself can't contain a $, so only vars in its value are found
+            find_env_vars_in(&braced[name_len..end.unwrap_or(braced.len())], result);
+            rest = match end {
+                Some(end) => &braced[end + 1..],
+                None => "",
             };
+        } else {
+            let name_len = env_var_name_len(rest);
+            if name_len > 0 {
+                result.push(&rest[..name_len]);
+            }
+            rest = &rest[name_len..];
         }
     }
-    result
+}
+
+/// The length of the env var name at the start of a string, 0 if it doesn't start with one
+fn env_var_name_len(string: &str) -> usize {
+    match string.as_bytes().first() {
+        Some(first) if first.is_ascii_alphabetic() || *first == b'_' => string
+            .bytes()
+            .take_while(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+            .count(),
+        _ => 0,
+    }
+}
+
+/// The index of the } that closes a ${, skipping nested ${...} and $$
+fn closing_brace(string: &str) -> Option<usize> {
+    let bytes = string.as_bytes();
+    let mut depth = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        match (bytes[index], bytes.get(index + 1)) {
+            (b'$', Some(b'$')) => index += 1,
+            (b'$', Some(b'{')) => {
+                depth += 1;
+                index += 1;
+            }
+            (b'}', _) if depth == 0 => return Some(index),
+            (b'}', _) => depth -= 1,
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_plain_and_braced_vars() {
+        assert_eq!(find_env_vars("$FOO"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("http://$HOST:${PORT}/"), vec!["HOST", "PORT"]);
+        assert_eq!(find_env_vars("${A}${B}$C"), vec!["A", "B", "C"]);
+        assert!(find_env_vars("no vars here").is_empty());
+    }
+
+    #[test]
+    fn names_end_at_invalid_characters() {
+        assert_eq!(find_env_vars("$APP_0"), vec!["APP_0"]);
+        assert_eq!(find_env_vars("$VAR_10_X"), vec!["VAR_10_X"]);
+        assert_eq!(find_env_vars("$_PRIVATE"), vec!["_PRIVATE"]);
+        assert_eq!(find_env_vars("$FOO-bar"), vec!["FOO"]);
+        assert_eq!(find_env_vars("$FOO.$BAR"), vec!["FOO", "BAR"]);
+        assert!(find_env_vars("$[FOO]").is_empty());
+        // Names can't start with a digit
+        assert!(find_env_vars("$1 $0FOO").is_empty());
+    }
+
+    #[test]
+    fn skips_escaped_dollars() {
+        assert!(find_env_vars("$$FOO").is_empty());
+        assert!(find_env_vars("$${FOO}").is_empty());
+        assert_eq!(find_env_vars("$$$FOO"), vec!["FOO"]);
+        assert!(find_env_vars("$$$$FOO").is_empty());
+        assert!(find_env_vars("0 * * * * echo $$HOME").is_empty());
+        assert!(find_env_vars("price: 5$").is_empty());
+    }
+
+    #[test]
+    fn finds_vars_in_modifier_values() {
+        assert_eq!(find_env_vars("${FOO:-default}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO-default}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO:-$BAR}"), vec!["FOO", "BAR"]);
+        assert_eq!(find_env_vars("${FOO:?$BAR is missing}"), vec!["FOO", "BAR"]);
+        assert_eq!(find_env_vars("${FOO?missing}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO:+${BAR}}"), vec!["FOO", "BAR"]);
+        assert_eq!(find_env_vars("${FOO+x}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO:-a-b:c}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO:-$$BAR}"), vec!["FOO"]);
+    }
+
+    #[test]
+    fn finds_vars_in_nested_defaults() {
+        assert_eq!(
+            find_env_vars("${A:-${B:-${C}}}-$D"),
+            vec!["A", "B", "C", "D"]
+        );
+        assert_eq!(find_env_vars("${A:-{}}$B"), vec!["A", "B"]);
+        assert_eq!(find_env_vars("${A:-x}${B:-${C:-y}}"), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn unescapes_values() {
+        assert_eq!(unescape_env_value("$$2y$$10$$abc"), "$2y$10$abc");
+        assert_eq!(unescape_env_value("0 * * * *"), "0 * * * *");
+        assert!(ends_with_escaping_dollar("user:$"));
+        assert!(!ends_with_escaping_dollar("user:$$"));
+        assert!(!ends_with_escaping_dollar("user:"));
+    }
+
+    #[test]
+    fn string_likes_keep_their_type() {
+        let values: BTreeMap<String, StringLike> = serde_yaml::from_str(
+            "INT: 42\nQUOTED_INT: \"42\"\nBOOL: true\nQUOTED_BOOL: \"true\"\nFLOAT: 1.5\nSTRING: bitcoin\n",
+        )
+        .unwrap();
+        assert_eq!(values["INT"], StringLike::Int(42));
+        assert_eq!(values["QUOTED_INT"], StringLike::String("42".to_owned()));
+        assert_eq!(values["BOOL"], StringLike::Bool(true));
+        assert_eq!(values["QUOTED_BOOL"], StringLike::String("true".to_owned()));
+        assert_eq!(values["FLOAT"], StringLike::Float(1.5));
+        assert_eq!(values["STRING"], StringLike::String("bitcoin".to_owned()));
+
+        let serialized = serde_yaml::to_string(&values).unwrap();
+        assert!(serialized.contains("INT: 42\n"));
+        assert!(serialized.contains("QUOTED_INT: '42'\n"));
+        assert!(!serialized.contains('!'));
+        assert_eq!(
+            serde_yaml::from_str::<BTreeMap<String, StringLike>>(&serialized).unwrap(),
+            values
+        );
+
+        let json = serde_json::to_value(&values).unwrap();
+        assert_eq!(json["INT"], serde_json::json!(42));
+        assert_eq!(json["QUOTED_BOOL"], serde_json::json!("true"));
+    }
+
+    #[test]
+    fn round_trips_app_ymls() {
+        use crate::composegenerator::v1::types::AppYml;
+
+        let app_yml: AppYml = serde_yaml::from_str(
+            "
+version: 1
+services:
+  main:
+    image: nextcloud
+    port: 80
+    environment:
+      - NEXTCLOUD_ADMIN_USER=admin
+      - CRON=0 * * * *
+      - EMPTY=
+    sysctls:
+      - net.core.somaxconn=1024
+  db:
+    image: postgres
+    environment:
+      POSTGRES_PORT: 5432
+      POSTGRES_DB: \"5432\"
+      POSTGRES_HOST_AUTH: false
+    sysctls:
+      net.ipv4.tcp_keepalive_time: 600
+metadata: {}
+",
+        )
+        .unwrap();
+        let main = &app_yml.services["main"];
+        assert_eq!(
+            main.environment["CRON"],
+            StringLike::String("0 * * * *".to_owned())
+        );
+        assert_eq!(main.environment["EMPTY"], StringLike::String(String::new()));
+        assert_eq!(
+            main.sysctls["net.core.somaxconn"],
+            StringOrNumber::String("1024".to_owned())
+        );
+        let db = &app_yml.services["db"];
+        assert_eq!(db.environment["POSTGRES_PORT"], StringLike::Int(5432));
+        assert_eq!(
+            db.environment["POSTGRES_DB"],
+            StringLike::String("5432".to_owned())
+        );
+        assert_eq!(
+            db.environment["POSTGRES_HOST_AUTH"],
+            StringLike::Bool(false)
+        );
+        assert_eq!(
+            db.sysctls["net.ipv4.tcp_keepalive_time"],
+            StringOrNumber::Int(600)
+        );
+
+        let serialized = serde_yaml::to_string(&app_yml).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<AppYml>(&serialized).unwrap(),
+            app_yml
+        );
+
+        let passthrough = "version: 1\nservices:\n  main:\n    image: nginx\n    environment: [HOME]\nmetadata: {}\n";
+        assert!(serde_yaml::from_str::<AppYml>(passthrough).is_err());
+    }
+
+    #[test]
+    fn handles_malformed_references() {
+        assert!(find_env_vars("$").is_empty());
+        assert!(find_env_vars("${}").is_empty());
+        assert!(find_env_vars("${").is_empty());
+        assert_eq!(find_env_vars("${FOO"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO:-${BAR"), vec!["FOO", "BAR"]);
+        assert_eq!(find_env_vars("${:-$FOO}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("${FOO BAR}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("}$FOO}"), vec!["FOO"]);
+        assert_eq!(find_env_vars("ü$FÖO ${ÄBC}"), vec!["F"]);
+    }
 }